@@ -0,0 +1,36 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// With the `embedded-translations` feature, lists every `i8n/<locale>/*.ftl` file as `(locale, file name, source)` for
+/// `include!`; the list is empty otherwise.
+fn main() {
+    println!("cargo:rerun-if-changed=i8n");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set"));
+    let mut resources = vec![];
+    if env::var_os("CARGO_FEATURE_EMBEDDED_TRANSLATIONS").is_some() {
+        for locale in sorted_entries(&manifest_dir.join("i8n")).into_iter().filter(|path| path.is_dir()) {
+            let files = sorted_entries(&locale).into_iter()
+                .filter(|file| file.extension().is_some_and(|extension| extension == "ftl"));
+            for file in files {
+                resources.push(format!(
+                    "    ({:?}, {:?}, include_str!({:?})),\n",
+                    locale.file_name().unwrap_or_default().to_string_lossy(),
+                    file.file_name().unwrap_or_default().to_string_lossy(),
+                    file.display().to_string(),
+                ));
+            }
+        }
+    }
+    let out = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set")).join("translations.rs");
+    fs::write(out, format!("&[\n{}]\n", resources.concat())).expect("Failed to write embedded translations");
+}
+
+fn sorted_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", dir.display(), e))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    entries.sort();
+    entries
+}
@@ -1,4 +0,0 @@
-verify_email_subject = Verify your email, { $email }!
-verify_email_body = To verify your email, click on this { $link }
-test = This is a test
-test_with_params = This is a { $param }
@@ -0,0 +1,38 @@
+-brand-name = Oxidize
+verify_email_subject = Verify your email, { $email }!
+verify_email_title = Verify your email
+verify_email_intro = Confirm that { $email } is your email address to finish setting up your account.
+verify_email_button = Verify email
+    .title = Opens the verification page
+verify_email_fallback = If the button does not work, open this link:
+email_change_subject = Confirm your new email address
+email_change_title = Confirm your new email
+email_change_intro = Confirm that { $email } should replace { $old_email } as the email address of your account.
+email_change_button = Confirm email
+    .title = Opens the confirmation page
+email_change_notice_subject = Your email address is being changed
+email_change_notice_title = Email change requested
+email_change_notice_intro = Someone asked to replace { $old_email } with { $email } as the email address of your account. Nothing changes until the new address is confirmed.
+email_change_notice_warning = If it was not you, sign in and cancel the change, then change your keys.
+magic_link_subject = Your sign-in link
+magic_link_title = Sign in
+magic_link_intro = Use this link to sign in, on the device you asked for it from, within { $minutes } minutes. It works once.
+magic_link_button = Sign in
+    .title = Signs you in
+magic_link_warning = If you did not ask for it, you can ignore this email.
+account_locked_subject = Your account was locked
+account_locked_title = Account locked
+account_locked_intro = After { $failures } failed attempts to sign in to your account, signing in is blocked until { $until }.
+account_locked_warning = If it was not you, someone may be trying to guess your credentials. An administrator can unlock your account sooner.
+mail_footer = You received this email because an account was created with this address on { -brand-name }.
+error_400 = Bad request
+error_401 = Authentication required
+error_403 = You are not allowed to do this
+error_404 = Not found
+error_409 = Conflict with the current state
+error_500 = Internal server error
+public_key_invalid = The public key is not a PEM encoded public key
+public_key_unsupported = Unsupported public key, use an RSA, Ed25519 or P-256 key
+public_key_too_short = RSA keys need at least { $min_bits } bits, this one has { $bits }
+test = This is a test
+test_with_params = This is a { $param }
@@ -1,4 +0,0 @@
-verify_email_subject = Verifica el correo, { $email }!
-verify_email_body = Para verificar tu correo electrónico, pulsa en este link { $link }
-test = This is a test
-test_with_params = This is a { $param }
\ No newline at end of file
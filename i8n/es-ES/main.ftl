@@ -0,0 +1,38 @@
+-brand-name = Oxidize
+verify_email_subject = Verifica el correo, { $email }!
+verify_email_title = Verifica tu correo
+verify_email_intro = Confirma que { $email } es tu dirección de correo para terminar de configurar tu cuenta.
+verify_email_button = Verificar correo
+    .title = Abre la página de verificación
+verify_email_fallback = Si el botón no funciona, abre este enlace:
+email_change_subject = Confirma tu nueva dirección de correo
+email_change_title = Confirma tu nuevo correo
+email_change_intro = Confirma que { $email } debe sustituir a { $old_email } como dirección de correo de tu cuenta.
+email_change_button = Confirmar correo
+    .title = Abre la página de confirmación
+email_change_notice_subject = Se está cambiando tu dirección de correo
+email_change_notice_title = Cambio de correo solicitado
+email_change_notice_intro = Alguien ha pedido sustituir { $old_email } por { $email } como dirección de correo de tu cuenta. Nada cambia hasta que se confirme la nueva dirección.
+email_change_notice_warning = Si no has sido tú, inicia sesión y cancela el cambio, y después cambia tus claves.
+magic_link_subject = Tu enlace para iniciar sesión
+magic_link_title = Iniciar sesión
+magic_link_intro = Usa este enlace para iniciar sesión, en el dispositivo desde el que lo pediste, en los próximos { $minutes } minutos. Solo funciona una vez.
+magic_link_button = Iniciar sesión
+    .title = Inicia tu sesión
+magic_link_warning = Si no lo has pedido, puedes ignorar este correo.
+account_locked_subject = Tu cuenta ha sido bloqueada
+account_locked_title = Cuenta bloqueada
+account_locked_intro = Tras { $failures } intentos fallidos de iniciar sesión en tu cuenta, el inicio de sesión queda bloqueado hasta { $until }.
+account_locked_warning = Si no fuiste tú, alguien podría estar intentando adivinar tus credenciales. Un administrador puede desbloquear tu cuenta antes.
+mail_footer = Recibes este correo porque se creó una cuenta con esta dirección en { -brand-name }.
+error_400 = Petición incorrecta
+error_401 = Se requiere autenticación
+error_403 = No tienes permiso para hacer esto
+error_404 = No encontrado
+error_409 = Conflicto con el estado actual
+error_500 = Error interno del servidor
+public_key_invalid = La clave pública no es una clave pública en formato PEM
+public_key_unsupported = Clave pública no soportada, usa una clave RSA, Ed25519 o P-256
+public_key_too_short = Las claves RSA necesitan al menos { $min_bits } bits, esta tiene { $bits }
+test = This is a test
+test_with_params = This is a { $param }
\ No newline at end of file
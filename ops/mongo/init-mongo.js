@@ -1,9 +1,13 @@
 //Create first user for the database based on docker environment variables defined in .env
+//The user can read and write any database, tests create and drop a database of their own.
 db.createUser({
     user:process.env.MONGO_TEST_USER,
     pwd:process.env.MONGO_TEST_PASSWORD,
     roles:[{
         role:"readWrite",
         db:process.env.MONGO_INITDB_DATABASE
+    },{
+        role:"readWriteAnyDatabase",
+        db:"admin"
     }]
-})
\ No newline at end of file
+})
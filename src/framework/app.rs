@@ -1,13 +1,98 @@
 use std::sync::Arc;
-use crate::modules::{self, mail::service::MailOracle};
-use modules::{mongo::service::MongoOracle, user::service::UserService, CRUDMongo};
+use log::{warn, LevelFilter};
+use rocket::fairing::AdHoc;
+use crate::modules::{self, auth::service::AuthService, lockout::service::Lockouts, mail::service::MailOracle};
+use modules::{storage::{migration::Migrator, Storage}, user::service::UserService};
 
+use super::config::{ConfigHandle, ConfigSection};
+use super::watcher::ConfigWatcher;
 use super::{config::OxidizeConfig, translator::OxidizeTranslator};
 
 pub struct App {
+    pub storage: Arc<Storage>,
     pub users:UserService,
-    pub config: Arc<OxidizeConfig>,
-    pub mail: Arc<MailOracle>
+    pub config: ConfigHandle,
+    pub mail: Arc<MailOracle>,
+    pub translator: Arc<OxidizeTranslator>,
+    pub auth: Arc<AuthService>,
+    pub lockouts: Arc<Lockouts>,
+}
+
+/// Configured `log_level`, falling back to the level set through RUST_LOG.
+fn max_log_level(config: &OxidizeConfig) -> LevelFilter {
+    config.log_level_filter()
+        .unwrap_or_else(|| env_logger::Builder::new().parse_default_env().build().filter())
+}
+
+/// Starts the logger. Everything is let through env_logger so `log_level` can be changed at runtime.
+fn init_logger(config: &OxidizeConfig) {
+    let _ = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .try_init();
+    log::set_max_level(max_log_level(config));
+}
+
+/// Logs the keys and arguments that differ between locales, which would otherwise only show up when translated.
+fn check_translations(translator: &OxidizeTranslator) {
+    for issue in translator.check() {
+        warn!("Translation issue: {}", issue);
+    }
+}
+
+/// Watches the configuration files and applies the sections that can change without a restart.
+fn watch_config() -> AdHoc {
+    AdHoc::on_liftoff("Config watcher", |rocket| Box::pin(async move {
+        let app = rocket.state::<App>().expect("Error retrieving app");
+        let mut watcher = ConfigWatcher::new(app.config.clone());
+        // Embedded translations may have no override directory to watch
+        if app.translator.resource_dir().exists() {
+            watcher = watcher.translations(app.translator.resource_dir().to_path_buf());
+        }
+        watcher.spawn();
+
+        let mut logging = app.config.subscribe(ConfigSection::Logging);
+        tokio::spawn(async move {
+            while let Some(config) = logging.changed().await {
+                log::set_max_level(max_log_level(&config));
+            }
+        });
+
+        let mut mail = app.config.subscribe(ConfigSection::Mail);
+        let oracle = app.mail.clone();
+        tokio::spawn(async move {
+            while mail.changed().await.is_some() {
+                oracle.reload_transport();
+            }
+        });
+
+        let mut translations = app.config.subscribe(ConfigSection::Translations);
+        let translator = app.translator.clone();
+        let mail = app.mail.clone();
+        tokio::spawn(async move {
+            while translations.changed().await.is_some() {
+                translator.reload();
+                check_translations(&translator);
+                mail.templates.reload();
+            }
+        });
+    }))
+}
+
+/// Starts sending the queued emails once the server is up.
+fn mail_outbox() -> AdHoc {
+    AdHoc::on_liftoff("Mail outbox", |rocket| Box::pin(async move {
+        let app = rocket.state::<App>().expect("Error retrieving app");
+        app.mail.spawn_outbox_worker();
+    }))
+}
+
+/// Keeps the server signing keys rotated once the server is up.
+fn signing_key_rotation() -> AdHoc {
+    AdHoc::on_liftoff("Signing key rotation", |rocket| Box::pin(async move {
+        let app = rocket.state::<App>().expect("Error retrieving app");
+        app.auth.clone().spawn_key_rotation();
+    }))
 }
 
 /// Creates a valid rocket instance. Input true or false for development mode (testing)
@@ -16,29 +101,45 @@ pub struct App {
 /// # async fn main() {
 ///     use rocket::local::asynchronous::Client;
 ///     use oxidize::framework::app::create_rocket_instance;
+/// #   oxidize::framework::testing::load_test_env();
 ///     let rocket = create_rocket_instance(false).await;
 ///     let client = Client::tracked(rocket).await.expect("valid rocket instance");
 /// # }
 /// ```
-
 pub async fn create_rocket_instance(dev_mode: bool) -> rocket::Rocket<rocket::Build> {
-    env_logger::init();
-    let config = Arc::new(OxidizeConfig::new().expect("Failed to load ENV VARIABLES"));
-    let mongo = Arc::new(MongoOracle::new(config.clone()).await);
-    let users = UserService::new(mongo.clone());
-    if dev_mode {
-        mongo.drop_database().await.expect("Error dropping database");
-        users.initialize_db().await.expect("Error initializing database");
-    }
+    let config = OxidizeConfig::new().expect("Failed to load ENV VARIABLES");
+    create_rocket_instance_from_config(config, dev_mode).await
+}
+
+/// Creates a rocket instance from an already loaded configuration.
+pub async fn create_rocket_instance_from_config(config: OxidizeConfig, dev_mode: bool) -> rocket::Rocket<rocket::Build> {
+    let config = Arc::new(config);
+    init_logger(&config);
+    let storage = Arc::new(Storage::connect(config.clone()).await.expect("Error connecting to storage"));
+    let users = UserService::new(storage.clone());
     let translator = Arc::new(OxidizeTranslator::new(config.clone()));
+    check_translations(&translator);
+    let config = ConfigHandle::new(config);
+
+    let mail = Arc::new(MailOracle::new(config.clone(),storage.clone(), translator.clone()));
+    let lockouts = Arc::new(Lockouts::new(config.clone(), &storage, mail.clone()));
+    let auth = Arc::new(AuthService::new(config.clone(), storage.clone(), mail.clone(), lockouts.clone()).expect("Error creating auth service"));
 
-    let mail = Arc::new(MailOracle::new(config.clone(),mongo.clone(), translator.clone())); 
+    let migrator = Migrator::new(storage.clone(), modules::migrations());
+    if dev_mode {
+        storage.drop_database().await.expect("Error dropping database");
+    }
+    migrator.up().await.expect("Error migrating database");
 
-    
-    let app : App = App { users, config:config.clone(), mail };
+    let app : App = App { storage, users, config, mail, translator, auth, lockouts };
     rocket::build()
         .mount("/", crate::modules::user::controller::get_routes())
         .mount("/", crate::modules::mail::controller::get_routes())
+        .mount("/", crate::modules::auth::controller::get_routes())
+        .mount("/", crate::modules::lockout::controller::get_routes())
+        .register("/", crate::framework::catchers::get_catchers())
+        .attach(watch_config())
+        .attach(mail_outbox())
+        .attach(signing_key_rotation())
         .manage(app)
 }
-
@@ -0,0 +1,249 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use fluent_syntax::ast;
+use fluent_syntax::parser;
+use fluent_syntax::serializer;
+
+/// What a message, attribute or term of a locale needs to be formatted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CatalogEntry {
+    /// Variables it references, without the `$`.
+    pub variables: BTreeSet<String>,
+    /// Messages (`id` or `id.attribute`) and terms (`-id`) it references.
+    pub references: BTreeSet<String>,
+}
+
+/// Keys defined by every locale: `message`, `message.attribute` and `-term`.
+#[derive(Debug, Default, Clone)]
+pub struct Catalog {
+    pub locales: BTreeMap<String, BTreeMap<String, CatalogEntry>>,
+    /// `.ftl` sources of every locale, in the order they were added.
+    sources: BTreeMap<String, Vec<String>>,
+}
+
+/// A difference between locales that makes some of them translate worse than others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranslationIssue {
+    /// `key` exists in other locales but not in `locale`.
+    MissingKey { locale: String, key: String },
+    /// `key` uses other variables in `locale` than in the default locale.
+    ArgumentMismatch { locale: String, key: String, expected: BTreeSet<String>, found: BTreeSet<String> },
+    /// `key` references a message or term that `locale` does not define.
+    UnresolvedReference { locale: String, key: String, reference: String },
+}
+
+impl fmt::Display for TranslationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let variables = |set: &BTreeSet<String>| set.iter().map(|v| format!("${}", v)).collect::<Vec<_>>().join(", ");
+        match self {
+            TranslationIssue::MissingKey { locale, key } => write!(f, "{}: missing {}", locale, key),
+            TranslationIssue::ArgumentMismatch { locale, key, expected, found } =>
+                write!(f, "{}: {} uses [{}] instead of [{}]", locale, key, variables(found), variables(expected)),
+            TranslationIssue::UnresolvedReference { locale, key, reference } =>
+                write!(f, "{}: {} references undefined {}", locale, key, reference),
+        }
+    }
+}
+
+impl Catalog {
+    /// Adds the entries of a `.ftl` source to `locale`. Entries that do not parse are left out.
+    pub fn add_resource(&mut self, locale: &str, source: &str) {
+        let resource = match parser::parse(source) {
+            Ok(resource) => resource,
+            Err((resource, _)) => resource,
+        };
+        self.sources.entry(locale.to_string()).or_default().push(source.to_string());
+        let entries = self.locales.entry(locale.to_string()).or_default();
+        for entry in &resource.body {
+            let (key, value, attributes) = match entry {
+                ast::Entry::Message(message) => (message.id.name.to_string(), message.value.as_ref(), &message.attributes),
+                ast::Entry::Term(term) => (format!("-{}", term.id.name), Some(&term.value), &term.attributes),
+                _ => continue,
+            };
+            if let Some(value) = value {
+                entries.insert(key.clone(), analyze(value));
+            }
+            // Attributes of terms are only usable from selectors, so they are part of the term
+            for attribute in attributes {
+                let analyzed = analyze(&attribute.value);
+                if key.starts_with('-') {
+                    let term = entries.entry(key.clone()).or_default();
+                    term.variables.extend(analyzed.variables);
+                    term.references.extend(analyzed.references);
+                } else {
+                    entries.insert(format!("{}.{}", key, attribute.id.name), analyzed);
+                }
+            }
+        }
+    }
+
+    /// Keys defined in any locale.
+    pub fn keys(&self) -> BTreeSet<&str> {
+        self.locales.values().flat_map(|entries| entries.keys().map(String::as_str)).collect()
+    }
+
+    pub fn get(&self, locale: &str, key: &str) -> Option<&CatalogEntry> {
+        self.locales.get(locale).and_then(|entries| entries.get(key))
+    }
+
+    /// Keys referenced by the messages and terms of any locale.
+    pub fn references(&self) -> BTreeSet<&str> {
+        self.locales.values()
+            .flat_map(|entries| entries.values().flat_map(|entry| entry.references.iter().map(String::as_str)))
+            .collect()
+    }
+
+    /// A `.ftl` resource with the messages and terms of `from` that `locale` lacks, still in the language of `from`
+    /// and marked for translation.
+    pub fn stub(&self, from: &str, locale: &str) -> String {
+        let existing = self.locales.get(locale);
+        let defined = |key: &str| existing.is_some_and(|entries| entries.contains_key(key));
+        let mut stub = String::new();
+        for source in self.sources.get(from).into_iter().flatten() {
+            let resource = match parser::parse(source.as_str()) {
+                Ok(resource) => resource,
+                Err((resource, _)) => resource,
+            };
+            let comment = format!("TODO: translate from {}", from);
+            let body: Vec<ast::Entry<&str>> = resource.body.into_iter()
+                .filter_map(|entry| match entry {
+                    ast::Entry::Message(mut message) if !defined(message.id.name) => {
+                        message.comment = Some(ast::Comment { content: vec![comment.as_str()] });
+                        Some(ast::Entry::Message(message))
+                    }
+                    ast::Entry::Term(mut term) if !defined(&format!("-{}", term.id.name)) => {
+                        term.comment = Some(ast::Comment { content: vec![comment.as_str()] });
+                        Some(ast::Entry::Term(term))
+                    }
+                    _ => None,
+                })
+                .collect();
+            stub.push_str(&serializer::serialize(&ast::Resource { body }));
+        }
+        stub
+    }
+
+    /// Keys missing from a locale, variables that differ from `default_locale` and references to undefined keys.
+    pub fn check(&self, default_locale: &str) -> Vec<TranslationIssue> {
+        let keys = self.keys();
+        let mut issues = vec![];
+        for (locale, entries) in &self.locales {
+            for key in &keys {
+                let Some(entry) = entries.get(*key) else {
+                    issues.push(TranslationIssue::MissingKey { locale: locale.clone(), key: key.to_string() });
+                    continue;
+                };
+                if let Some(expected) = self.get(default_locale, key).filter(|_| locale != default_locale) {
+                    if expected.variables != entry.variables {
+                        issues.push(TranslationIssue::ArgumentMismatch {
+                            locale: locale.clone(),
+                            key: key.to_string(),
+                            expected: expected.variables.clone(),
+                            found: entry.variables.clone(),
+                        });
+                    }
+                }
+                for reference in entry.references.iter().filter(|reference| !entries.contains_key(*reference)) {
+                    issues.push(TranslationIssue::UnresolvedReference {
+                        locale: locale.clone(),
+                        key: key.to_string(),
+                        reference: reference.clone(),
+                    });
+                }
+            }
+        }
+        issues
+    }
+}
+
+fn analyze(pattern: &ast::Pattern<&str>) -> CatalogEntry {
+    let mut entry = CatalogEntry::default();
+    visit_pattern(pattern, &mut entry);
+    entry
+}
+
+fn visit_pattern(pattern: &ast::Pattern<&str>, entry: &mut CatalogEntry) {
+    for element in &pattern.elements {
+        if let ast::PatternElement::Placeable { expression } = element {
+            visit_expression(expression, entry);
+        }
+    }
+}
+
+fn visit_expression(expression: &ast::Expression<&str>, entry: &mut CatalogEntry) {
+    match expression {
+        ast::Expression::Select { selector, variants } => {
+            visit_inline(selector, entry);
+            for variant in variants {
+                visit_pattern(&variant.value, entry);
+            }
+        }
+        ast::Expression::Inline(inline) => visit_inline(inline, entry),
+    }
+}
+
+fn visit_inline(inline: &ast::InlineExpression<&str>, entry: &mut CatalogEntry) {
+    match inline {
+        ast::InlineExpression::VariableReference { id } => {
+            entry.variables.insert(id.name.to_string());
+        }
+        ast::InlineExpression::MessageReference { id, attribute } => {
+            let reference = match attribute {
+                Some(attribute) => format!("{}.{}", id.name, attribute.name),
+                None => id.name.to_string(),
+            };
+            entry.references.insert(reference);
+        }
+        ast::InlineExpression::TermReference { id, arguments, .. } => {
+            entry.references.insert(format!("-{}", id.name));
+            if let Some(arguments) = arguments {
+                visit_arguments(arguments, entry);
+            }
+        }
+        ast::InlineExpression::FunctionReference { arguments, .. } => visit_arguments(arguments, entry),
+        ast::InlineExpression::Placeable { expression } => visit_expression(expression, entry),
+        ast::InlineExpression::StringLiteral { .. } | ast::InlineExpression::NumberLiteral { .. } => {}
+    }
+}
+
+fn visit_arguments(arguments: &ast::CallArguments<&str>, entry: &mut CatalogEntry) {
+    for argument in &arguments.positional {
+        visit_inline(argument, entry);
+    }
+    for argument in &arguments.named {
+        visit_inline(&argument.value, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_check() {
+        let mut catalog = Catalog::default();
+        catalog.add_resource("en-US", "-brand = Oxidize\nhello = Hi { $name }, welcome to { -brand }\n    .title = Welcome\nbye = Bye\n");
+        catalog.add_resource("es-ES", "hello = Hola { $nombre }, bienvenido a { -brand }\n    .title = Bienvenido\n");
+
+        let entry = catalog.get("en-US", "hello").expect("Message not in catalog");
+        assert_eq!(entry.variables, BTreeSet::from([String::from("name")]));
+        assert_eq!(entry.references, BTreeSet::from([String::from("-brand")]));
+        assert!(catalog.get("en-US", "hello.title").is_some());
+
+        let issues: Vec<String> = catalog.check("en-US").iter().map(ToString::to_string).collect();
+        assert_eq!(issues, vec![
+            "es-ES: missing -brand",
+            "es-ES: missing bye",
+            "es-ES: hello uses [$nombre] instead of [$name]",
+            "es-ES: hello references undefined -brand",
+        ]);
+        assert_eq!(catalog.references(), BTreeSet::from(["-brand"]));
+
+        // The stub holds what es-ES lacks, and parses back into the missing keys
+        let stub = catalog.stub("en-US", "es-ES");
+        assert_eq!(stub, "# TODO: translate from en-US\n-brand = Oxidize\n# TODO: translate from en-US\nbye = Bye\n");
+        catalog.add_resource("es-ES", &stub);
+        assert!(catalog.check("en-US").iter().all(|issue| !matches!(issue, TranslationIssue::MissingKey { .. })));
+    }
+}
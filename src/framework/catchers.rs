@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use rocket::http::{Header, Status};
+use rocket::response::{self, status, Responder};
+use rocket::serde::json::Json;
+use rocket::{catch, catchers, Catcher, Request};
+use serde::{Deserialize, Serialize};
+
+use super::app::App;
+use super::translator::{Locale, DEFAULT_LOCALE};
+
+/// Body of the errors raised outside of the handlers, e.g. by a failing guard or an unknown route.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub status: u16,
+    pub error: String,
+}
+
+/// `Retry-After` header for the seconds left until `until`.
+pub fn retry_after(until: DateTime<Utc>) -> Header<'static> {
+    let seconds = (until - Utc::now()).num_seconds().max(1);
+    Header::new("Retry-After", seconds.to_string())
+}
+
+/// When a guard refusing the request with `429 Too Many Requests` lets it be tried again, kept in the request cache
+/// for the catcher to answer in a `Retry-After` header.
+pub struct RetryAfter(pub Option<DateTime<Utc>>);
+
+/// The error body, with the `Retry-After` header of the guard that refused the request, if any.
+pub struct CaughtError {
+    inner: status::Custom<Json<ErrorResponse>>,
+    retry_after: Option<DateTime<Utc>>,
+}
+
+impl<'r> Responder<'r, 'static> for CaughtError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = self.inner.respond_to(request)?;
+        if let Some(until) = self.retry_after {
+            response.set_header(retry_after(until));
+        }
+        Ok(response)
+    }
+}
+
+/// `429 Too Many Requests` with the time left in a `Retry-After` header, and `body` if there is something to tell.
+pub struct TooManyRequests<R = ()> {
+    body: R,
+    until: DateTime<Utc>,
+}
+
+impl TooManyRequests {
+    pub fn until(until: DateTime<Utc>) -> Self {
+        TooManyRequests { body: (), until }
+    }
+}
+
+impl<R> TooManyRequests<R> {
+    pub fn with_body(until: DateTime<Utc>, body: R) -> Self {
+        TooManyRequests { body, until }
+    }
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for TooManyRequests<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = status::Custom(Status::TooManyRequests, self.body).respond_to(request)?;
+        response.set_header(retry_after(self.until));
+        Ok(response)
+    }
+}
+
+/// Describes the error in the locale of the request, using the `error_<code>` message when there is one.
+#[catch(default)]
+pub async fn default_catcher(status: Status, request: &Request<'_>) -> CaughtError {
+    let locale = request.guard::<Locale>().await.succeeded()
+        .map(|locale| locale.id)
+        .unwrap_or_else(|| String::from(DEFAULT_LOCALE));
+    let key = format!("error_{}", status.code);
+    let error = match request.rocket().state::<App>() {
+        Some(app) if app.translator.has_message(&locale, &key) => app.translator.get_in(&locale, &key, None),
+        _ => status.reason().unwrap_or("Error").to_string(),
+    };
+    CaughtError {
+        inner: status::Custom(status, Json(ErrorResponse { status: status.code, error })),
+        retry_after: request.local_cache(|| RetryAfter(None)).0,
+    }
+}
+
+pub fn get_catchers() -> Vec<Catcher> {
+    catchers![default_catcher]
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Header, Status};
+
+    use crate::framework::testing::TestingRuntime;
+
+    use super::ErrorResponse;
+
+    #[tokio::test]
+    async fn test_translated_errors() {
+        let runtime = TestingRuntime::new().await;
+        let response = runtime.client.get("/nowhere").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let body: ErrorResponse = response.into_json().await.expect("Invalid error body");
+        assert_eq!(body, ErrorResponse { status: 404, error: String::from("Not found") });
+
+        let response = runtime.client.get("/mail/verifications/status")
+            .header(Header::new("Accept", "application/json"))
+            .header(Header::new("Accept-Language", "es-AR, en;q=0.5"))
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let body: ErrorResponse = response.into_json().await.expect("Invalid error body");
+        assert_eq!(body.error, "Se requiere autenticación");
+
+        // Statuses without a message fall back to their reason
+        let response = runtime.client.post("/user").header(ContentType::JSON).body("{}").dispatch().await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: ErrorResponse = response.into_json().await.expect("Invalid error body");
+        assert_eq!(body.error, "Unprocessable Entity");
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::modules::{self, storage::{migration::Migrator, Storage}};
+
+use super::config::OxidizeConfig;
+use super::lint;
+use super::translator::{OxidizeTranslator, DEFAULT_LOCALE};
+
+const USAGE: &str = "Usage:
+    oxidize                         Launch the server
+    oxidize migrate [up]            Apply pending migrations
+    oxidize migrate down [steps]    Roll back the last applied migrations (1 by default)
+    oxidize migrate list            List migrations and whether they are applied
+    oxidize translations lint [sources] [templates]
+                                    Check the keys used in the sources (src) and templates (templates) against
+                                    the translations
+    oxidize translations stub <locale>
+                                    Print the messages <locale> lacks as a .ftl resource to translate";
+
+/// Runs a command line subcommand. Returns None when no subcommand was given so the server is launched instead,
+/// otherwise the exit code of the command.
+pub async fn run(args: &[String]) -> Option<i32> {
+    let (command, rest) = args.split_first()?;
+    let result = match command.as_str() {
+        "migrate" => migrate(rest).await,
+        "translations" => translations(rest),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("Unknown command {}\n{}", command, USAGE)),
+    };
+    match result {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            Some(1)
+        }
+    }
+}
+
+async fn migrate(args: &[String]) -> Result<(), String> {
+    let config = Arc::new(OxidizeConfig::new().map_err(|e| format!("Failed to load ENV VARIABLES: {}", e))?);
+    let storage = Arc::new(Storage::connect(config).await.map_err(|e| format!("Error connecting to storage: {}", e))?);
+    let migrator = Migrator::new(storage, modules::migrations());
+
+    match args.first().map(String::as_str) {
+        None | Some("up") => {
+            let applied = migrator.up().await.map_err(|e| format!("Error applying migrations: {}", e))?;
+            println!("Applied {} migration(s) {:?}", applied.len(), applied);
+        }
+        Some("down") => {
+            let steps = match args.get(1) {
+                Some(steps) => steps.parse().map_err(|_| format!("Invalid number of steps: {}", steps))?,
+                None => 1,
+            };
+            let rolled_back = migrator.down(steps).await.map_err(|e| format!("Error rolling back migrations: {}", e))?;
+            println!("Rolled back {} migration(s) {:?}", rolled_back.len(), rolled_back);
+        }
+        Some("list") => {
+            let status = migrator.status().await.map_err(|e| format!("Error listing migrations: {}", e))?;
+            for migration in status {
+                let applied = migration.applied
+                    .map(|date| date.to_string())
+                    .unwrap_or_else(|| String::from("pending"));
+                println!("{:>6}  {:<45} {}", migration.version, migration.name, applied);
+            }
+        }
+        Some(other) => return Err(format!("Unknown migrate command {}\n{}", other, USAGE)),
+    }
+    Ok(())
+}
+
+fn translations(args: &[String]) -> Result<(), String> {
+    let config = Arc::new(OxidizeConfig::new().map_err(|e| format!("Failed to load ENV VARIABLES: {}", e))?);
+    let catalog = OxidizeTranslator::new(config).catalog();
+
+    match args.first().map(String::as_str) {
+        Some("lint") => {
+            let sources = Path::new(args.get(1).map_or("src", String::as_str));
+            let templates = Path::new(args.get(2).map_or("templates", String::as_str));
+            let mut usages = lint::scan_sources(sources).map_err(|e| format!("Error scanning {}: {}", sources.display(), e))?;
+            usages.extend(lint::scan_templates(templates).map_err(|e| format!("Error scanning {}: {}", templates.display(), e))?);
+            let issues = lint::lint(&catalog, &usages, DEFAULT_LOCALE);
+            for issue in &issues {
+                println!("{}  {}", if issue.is_error() { "error  " } else { "warning" }, issue);
+            }
+            let errors = issues.iter().filter(|issue| issue.is_error()).count();
+            println!("{} key(s) in use, {} error(s), {} warning(s)", usages.len(), errors, issues.len() - errors);
+            if errors > 0 {
+                return Err(String::from("Translations are not consistent"));
+            }
+        }
+        Some("stub") => {
+            let locale = args.get(1).ok_or_else(|| format!("Missing locale\n{}", USAGE))?;
+            print!("{}", catalog.stub(DEFAULT_LOCALE, locale));
+        }
+        Some(other) => return Err(format!("Unknown translations command {}\n{}", other, USAGE)),
+        None => return Err(format!("Missing translations command\n{}", USAGE)),
+    }
+    Ok(())
+}
@@ -1,8 +1,12 @@
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use config::{Config, Environment};
+use std::sync::{Arc, RwLock};
+use config::{Config, ConfigError, Environment};
+use log::{info, warn, LevelFilter};
 use rsa::pkcs8::der::zeroize::Zeroizing;
 use serde::{Deserialize, Deserializer};
 use dotenv::dotenv;
+use tokio::sync::broadcast::{self, error::RecvError};
 
 #[derive(Debug, Clone)]
 pub struct ZeroizedString(Zeroizing<String>);
@@ -43,13 +47,156 @@ pub struct OxidizeConfigEnvironment {
     pub mongodb_root_pwd: String,
     pub mongo_test_user: String,
     pub mongo_test_password:String,
+    /// Full connection string, used instead of the host/port/credential settings when present.
+    #[serde(default)]
+    pub mongodb_uri: Option<String>,
+    #[serde(default)]
+    pub mongodb_auth_source: Option<String>,
+    #[serde(default)]
+    pub mongodb_tls: Option<bool>,
+    #[serde(default)]
+    pub mongodb_tls_ca_file: Option<String>,
+    #[serde(default)]
+    pub mongodb_tls_cert_key_file: Option<String>,
+    #[serde(default)]
+    pub mongodb_tls_allow_invalid_certificates: Option<bool>,
+    #[serde(default)]
+    pub mongodb_replica_set: Option<String>,
+    #[serde(default)]
+    pub mongodb_read_concern: Option<String>,
+    #[serde(default)]
+    pub mongodb_write_concern: Option<String>,
+    #[serde(default)]
+    pub mongodb_min_pool_size: Option<u32>,
+    #[serde(default)]
+    pub mongodb_max_pool_size: Option<u32>,
+    #[serde(default)]
+    pub mongodb_connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub mongodb_server_selection_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub mongodb_connect_retries: Option<u32>,
+    #[serde(default)]
+    pub mongodb_connect_backoff_ms: Option<u64>,
+    /// Persistence backend, `mongo` (default) or `memory`.
+    #[serde(default)]
+    pub storage_backend: Option<String>,
     pub default_email_verification_key_length:usize,
     pub email_sender_from:String,
     pub email_reply_to:String,
     pub smtp_user:String,
     pub smtp_password:ZeroizedString,
     pub smtp_host:String,
+    /// Mail transport, `smtp`, `file` or `memory`. Defaults to `file` in dev mode and `smtp` otherwise.
+    #[serde(default)]
+    pub mail_transport: Option<String>,
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    /// `implicit` (default), `starttls` or `none`.
+    #[serde(default)]
+    pub smtp_tls: Option<String>,
+    #[serde(default)]
+    pub smtp_pool_max_size: Option<u32>,
+    #[serde(default)]
+    pub smtp_timeout_ms: Option<u64>,
+    /// Directory of the file transport.
+    #[serde(default)]
+    pub mail_dir: Option<String>,
+    /// `eml` (default) or `maildir`.
+    #[serde(default)]
+    pub mail_dir_format: Option<String>,
+    /// Attempts before an outbox message is dead-lettered, 5 by default.
+    #[serde(default)]
+    pub mail_max_attempts: Option<u32>,
+    /// Delay after the first failed attempt, doubled after each further one.
+    #[serde(default)]
+    pub mail_retry_backoff_ms: Option<u64>,
+    #[serde(default)]
+    pub mail_retry_max_backoff_ms: Option<u64>,
+    /// How often the outbox worker looks for due messages.
+    #[serde(default)]
+    pub mail_outbox_poll_ms: Option<u64>,
     pub run_mode:String,
+    /// Directory with a subdirectory of `.ftl` files per locale, `i8n` by default. Read at boot. With the
+    /// `embedded-translations` feature it is optional and its messages override the embedded ones.
+    #[serde(default)]
+    pub translations_dir: Option<String>,
+    /// Comma separated emails of the users allowed on the admin endpoints.
+    #[serde(default)]
+    pub admin_emails: Option<String>,
+    /// Time a user waits between two verification emails, 60 by default.
+    #[serde(default)]
+    pub verification_resend_cooldown_secs: Option<u64>,
+    /// Verification emails a single IP may ask for in `verification_resend_ip_window_secs`, 10 by default.
+    #[serde(default)]
+    pub verification_resend_ip_limit: Option<u32>,
+    /// 3600 by default.
+    #[serde(default)]
+    pub verification_resend_ip_window_secs: Option<u64>,
+    /// Time the link confirming a new email address stays valid, 86400 by default.
+    #[serde(default)]
+    pub email_change_ttl_secs: Option<u64>,
+    /// Secret encrypting the private halves of the server signing keys at rest, shared by every instance. Read at
+    /// boot. When unset, each instance encrypts with a random secret and can only sign with the keys it created.
+    #[serde(default)]
+    pub auth_signing_key_secret: Option<ZeroizedString>,
+    /// Time a server signing key signs tokens, 30 days by default.
+    #[serde(default)]
+    pub auth_signing_key_rotation_secs: Option<u64>,
+    /// Time the next signing key is published before it signs, and a retired one after it, 86400 by default and at
+    /// least `auth_token_ttl_secs`.
+    #[serde(default)]
+    pub auth_signing_key_overlap_secs: Option<u64>,
+    /// Time a login challenge can be answered, 60 by default.
+    #[serde(default)]
+    pub auth_challenge_ttl_secs: Option<u64>,
+    /// Login challenges a single IP may ask for in `auth_challenge_window_secs`, 30 by default.
+    #[serde(default)]
+    pub auth_challenge_ip_limit: Option<u32>,
+    /// 60 by default.
+    #[serde(default)]
+    pub auth_challenge_window_secs: Option<u64>,
+    /// Lifetime of the tokens issued by the server, 900 by default.
+    #[serde(default)]
+    pub auth_token_ttl_secs: Option<u64>,
+    /// Time a user has to give a TOTP code once the login challenge is signed, 300 by default.
+    #[serde(default)]
+    pub auth_step_up_ttl_secs: Option<u64>,
+    /// Issuer shown by authenticator apps for the TOTP secrets, `Oxidize` by default.
+    #[serde(default)]
+    pub auth_totp_issuer: Option<String>,
+    /// Time a magic link can be followed, 900 by default.
+    #[serde(default)]
+    pub auth_magic_link_ttl_secs: Option<u64>,
+    /// Magic links a single email may ask for in `auth_magic_link_window_secs`, 3 by default.
+    #[serde(default)]
+    pub auth_magic_link_email_limit: Option<u32>,
+    /// Magic links a single IP may ask for in `auth_magic_link_window_secs`, 10 by default.
+    #[serde(default)]
+    pub auth_magic_link_ip_limit: Option<u32>,
+    /// 3600 by default.
+    #[serde(default)]
+    pub auth_magic_link_window_secs: Option<u64>,
+    /// Failed attempts against an account before it is locked, 5 by default.
+    #[serde(default)]
+    pub auth_lockout_account_threshold: Option<u32>,
+    /// Failed attempts from an IP before it is locked, 20 by default.
+    #[serde(default)]
+    pub auth_lockout_ip_threshold: Option<u32>,
+    /// Length of a first lockout, doubled by each following one, 60 by default.
+    #[serde(default)]
+    pub auth_lockout_base_secs: Option<u64>,
+    /// Longest lockout, 86400 by default.
+    #[serde(default)]
+    pub auth_lockout_max_secs: Option<u64>,
+    /// Time without failures after which the failures and lockouts are forgotten, 86400 by default.
+    #[serde(default)]
+    pub auth_lockout_reset_secs: Option<u64>,
+    /// Furthest expiry accepted in the tokens clients sign themselves, 3600 by default.
+    #[serde(default)]
+    pub client_token_max_lifetime_secs: Option<u64>,
+    #[serde(default)]
+    pub log_level: Option<String>,
 }
 
 /// Creates a valid oxidizeConfig 
@@ -62,16 +209,282 @@ pub struct OxidizeConfigEnvironment {
 #[derive(Debug, Deserialize, Clone)]
 pub struct OxidizeConfig {
     pub env: OxidizeConfigEnvironment,
+    /// Env file the configuration was loaded from, if any. Watched for hot reloads.
+    #[serde(skip)]
+    pub env_file: Option<PathBuf>,
+}
+
+/// Groups of settings that modules can subscribe to through a [`ConfigHandle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConfigSection {
+    Server,
+    Mongo,
+    Mail,
+    Logging,
+    Runtime,
+    Translations,
+}
+
+impl ConfigSection {
+    /// Structural sections are only read at boot, changing them requires a restart.
+    pub fn restart_required(&self) -> bool {
+        matches!(self, ConfigSection::Server | ConfigSection::Mongo)
+    }
 }
 
 impl OxidizeConfig{
     pub fn new() -> Result<Self, config::ConfigError> {
-        dotenv().ok();
+        let env_file = dotenv().ok();
         let config = Config::builder()
             .add_source(Environment::default())
             .build()?
             .try_deserialize()?;
-        Ok(Self { env: config })
+        Ok(Self { env: config, env_file })
+    }
+
+    /// Reads the configuration again, values in the env file take precedence over the process environment.
+    // `dotenv::from_path` never overrides variables that are already set, so the file is parsed directly.
+    #[allow(deprecated)]
+    pub fn from_env_file(path: &Path) -> Result<Self, config::ConfigError> {
+        let mut builder = Config::builder().add_source(Environment::default());
+        let pairs = dotenv::from_path_iter(path)
+            .map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+        for pair in pairs {
+            let (key, value) = pair.map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+            builder = builder.set_override(key.to_lowercase(), value)?;
+        }
+        let config = builder.build()?.try_deserialize()?;
+        Ok(Self { env: config, env_file: Some(path.to_path_buf()) })
+    }
+
+    /// Returns the sections whose values differ between both configurations.
+    pub fn changed_sections(&self, other: &OxidizeConfig) -> Vec<ConfigSection> {
+        let (a, b) = (&self.env, &other.env);
+        let mut sections = vec![];
+        if a.default_port != b.default_port
+            || a.translations_dir != b.translations_dir
+            || a.auth_signing_key_secret.as_deref() != b.auth_signing_key_secret.as_deref() {
+            sections.push(ConfigSection::Server);
+        }
+        if a.mongodb_host != b.mongodb_host
+            || a.mongodb_port != b.mongodb_port
+            || a.mongodb_database_name != b.mongodb_database_name
+            || a.mongodb_root_username != b.mongodb_root_username
+            || a.mongodb_root_pwd != b.mongodb_root_pwd
+            || a.mongo_test_user != b.mongo_test_user
+            || a.mongo_test_password != b.mongo_test_password
+            || a.mongodb_uri != b.mongodb_uri
+            || a.mongodb_auth_source != b.mongodb_auth_source
+            || a.mongodb_tls != b.mongodb_tls
+            || a.mongodb_tls_ca_file != b.mongodb_tls_ca_file
+            || a.mongodb_tls_cert_key_file != b.mongodb_tls_cert_key_file
+            || a.mongodb_tls_allow_invalid_certificates != b.mongodb_tls_allow_invalid_certificates
+            || a.mongodb_replica_set != b.mongodb_replica_set
+            || a.mongodb_read_concern != b.mongodb_read_concern
+            || a.mongodb_write_concern != b.mongodb_write_concern
+            || a.mongodb_min_pool_size != b.mongodb_min_pool_size
+            || a.mongodb_max_pool_size != b.mongodb_max_pool_size
+            || a.mongodb_connect_timeout_ms != b.mongodb_connect_timeout_ms
+            || a.mongodb_server_selection_timeout_ms != b.mongodb_server_selection_timeout_ms
+            || a.mongodb_connect_retries != b.mongodb_connect_retries
+            || a.mongodb_connect_backoff_ms != b.mongodb_connect_backoff_ms
+            || a.storage_backend != b.storage_backend {
+            sections.push(ConfigSection::Mongo);
+        }
+        if a.default_email_verification_key_length != b.default_email_verification_key_length
+            || a.email_sender_from != b.email_sender_from
+            || a.email_reply_to != b.email_reply_to
+            || a.smtp_user != b.smtp_user
+            || *a.smtp_password != *b.smtp_password
+            || a.smtp_host != b.smtp_host
+            || a.mail_transport != b.mail_transport
+            || a.smtp_port != b.smtp_port
+            || a.smtp_tls != b.smtp_tls
+            || a.smtp_pool_max_size != b.smtp_pool_max_size
+            || a.smtp_timeout_ms != b.smtp_timeout_ms
+            || a.mail_dir != b.mail_dir
+            || a.mail_dir_format != b.mail_dir_format
+            || a.mail_max_attempts != b.mail_max_attempts
+            || a.mail_retry_backoff_ms != b.mail_retry_backoff_ms
+            || a.mail_retry_max_backoff_ms != b.mail_retry_max_backoff_ms
+            || a.mail_outbox_poll_ms != b.mail_outbox_poll_ms {
+            sections.push(ConfigSection::Mail);
+        }
+        if a.log_level != b.log_level {
+            sections.push(ConfigSection::Logging);
+        }
+        if a.run_mode != b.run_mode
+            || a.admin_emails != b.admin_emails
+            || a.verification_resend_cooldown_secs != b.verification_resend_cooldown_secs
+            || a.verification_resend_ip_limit != b.verification_resend_ip_limit
+            || a.verification_resend_ip_window_secs != b.verification_resend_ip_window_secs
+            || a.email_change_ttl_secs != b.email_change_ttl_secs
+            || a.auth_signing_key_rotation_secs != b.auth_signing_key_rotation_secs
+            || a.auth_signing_key_overlap_secs != b.auth_signing_key_overlap_secs
+            || a.auth_challenge_ttl_secs != b.auth_challenge_ttl_secs
+            || a.auth_challenge_ip_limit != b.auth_challenge_ip_limit
+            || a.auth_challenge_window_secs != b.auth_challenge_window_secs
+            || a.auth_token_ttl_secs != b.auth_token_ttl_secs
+            || a.auth_step_up_ttl_secs != b.auth_step_up_ttl_secs
+            || a.auth_totp_issuer != b.auth_totp_issuer
+            || a.auth_magic_link_ttl_secs != b.auth_magic_link_ttl_secs
+            || a.auth_magic_link_email_limit != b.auth_magic_link_email_limit
+            || a.auth_magic_link_ip_limit != b.auth_magic_link_ip_limit
+            || a.auth_magic_link_window_secs != b.auth_magic_link_window_secs
+            || a.auth_lockout_account_threshold != b.auth_lockout_account_threshold
+            || a.auth_lockout_ip_threshold != b.auth_lockout_ip_threshold
+            || a.auth_lockout_base_secs != b.auth_lockout_base_secs
+            || a.auth_lockout_max_secs != b.auth_lockout_max_secs
+            || a.auth_lockout_reset_secs != b.auth_lockout_reset_secs
+            || a.client_token_max_lifetime_secs != b.client_token_max_lifetime_secs {
+            sections.push(ConfigSection::Runtime);
+        }
+        sections
+    }
+
+    /// Copies the structural (restart-required) values of `previous` into this configuration.
+    fn keep_structural(mut self, previous: &OxidizeConfig) -> Self {
+        let (env, old) = (&mut self.env, &previous.env);
+        env.default_port = old.default_port;
+        env.translations_dir = old.translations_dir.clone();
+        env.auth_signing_key_secret = old.auth_signing_key_secret.clone();
+        env.mongodb_host = old.mongodb_host.clone();
+        env.mongodb_port = old.mongodb_port;
+        env.mongodb_database_name = old.mongodb_database_name.clone();
+        env.mongodb_root_username = old.mongodb_root_username.clone();
+        env.mongodb_root_pwd = old.mongodb_root_pwd.clone();
+        env.mongo_test_user = old.mongo_test_user.clone();
+        env.mongo_test_password = old.mongo_test_password.clone();
+        env.mongodb_uri = old.mongodb_uri.clone();
+        env.mongodb_auth_source = old.mongodb_auth_source.clone();
+        env.mongodb_tls = old.mongodb_tls;
+        env.mongodb_tls_ca_file = old.mongodb_tls_ca_file.clone();
+        env.mongodb_tls_cert_key_file = old.mongodb_tls_cert_key_file.clone();
+        env.mongodb_tls_allow_invalid_certificates = old.mongodb_tls_allow_invalid_certificates;
+        env.mongodb_replica_set = old.mongodb_replica_set.clone();
+        env.mongodb_read_concern = old.mongodb_read_concern.clone();
+        env.mongodb_write_concern = old.mongodb_write_concern.clone();
+        env.mongodb_min_pool_size = old.mongodb_min_pool_size;
+        env.mongodb_max_pool_size = old.mongodb_max_pool_size;
+        env.mongodb_connect_timeout_ms = old.mongodb_connect_timeout_ms;
+        env.mongodb_server_selection_timeout_ms = old.mongodb_server_selection_timeout_ms;
+        env.mongodb_connect_retries = old.mongodb_connect_retries;
+        env.mongodb_connect_backoff_ms = old.mongodb_connect_backoff_ms;
+        env.storage_backend = old.storage_backend.clone();
+        self
+    }
+
+    /// Parses `log_level` (error, warn, info, debug, trace, off) into a filter.
+    pub fn log_level_filter(&self) -> Option<LevelFilter> {
+        self.env.log_level.as_ref().and_then(|level| level.parse().ok())
+    }
+
+    /// Whether `email` is listed in `admin_emails`, compared case-insensitively.
+    pub fn is_admin(&self, email: &str) -> bool {
+        self.env.admin_emails.as_deref().unwrap_or_default()
+            .split(',')
+            .any(|admin| !admin.trim().is_empty() && admin.trim().eq_ignore_ascii_case(email))
+    }
+}
+
+/// Outcome of a configuration reload.
+#[derive(Debug, Default)]
+pub struct ConfigReload {
+    /// Sections whose new values are now live.
+    pub applied: Vec<ConfigSection>,
+    /// Sections that changed on disk but keep their old values until restart.
+    pub restart_required: Vec<ConfigSection>,
+}
+
+/// Shared, swappable access to the live configuration.
+/// ```
+/// use std::sync::Arc;
+/// use oxidize::framework::config::{ConfigHandle, OxidizeConfig};
+/// # oxidize::framework::testing::load_test_env();
+/// let handle = ConfigHandle::new(Arc::new(OxidizeConfig::new().unwrap()));
+/// let port = handle.current().env.default_port;
+/// ```
+#[derive(Clone)]
+pub struct ConfigHandle {
+    current: Arc<RwLock<Arc<OxidizeConfig>>>,
+    events: broadcast::Sender<ConfigSection>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: Arc<OxidizeConfig>) -> Self {
+        let (events, _) = broadcast::channel(16);
+        Self { current: Arc::new(RwLock::new(config)), events }
+    }
+
+    /// Snapshot of the configuration. Hold on to it only for the duration of an operation.
+    pub fn current(&self) -> Arc<OxidizeConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Subscribes to changes of a single section.
+    pub fn subscribe(&self, section: ConfigSection) -> ConfigSubscription {
+        ConfigSubscription { section, events: self.events.subscribe(), current: self.current.clone() }
+    }
+
+    /// Announces a change of a section that does not live in the env file, e.g. translation resources.
+    pub fn notify(&self, section: ConfigSection) {
+        let _ = self.events.send(section);
+    }
+
+    /// Swaps in a new configuration. Structural sections keep their current values.
+    pub fn replace(&self, config: OxidizeConfig) -> ConfigReload {
+        let mut reload = ConfigReload::default();
+        {
+            let mut current = self.current.write().unwrap();
+            for section in current.changed_sections(&config) {
+                if section.restart_required() {
+                    reload.restart_required.push(section);
+                } else {
+                    reload.applied.push(section);
+                }
+            }
+            *current = Arc::new(config.keep_structural(&current));
+        }
+        for section in &reload.restart_required {
+            warn!("Configuration section {:?} changed, restart required to apply it", section);
+        }
+        for section in &reload.applied {
+            info!("Configuration section {:?} reloaded", section);
+            self.notify(*section);
+        }
+        reload
+    }
+
+    /// Reloads the configuration from the env file it was originally loaded from.
+    pub fn reload(&self) -> Result<ConfigReload, ConfigError> {
+        let env_file = self.current().env_file.clone();
+        let config = match env_file {
+            Some(path) => OxidizeConfig::from_env_file(&path)?,
+            None => OxidizeConfig::new()?,
+        };
+        Ok(self.replace(config))
+    }
+}
+
+/// Receives the configuration every time its section changes.
+pub struct ConfigSubscription {
+    section: ConfigSection,
+    events: broadcast::Receiver<ConfigSection>,
+    current: Arc<RwLock<Arc<OxidizeConfig>>>,
+}
+
+impl ConfigSubscription {
+    /// Waits for the next change of the section. Returns None once the handle is gone.
+    pub async fn changed(&mut self) -> Option<Arc<OxidizeConfig>> {
+        loop {
+            match self.events.recv().await {
+                Ok(section) if section == self.section => break,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => break,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+        Some(self.current.read().unwrap().clone())
     }
 }
 
@@ -79,10 +492,12 @@ impl OxidizeConfig{
 mod tests{
     use dotenv::var;
 
+    use crate::framework::testing::load_test_env;
     use super::*;
 
     #[test]
     fn test_load_config() {
+        load_test_env();
         let config = OxidizeConfig::new().expect("Failed to load configuration");
         // Verify that the configuration was loaded correctly
         assert_eq!(config.env.mongodb_host, var("mongodb_host").expect("No MongoDB host found in ENV FILE"));
@@ -103,4 +518,26 @@ mod tests{
         assert_eq!(config.env.smtp_password.to_string(), var("smtp_password").expect("No smtp password found in ENV FILE"));
         assert_eq!(config.env.run_mode.to_string(), var("run_mode").expect("No  run mode found in ENV FILE"));
     }
+
+    #[tokio::test]
+    async fn test_config_handle_reload() {
+        load_test_env();
+        let config = OxidizeConfig::new().expect("Failed to load configuration");
+        let handle = ConfigHandle::new(Arc::new(config.clone()));
+        let mut mail = handle.subscribe(ConfigSection::Mail);
+
+        let mut changed = config.clone();
+        changed.env.smtp_host = String::from("smtp.changed.com");
+        changed.env.mongodb_port = config.env.mongodb_port.wrapping_add(1);
+        let reload = handle.replace(changed);
+
+        // Mail settings are swapped, structural ones wait for a restart
+        assert_eq!(reload.applied, vec![ConfigSection::Mail]);
+        assert_eq!(reload.restart_required, vec![ConfigSection::Mongo]);
+        assert_eq!(handle.current().env.smtp_host, "smtp.changed.com");
+        assert_eq!(handle.current().env.mongodb_port, config.env.mongodb_port);
+
+        let notified = mail.changed().await.expect("Mail subscription closed");
+        assert_eq!(notified.env.smtp_host, "smtp.changed.com");
+    }
 }
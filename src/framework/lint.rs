@@ -0,0 +1,238 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use super::catalog::{Catalog, TranslationIssue};
+
+/// A translation key looked up by the code or the templates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// The key, or the start of the keys built at runtime with `format!("prefix_{}", ..)`.
+    pub key: String,
+    /// Whether `key` is a prefix rather than a whole key.
+    pub prefix: bool,
+    /// Arguments passed along with the key.
+    pub arguments: BTreeSet<String>,
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+/// A problem found comparing the keys in use with the catalog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintIssue {
+    /// `usage` has no translation in `locale`, so it falls back to another locale or to the key.
+    Undefined { locale: String, usage: KeyUsage },
+    /// The translation of `usage` in `locale` uses `variable`, which the call does not pass.
+    MissingArgument { locale: String, variable: String, usage: KeyUsage },
+    /// `key` is defined but neither looked up nor referenced by another message.
+    Unused { key: String },
+    /// Locales differ from each other.
+    Catalog(TranslationIssue),
+}
+
+impl LintIssue {
+    /// Whether the issue shows up as a wrong translation; unused keys are harmless.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, LintIssue::Unused { .. })
+    }
+}
+
+impl fmt::Display for KeyUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintIssue::Undefined { locale, usage } => write!(f, "{}: {} used at {} is not defined", locale, usage.key, usage),
+            LintIssue::MissingArgument { locale, variable, usage } =>
+                write!(f, "{}: {} uses ${} but {} does not pass it", locale, usage.key, variable, usage),
+            LintIssue::Unused { key } => write!(f, "{} is never used", key),
+            LintIssue::Catalog(issue) => issue.fmt(f),
+        }
+    }
+}
+
+/// Files under `dir` with the given extension, sorted.
+fn files(dir: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            found.extend(files(&path, extension)?);
+        } else if path.extension().is_some_and(|ext| ext == extension) {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+/// Keys passed as literals to `get` and `get_in`, and key prefixes built with `format!`, in the `.rs` files of `dir`.
+/// Test modules are left out, as they look up missing keys on purpose.
+pub fn scan_sources(dir: &Path) -> io::Result<Vec<KeyUsage>> {
+    let call = Regex::new(r#"(?s)\.get(?:_in)?\(\s*(?:"[^"]*"\s*,\s*|[^,()"]+,\s*)?"([A-Za-z][\w.-]*)"\s*,\s*(None|Some\(\s*vec!\[(.*?)\]\s*\))"#)
+        .expect("Invalid call pattern");
+    let argument = Regex::new(r#"\(\s*"(\w+)"\s*,"#).expect("Invalid argument pattern");
+    let prefix = Regex::new(r#"format!\(\s*"([a-z][a-z0-9_-]*_)\{"#).expect("Invalid prefix pattern");
+    let mut usages = vec![];
+    for file in files(dir, "rs")? {
+        let source = fs::read_to_string(&file)?;
+        let source = source.split("#[cfg(test)]").next().unwrap_or_default();
+        for captures in call.captures_iter(source) {
+            usages.push(KeyUsage {
+                key: captures[1].to_string(),
+                prefix: false,
+                arguments: captures.get(3)
+                    .map(|arguments| argument.captures_iter(arguments.as_str()).map(|argument| argument[1].to_string()).collect())
+                    .unwrap_or_default(),
+                file: file.clone(),
+                line: line_of(source, captures.get(0).map_or(0, |call| call.start())),
+            });
+        }
+        for captures in prefix.captures_iter(source) {
+            usages.push(KeyUsage {
+                key: captures[1].to_string(),
+                prefix: true,
+                arguments: BTreeSet::new(),
+                file: file.clone(),
+                line: line_of(source, captures.get(0).map_or(0, |call| call.start())),
+            });
+        }
+    }
+    Ok(usages)
+}
+
+/// Keys passed to the `t` function of the `.tera` templates under `dir`.
+pub fn scan_templates(dir: &Path) -> io::Result<Vec<KeyUsage>> {
+    let call = Regex::new(r#"\bt\(\s*key\s*=\s*"([^"]+)"((?:\s*,\s*\w+\s*=\s*[^,)]+)*)\s*\)"#).expect("Invalid call pattern");
+    let argument = Regex::new(r"(\w+)\s*=").expect("Invalid argument pattern");
+    let mut usages = vec![];
+    for file in files(dir, "tera")? {
+        let source = fs::read_to_string(&file)?;
+        for captures in call.captures_iter(&source) {
+            usages.push(KeyUsage {
+                key: captures[1].to_string(),
+                prefix: false,
+                arguments: argument.captures_iter(&captures[2]).map(|argument| argument[1].to_string()).collect(),
+                file: file.clone(),
+                line: line_of(&source, captures.get(0).map_or(0, |call| call.start())),
+            });
+        }
+    }
+    Ok(usages)
+}
+
+/// Compares the keys in use with the catalog: keys undefined in a locale, variables the calls do not pass, keys
+/// nobody uses, and the differences between locales.
+pub fn lint(catalog: &Catalog, usages: &[KeyUsage], default_locale: &str) -> Vec<LintIssue> {
+    let mut issues = vec![];
+    for usage in usages.iter().filter(|usage| !usage.prefix) {
+        for (locale, entries) in &catalog.locales {
+            let Some(entry) = entries.get(&usage.key) else {
+                issues.push(LintIssue::Undefined { locale: locale.clone(), usage: usage.clone() });
+                continue;
+            };
+            for variable in entry.variables.difference(&usage.arguments) {
+                issues.push(LintIssue::MissingArgument { locale: locale.clone(), variable: variable.clone(), usage: usage.clone() });
+            }
+        }
+    }
+
+    let references = catalog.references();
+    let used = |key: &str| {
+        references.contains(key) || usages.iter().any(|usage| match usage.prefix {
+            true => key.starts_with(&usage.key),
+            false => usage.key == key,
+        })
+    };
+    for key in catalog.keys().into_iter().filter(|key| !used(key)) {
+        issues.push(LintIssue::Unused { key: key.to_string() });
+    }
+
+    issues.extend(catalog.check(default_locale).into_iter().map(LintIssue::Catalog));
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use rocket_db_pools::mongodb::bson::oid::ObjectId;
+
+    use crate::framework::catalog::Catalog;
+    use crate::framework::config::OxidizeConfig;
+    use crate::framework::testing::load_test_env;
+    use crate::framework::translator::{OxidizeTranslator, DEFAULT_LOCALE};
+
+    use super::*;
+
+    #[test]
+    fn test_lint() {
+        let dir = std::env::temp_dir().join(format!("oxidize_lint_{}", ObjectId::new().to_hex()));
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        fs::write(dir.join("src/main.rs"), concat!(
+            "fn main() {\n",
+            "    translator.get(\"greeting\", Some(vec![(\"name\", name.into())]));\n",
+            "    translator.get_in(&locale, \"subject\",\n        None);\n",
+            "    let key = format!(\"error_{}\", code);\n",
+            "}\n",
+            "#[cfg(test)]\nmod tests { fn t() { translator.get(\"nonexistent\", None); } }\n",
+        )).unwrap();
+        fs::write(dir.join("src/nested/other.rs"), "translator.get_in(\"es-ES\", \"missing\", None);\n").unwrap();
+        fs::write(dir.join("templates/mail.html.tera"), "{{ t(key=\"body\", email=email) }}\n{{ t(key=\"button.title\") }}\n").unwrap();
+
+        let sources = scan_sources(&dir.join("src")).unwrap();
+        let keys: Vec<(&str, bool, usize)> = sources.iter().map(|usage| (usage.key.as_str(), usage.prefix, usage.line)).collect();
+        assert_eq!(keys, vec![("greeting", false, 2), ("subject", false, 3), ("error_", true, 5), ("missing", false, 1)]);
+        assert_eq!(sources[0].arguments, BTreeSet::from([String::from("name")]));
+        let templates = scan_templates(&dir.join("templates")).unwrap();
+        assert_eq!(templates[0].key, "body");
+        assert_eq!(templates[0].arguments, BTreeSet::from([String::from("email")]));
+        assert_eq!(templates[1].key, "button.title");
+
+        let mut catalog = Catalog::default();
+        catalog.add_resource("en-US", concat!(
+            "greeting = Hi { $name }\nsubject = Welcome { $email }\nbody = { $email } { -brand }\n",
+            "button = Go\n    .title = Go there\n-brand = Oxidize\nerror_404 = Not found\nstale = Old\n",
+        ));
+        let mut usages = sources;
+        usages.extend(templates);
+        let issues: Vec<String> = lint(&catalog, &usages, "en-US").iter()
+            .map(|issue| issue.to_string().replace(&dir.display().to_string(), ""))
+            .collect();
+        assert_eq!(issues, vec![
+            "en-US: subject uses $email but /src/main.rs:3 does not pass it",
+            "en-US: missing used at /src/nested/other.rs:1 is not defined",
+            "button is never used",
+            "stale is never used",
+        ]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_lint_repository() {
+        load_test_env();
+        let translator = OxidizeTranslator::new(Arc::new(OxidizeConfig::new().expect("Error creating config")));
+        let mut usages = scan_sources(Path::new("src")).expect("Error scanning sources");
+        usages.extend(scan_templates(Path::new("templates")).expect("Error scanning templates"));
+        assert!(usages.iter().any(|usage| usage.key == "verify_email_subject"));
+        let errors: Vec<String> = lint(&translator.catalog(), &usages, DEFAULT_LOCALE).iter()
+            .filter(|issue| issue.is_error())
+            .map(ToString::to_string)
+            .collect();
+        assert!(errors.is_empty(), "Translation errors:\n{}", errors.join("\n"));
+    }
+}
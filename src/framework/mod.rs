@@ -2,4 +2,9 @@ pub mod app;
 pub mod testing;
 pub mod auth;
 pub mod config;
-pub mod translator;
\ No newline at end of file
+pub mod translator;
+pub mod watcher;
+pub mod cli;
+pub mod catchers;
+pub mod catalog;
+pub mod lint;
\ No newline at end of file
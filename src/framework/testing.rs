@@ -1,29 +1,183 @@
-use async_once_cell::OnceCell;
+use std::path::Path;
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+
+use log::error;
+use rocket::http::Header;
 use rocket::local::asynchronous::Client;
+use rocket_db_pools::mongodb::bson::oid::ObjectId;
+use rsa::pkcs8::der::zeroize::Zeroizing;
 
-use super::app::create_rocket_instance;
-pub struct TestingRuntime {
-    pub client: Client,
-}
+use crate::modules::mail::transport::Email;
+use crate::modules::mongo::service::MongoOracle;
+use crate::modules::storage::StorageBackend;
+use crate::modules::user::dto::User;
+use super::app::{create_rocket_instance_from_config, App};
+use super::auth::{generate_jwt_token, generate_rsa_key_pair_pem};
+use super::config::OxidizeConfig;
+use super::translator::OxidizeTranslator;
 
 pub trait Mock {
     fn mock() -> Self;
 }
 
-impl TestingRuntime{
-    async fn new() -> Self {
-        let rocket = create_rocket_instance(true).await;
-        let client = Client::tracked(rocket).await.expect("valid rocket instance");
-        Self { client }
+static TEST_ENV: Once = Once::new();
+
+/// Loads the environment for tests: `.env` when present, `.env.dist` otherwise. Tests store their data in memory
+/// unless `test_storage_backend` names another backend, e.g. `test_storage_backend=mongo cargo test`, and
+/// capture emails in memory instead of sending them.
+pub fn load_test_env() {
+    TEST_ENV.call_once(|| {
+        if dotenv::dotenv().is_err() {
+            let _ = dotenv::from_path(Path::new(env!("CARGO_MANIFEST_DIR")).join(".env.dist"));
+        }
+        let backend = std::env::var("test_storage_backend").unwrap_or_else(|_| String::from("memory"));
+        std::env::set_var("storage_backend", backend);
+        std::env::set_var("mail_transport", "memory");
+        // TOTP secrets cannot be encrypted without it
+        if std::env::var("auth_signing_key_secret").is_err() {
+            std::env::set_var("auth_signing_key_secret", "test signing key secret");
+        }
+    });
+}
+
+/// A uniquely named database for a single test, on the test storage backend. It is dropped together with this value.
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+///     use oxidize::framework::testing::TestDatabase;
+///     use oxidize::modules::storage::Storage;
+///     let database = TestDatabase::new();
+///     let storage = Storage::connect(database.config.clone()).await.unwrap();
+/// # }
+/// ```
+pub struct TestDatabase {
+    pub config: Arc<OxidizeConfig>,
+}
+
+impl TestDatabase {
+    pub fn new() -> Self {
+        load_test_env();
+        let mut config = OxidizeConfig::new().expect("Failed to load ENV VARIABLES");
+        let base_name = config.env.mongodb_database_name.clone();
+        // The test user keeps authenticating against the database it was created in
+        if config.env.mongodb_auth_source.is_none() {
+            config.env.mongodb_auth_source = Some(base_name.clone());
+        }
+        config.env.mongodb_database_name = format!("{}_test_{}", base_name, ObjectId::new().to_hex());
+        Self { config: Arc::new(config) }
     }
 
-    pub async fn get() -> &'static TestingRuntime {
-        TEST_RUNTIME.get_or_init(async {
-            TestingRuntime::new().await
-        }).await
+    pub fn name(&self) -> &str {
+        &self.config.env.mongodb_database_name
+    }
+}
+
+impl Default for TestDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        // Memory storage goes away with the instances using it
+        if !matches!(StorageBackend::from_config(&self.config), Ok(StorageBackend::Mongo)) {
+            return;
+        }
+        // The runtime of the test may be shutting down, so the database is dropped from a runtime of its own.
+        let config = self.config.clone();
+        let result = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(async {
+                let mongo = MongoOracle::new(config.clone()).await?;
+                mongo.db.as_ref().expect("Database not initialized").drop(None).await
+            }).map_err(std::io::Error::other)
+        }).join();
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Error dropping test database {}: {}", self.name(), e),
+            Err(_) => error!("Error dropping test database {}", self.name()),
+        }
+    }
+}
+
+/// A user registered in the testing runtime, along with its private key and a valid token.
+pub struct AuthenticatedUser {
+    pub user: User,
+    pub private_key: Zeroizing<String>,
+    pub token: String,
+}
+
+impl AuthenticatedUser {
+    /// Authorization header to attach to requests made on behalf of the user.
+    pub fn header(&self) -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {}", self.token))
     }
 }
 
-static TEST_RUNTIME: OnceCell<TestingRuntime> = OnceCell::new();
+/// Fails when the locales of `translator` have diverging keys, arguments or unresolved references.
+pub fn assert_translations_consistent(translator: &OxidizeTranslator) {
+    let issues: Vec<String> = translator.check().iter().map(ToString::to_string).collect();
+    assert!(issues.is_empty(), "Inconsistent translations in {}:\n{}", translator.resource_dir().display(), issues.join("\n"));
+}
+
+/// A rocket client running against its own database.
+pub struct TestingRuntime {
+    pub client: Client,
+    pub database: TestDatabase,
+}
 
+impl TestingRuntime {
+    pub async fn new() -> Self {
+        let database = TestDatabase::new();
+        let rocket = create_rocket_instance_from_config((*database.config).clone(), true).await;
+        let client = Client::tracked(rocket).await.expect("valid rocket instance");
+        Self { client, database }
+    }
 
+    pub fn app(&self) -> &App {
+        self.client.rocket().state::<App>().expect("No instance of App in testing Runtime")
+    }
+
+    /// Sends the due messages of the outbox and waits for those the background worker is sending.
+    pub async fn flush_outbox(&self) {
+        let mail = &self.app().mail;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            mail.process_outbox().await.expect("Error processing mail outbox");
+            if mail.outbox.in_flight().await.expect("Error reading mail outbox") == 0 || Instant::now() > deadline {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Emails sent so far to `to`, oldest first, after flushing the outbox.
+    pub async fn sent_mails(&self, to: &str) -> Vec<Email> {
+        self.flush_outbox().await;
+        self.app().mail.transport().as_memory().expect("Testing runtime does not capture emails").sent_to(to)
+    }
+
+    /// Registers a fresh mock user and issues a token for it.
+    pub async fn authenticated_user(&self) -> AuthenticatedUser {
+        let (public_key, private_key) = generate_rsa_key_pair_pem();
+        let mut user = User::mock();
+        user.public_key = public_key;
+        user._id = self.app().users.insert(&user, None).await
+            .expect("Error while inserting user")
+            .expect("Mock user already exists")
+            .inserted_id.as_object_id();
+        let token = generate_jwt_token(&user._id.expect("No user id").to_string(), &private_key, chrono::Duration::hours(1))
+            .expect("Error generating token");
+        AuthenticatedUser { user, private_key, token }
+    }
+
+    /// Marks the email of `user` verified, as if the link of the verification email had been followed.
+    pub async fn verify_email(&self, user: &User) {
+        let mail = &self.app().mail;
+        let verification = mail.prepare_verification(user, None).await.expect("Error preparing verification");
+        mail.finish_verification(&verification.user_id, &verification._id.expect("Verification without id"), &verification.secret).await
+            .expect("Error verifying email");
+    }
+}
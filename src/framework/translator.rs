@@ -1,44 +1,255 @@
 use fluent::{FluentArgs, FluentResource, FluentValue};
 use fluent_bundle::bundle::FluentBundle as FluentBundleConcurrent;
+use fluent_syntax::ast::Pattern;
+use log::{error, info, warn};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::sync::Arc;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
+use fluent_langneg::{accepted_languages, convert_vec_str_to_langids_lossy, negotiate_languages, NegotiationStrategy};
 use unic_langid::LanguageIdentifier;
+use super::app::App;
+use super::catalog::{Catalog, TranslationIssue};
 use super::config::OxidizeConfig;
-use std::path::PathBuf;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+type Bundle = FluentBundleConcurrent<FluentResource, intl_memoizer::concurrent::IntlLangMemoizer>;
+
+/// Locale used when nothing better matches the requested one. Its resources must exist.
+pub const DEFAULT_LOCALE: &str = "en-US";
+const DEFAULT_TRANSLATIONS_DIR: &str = "i8n";
+
+/// `.ftl` resources compiled in with the `embedded-translations` feature, as `(locale, file name, source)`.
+const EMBEDDED: &[(&str, &str, &str)] = include!(concat!(env!("OUT_DIR"), "/translations.rs"));
+
+/// Translations of every locale found in `translations_dir`, one bundle per locale directory.
 pub struct OxidizeTranslator{
     pub config : Arc<OxidizeConfig>,
-    bundle: Arc<FluentBundleConcurrent<FluentResource, intl_memoizer::concurrent::IntlLangMemoizer>>
+    dir: PathBuf,
+    /// Resources compiled into the binary. When there are any, `dir` only holds overrides and may not exist.
+    embedded: &'static [(&'static str, &'static str, &'static str)],
+    bundles: RwLock<Arc<Vec<(LanguageIdentifier, Bundle)>>>,
+    catalog: RwLock<Arc<Catalog>>,
+    /// Missing keys and formatting errors already logged, so every problem is logged once per load.
+    reported: Mutex<HashSet<String>>,
+}
+
+/// A `.ftl` file to add to the bundle of a locale.
+struct Source {
+    name: String,
+    text: String,
+    /// Whether its messages replace those of the previous resources instead of clashing with them.
+    overriding: bool,
 }
 
 impl OxidizeTranslator {
-    fn load_resource(path: &str) -> FluentResource {
-        let mut base = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        base.push(path);
-        let source = fs::read_to_string(base).expect("Failed to read localization file");
-        FluentResource::try_new(source).expect("Failed to parse localization file")
+    /// Directory holding a subdirectory of `.ftl` resources per locale, relative to the working directory unless
+    /// `translations_dir` is absolute. With embedded resources its messages override the embedded ones.
+    pub fn resource_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether the resources are compiled into the binary.
+    pub fn is_embedded(&self) -> bool {
+        !self.embedded.is_empty()
+    }
+
+    /// Reads the `.ftl` files of every directory of `dir` named after a locale.
+    fn read_dir(dir: &Path, overriding: bool, sources: &mut BTreeMap<String, Vec<Source>>) -> Result<(), String> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format!("Failed to read localization directory {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| format!("Failed to read localization directory {}: {}", dir.display(), e))?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let mut files: Vec<PathBuf> = fs::read_dir(&path)
+                .map_err(|e| format!("Failed to read localization directory {}: {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| file.extension().is_some_and(|extension| extension == "ftl"))
+                .collect();
+            files.sort();
+            for file in files {
+                let text = fs::read_to_string(&file)
+                    .map_err(|e| format!("Failed to read localization file {}: {}", file.display(), e))?;
+                sources.entry(name.clone()).or_default().push(Source { name: file.display().to_string(), text, overriding });
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds a bundle for every locale, with the embedded resources and all the `.ftl` files of its directory, and
+    /// the catalog of their keys.
+    fn build_bundles(dir: &Path, embedded: &[(&str, &str, &str)]) -> Result<(Vec<(LanguageIdentifier, Bundle)>, Catalog), String> {
+        let mut sources: BTreeMap<String, Vec<Source>> = BTreeMap::new();
+        for (locale, file, text) in embedded {
+            let source = Source { name: format!("embedded {}/{}", locale, file), text: text.to_string(), overriding: false };
+            sources.entry(locale.to_string()).or_default().push(source);
+        }
+        if embedded.is_empty() || dir.exists() {
+            Self::read_dir(dir, !embedded.is_empty(), &mut sources)?;
+        }
+
+        let mut bundles = vec![];
+        let mut catalog = Catalog::default();
+        for (name, sources) in sources {
+            let Ok(langid) = name.parse::<LanguageIdentifier>() else {
+                warn!("Skipping {} in {}, its name is not a locale", name, dir.display());
+                continue;
+            };
+            let mut bundle = FluentBundleConcurrent::new_concurrent(vec![langid.clone()]);
+            for source in sources {
+                catalog.add_resource(&name, &source.text);
+                let resource = FluentResource::try_new(source.text)
+                    .map_err(|_| format!("Failed to parse localization file {}", source.name))?;
+                if source.overriding {
+                    bundle.add_resource_overriding(resource);
+                } else {
+                    bundle.add_resource(resource)
+                        .map_err(|_| format!("Failed to add {}, it redefines messages of {}", source.name, name))?;
+                }
+            }
+            bundles.push((langid, bundle));
+        }
+        if !bundles.iter().any(|(langid, _)| *langid == DEFAULT_LOCALE) {
+            return Err(format!("No resources for the default locale {} in {}", DEFAULT_LOCALE, dir.display()));
+        }
+        Ok((bundles, catalog))
     }
 
     pub fn new(config: Arc<OxidizeConfig>)-> Self {
-         // Load localization files
-        let en_us = Self::load_resource("./i8n/en-US.ftl");
-        let _es_es = Self::load_resource("./i8n/es-ES.ftl");
-
-        // Create FluentBundle with IntlLangMemoizer
-        let langid_en_us: LanguageIdentifier = "en-US".parse().expect("Parsing langid failed");
-        let langid_es_es: LanguageIdentifier = "es-ES".parse().expect("Parsing langid failed");
-        let mut bundle = FluentBundleConcurrent::new_concurrent(vec![langid_en_us, langid_es_es]);
-        bundle.add_resource(en_us).expect("Failed to add en-US resource");
-        //bundle.add_resource(es_es).expect("Failed to add es-ES resource");
-        Self { config: config.clone(), bundle:Arc::new(bundle)}
+        Self::with_embedded(config, EMBEDDED)
+    }
+
+    fn with_embedded(config: Arc<OxidizeConfig>, embedded: &'static [(&'static str, &'static str, &'static str)]) -> Self {
+        let dir = PathBuf::from(config.env.translations_dir.as_deref().unwrap_or(DEFAULT_TRANSLATIONS_DIR));
+        let (bundles, catalog) = Self::build_bundles(&dir, embedded).expect("Failed to load localization files");
+        info!("Loaded {}translations for {}", if embedded.is_empty() { "" } else { "embedded " },
+            bundles.iter().map(|(langid, _)| langid.to_string()).collect::<Vec<_>>().join(", "));
+        Self {
+            config: config.clone(),
+            dir,
+            embedded,
+            bundles: RwLock::new(Arc::new(bundles)),
+            catalog: RwLock::new(Arc::new(catalog)),
+            reported: Mutex::new(HashSet::new()),
+        }
     } 
 
+    /// Loads the localization files again, picking up new locales. The previous resources stay in use if they fail to load.
+    pub fn reload(&self) {
+        match Self::build_bundles(&self.dir, self.embedded) {
+            Ok((bundles, catalog)) => {
+                *self.bundles.write().unwrap() = Arc::new(bundles);
+                *self.catalog.write().unwrap() = Arc::new(catalog);
+                self.reported.lock().unwrap().clear();
+                info!("Localization files reloaded");
+            }
+            Err(e) => error!("Error reloading localization files, keeping the previous ones: {}", e),
+        }
+    }
+
+    /// Locales with resources, sorted.
+    pub fn locales(&self) -> Vec<String> {
+        self.bundles.read().unwrap().iter().map(|(langid, _)| langid.to_string()).collect()
+    }
+
+    /// Keys, variables and references of the loaded resources.
+    pub fn catalog(&self) -> Arc<Catalog> {
+        self.catalog.read().unwrap().clone()
+    }
+
+    /// Keys missing from some locales, arguments that differ from the default locale and unresolved references.
+    pub fn check(&self) -> Vec<TranslationIssue> {
+        self.catalog().check(DEFAULT_LOCALE)
+    }
+
+    /// Whether `key`, or the `message.attribute` it names, can be translated in `locale` or one of its fallbacks.
+    pub fn has_message(&self, locale: &str, key: &str) -> bool {
+        let bundles = self.bundles.read().unwrap().clone();
+        self.fallback_chain(locale).iter()
+            .filter_map(|locale| bundles.iter().find(|(langid, _)| &langid.to_string() == locale))
+            .any(|(_, bundle)| Self::pattern(bundle, key).is_some())
+    }
+
+    /// Pattern of a message, or of one of its attributes when `key` is `message.attribute`.
+    fn pattern<'b>(bundle: &'b Bundle, key: &str) -> Option<&'b Pattern<&'b str>> {
+        let (id, attribute) = match key.split_once('.') {
+            Some((id, attribute)) => (id, Some(attribute)),
+            None => (key, None),
+        };
+        let message = bundle.get_message(id)?;
+        match attribute {
+            Some(attribute) => message.get_attribute(attribute).map(|attribute| attribute.value()),
+            None => message.value(),
+        }
+    }
+
+    /// Logs `problem` unless it was already logged since the resources were loaded.
+    fn report(&self, problem: String) {
+        if self.reported.lock().unwrap().insert(problem.clone()) {
+            warn!("{}", problem);
+        }
+    }
+
+    /// Available locales that can stand in for `requested`, best first, always ending with the default locale.
+    /// ```
+    /// # oxidize::framework::testing::load_test_env();
+    /// # let config = std::sync::Arc::new(oxidize::framework::config::OxidizeConfig::new().unwrap());
+    /// let translator = oxidize::framework::translator::OxidizeTranslator::new(config);
+    /// assert_eq!(translator.fallback_chain("es-MX"), vec!["es-ES", "en-US"]);
+    /// assert_eq!(translator.fallback_chain("fr"), vec!["en-US"]);
+    /// ```
+    pub fn fallback_chain(&self, requested: &str) -> Vec<String> {
+        let bundles = self.bundles.read().unwrap().clone();
+        let available: Vec<&LanguageIdentifier> = bundles.iter().map(|(langid, _)| langid).collect();
+        let default: LanguageIdentifier = DEFAULT_LOCALE.parse().expect("Parsing langid failed");
+        let requested = convert_vec_str_to_langids_lossy([requested]);
+        let mut chain: Vec<String> = negotiate_languages(&requested, &available, Some(&&default), NegotiationStrategy::Filtering)
+            .into_iter()
+            .map(|langid| langid.to_string())
+            .collect();
+        if !chain.iter().any(|locale| locale == DEFAULT_LOCALE) {
+            chain.push(String::from(DEFAULT_LOCALE));
+        }
+        chain
+    }
+
+    /// Best available locale for an `Accept-Language` header, if any of its languages is available.
+    pub fn from_accept_language(&self, header: &str) -> Option<String> {
+        let bundles = self.bundles.read().unwrap().clone();
+        let available: Vec<&LanguageIdentifier> = bundles.iter().map(|(langid, _)| langid).collect();
+        let requested = accepted_languages::parse(header);
+        negotiate_languages(&requested, &available, None, NegotiationStrategy::Filtering)
+            .first()
+            .map(|langid| langid.to_string())
+    }
+
+    /// Translates `str` in the default locale.
     pub fn get(&self, str: &str, params: Option<Vec<(&str, FluentValue)>>) -> String {
-        let msg = self.bundle.get_message(str)
-            .expect("Message doesn't exist.");
-        let mut errors = vec![];
-        let pattern = msg.value()
-            .expect("Message has no value.");
-    
+        self.get_in(DEFAULT_LOCALE, str, params)
+    }
+
+    /// Translates `str`, a message or a `message.attribute`, in `locale`, or in the first locale of its fallback chain
+    /// that has it. Unknown keys translate to the key itself; they and formatting errors are logged once.
+    pub fn get_in(&self, locale: &str, str: &str, params: Option<Vec<(&str, FluentValue)>>) -> String {
+        let bundles = self.bundles.read().unwrap().clone();
+        let chain = self.fallback_chain(locale);
+        let found = chain.iter()
+            .filter_map(|locale| bundles.iter().find(|(langid, _)| &langid.to_string() == locale))
+            .find_map(|(langid, bundle)| Self::pattern(bundle, str).map(|pattern| (langid, bundle, pattern)));
+        let Some((langid, bundle, pattern)) = found else {
+            self.report(format!("Missing translation {} in {}", str, chain.join(", ")));
+            return str.to_string();
+        };
+        if *langid != chain[0].as_str() {
+            self.report(format!("Missing translation {} in {}, using {}", str, chain[0], langid));
+        }
+
         // Convert the vector of parameters into FluentArgs
         let fluent_args = params.map(|params| {
             params.into_iter().fold(FluentArgs::new(), |mut args, (key, value)| {
@@ -46,22 +257,72 @@ impl OxidizeTranslator {
                 args
             })
         });
-    
-        let value = self.bundle.format_pattern(&pattern, fluent_args.as_ref(), &mut errors);
+
+        let mut errors = vec![];
+        let value = bundle.format_pattern(pattern, fluent_args.as_ref(), &mut errors);
+        for e in errors {
+            self.report(format!("Error formatting {} in {}: {}", str, langid, e));
+        }
         value.to_string()
     }
 }
 
+/// Locale of a request, negotiated from its `Accept-Language` header against the available locales.
+/// ```
+/// # use rocket::get;
+/// use oxidize::framework::app::App;
+/// use oxidize::framework::translator::Locale;
+///
+/// #[get("/hello")]
+/// fn hello(app: &rocket::State<App>, locale: Locale) -> String {
+///     app.translator.get_in(&locale, "test", None)
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locale {
+    /// Best available locale, the default one when nothing in the header matched.
+    pub id: String,
+    /// Whether `id` was negotiated from the header rather than being the default.
+    pub negotiated: bool,
+}
+
+impl Deref for Locale {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.id
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Locale {
+    type Error = Infallible;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let app = request.rocket().state::<App>().expect("Error retrieving app");
+        let negotiated = request.headers().get_one("Accept-Language")
+            .and_then(|header| app.translator.from_accept_language(header));
+        Outcome::Success(match negotiated {
+            Some(id) => Locale { id, negotiated: true },
+            None => Locale { id: String::from(DEFAULT_LOCALE), negotiated: false },
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use std::sync::Arc;
 
-    use crate::framework::config::OxidizeConfig;
+    use rocket_db_pools::mongodb::bson::oid::ObjectId;
+
+    use crate::framework::catalog::TranslationIssue;
+    use crate::framework::{config::OxidizeConfig, testing::{assert_translations_consistent, load_test_env}};
 
     use super::OxidizeTranslator;
 
     #[test]
     fn test_oxidize_translator() {
+        load_test_env();
         let config = Arc::new(OxidizeConfig::new().expect("Error creating config"));
         let translator = OxidizeTranslator::new(config);
         let value = translator.get("test", None);
@@ -70,5 +331,133 @@ mod tests {
         let value = translator.get("test_with_params", Some(vec![("param", "param".into())]));
         assert_eq!(&value, "This is a \u{2068}param\u{2069}");
 
+        // Messages come from the requested locale, falling back to the default one
+        let value = translator.get_in("es", "verify_email_title", None);
+        assert_eq!(&value, "Verifica tu correo");
+        let value = translator.get_in("fr-FR", "verify_email_title", None);
+        assert_eq!(&value, "Verify your email");
+        assert_eq!(translator.from_accept_language("fr-CH, es;q=0.8, en;q=0.5").as_deref(), Some("es-ES"));
+        assert_eq!(translator.from_accept_language("fr"), None);
+        assert_eq!(translator.locales(), vec!["en-US", "es-ES"]);
+        assert!(translator.has_message("es-MX", "test"));
+        assert!(!translator.has_message("es-MX", "nonexistent"));
+        assert_translations_consistent(&translator);
+    }
+
+    #[test]
+    fn test_missing_translations() {
+        load_test_env();
+        let config = Arc::new(OxidizeConfig::new().expect("Error creating config"));
+        let translator = OxidizeTranslator::new(config);
+
+        // Unknown keys and attributes fall back to the key instead of failing the request
+        assert_eq!(translator.get_in("es-ES", "nonexistent", None), "nonexistent");
+        assert_eq!(translator.get_in("es-ES", "nonexistent", None), "nonexistent");
+        assert_eq!(translator.get("test.nonexistent", None), "test.nonexistent");
+        assert_eq!(translator.reported.lock().unwrap().len(), 2);
+
+        // Attributes are translated like messages, and terms are resolved
+        assert_eq!(translator.get_in("es-ES", "verify_email_button.title", None), "Abre la página de verificación");
+        assert!(translator.has_message("en-US", "verify_email_button.title"));
+        assert!(translator.get("mail_footer", None).ends_with("on Oxidize."));
+
+        // A missing argument is reported, and rendered as its name
+        assert_eq!(translator.get("test_with_params", None), "This is a \u{2068}{$param}\u{2069}");
+        assert_eq!(translator.reported.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_embedded_translations() {
+        load_test_env();
+        let dir = std::env::temp_dir().join(format!("oxidize_i8n_{}", ObjectId::new().to_hex()));
+        let embedded = &[
+            ("en-US", "main.ftl", "greeting = Hello\nfarewell = Bye\n"),
+            ("es-ES", "main.ftl", "greeting = Hola\n"),
+        ];
+        let mut config = OxidizeConfig::new().expect("Error creating config");
+        config.env.translations_dir = Some(dir.to_string_lossy().to_string());
+        let translator = OxidizeTranslator::with_embedded(Arc::new(config), embedded);
+
+        // The override directory is optional
+        assert!(translator.is_embedded());
+        assert_eq!(translator.locales(), vec!["en-US", "es-ES"]);
+        assert_eq!(translator.get_in("es-ES", "greeting", None), "Hola");
+
+        // Its messages replace the embedded ones, the rest stay
+        fs::create_dir_all(dir.join("en-US")).unwrap();
+        fs::write(dir.join("en-US").join("main.ftl"), "greeting = Hi\n").unwrap();
+        fs::create_dir_all(dir.join("fr")).unwrap();
+        fs::write(dir.join("fr").join("fixes.ftl"), "greeting = Bonjour\n").unwrap();
+        translator.reload();
+        assert_eq!(translator.get("greeting", None), "Hi");
+        assert_eq!(translator.get("farewell", None), "Bye");
+        assert_eq!(translator.get_in("fr", "greeting", None), "Bonjour");
+
+        // Only embedded resources do without the directory
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(OxidizeTranslator::build_bundles(&dir, &[]).is_err());
+        assert!(OxidizeTranslator::build_bundles(&dir, embedded).is_ok());
+    }
+
+    #[test]
+    fn test_diverging_translations() {
+        load_test_env();
+        let dir = std::env::temp_dir().join(format!("oxidize_i8n_{}", ObjectId::new().to_hex()));
+        for (locale, content) in [
+            ("en-US", "-brand = Oxidize\nhello = Hello { $name } from { -brand }\n    .title = Welcome\nbye = Bye\n"),
+            ("es-ES", "hello = Hola { $nombre } desde { -brand }\n    .title = Bienvenida\n"),
+        ] {
+            fs::create_dir_all(dir.join(locale)).unwrap();
+            fs::write(dir.join(locale).join("main.ftl"), content).unwrap();
+        }
+        let mut config = OxidizeConfig::new().expect("Error creating config");
+        config.env.translations_dir = Some(dir.to_string_lossy().to_string());
+        let translator = OxidizeTranslator::with_embedded(Arc::new(config), &[]);
+
+        let issues = translator.check();
+        assert!(issues.contains(&TranslationIssue::MissingKey { locale: String::from("es-ES"), key: String::from("bye") }));
+        assert!(issues.iter().any(|issue| matches!(issue, TranslationIssue::ArgumentMismatch { key, .. } if key == "hello")));
+        assert!(issues.iter().any(|issue| matches!(issue, TranslationIssue::UnresolvedReference { reference, .. } if reference == "-brand")));
+        let consistent = std::panic::catch_unwind(|| assert_translations_consistent(&translator));
+        assert!(consistent.is_err());
+
+        // Missing keys fall back to the default locale
+        assert_eq!(translator.get_in("es-ES", "bye", None), "Bye");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_discovered_locales() {
+        load_test_env();
+        let dir = std::env::temp_dir().join(format!("oxidize_i8n_{}", ObjectId::new().to_hex()));
+        for (locale, file, content) in [
+            ("en-US", "main.ftl", "greeting = Hello\n"),
+            ("en-US", "extra.ftl", "farewell = Bye\n"),
+            ("fr", "main.ftl", "greeting = Bonjour\n"),
+        ] {
+            fs::create_dir_all(dir.join(locale)).unwrap();
+            fs::write(dir.join(locale).join(file), content).unwrap();
+        }
+        fs::create_dir_all(dir.join("not a locale")).unwrap();
+        let mut config = OxidizeConfig::new().expect("Error creating config");
+        config.env.translations_dir = Some(dir.to_string_lossy().to_string());
+        let translator = OxidizeTranslator::with_embedded(Arc::new(config), &[]);
+
+        assert_eq!(translator.resource_dir(), dir.as_path());
+        assert!(!translator.is_embedded());
+        assert_eq!(translator.locales(), vec!["en-US", "fr"]);
+        assert_eq!(translator.get_in("fr-CA", "greeting", None), "Bonjour");
+        assert_eq!(translator.get_in("fr-CA", "farewell", None), "Bye");
+
+        // A new locale is picked up on reload, a broken one keeps the previous resources
+        fs::create_dir_all(dir.join("de")).unwrap();
+        fs::write(dir.join("de").join("main.ftl"), "greeting = Hallo\n").unwrap();
+        translator.reload();
+        assert_eq!(translator.from_accept_language("de-AT").as_deref(), Some("de"));
+        fs::remove_dir_all(dir.join("en-US")).unwrap();
+        translator.reload();
+        assert_eq!(translator.get("farewell", None), "Bye");
+        assert!(OxidizeTranslator::build_bundles(&dir, &[]).is_err());
+        fs::remove_dir_all(dir).unwrap();
     }
 }
\ No newline at end of file
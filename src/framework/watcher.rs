@@ -0,0 +1,145 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::config::{ConfigHandle, ConfigSection};
+
+/// Time given to editors to finish writing a file before it is read again.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+enum WatchEvent {
+    EnvFile,
+    Translations,
+}
+
+/// Reloads the configuration when the env file or the translation resources change on disk,
+/// or when the process receives SIGHUP.
+pub struct ConfigWatcher {
+    handle: ConfigHandle,
+    env_file: Option<PathBuf>,
+    translations_dir: Option<PathBuf>,
+}
+
+impl ConfigWatcher {
+    pub fn new(handle: ConfigHandle) -> Self {
+        let env_file = handle.current().env_file.clone();
+        Self { handle, env_file, translations_dir: None }
+    }
+
+    pub fn translations(mut self, dir: PathBuf) -> Self {
+        self.translations_dir = Some(dir);
+        self
+    }
+
+    fn watch(&self, tx: mpsc::UnboundedSender<WatchEvent>) -> notify::Result<RecommendedWatcher> {
+        let env_file = self.env_file.clone();
+        let translations_dir = self.translations_dir.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let event = match res {
+                Ok(event) if !event.kind.is_access() => event,
+                Ok(_) => return,
+                Err(e) => {
+                    error!("Error watching configuration files: {}", e);
+                    return;
+                }
+            };
+            for path in &event.paths {
+                if env_file.as_deref().is_some_and(|file| same_file_name(file, path)) {
+                    let _ = tx.send(WatchEvent::EnvFile);
+                } else if translations_dir.as_deref().is_some_and(|dir| path.starts_with(dir)) {
+                    let _ = tx.send(WatchEvent::Translations);
+                }
+            }
+        })?;
+        // Editors usually replace files instead of writing them in place, so watch the parent directory.
+        if let Some(dir) = self.env_file.as_deref().and_then(Path::parent) {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        if let Some(dir) = &self.translations_dir {
+            watcher.watch(dir, RecursiveMode::Recursive)?;
+        }
+        Ok(watcher)
+    }
+
+    /// Spawns the watcher on the current tokio runtime.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let _watcher = match self.watch(tx) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    warn!("Configuration files will not be watched: {}", e);
+                    None
+                }
+            };
+            let mut hangup = hangup_signal();
+            loop {
+                let (env_file, translations) = tokio::select! {
+                    Some(event) = rx.recv() => match event {
+                        WatchEvent::EnvFile => (true, false),
+                        WatchEvent::Translations => (false, true),
+                    },
+                    Some(_) = recv_hangup(&mut hangup) => {
+                        info!("SIGHUP received, reloading configuration");
+                        (true, true)
+                    },
+                    else => break,
+                };
+                tokio::time::sleep(DEBOUNCE).await;
+                let (mut env_file, mut translations) = (env_file, translations);
+                while let Ok(event) = rx.try_recv() {
+                    match event {
+                        WatchEvent::EnvFile => env_file = true,
+                        WatchEvent::Translations => translations = true,
+                    }
+                }
+                if env_file {
+                    if let Err(e) = self.handle.reload() {
+                        error!("Error reloading configuration, keeping the previous one: {}", e);
+                    }
+                }
+                if translations {
+                    self.handle.notify(ConfigSection::Translations);
+                }
+            }
+        })
+    }
+}
+
+fn same_file_name(a: &Path, b: &Path) -> bool {
+    a.file_name().is_some() && a.file_name() == b.file_name() && a.parent() == b.parent()
+}
+
+#[cfg(unix)]
+fn hangup_signal() -> Option<tokio::signal::unix::Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            warn!("Could not listen for SIGHUP: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Option<()> {
+    None
+}
+
+#[cfg(unix)]
+async fn recv_hangup(signal: &mut Option<tokio::signal::unix::Signal>) -> Option<()> {
+    match signal {
+        Some(signal) => signal.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn recv_hangup(_: &mut Option<()>) -> Option<()> {
+    std::future::pending().await
+}
@@ -1,12 +1,16 @@
-#[macro_use] 
-extern crate rocket;
 use framework::app::create_rocket_instance;
+use framework::cli;
 use oxidize::framework;
 
 
-#[launch]
-async fn rocket() -> _ {
-    create_rocket_instance(false).await
+#[rocket::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = cli::run(&args).await {
+        std::process::exit(code);
+    }
+    if let Err(e) = create_rocket_instance(false).await.launch().await {
+        eprintln!("Error launching rocket: {}", e);
+        std::process::exit(1);
+    }
 }
-
-
@@ -0,0 +1,42 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use rsa::pkcs8::der::zeroize::Zeroizing;
+
+/// Encrypts the secrets kept in the storage with AES-256-GCM, under a key derived from `auth_signing_key_secret`
+/// and a `purpose`, so each kind of secret has a key of its own.
+pub struct SecretCipher {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl SecretCipher {
+    pub fn new(secret: &[u8], purpose: &[u8]) -> Self {
+        let prk = Salt::new(HKDF_SHA256, purpose).extract(secret);
+        let okm = prk.expand(&[b"aes-256-gcm"], &AES_256_GCM).expect("Error deriving an encryption key");
+        Self { key: LessSafeKey::new(UnboundKey::from(okm)), rng: SystemRandom::new() }
+    }
+
+    /// Base64url of a random nonce followed by the ciphertext of `plaintext`, bound to `aad`.
+    pub fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).expect("Error generating a nonce");
+        let mut sealed = plaintext.to_vec();
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut sealed)
+            .expect("Error encrypting a secret");
+        URL_SAFE_NO_PAD.encode([nonce.as_slice(), &sealed].concat())
+    }
+
+    /// The plaintext of `sealed`, None if it was encrypted with another secret or for another `aad`.
+    pub fn decrypt(&self, aad: &[u8], sealed: &str) -> Option<Zeroizing<Vec<u8>>> {
+        let mut sealed = Zeroizing::new(URL_SAFE_NO_PAD.decode(sealed).ok()?);
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).ok()?;
+        let plaintext = self.key.open_in_place(nonce, Aad::from(aad), &mut sealed[NONCE_LEN..]).ok()?;
+        Some(Zeroizing::new(plaintext.to_vec()))
+    }
+}
@@ -0,0 +1,252 @@
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Utc;
+use log::{error, warn};
+use jsonwebtoken::jwk::JwkSet;
+use rand::RngCore;
+use rocket::{delete, get, post, routes, Route, State};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket_db_pools::mongodb::bson::oid::ObjectId;
+
+use crate::framework::app::App;
+use crate::framework::catchers::TooManyRequests;
+use crate::modules::lockout::service::Locked;
+use crate::modules::user::guard::{AdminSession, TokenSession};
+
+use super::dto::{ChallengeAnswer, ChallengeRequest, IssuedChallenge, IssuedToken, LoginResponse, MagicLinkRequest, MagicLinkSent, RecoveryCodes, StepUpAnswer, TotpCode, TotpEnrollment};
+use super::service::MagicLinkError;
+
+const DEFAULT_TOTP_ISSUER: &str = "Oxidize";
+const DEFAULT_CHALLENGE_IP_LIMIT: u32 = 30;
+const DEFAULT_CHALLENGE_WINDOW_SECS: u64 = 60;
+/// Random id of the browser asking for magic links, which only work in that browser.
+pub const DEVICE_COOKIE: &str = "oxidize_device";
+const DEVICE_BYTES: usize = 32;
+
+/// Hands out a nonce to be signed with the key of the user owning `email`. A single IP may ask for
+/// `auth_challenge_ip_limit` challenges per `auth_challenge_window_secs`.
+#[post("/auth/challenge", format = "application/json", data = "<request>")]
+pub async fn create_challenge(app: &State<App>, request: Json<ChallengeRequest>, ip: Option<IpAddr>)
+    -> Result<status::Custom<Json<Option<IssuedChallenge>>>, TooManyRequests> {
+    let env = &app.config.current().env;
+    let key = format!("challenge:ip:{}", ip.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("unknown")));
+    let window = Duration::from_secs(env.auth_challenge_window_secs.unwrap_or(DEFAULT_CHALLENGE_WINDOW_SECS));
+    match app.auth.throttle.hit(&key, env.auth_challenge_ip_limit.unwrap_or(DEFAULT_CHALLENGE_IP_LIMIT), window).await {
+        Ok(Some(until)) => return Err(TooManyRequests::until(until)),
+        Ok(None) => {}
+        Err(e) => {
+            error!("Error counting challenges of {}: {}", key, e);
+            return Ok(status::Custom(Status::InternalServerError, Json::from(None)));
+        }
+    }
+    Ok(match app.auth.create_challenge(&request.email).await {
+        Ok(challenge) => status::Custom(Status::Created, Json::from(Some(IssuedChallenge::from(&challenge)))),
+        Err(e) => {
+            error!("Error creating challenge for {}: {}", request.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    })
+}
+
+/// Exchanges a signed nonce for a short-lived token signed by the server, or for a step-up challenge answered with
+/// `/auth/challenge/step-up` when the user enabled TOTP.
+#[post("/auth/challenge/verify", format = "application/json", data = "<answer>")]
+pub async fn verify_challenge(app: &State<App>, answer: Json<ChallengeAnswer>, ip: Option<IpAddr>) -> Result<status::Custom<Json<Option<LoginResponse>>>, TooManyRequests> {
+    let Ok(challenge_id) = ObjectId::parse_str(&answer.challenge_id) else {
+        return Ok(status::Custom(Status::BadRequest, Json::from(None)));
+    };
+    Ok(match app.auth.answer_challenge(&challenge_id, answer.kid.as_deref(), &answer.signature, ip).await {
+        Ok(response @ LoginResponse::Token(_)) => status::Custom(Status::Ok, Json::from(Some(response))),
+        Ok(response @ LoginResponse::StepUp { .. }) => status::Custom(Status::Accepted, Json::from(Some(response))),
+        Err(err) => {
+            if let Some(locked) = Locked::from_io(&err) {
+                return Err(locked.into());
+            } else if err.kind() == io::ErrorKind::NotFound {
+                status::Custom(Status::NotFound, Json::from(None))
+            } else if err.kind() == io::ErrorKind::PermissionDenied {
+                status::Custom(Status::Unauthorized, Json::from(None))
+            } else {
+                error!("Error verifying challenge {}: {}", challenge_id, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        }
+    })
+}
+
+/// Exchanges the nonce of a step-up challenge and a TOTP or recovery code for a token signed by the server.
+#[post("/auth/challenge/step-up", format = "application/json", data = "<answer>")]
+pub async fn verify_step_up(app: &State<App>, answer: Json<StepUpAnswer>, ip: Option<IpAddr>) -> Result<status::Custom<Json<Option<IssuedToken>>>, TooManyRequests> {
+    let Ok(challenge_id) = ObjectId::parse_str(&answer.challenge_id) else {
+        return Ok(status::Custom(Status::BadRequest, Json::from(None)));
+    };
+    Ok(match app.auth.answer_step_up(&challenge_id, &answer.nonce, &answer.code, ip).await {
+        Ok(token) => status::Custom(Status::Ok, Json::from(Some(token))),
+        Err(err) => {
+            if let Some(locked) = Locked::from_io(&err) {
+                return Err(locked.into());
+            } else if err.kind() == io::ErrorKind::NotFound {
+                status::Custom(Status::NotFound, Json::from(None))
+            } else if err.kind() == io::ErrorKind::PermissionDenied {
+                status::Custom(Status::Unauthorized, Json::from(None))
+            } else {
+                error!("Error verifying step-up challenge {}: {}", challenge_id, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        }
+    })
+}
+
+/// Generates a TOTP secret for the signed in user, enforced once confirmed with `/auth/totp/confirm`. Replaces a
+/// secret not confirmed yet.
+#[post("/auth/totp")]
+pub async fn enroll_totp(app: &State<App>, token: TokenSession) -> status::Custom<Json<Option<TotpEnrollment>>> {
+    let issuer = app.config.current().env.auth_totp_issuer.clone().unwrap_or_else(|| String::from(DEFAULT_TOTP_ISSUER));
+    match app.auth.two_factor.enroll(&token.session.user, &issuer).await {
+        Ok(enrollment) => status::Custom(Status::Created, Json::from(Some(enrollment))),
+        Err(err) => match err.kind() {
+            io::ErrorKind::AlreadyExists => status::Custom(Status::Conflict, Json::from(None)),
+            io::ErrorKind::Unsupported => status::Custom(Status::ServiceUnavailable, Json::from(None)),
+            _ => {
+                error!("Error enrolling TOTP for user {}: {}", token.session.user.email, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    }
+}
+
+/// Enables the pending TOTP secret of the signed in user with a first code, and hands out its recovery codes.
+#[post("/auth/totp/confirm", format = "application/json", data = "<code>")]
+pub async fn confirm_totp(app: &State<App>, code: Json<TotpCode>, token: TokenSession) -> status::Custom<Json<Option<RecoveryCodes>>> {
+    match app.auth.two_factor.confirm(&token.session.user._id.unwrap(), &code.code).await {
+        Ok(codes) => status::Custom(Status::Ok, Json::from(Some(codes))),
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => status::Custom(Status::NotFound, Json::from(None)),
+            io::ErrorKind::PermissionDenied => status::Custom(Status::BadRequest, Json::from(None)),
+            _ => {
+                error!("Error confirming TOTP for user {}: {}", token.session.user.email, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    }
+}
+
+/// Disables TOTP for the signed in user, given a current TOTP or recovery code. Wrong codes count against the user
+/// like those of the step-up challenges.
+#[delete("/auth/totp", format = "application/json", data = "<code>")]
+pub async fn disable_totp(app: &State<App>, code: Json<TotpCode>, token: TokenSession, ip: Option<IpAddr>) -> Result<status::Custom<Json<Option<bool>>>, TooManyRequests> {
+    let user_id = token.session.user._id.unwrap();
+    let attempt = async {
+        match app.auth.two_factor.verify(&user_id, &code.code).await? {
+            true => app.auth.two_factor.disable(&user_id).await.map_err(io::Error::other),
+            false => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Invalid code")),
+        }
+    };
+    Ok(match app.lockouts.attempt(Some(&user_id), ip, &[io::ErrorKind::PermissionDenied], attempt).await {
+        Ok(_) => status::Custom(Status::Ok, Json::from(Some(true))),
+        Err(err) => match (Locked::from_io(&err), err.kind()) {
+            (Some(locked), _) => return Err(locked.into()),
+            (None, io::ErrorKind::PermissionDenied) => status::Custom(Status::BadRequest, Json::from(None)),
+            _ => {
+                error!("Error disabling TOTP for user {}: {}", token.session.user.email, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    })
+}
+
+/// Removes the TOTP factor of a user who lost it, along with its recovery codes. Admins only.
+#[delete("/auth/totp/<user_id>")]
+pub async fn reset_totp(app: &State<App>, user_id: &str, admin: AdminSession) -> status::Custom<Json<Option<bool>>> {
+    let Ok(user_id) = ObjectId::parse_str(user_id) else {
+        return status::Custom(Status::BadRequest, Json::from(None));
+    };
+    match app.auth.two_factor.disable(&user_id).await {
+        Ok(true) => {
+            warn!("TOTP of user {} reset by {}", user_id, admin.session.user.email);
+            status::Custom(Status::Ok, Json::from(Some(true)))
+        }
+        Ok(false) => status::Custom(Status::NotFound, Json::from(None)),
+        Err(e) => {
+            error!("Error resetting TOTP of user {}: {}", user_id, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Emails a sign-in link to the account of `email`, if there is one. The link only works in the browser that asked for
+/// it, identified by the `oxidize_device` cookie set here.
+#[post("/auth/magic-link", format = "application/json", data = "<request>")]
+pub async fn request_magic_link(app: &State<App>, request: Json<MagicLinkRequest>, cookies: &CookieJar<'_>, ip: Option<IpAddr>)
+    -> Result<status::Custom<Json<Option<MagicLinkSent>>>, TooManyRequests> {
+    let device = match cookies.get(DEVICE_COOKIE).map(|cookie| cookie.value().to_string()) {
+        Some(device) if !device.is_empty() => device,
+        _ => {
+            let mut device = [0u8; DEVICE_BYTES];
+            rand::thread_rng().fill_bytes(&mut device);
+            URL_SAFE_NO_PAD.encode(device)
+        }
+    };
+    match app.auth.request_magic_link(&request.email, &device, ip).await {
+        Ok(expires) => {
+            let max_age = rocket::time::Duration::seconds((expires - Utc::now()).num_seconds().max(0));
+            cookies.add(Cookie::build((DEVICE_COOKIE, device))
+                .path("/auth/magic-link")
+                .http_only(true)
+                .secure(true)
+                // Lax, so the cookie comes along when the link is opened from a webmail
+                .same_site(SameSite::Lax)
+                .max_age(max_age));
+            Ok(status::Custom(Status::Accepted, Json::from(Some(MagicLinkSent { expires }))))
+        }
+        Err(MagicLinkError::Throttled(until)) => Err(TooManyRequests::until(until)),
+        Err(MagicLinkError::Storage(e)) => {
+            error!("Error sending magic link to {}: {}", request.email, e);
+            Ok(status::Custom(Status::InternalServerError, Json::from(None)))
+        }
+    }
+}
+
+/// Exchanges a magic link for a token signed by the server, or for a step-up challenge when the user enabled TOTP.
+#[get("/auth/magic-link/<id>/<secret>")]
+pub async fn redeem_magic_link(app: &State<App>, id: &str, secret: &str, cookies: &CookieJar<'_>, ip: Option<IpAddr>)
+    -> Result<status::Custom<Json<Option<LoginResponse>>>, TooManyRequests> {
+    let Ok(link_id) = ObjectId::parse_str(id) else {
+        return Ok(status::Custom(Status::BadRequest, Json::from(None)));
+    };
+    let device = cookies.get(DEVICE_COOKIE).map(|cookie| cookie.value().to_string());
+    Ok(match app.auth.redeem_magic_link(&link_id, secret, device.as_deref(), ip).await {
+        Ok(response @ LoginResponse::Token(_)) => status::Custom(Status::Ok, Json::from(Some(response))),
+        Ok(response @ LoginResponse::StepUp { .. }) => status::Custom(Status::Accepted, Json::from(Some(response))),
+        Err(err) => match (Locked::from_io(&err), err.kind()) {
+            (Some(locked), _) => return Err(locked.into()),
+            (None, io::ErrorKind::NotFound) => status::Custom(Status::NotFound, Json::from(None)),
+            (None, io::ErrorKind::PermissionDenied) => status::Custom(Status::Forbidden, Json::from(None)),
+            _ => {
+                error!("Error redeeming magic link {}: {}", link_id, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    })
+}
+
+/// The public keys of the server tokens: the active signing key, the next one and the recently retired ones.
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(app: &State<App>) -> status::Custom<Json<Option<JwkSet>>> {
+    match app.auth.signing_keys.jwks().await {
+        Ok(jwks) => status::Custom(Status::Ok, Json::from(Some(jwks))),
+        Err(e) => {
+            error!("Error listing signing keys: {}", e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+pub fn get_routes() -> Vec<Route> {
+    routes![create_challenge, verify_challenge, verify_step_up, request_magic_link, redeem_magic_link, enroll_totp, confirm_totp, disable_totp, reset_totp, jwks]
+}
@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use rocket::serde::{Deserialize, Serialize};
+use rocket_db_pools::mongodb::bson::{self, oid::ObjectId};
+
+/// A nonce handed out for `email`, to be signed with the key of that user. It can be answered once.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Challenge {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub email: String,
+    /// Set on step-up challenges, handed out to the user who signed a challenge but still has to give a second
+    /// factor. Their nonce is sent back along with the code rather than signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<ObjectId>,
+    pub nonce: String,
+    pub created: DateTime<Utc>,
+    /// The challenge is dropped after this date.
+    pub expires: bson::DateTime,
+}
+
+/// Body of a challenge request.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ChallengeRequest {
+    pub email: String,
+}
+
+/// A challenge as handed to the client.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct IssuedChallenge {
+    /// Hex id of the challenge, sent back along with the signature.
+    pub challenge_id: String,
+    pub nonce: String,
+    pub expires: DateTime<Utc>,
+}
+
+impl From<&Challenge> for IssuedChallenge {
+    fn from(challenge: &Challenge) -> Self {
+        IssuedChallenge {
+            challenge_id: challenge._id.map(|id| id.to_hex()).unwrap_or_default(),
+            nonce: challenge.nonce.clone(),
+            expires: DateTime::from_timestamp_millis(challenge.expires.timestamp_millis()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Answer to a challenge: the nonce signed with the private key of the user as in a JWS signature, with the algorithm
+/// of the key (RS512, EdDSA or ES256) and in base64url without padding.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ChallengeAnswer {
+    pub challenge_id: String,
+    /// Registered key that signed the nonce, the signup key of the user when absent.
+    #[serde(default)]
+    pub kid: Option<String>,
+    pub signature: String,
+}
+
+/// A token signed by the server, to be sent as `Authorization: Bearer <token>`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct IssuedToken {
+    pub token: String,
+    pub token_type: String,
+    /// Seconds the token stays valid.
+    pub expires_in: u64,
+}
+
+/// Outcome of a signed challenge: a token, or a step-up challenge when the user enabled two-factor authentication.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Token(IssuedToken),
+    StepUp { step_up: IssuedChallenge },
+}
+
+/// Answer to a step-up challenge: its nonce and a TOTP or recovery code.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StepUpAnswer {
+    pub challenge_id: String,
+    pub nonce: String,
+    pub code: String,
+}
+
+/// A TOTP or recovery code.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+/// A TOTP secret waiting for its first code, as handed to the user.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct TotpEnrollment {
+    /// The secret in base32, for authenticator apps that cannot read `provisioning_uri`.
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code.
+    pub provisioning_uri: String,
+}
+
+/// Recovery codes of a user, shown once. Each one can replace a TOTP code a single time.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// A sign-in link sent by email. It can be followed once, from the device that asked for it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MagicLink {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    /// Base64url SHA-256 digest of the secret of the link.
+    pub secret: String,
+    /// Base64url SHA-256 digest of the device cookie of the browser that asked for the link.
+    pub device: String,
+    pub created: DateTime<Utc>,
+    /// The link is dropped after this date.
+    pub expires: bson::DateTime,
+}
+
+/// Body of a magic link request.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+/// Answer to a magic link request, the same whether the email belongs to an account or not.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct MagicLinkSent {
+    /// When the link stops working.
+    pub expires: DateTime<Utc>,
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rocket_db_pools::mongodb::bson::{doc, Document};
+
+use crate::modules::storage::migration::Migration;
+use crate::modules::storage::{Index, Storage, StorageError};
+use super::service::{CHALLENGES_COLLECTION, MAGIC_LINKS_COLLECTION};
+use super::signing_keys::SIGNING_KEYS_COLLECTION;
+
+/// Drops login challenges once they expire.
+pub struct CreateChallengesExpiryIndex;
+
+#[async_trait]
+impl Migration for CreateChallengesExpiryIndex {
+    fn version(&self) -> i64 { 6 }
+
+    fn name(&self) -> &'static str { "create_challenges_expires_index" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let index = Index::new("expires_1", doc! { "expires": 1 }).expire_after(Duration::ZERO);
+        storage.collection::<Document>(CHALLENGES_COLLECTION).create_index(index).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>(CHALLENGES_COLLECTION).drop_index("expires_1").await
+    }
+}
+
+/// Drops signing keys once they are no longer published.
+pub struct CreateSigningKeysExpiryIndex;
+
+#[async_trait]
+impl Migration for CreateSigningKeysExpiryIndex {
+    fn version(&self) -> i64 { 8 }
+
+    fn name(&self) -> &'static str { "create_signing_keys_expires_index" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let index = Index::new("expires_1", doc! { "expires": 1 }).expire_after(Duration::ZERO);
+        storage.collection::<Document>(SIGNING_KEYS_COLLECTION).create_index(index).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>(SIGNING_KEYS_COLLECTION).drop_index("expires_1").await
+    }
+}
+
+/// Drops magic links once they expire.
+pub struct CreateMagicLinksExpiryIndex;
+
+#[async_trait]
+impl Migration for CreateMagicLinksExpiryIndex {
+    fn version(&self) -> i64 { 9 }
+
+    fn name(&self) -> &'static str { "create_magic_links_expires_index" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let index = Index::new("expires_1", doc! { "expires": 1 }).expire_after(Duration::ZERO);
+        storage.collection::<Document>(MAGIC_LINKS_COLLECTION).create_index(index).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>(MAGIC_LINKS_COLLECTION).drop_index("expires_1").await
+    }
+}
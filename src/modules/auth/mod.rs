@@ -0,0 +1,7 @@
+pub mod service;
+pub mod controller;
+pub mod dto;
+pub mod migrations;
+pub mod signing_keys;
+pub mod cipher;
+pub mod totp;
@@ -0,0 +1,418 @@
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{crypto, decode_header};
+use log::{error, warn};
+use rand::RngCore;
+use rocket::uri;
+use rocket_db_pools::mongodb::bson::{self, doc, oid::ObjectId};
+
+use crate::framework::auth::{decode_server_token, generate_server_token, secret_digest, verifying_key, Claims};
+use crate::framework::config::ConfigHandle;
+use crate::modules::lockout::service::Lockouts;
+use crate::modules::mail::service::MailOracle;
+use crate::modules::storage::{Collection, Storage, StorageError};
+use crate::modules::throttle::service::Throttle;
+use crate::modules::user::dto::User;
+use crate::modules::user::keys::UserKeys;
+use super::dto::{Challenge, IssuedChallenge, IssuedToken, LoginResponse, MagicLink};
+use super::signing_keys::{RotationPolicy, SigningKeys};
+use super::totp::TwoFactor;
+
+pub const CHALLENGES_COLLECTION: &str = "challenges";
+pub const MAGIC_LINKS_COLLECTION: &str = "magic_links";
+
+const NONCE_BYTES: usize = 32;
+const DEFAULT_CHALLENGE_TTL_SECS: u64 = 60;
+const DEFAULT_TOKEN_TTL_SECS: u64 = 900;
+const DEFAULT_STEP_UP_TTL_SECS: u64 = 300;
+const DEFAULT_MAGIC_LINK_TTL_SECS: u64 = 900;
+const DEFAULT_MAGIC_LINK_EMAIL_LIMIT: u32 = 3;
+const DEFAULT_MAGIC_LINK_IP_LIMIT: u32 = 10;
+const DEFAULT_MAGIC_LINK_WINDOW_SECS: u64 = 3_600;
+const DEFAULT_SIGNING_KEY_ROTATION_SECS: u64 = 30 * 86_400;
+const DEFAULT_SIGNING_KEY_OVERLAP_SECS: u64 = 86_400;
+const DEFAULT_CLIENT_TOKEN_MAX_LIFETIME_SECS: u64 = 3_600;
+/// How often the signing keys are checked for a rotation.
+const ROTATION_CHECK_SECS: u64 = 60;
+
+/// Why a magic link was not sent.
+#[derive(Debug)]
+pub enum MagicLinkError {
+    /// Too many links were asked for, for the email or from the same IP, until the given time.
+    Throttled(DateTime<Utc>),
+    Storage(StorageError),
+}
+
+impl From<StorageError> for MagicLinkError {
+    fn from(e: StorageError) -> Self {
+        MagicLinkError::Storage(e)
+    }
+}
+
+/// Exchanges nonces signed with the key of a user, or magic links, for short-lived tokens signed by the server.
+pub struct AuthService {
+    pub config: ConfigHandle,
+    pub storage: Arc<Storage>,
+    pub challenges: Collection<Challenge>,
+    /// Registered keys of the users, which can answer challenges too.
+    pub keys: UserKeys,
+    /// Keys signing the server tokens.
+    pub signing_keys: SigningKeys,
+    /// TOTP factors, asked for once the challenge is signed.
+    pub two_factor: TwoFactor,
+    pub magic_links: Collection<MagicLink>,
+    /// Sends the magic links.
+    pub mail: Arc<MailOracle>,
+    /// Limits how many magic links are asked for per email and per IP.
+    pub throttle: Throttle,
+    /// Locks the accounts and IPs failing to sign in.
+    pub lockouts: Arc<Lockouts>,
+}
+
+impl AuthService {
+    /// Fails outside of the `dev` run mode when `auth_signing_key_secret` is unset, as the keys would be encrypted with
+    /// a secret lost at the next start.
+    pub fn new(config: ConfigHandle, storage: Arc<Storage>, mail: Arc<MailOracle>, lockouts: Arc<Lockouts>) -> Result<Self, io::Error> {
+        storage.add_collection(CHALLENGES_COLLECTION);
+        storage.add_collection(MAGIC_LINKS_COLLECTION);
+        let challenges = storage.collection(CHALLENGES_COLLECTION);
+        let magic_links = storage.collection(MAGIC_LINKS_COLLECTION);
+        let throttle = Throttle::new(&storage);
+        let keys = UserKeys::new(&storage);
+        let secret = config.current().env.auth_signing_key_secret.clone();
+        let two_factor = TwoFactor::new(&storage, secret.as_ref().map(|secret| secret.as_bytes()));
+        let signing_keys = match &secret {
+            Some(secret) => SigningKeys::new(&storage, secret.as_bytes()),
+            None if config.current().env.run_mode != "dev" => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "auth_signing_key_secret must be set outside of the dev run mode"));
+            }
+            None => {
+                warn!("auth_signing_key_secret is not set, this instance signs with keys of its own until it stops");
+                let mut secret = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                SigningKeys::new(&storage, &secret)
+            }
+        };
+        Ok(Self { config, storage, challenges, keys, signing_keys, two_factor, magic_links, mail, throttle, lockouts })
+    }
+
+    /// How long the signing keys sign and stay published. A retired key stays published at least as long as the
+    /// tokens it signed are valid.
+    pub fn rotation_policy(&self) -> RotationPolicy {
+        let env = &self.config.current().env;
+        let rotation = env.auth_signing_key_rotation_secs.unwrap_or(DEFAULT_SIGNING_KEY_ROTATION_SECS);
+        let overlap = env.auth_signing_key_overlap_secs.unwrap_or(DEFAULT_SIGNING_KEY_OVERLAP_SECS)
+            .max(env.auth_token_ttl_secs.unwrap_or(DEFAULT_TOKEN_TTL_SECS));
+        RotationPolicy { rotation: chrono::Duration::seconds(rotation as i64), overlap: chrono::Duration::seconds(overlap as i64) }
+    }
+
+    /// Rotates the signing keys in the background, so the next key is published ahead of time even when no token
+    /// is issued.
+    pub fn spawn_key_rotation(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.signing_keys.rotate(&self.rotation_policy()).await {
+                    error!("Error rotating signing keys: {}", e);
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(ROTATION_CHECK_SECS)).await;
+            }
+        });
+    }
+
+    /// Hands out a new nonce for `email`. Challenges are issued for unknown emails too, so they do not tell which
+    /// accounts exist; answering them fails.
+    pub async fn create_challenge(&self, email: &str) -> Result<Challenge, StorageError> {
+        let ttl = self.config.current().env.auth_challenge_ttl_secs.unwrap_or(DEFAULT_CHALLENGE_TTL_SECS);
+        self.insert_challenge(email, None, ttl).await
+    }
+
+    async fn insert_challenge(&self, email: &str, user_id: Option<ObjectId>, ttl: u64) -> Result<Challenge, StorageError> {
+        let mut nonce = [0u8; NONCE_BYTES];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut challenge = Challenge {
+            _id: None,
+            email: email.to_string(),
+            user_id,
+            nonce: URL_SAFE_NO_PAD.encode(nonce),
+            created: Utc::now(),
+            expires: bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + ttl as i64 * 1000),
+        };
+        challenge._id = self.challenges.insert_one(&challenge, None).await?.inserted_id.as_object_id();
+        Ok(challenge)
+    }
+
+    /// Checks `signature` against the nonce of `challenge_id` and the public key of the user it was issued for, the
+    /// registered key `kid` if given, and issues a server token for that user, or a step-up challenge if the user
+    /// enabled TOTP. The challenge is spent by the first answer, right or wrong. Wrong answers count against the user
+    /// and `ip`, and fail with `Locked` once either is locked.
+    pub async fn answer_challenge(&self, challenge_id: &ObjectId, kid: Option<&str>, signature: &str, ip: Option<IpAddr>) -> Result<LoginResponse, io::Error> {
+        let filter = doc! {"_id": challenge_id, "user_id": {"$exists": false}, "expires": {"$gt": bson::DateTime::now()}};
+        let Some(challenge) = self.challenges.find_one(filter, None).await.map_err(io::Error::other)? else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Challenge not found"));
+        };
+        // Only the answer that removes the challenge goes on, concurrent ones find nothing to remove
+        if self.challenges.delete_one(doc! {"_id": challenge_id}, None).await.map_err(io::Error::other)?.deleted_count == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Challenge not found"));
+        }
+
+        let users: Collection<User> = self.storage.collection("users");
+        let user = users.find_one(doc! {"email": &challenge.email}, None).await.map_err(io::Error::other)?;
+        let invalid = || io::Error::new(io::ErrorKind::PermissionDenied, "Invalid signature");
+        let Some((user_id, public_key)) = user.and_then(|user| Some((user._id?, user.public_key))) else {
+            self.lockouts.check(None, ip).await?;
+            return Err(self.lockouts.reject(None, ip, invalid()).await);
+        };
+        self.lockouts.check(Some(&user_id), ip).await?;
+        let public_key = match kid {
+            Some(kid) => match self.keys.find(&user_id, kid).await.map_err(io::Error::other)? {
+                Some(key) => key.public_key,
+                None => return Err(self.lockouts.reject(Some(&user_id), ip, invalid()).await),
+            },
+            None => public_key,
+        };
+        let verified = verifying_key(&public_key)
+            .and_then(|(key, algorithm)| crypto::verify(signature, challenge.nonce.as_bytes(), &key, algorithm).ok())
+            .unwrap_or(false);
+        if !verified {
+            return Err(self.lockouts.reject(Some(&user_id), ip, invalid()).await);
+        }
+        if let Some(kid) = kid {
+            self.keys.touch(kid).await.map_err(io::Error::other)?;
+        }
+        self.complete_login(&user_id, &challenge.email).await
+    }
+
+    /// Issues a server token for a user who proved the first factor, or a step-up challenge if the user enabled TOTP.
+    async fn complete_login(&self, user_id: &ObjectId, email: &str) -> Result<LoginResponse, io::Error> {
+        if self.two_factor.is_enabled(user_id).await.map_err(io::Error::other)? {
+            let ttl = self.config.current().env.auth_step_up_ttl_secs.unwrap_or(DEFAULT_STEP_UP_TTL_SECS);
+            let step_up = self.insert_challenge(email, Some(*user_id), ttl).await.map_err(io::Error::other)?;
+            return Ok(LoginResponse::StepUp { step_up: IssuedChallenge::from(&step_up) });
+        }
+        Ok(LoginResponse::Token(self.sign_in(user_id).await?))
+    }
+
+    /// Issues a server token for a user who proved every factor, forgetting the failed attempts against the account.
+    /// They are only forgotten then, so proving the first factor again does not give more guesses at the second.
+    async fn sign_in(&self, user_id: &ObjectId) -> Result<IssuedToken, io::Error> {
+        self.lockouts.record_success(user_id).await.map_err(io::Error::other)?;
+        self.issue_token(user_id).await
+    }
+
+    /// Emails a sign-in link to `email`, to be followed from the browser holding the `device` cookie. Like the
+    /// challenges, requests for unknown emails succeed without sending anything. Returns when the link expires.
+    pub async fn request_magic_link(&self, email: &str, device: &str, ip: Option<IpAddr>) -> Result<DateTime<Utc>, MagicLinkError> {
+        let env = &self.config.current().env;
+        let window = Duration::from_secs(env.auth_magic_link_window_secs.unwrap_or(DEFAULT_MAGIC_LINK_WINDOW_SECS));
+        let ip_key = format!("magic_link:ip:{}", ip.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("unknown")));
+        if let Some(until) = self.throttle.hit(&ip_key, env.auth_magic_link_ip_limit.unwrap_or(DEFAULT_MAGIC_LINK_IP_LIMIT), window).await? {
+            return Err(MagicLinkError::Throttled(until));
+        }
+        let email_key = format!("magic_link:email:{}", email.trim().to_lowercase());
+        if let Some(until) = self.throttle.hit(&email_key, env.auth_magic_link_email_limit.unwrap_or(DEFAULT_MAGIC_LINK_EMAIL_LIMIT), window).await? {
+            return Err(MagicLinkError::Throttled(until));
+        }
+
+        let ttl = env.auth_magic_link_ttl_secs.unwrap_or(DEFAULT_MAGIC_LINK_TTL_SECS);
+        let expires = Utc::now() + chrono::Duration::seconds(ttl as i64);
+        let users: Collection<User> = self.storage.collection("users");
+        let Some(user) = users.find_one(doc! {"email": email.trim()}, None).await? else {
+            return Ok(expires);
+        };
+        let Some(user_id) = user._id else {
+            return Ok(expires);
+        };
+        let secret = self.mail.generate_random_url_safe_string(env.default_email_verification_key_length);
+        let mut link = MagicLink {
+            _id: None,
+            user_id,
+            secret: secret_digest(&secret),
+            device: secret_digest(device),
+            created: Utc::now(),
+            expires: bson::DateTime::from_millis(expires.timestamp_millis()),
+        };
+        link._id = self.magic_links.insert_one(&link, None).await?.inserted_id.as_object_id();
+        let Some(id) = link._id else {
+            return Ok(expires);
+        };
+        let url = uri!(crate::modules::auth::controller::redeem_magic_link(id = id.to_hex(), secret = secret));
+        self.mail.send_magic_link_mail(&user.email, user.locale.as_deref(), &url.to_string(), ttl).await;
+        Ok(expires)
+    }
+
+    /// Exchanges the magic link `link_id` for a server token, or a step-up challenge if the user enabled TOTP. Fails
+    /// with NotFound if the link does not exist, expired, was followed already or `secret` is wrong, and with
+    /// PermissionDenied if `device` is not the cookie of the browser that asked for it, which does not spend it. Wrong
+    /// secrets count against the user of the link and `ip`, and unknown links against `ip`.
+    pub async fn redeem_magic_link(&self, link_id: &ObjectId, secret: &str, device: Option<&str>, ip: Option<IpAddr>) -> Result<LoginResponse, io::Error> {
+        let filter = doc! {"_id": link_id, "expires": {"$gt": bson::DateTime::now()}};
+        let link = self.magic_links.find_one(filter, None).await.map_err(io::Error::other)?;
+        self.lockouts.check(link.as_ref().map(|link| &link.user_id), ip).await?;
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "Magic link not found");
+        let link = match link {
+            Some(link) if link.secret == secret_digest(secret) => link,
+            Some(link) => return Err(self.lockouts.reject(Some(&link.user_id), ip, not_found()).await),
+            None => return Err(self.lockouts.reject(None, ip, not_found()).await),
+        };
+        if device.map(secret_digest).as_deref() != Some(link.device.as_str()) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Magic link asked for from another device"));
+        }
+        // Only the request that removes the link goes on
+        if self.magic_links.delete_one(doc! {"_id": link_id}, None).await.map_err(io::Error::other)?.deleted_count == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Magic link not found"));
+        }
+        let users: Collection<User> = self.storage.collection("users");
+        let Some(user) = users.find_one(doc! {"_id": link.user_id}, None).await.map_err(io::Error::other)? else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "User not found"));
+        };
+        self.complete_login(&link.user_id, &user.email).await
+    }
+
+    /// Checks `code`, a TOTP or recovery code, for the user of the step-up challenge `challenge_id` and issues a
+    /// server token for that user. Like the challenges, the step-up is spent by the first answer, right or wrong, and
+    /// wrong codes count against the user and `ip`.
+    pub async fn answer_step_up(&self, challenge_id: &ObjectId, nonce: &str, code: &str, ip: Option<IpAddr>) -> Result<IssuedToken, io::Error> {
+        let filter = doc! {"_id": challenge_id, "nonce": nonce, "user_id": {"$exists": true}, "expires": {"$gt": bson::DateTime::now()}};
+        let Some(user_id) = self.challenges.find_one(filter, None).await.map_err(io::Error::other)?.and_then(|step_up| step_up.user_id) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Step-up challenge not found"));
+        };
+        if self.challenges.delete_one(doc! {"_id": challenge_id}, None).await.map_err(io::Error::other)?.deleted_count == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Step-up challenge not found"));
+        }
+        self.lockouts.check(Some(&user_id), ip).await?;
+        if !self.two_factor.verify(&user_id, code).await? {
+            return Err(self.lockouts.reject(Some(&user_id), ip, io::Error::new(io::ErrorKind::PermissionDenied, "Invalid code")).await);
+        }
+        self.sign_in(&user_id).await
+    }
+
+    /// Signs a token for `user_id` with the active signing key, valid for `auth_token_ttl_secs`.
+    pub async fn issue_token(&self, user_id: &ObjectId) -> Result<IssuedToken, io::Error> {
+        let ttl = self.config.current().env.auth_token_ttl_secs.unwrap_or(DEFAULT_TOKEN_TTL_SECS);
+        let (key, encoding_key) = self.signing_keys.signing_key(&self.rotation_policy()).await.map_err(io::Error::other)?;
+        let token = generate_server_token(&user_id.to_hex(), &key._id, &encoding_key, chrono::Duration::seconds(ttl as i64))
+            .map_err(io::Error::other)?;
+        Ok(IssuedToken { token, token_type: String::from("Bearer"), expires_in: ttl })
+    }
+
+    /// The claims of a valid, unexpired token signed by a published signing key.
+    pub async fn verify_token(&self, token: &str) -> Option<Claims> {
+        let kid = decode_header(token).ok()?.kid?;
+        let key = match self.signing_keys.find(&kid).await {
+            Ok(key) => key?,
+            Err(e) => {
+                error!("Error reading signing key {}: {}", kid, e);
+                return None;
+            }
+        };
+        decode_server_token(token, &key.decoding_key()?).ok()
+    }
+
+    /// Whether a token signed by a client expires within `client_token_max_lifetime_secs`, an hour by default.
+    pub fn accepts_client_expiry(&self, claims: &Claims) -> bool {
+        let max = self.config.current().env.client_token_max_lifetime_secs.unwrap_or(DEFAULT_CLIENT_TOKEN_MAX_LIFETIME_SECS);
+        claims.exp as i64 <= Utc::now().timestamp() + max as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use jsonwebtoken::{crypto, Algorithm, EncodingKey};
+    use rocket_db_pools::mongodb::bson::oid::ObjectId;
+
+    use crate::framework::auth::{generate_rsa_key_pair_pem, Claims};
+    use crate::framework::config::{ConfigHandle, OxidizeConfig};
+    use crate::framework::testing::{load_test_env, Mock};
+    use crate::framework::translator::OxidizeTranslator;
+    use crate::modules::lockout::service::Lockouts;
+    use crate::modules::mail::service::MailOracle;
+    use crate::modules::storage::Storage;
+    use crate::modules::user::dto::User;
+    use crate::modules::user::service::UserService;
+
+    use crate::modules::auth::dto::LoginResponse;
+    use super::AuthService;
+
+    #[tokio::test]
+    async fn test_challenge() {
+        load_test_env();
+        let mut config = OxidizeConfig::new().expect("Error creating config");
+        config.env.client_token_max_lifetime_secs = Some(3600);
+        let config = Arc::new(config);
+        let storage = Arc::new(Storage::memory(config.clone()));
+        let users = UserService::new(storage.clone());
+        let translator = Arc::new(OxidizeTranslator::new(config.clone()));
+        let mail = Arc::new(MailOracle::new(ConfigHandle::new(config.clone()), storage.clone(), translator));
+        let lockouts = Arc::new(Lockouts::new(ConfigHandle::new(config.clone()), &storage, mail.clone()));
+        let auth = AuthService::new(ConfigHandle::new(config), storage, mail, lockouts).expect("Error creating auth service");
+
+        let (public_key, private_key) = generate_rsa_key_pair_pem();
+        let (_, other_key) = generate_rsa_key_pair_pem();
+        let mut user = User::mock();
+        user.public_key = public_key;
+        let user_id = users.insert(&user, None).await.unwrap().unwrap().inserted_id.as_object_id().unwrap();
+        let sign = |nonce: &str, key: &str| {
+            crypto::sign(nonce.as_bytes(), &EncodingKey::from_rsa_pem(key.as_bytes()).unwrap(), Algorithm::RS512).unwrap()
+        };
+
+        let challenge = auth.create_challenge(&user.email).await.unwrap();
+        let LoginResponse::Token(issued) = auth.answer_challenge(&challenge._id.unwrap(), None, &sign(&challenge.nonce, &private_key), None).await
+            .expect("Valid answer was refused") else {
+            panic!("Step-up asked for without TOTP");
+        };
+        let claims = auth.verify_token(&issued.token).await.expect("Issued token does not verify");
+        assert_eq!(claims.user_id, user_id.to_hex());
+        // Challenges are single use
+        let error = auth.answer_challenge(&challenge._id.unwrap(), None, &sign(&challenge.nonce, &private_key), None).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        // A wrong key spends the challenge too
+        let challenge = auth.create_challenge(&user.email).await.unwrap();
+        let error = auth.answer_challenge(&challenge._id.unwrap(), None, &sign(&challenge.nonce, &other_key), None).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        let error = auth.answer_challenge(&challenge._id.unwrap(), None, &sign(&challenge.nonce, &private_key), None).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        // Unknown emails get a challenge nobody can answer
+        let challenge = auth.create_challenge("nobody@example.com").await.unwrap();
+        let error = auth.answer_challenge(&challenge._id.unwrap(), None, &sign(&challenge.nonce, &private_key), None).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(auth.answer_challenge(&ObjectId::new(), None, "garbage", None).await.is_err());
+
+        assert!(auth.verify_token("not a token").await.is_none());
+
+        // Tokens signed by clients may not expire later than allowed
+        let claims = |hours: i64| Claims { user_id: user_id.to_hex(), exp: (Utc::now() + chrono::Duration::hours(hours)).timestamp() as usize, iss: None };
+        assert!(auth.accepts_client_expiry(&claims(1)));
+        assert!(!auth.accepts_client_expiry(&claims(2)));
+    }
+
+    #[tokio::test]
+    async fn test_signing_key_secret_required() {
+        load_test_env();
+        let mut config = OxidizeConfig::new().expect("Error creating config");
+        config.env.auth_signing_key_secret = None;
+        let service = |config: OxidizeConfig| {
+            let config = Arc::new(config);
+            let storage = Arc::new(Storage::memory(config.clone()));
+            let translator = Arc::new(OxidizeTranslator::new(config.clone()));
+            let mail = Arc::new(MailOracle::new(ConfigHandle::new(config.clone()), storage.clone(), translator));
+            let lockouts = Arc::new(Lockouts::new(ConfigHandle::new(config.clone()), &storage, mail.clone()));
+            AuthService::new(ConfigHandle::new(config), storage, mail, lockouts)
+        };
+
+        config.env.run_mode = String::from("dev");
+        assert!(service(config.clone()).is_ok());
+        config.env.run_mode = String::from("production");
+        assert!(service(config).is_err());
+    }
+}
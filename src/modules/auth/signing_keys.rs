@@ -0,0 +1,223 @@
+use std::sync::RwLock;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use log::{info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rocket_db_pools::mongodb::bson::{self, doc};
+use rsa::pkcs8::der::zeroize::Zeroizing;
+use serde::{Deserialize, Serialize};
+
+use crate::modules::storage::{Collection, Storage, StorageError};
+use super::cipher::SecretCipher;
+
+pub const SIGNING_KEYS_COLLECTION: &str = "signing_keys";
+
+/// A P-256 key the server signs its tokens with. Keys are published from their creation until `expires`, and sign
+/// tokens from `activates` until `retires`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningKey {
+    /// The `kid` of the tokens it signs.
+    pub _id: String,
+    /// Uncompressed public point, base64url.
+    pub public_key: String,
+    /// PKCS#8 document encrypted with AES-256-GCM, base64url of the nonce followed by the ciphertext.
+    pub private_key: String,
+    pub created: DateTime<Utc>,
+    pub activates: bson::DateTime,
+    pub retires: bson::DateTime,
+    /// The key is unpublished and dropped after this date.
+    pub expires: bson::DateTime,
+}
+
+impl SigningKey {
+    /// The public key as a JWK, x and y being the halves of the uncompressed point.
+    pub fn jwk(&self) -> Option<Jwk> {
+        let point = URL_SAFE_NO_PAD.decode(&self.public_key).ok().filter(|point| point.len() == 65)?;
+        Some(Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::ES256),
+                key_id: Some(self._id.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+                y: URL_SAFE_NO_PAD.encode(&point[33..]),
+            }),
+        })
+    }
+
+    pub fn decoding_key(&self) -> Option<DecodingKey> {
+        DecodingKey::from_jwk(&self.jwk()?).ok()
+    }
+}
+
+/// The lifetimes of the signing keys.
+#[derive(Debug, Clone, Copy)]
+pub struct RotationPolicy {
+    /// Time a key signs tokens.
+    pub rotation: chrono::Duration,
+    /// Time the next key is published before it signs, and a retired key stays published after it.
+    pub overlap: chrono::Duration,
+}
+
+/// Signing keys shared by every instance through the storage, their private halves encrypted at rest.
+pub struct SigningKeys {
+    pub keys: Collection<SigningKey>,
+    /// Encrypts the private keys, derived from `auth_signing_key_secret`.
+    cipher: SecretCipher,
+    /// The key signing tokens right now, along with its decrypted private key.
+    current: RwLock<Option<(SigningKey, EncodingKey)>>,
+    rng: SystemRandom,
+}
+
+impl SigningKeys {
+    pub fn new(storage: &Storage, secret: &[u8]) -> Self {
+        storage.add_collection(SIGNING_KEYS_COLLECTION);
+        Self {
+            keys: storage.collection(SIGNING_KEYS_COLLECTION),
+            cipher: SecretCipher::new(secret, b"oxidize signing keys"),
+            current: RwLock::new(None),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// The published keys: the next one, the active one and those retired less than the overlap ago.
+    pub async fn published(&self) -> Result<Vec<SigningKey>, StorageError> {
+        let mut keys = self.keys.find(doc! {"expires": {"$gt": bson::DateTime::now()}}, Default::default(), None).await?;
+        keys.sort_by_key(|key| (key.activates, key._id.clone()));
+        Ok(keys)
+    }
+
+    pub async fn jwks(&self) -> Result<JwkSet, StorageError> {
+        Ok(JwkSet { keys: self.published().await?.iter().filter_map(SigningKey::jwk).collect() })
+    }
+
+    /// The published key `kid`.
+    pub async fn find(&self, kid: &str) -> Result<Option<SigningKey>, StorageError> {
+        self.keys.find_one(doc! {"_id": kid, "expires": {"$gt": bson::DateTime::now()}}, None).await
+    }
+
+    /// The PKCS#8 document of `key`, None if it was encrypted with another secret.
+    fn decrypt(&self, key: &SigningKey) -> Option<Zeroizing<Vec<u8>>> {
+        self.cipher.decrypt(key._id.as_bytes(), &key.private_key)
+    }
+
+    /// Generates and stores a key signing from `activates` for a rotation.
+    async fn create(&self, activates: DateTime<Utc>, policy: &RotationPolicy) -> Result<SigningKey, StorageError> {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &self.rng).expect("Error generating a signing key");
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &self.rng)
+            .expect("Error reading the generated signing key");
+        let mut kid = [0u8; 16];
+        self.rng.fill(&mut kid).expect("Error generating a key id");
+        let kid = URL_SAFE_NO_PAD.encode(kid);
+        let retires = activates + policy.rotation;
+        let key = SigningKey {
+            private_key: self.cipher.encrypt(kid.as_bytes(), pkcs8.as_ref()),
+            _id: kid,
+            public_key: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+            created: Utc::now(),
+            activates: bson::DateTime::from_millis(activates.timestamp_millis()),
+            retires: bson::DateTime::from_millis(retires.timestamp_millis()),
+            expires: bson::DateTime::from_millis((retires + policy.overlap).timestamp_millis()),
+        };
+        self.keys.insert_one(&key, None).await?;
+        info!("Created signing key {}, active from {}", key._id, activates);
+        Ok(key)
+    }
+
+    /// Creates the keys the policy calls for: an active one if none can sign, and the next one once the active key
+    /// is within the overlap of its retirement. Returns the active key.
+    pub async fn rotate(&self, policy: &RotationPolicy) -> Result<(SigningKey, EncodingKey), StorageError> {
+        let now = bson::DateTime::now();
+        let keys = self.published().await?;
+        let active = keys.iter().rev()
+            .filter(|key| key.activates <= now && now < key.retires)
+            .find_map(|key| Some((key.clone(), EncodingKey::from_ec_der(&self.decrypt(key)?))));
+        let (active, encoding_key) = match active {
+            Some(active) => active,
+            None => {
+                if keys.iter().any(|key| key.activates <= now && now < key.retires) {
+                    warn!("The active signing keys were encrypted with another auth_signing_key_secret, creating one");
+                }
+                let key = self.create(Utc::now(), policy).await?;
+                let encoding_key = EncodingKey::from_ec_der(&self.decrypt(&key).expect("Error decrypting a new signing key"));
+                (key, encoding_key)
+            }
+        };
+        let has_next = keys.iter().any(|key| key.activates >= active.retires);
+        if !has_next && now.timestamp_millis() >= active.retires.timestamp_millis() - policy.overlap.num_milliseconds() {
+            let activates = DateTime::from_timestamp_millis(active.retires.timestamp_millis()).unwrap_or_default();
+            self.create(activates, policy).await?;
+        }
+        *self.current.write().unwrap() = Some((active.clone(), encoding_key.clone()));
+        Ok((active, encoding_key))
+    }
+
+    /// The key to sign tokens with right now, rotating the keys when the cached one retired.
+    pub async fn signing_key(&self, policy: &RotationPolicy) -> Result<(SigningKey, EncodingKey), StorageError> {
+        if let Some((key, encoding_key)) = self.current.read().unwrap().as_ref() {
+            if bson::DateTime::now() < key.retires {
+                return Ok((key.clone(), encoding_key.clone()));
+            }
+        }
+        self.rotate(policy).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::framework::config::OxidizeConfig;
+    use crate::framework::testing::load_test_env;
+    use crate::modules::storage::Storage;
+
+    use super::{RotationPolicy, SigningKeys};
+
+    #[tokio::test]
+    async fn test_signing_key_rotation() {
+        load_test_env();
+        let config = Arc::new(OxidizeConfig::new().expect("Error creating config"));
+        let storage = Storage::memory(config);
+        let keys = SigningKeys::new(&storage, b"secret");
+        let policy = RotationPolicy { rotation: chrono::Duration::milliseconds(1000), overlap: chrono::Duration::milliseconds(500) };
+
+        let (first, _) = keys.signing_key(&policy).await.unwrap();
+        assert_eq!(keys.signing_key(&policy).await.unwrap().0._id, first._id);
+        assert_eq!(keys.jwks().await.unwrap().keys.len(), 1);
+        assert!(keys.decrypt(&first).is_some());
+        let stored = keys.find(&first._id).await.unwrap().expect("Signing key not stored");
+        assert!(!stored.private_key.contains("PRIVATE"));
+
+        // The next key is published ahead of its activation
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        keys.rotate(&policy).await.unwrap();
+        let published = keys.published().await.unwrap();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[1].activates, first.retires);
+        assert_eq!(keys.signing_key(&policy).await.unwrap().0._id, first._id);
+
+        // It signs once the first one retires, which stays published for the overlap
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let (second, _) = keys.signing_key(&policy).await.unwrap();
+        assert_eq!(second._id, published[1]._id);
+        let jwks = keys.jwks().await.unwrap();
+        assert!(jwks.find(&first._id).is_some());
+        assert!(jwks.find(&second._id).is_some());
+
+        // Another secret cannot use the stored keys and makes its own
+        let other = SigningKeys::new(&storage, b"other secret");
+        assert!(other.decrypt(&second).is_none());
+        let (own, _) = other.signing_key(&policy).await.unwrap();
+        assert_ne!(own._id, second._id);
+    }
+}
@@ -0,0 +1,284 @@
+use std::io;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::RngCore;
+use ring::{digest, hmac};
+use rocket_db_pools::mongodb::bson::{self, doc, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+use crate::modules::storage::{Collection, Storage, StorageError};
+use crate::modules::user::dto::User;
+use super::cipher::SecretCipher;
+use super::dto::{RecoveryCodes, TotpEnrollment};
+
+const SECRET_BYTES: usize = 20;
+const DIGITS: u32 = 6;
+const PERIOD_SECS: i64 = 30;
+/// Codes of the previous and next time steps are accepted too, for clocks slightly off.
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_BYTES: usize = 6;
+
+/// The TOTP factor of a user, kept in its document of the `users` collection under `totp`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Totp {
+    /// The secret encrypted for the id of the user, see `SecretCipher`.
+    pub secret: String,
+    pub created: DateTime<Utc>,
+    /// Set by the first code given, the factor is only enforced from then on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirmed: Option<DateTime<Utc>>,
+    /// Time step of the last accepted code, codes of earlier steps or the same one are refused.
+    #[serde(default)]
+    pub last_step: i64,
+    /// Base64url SHA-256 digests of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+}
+
+/// The part of a user document holding its TOTP factor, so the factor never travels with `User`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserTotp {
+    _id: ObjectId,
+    #[serde(default)]
+    totp: Option<Totp>,
+}
+
+/// The RFC 6238 code of `secret` for the time step `step`, with HMAC-SHA1 and 6 digits.
+/// ```
+/// use oxidize::modules::auth::totp::totp_code;
+/// // Test vector of RFC 6238 at 59 seconds, truncated to 6 digits
+/// assert_eq!(totp_code(b"12345678901234567890", 1), "287082");
+/// ```
+pub fn totp_code(secret: &[u8], step: i64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let digest = tag.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// The time step of `time`.
+pub fn totp_step(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(PERIOD_SECS)
+}
+
+/// Recovery codes are compared without case, spaces or dashes.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_lowercase();
+    URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, normalized.as_bytes()))
+}
+
+fn is_totp_code(code: &str) -> bool {
+    code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// TOTP second factors of the users, their secrets encrypted at rest.
+pub struct TwoFactor {
+    users: Collection<UserTotp>,
+    /// None when `auth_signing_key_secret` is unset, secrets encrypted with a key lost at the next start would lock
+    /// the users out, so nobody can enroll.
+    cipher: Option<SecretCipher>,
+}
+
+impl TwoFactor {
+    pub fn new(storage: &Storage, secret: Option<&[u8]>) -> Self {
+        Self {
+            users: storage.collection("users"),
+            cipher: secret.map(|secret| SecretCipher::new(secret, b"oxidize totp secrets")),
+        }
+    }
+
+    async fn find(&self, user_id: &ObjectId) -> Result<Option<Totp>, StorageError> {
+        Ok(self.users.find_one(doc! {"_id": user_id}, None).await?.and_then(|user| user.totp))
+    }
+
+    /// Whether `user_id` confirmed a TOTP factor.
+    pub async fn is_enabled(&self, user_id: &ObjectId) -> Result<bool, StorageError> {
+        Ok(self.find(user_id).await?.is_some_and(|totp| totp.confirmed.is_some()))
+    }
+
+    fn cipher(&self) -> Result<&SecretCipher, io::Error> {
+        self.cipher.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "auth_signing_key_secret is not set"))
+    }
+
+    fn decrypt(&self, user_id: &ObjectId, totp: &Totp) -> Result<Vec<u8>, io::Error> {
+        self.cipher()?.decrypt(user_id.to_hex().as_bytes(), &totp.secret)
+            .map(|secret| secret.to_vec())
+            .ok_or_else(|| io::Error::other(format!("The TOTP secret of user {} cannot be decrypted", user_id)))
+    }
+
+    /// Generates a new secret for `user`, replacing one not confirmed yet. Fails with AlreadyExists if the user
+    /// confirmed one already, and with Unsupported if secrets cannot be encrypted.
+    pub async fn enroll(&self, user: &User, issuer: &str) -> Result<TotpEnrollment, io::Error> {
+        let user_id = user._id.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "User without id"))?;
+        let mut secret = [0u8; SECRET_BYTES];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let totp = Totp {
+            secret: self.cipher()?.encrypt(user_id.to_hex().as_bytes(), &secret),
+            created: Utc::now(),
+            confirmed: None,
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        };
+        let totp = bson::to_document(&totp).map_err(io::Error::other)?;
+        let filter = doc! {"_id": user_id, "totp.confirmed": {"$exists": false}};
+        if self.users.update_one(filter, doc! {"$set": {"totp": totp}}, None).await.map_err(io::Error::other)?.matched_count == 0 {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "TOTP is enabled already"));
+        }
+
+        let secret = BASE32_NOPAD.encode(&secret);
+        let label = format!("{}:{}", utf8_percent_encode(issuer, NON_ALPHANUMERIC), utf8_percent_encode(&user.email, NON_ALPHANUMERIC));
+        let provisioning_uri = format!(
+            "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            label, secret, utf8_percent_encode(issuer, NON_ALPHANUMERIC), DIGITS, PERIOD_SECS
+        );
+        Ok(TotpEnrollment { secret, provisioning_uri })
+    }
+
+    /// Enables the pending secret of `user_id` if `code` is valid for it, and hands out its recovery codes. Fails
+    /// with NotFound if there is no pending secret and with PermissionDenied if the code is wrong.
+    pub async fn confirm(&self, user_id: &ObjectId, code: &str) -> Result<RecoveryCodes, io::Error> {
+        let Some(totp) = self.find(user_id).await.map_err(io::Error::other)?.filter(|totp| totp.confirmed.is_none()) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No TOTP enrollment pending"));
+        };
+        let secret = self.decrypt(user_id, &totp)?;
+        let Some(step) = matching_step(&secret, code.trim(), totp.last_step) else {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Invalid code"));
+        };
+
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODES).map(|_| {
+            let mut bytes = [0u8; RECOVERY_CODE_BYTES];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = BASE32_NOPAD.encode(&bytes).to_ascii_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        }).collect();
+        let hashes: Vec<String> = recovery_codes.iter().map(|code| hash_recovery_code(code)).collect();
+        let now = bson::to_bson(&Utc::now()).map_err(io::Error::other)?;
+        // The secret is matched as well, so a code of a secret replaced in the meantime does not confirm the new one
+        let filter = doc! {"_id": user_id, "totp.secret": &totp.secret, "totp.confirmed": {"$exists": false}};
+        let update = doc! {"$set": {"totp.confirmed": now, "totp.last_step": step, "totp.recovery_codes": hashes}};
+        if self.users.update_one(filter, update, None).await.map_err(io::Error::other)?.matched_count == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No TOTP enrollment pending"));
+        }
+        Ok(RecoveryCodes { recovery_codes })
+    }
+
+    /// Whether `code` is a valid TOTP code or an unused recovery code of `user_id`, which is spent either way: a
+    /// TOTP code cannot be given twice, nor one older than the last given.
+    pub async fn verify(&self, user_id: &ObjectId, code: &str) -> Result<bool, io::Error> {
+        let Some(totp) = self.find(user_id).await.map_err(io::Error::other)?.filter(|totp| totp.confirmed.is_some()) else {
+            return Ok(false);
+        };
+        let code = code.trim();
+        if is_totp_code(code) {
+            let secret = self.decrypt(user_id, &totp)?;
+            let Some(step) = matching_step(&secret, code, totp.last_step) else {
+                return Ok(false);
+            };
+            // Only moves forward from the step read, a concurrent use of the same code finds it moved already
+            let filter = doc! {"_id": user_id, "totp.last_step": totp.last_step};
+            let update = doc! {"$set": {"totp.last_step": step}};
+            return Ok(self.users.update_one(filter, update, None).await.map_err(io::Error::other)?.modified_count > 0);
+        }
+        let hash = hash_recovery_code(code);
+        let filter = doc! {"_id": user_id, "totp.recovery_codes": &hash};
+        let update = doc! {"$pull": {"totp.recovery_codes": &hash}};
+        Ok(self.users.update_one(filter, update, None).await.map_err(io::Error::other)?.modified_count > 0)
+    }
+
+    /// Removes the TOTP factor of `user_id`, pending or not. Returns whether there was one.
+    pub async fn disable(&self, user_id: &ObjectId) -> Result<bool, StorageError> {
+        let filter = doc! {"_id": user_id, "totp": {"$exists": true}};
+        Ok(self.users.update_one(filter, doc! {"$unset": {"totp": ""}}, None).await?.modified_count > 0)
+    }
+}
+
+/// The time step within the skew of now for which `code` is valid, if it is later than `last_step`.
+fn matching_step(secret: &[u8], code: &str, last_step: i64) -> Option<i64> {
+    if !is_totp_code(code) {
+        return None;
+    }
+    let now = totp_step(Utc::now());
+    (now - SKEW_STEPS..=now + SKEW_STEPS)
+        .filter(|step| *step > last_step)
+        .find(|step| totp_code(secret, *step) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use crate::framework::config::OxidizeConfig;
+    use crate::framework::testing::{load_test_env, Mock};
+    use crate::modules::storage::Storage;
+    use crate::modules::user::dto::User;
+    use crate::modules::user::service::UserService;
+
+    use super::{totp_code, totp_step, TwoFactor};
+
+    #[test]
+    fn test_totp_code() {
+        // RFC 6238 test vectors for SHA1, truncated to 6 digits
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, 59 / 30), "287082");
+        assert_eq!(totp_code(secret, 1111111109 / 30), "081804");
+        assert_eq!(totp_code(secret, 1234567890 / 30), "005924");
+        assert_eq!(totp_code(secret, 20000000000 / 30), "353130");
+    }
+
+    #[tokio::test]
+    async fn test_two_factor() {
+        load_test_env();
+        let config = Arc::new(OxidizeConfig::new().expect("Error creating config"));
+        let storage = Arc::new(Storage::memory(config));
+        let users = UserService::new(storage.clone());
+        let two_factor = TwoFactor::new(&storage, Some(b"secret"));
+        let mut user = User::mock();
+        user._id = users.insert(&user, None).await.unwrap().unwrap().inserted_id.as_object_id();
+        let user_id = user._id.unwrap();
+
+        let enrollment = two_factor.enroll(&user, "Oxidize").await.unwrap();
+        assert!(enrollment.provisioning_uri.starts_with("otpauth://totp/Oxidize:"));
+        assert!(enrollment.provisioning_uri.contains(&format!("secret={}", enrollment.secret)));
+        let secret = data_encoding::BASE32_NOPAD.decode(enrollment.secret.as_bytes()).unwrap();
+        // The secret is encrypted at rest
+        let stored = two_factor.find(&user_id).await.unwrap().unwrap();
+        assert!(!stored.secret.contains(&enrollment.secret));
+        assert!(!two_factor.is_enabled(&user_id).await.unwrap());
+
+        let step = totp_step(Utc::now());
+        let code = totp_code(&secret, step);
+        assert_eq!(two_factor.confirm(&user_id, "000000x").await.unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        let recovery = two_factor.confirm(&user_id, &code).await.unwrap();
+        assert_eq!(recovery.recovery_codes.len(), 10);
+        assert!(two_factor.is_enabled(&user_id).await.unwrap());
+        assert_eq!(two_factor.enroll(&user, "Oxidize").await.unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+
+        // Codes are single use, the confirming one included
+        assert!(!two_factor.verify(&user_id, &code).await.unwrap());
+        let next = totp_code(&secret, step + 1);
+        assert!(two_factor.verify(&user_id, &next).await.unwrap());
+        assert!(!two_factor.verify(&user_id, &next).await.unwrap());
+        assert!(two_factor.verify(&user_id, &recovery.recovery_codes[0].to_uppercase()).await.unwrap());
+        assert!(!two_factor.verify(&user_id, &recovery.recovery_codes[0]).await.unwrap());
+        assert!(two_factor.verify(&user_id, &recovery.recovery_codes[1]).await.unwrap());
+
+        // Secrets need the secret they were encrypted with
+        let other = TwoFactor::new(&storage, Some(b"other secret"));
+        assert!(other.verify(&user_id, &totp_code(&secret, step + 1)).await.is_err());
+        let unsupported = TwoFactor::new(&storage, None).enroll(&user, "Oxidize").await.unwrap_err();
+        assert_eq!(unsupported.kind(), std::io::ErrorKind::Unsupported);
+
+        assert!(two_factor.disable(&user_id).await.unwrap());
+        assert!(!two_factor.is_enabled(&user_id).await.unwrap());
+        assert!(!two_factor.disable(&user_id).await.unwrap());
+    }
+}
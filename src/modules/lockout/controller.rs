@@ -0,0 +1,63 @@
+use std::net::IpAddr;
+
+use log::{error, warn};
+use rocket::{delete, routes, Route, State};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket_db_pools::mongodb::bson::oid::ObjectId;
+
+use crate::framework::app::App;
+use crate::framework::catchers::TooManyRequests;
+use crate::modules::user::guard::AdminSession;
+
+use super::service::Locked;
+
+/// Attempts on a locked account or from a locked IP are answered with `429 Too Many Requests` until the lockout ends.
+impl From<Locked> for TooManyRequests {
+    fn from(Locked(until): Locked) -> Self {
+        TooManyRequests::until(until)
+    }
+}
+
+/// Lifts the lockout of an account and forgets its failed attempts. Admins only.
+#[delete("/lockouts/accounts/<user_id>")]
+pub async fn unlock_account(app: &State<App>, user_id: &str, admin: AdminSession) -> status::Custom<Json<Option<bool>>> {
+    let Ok(user_id) = ObjectId::parse_str(user_id) else {
+        return status::Custom(Status::BadRequest, Json::from(None));
+    };
+    match app.lockouts.unlock_account(&user_id).await {
+        Ok(true) => {
+            warn!("Account {} unlocked by {}", user_id, admin.session.user.email);
+            status::Custom(Status::Ok, Json::from(Some(true)))
+        }
+        Ok(false) => status::Custom(Status::NotFound, Json::from(None)),
+        Err(e) => {
+            error!("Error unlocking account {}: {}", user_id, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Lifts the lockout of an IP and forgets its failed attempts. Admins only.
+#[delete("/lockouts/ips/<ip>")]
+pub async fn unlock_ip(app: &State<App>, ip: &str, admin: AdminSession) -> status::Custom<Json<Option<bool>>> {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return status::Custom(Status::BadRequest, Json::from(None));
+    };
+    match app.lockouts.unlock_ip(&ip).await {
+        Ok(true) => {
+            warn!("IP {} unlocked by {}", ip, admin.session.user.email);
+            status::Custom(Status::Ok, Json::from(Some(true)))
+        }
+        Ok(false) => status::Custom(Status::NotFound, Json::from(None)),
+        Err(e) => {
+            error!("Error unlocking IP {}: {}", ip, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+pub fn get_routes() -> Vec<Route> {
+    routes![unlock_account, unlock_ip]
+}
@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rocket_db_pools::mongodb::bson::{doc, Document};
+
+use crate::modules::storage::migration::Migration;
+use crate::modules::storage::{Index, Storage, StorageError};
+use super::service::LOCKOUTS_COLLECTION;
+
+/// Drops the failures of accounts and IPs once they are forgotten.
+pub struct CreateLockoutsExpiryIndex;
+
+#[async_trait]
+impl Migration for CreateLockoutsExpiryIndex {
+    fn version(&self) -> i64 { 10 }
+
+    fn name(&self) -> &'static str { "create_lockouts_expires_index" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let index = Index::new("expires_1", doc! { "expires": 1 }).expire_after(Duration::ZERO);
+        storage.collection::<Document>(LOCKOUTS_COLLECTION).create_index(index).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>(LOCKOUTS_COLLECTION).drop_index("expires_1").await
+    }
+}
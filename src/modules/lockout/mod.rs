@@ -0,0 +1,3 @@
+pub mod service;
+pub mod controller;
+pub mod migrations;
@@ -0,0 +1,381 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use log::warn;
+use rocket_db_pools::mongodb::bson::{doc, oid::ObjectId, Bson, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::framework::config::ConfigHandle;
+use crate::modules::mail::service::MailOracle;
+use crate::modules::storage::{Collection, FindOneAndUpdateOptions, Storage, StorageError};
+use crate::modules::user::dto::User;
+
+pub const LOCKOUTS_COLLECTION: &str = "lockouts";
+
+const DEFAULT_ACCOUNT_THRESHOLD: u32 = 5;
+const DEFAULT_IP_THRESHOLD: u32 = 20;
+const DEFAULT_BASE_SECS: u64 = 60;
+const DEFAULT_MAX_SECS: u64 = 86_400;
+const DEFAULT_RESET_SECS: u64 = 86_400;
+
+/// Failed attempts counted against an account or from an IP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockoutState {
+    /// What failed, `account:<user id>` or `ip:<address>`.
+    pub _id: String,
+    /// Failures since the last lockout.
+    pub failures: u32,
+    /// Lockouts so far, each one twice as long as the previous one.
+    pub lockouts: u32,
+    #[serde(default)]
+    pub locked_until: Option<DateTime>,
+    /// `auth_lockout_reset_secs` after the last failure or the end of the lockout, the document is dropped after it.
+    pub expires: DateTime,
+}
+
+/// How many failures lock, and for how long.
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    pub threshold: u32,
+    /// Length of a first lockout.
+    pub base: chrono::Duration,
+    pub max: chrono::Duration,
+    /// Time without failures after which they are forgotten.
+    pub reset: chrono::Duration,
+}
+
+impl LockoutPolicy {
+    /// Length of the `lockouts`th lockout, the base doubled by each previous one.
+    pub fn duration(&self, lockouts: u32) -> chrono::Duration {
+        let doublings = lockouts.saturating_sub(1).min(30);
+        (self.base * (1 << doublings)).min(self.max)
+    }
+}
+
+/// An attempt refused because the account or the IP is locked, until the given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locked(pub chrono::DateTime<Utc>);
+
+impl Locked {
+    /// The lockout `err` stands for, if it is one.
+    pub fn from_io(err: &io::Error) -> Option<Locked> {
+        err.get_ref()?.downcast_ref::<Locked>().copied()
+    }
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Locked until {}", self.0)
+    }
+}
+
+impl std::error::Error for Locked {}
+
+impl From<Locked> for io::Error {
+    fn from(locked: Locked) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, locked)
+    }
+}
+
+fn to_chrono(date: DateTime) -> chrono::DateTime<Utc> {
+    Utc.timestamp_millis_opt(date.timestamp_millis()).single().unwrap_or_else(Utc::now)
+}
+
+fn to_bson(date: chrono::DateTime<Utc>) -> DateTime {
+    DateTime::from_millis(date.timestamp_millis())
+}
+
+fn account_key(user_id: &ObjectId) -> String {
+    format!("account:{}", user_id.to_hex())
+}
+
+fn ip_key(ip: &IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Locks accounts and IPs after repeated failed authentication attempts, for longer after each lockout. Shared by
+/// every instance through the storage. Attempts from an unknown IP are only counted against the account.
+pub struct Lockouts {
+    pub config: ConfigHandle,
+    pub states: Collection<LockoutState>,
+    users: Collection<User>,
+    /// Tells users their account got locked.
+    mail: Arc<MailOracle>,
+}
+
+impl Lockouts {
+    pub fn new(config: ConfigHandle, storage: &Storage, mail: Arc<MailOracle>) -> Self {
+        storage.add_collection(LOCKOUTS_COLLECTION);
+        Self { config, states: storage.collection(LOCKOUTS_COLLECTION), users: storage.collection("users"), mail }
+    }
+
+    fn policy(&self, threshold: u32) -> LockoutPolicy {
+        let env = &self.config.current().env;
+        LockoutPolicy {
+            threshold: threshold.max(1),
+            base: chrono::Duration::seconds(env.auth_lockout_base_secs.unwrap_or(DEFAULT_BASE_SECS) as i64),
+            max: chrono::Duration::seconds(env.auth_lockout_max_secs.unwrap_or(DEFAULT_MAX_SECS) as i64),
+            reset: chrono::Duration::seconds(env.auth_lockout_reset_secs.unwrap_or(DEFAULT_RESET_SECS) as i64),
+        }
+    }
+
+    pub fn account_policy(&self) -> LockoutPolicy {
+        self.policy(self.config.current().env.auth_lockout_account_threshold.unwrap_or(DEFAULT_ACCOUNT_THRESHOLD))
+    }
+
+    pub fn ip_policy(&self) -> LockoutPolicy {
+        self.policy(self.config.current().env.auth_lockout_ip_threshold.unwrap_or(DEFAULT_IP_THRESHOLD))
+    }
+
+    async fn current(&self, key: &str) -> Result<Option<LockoutState>, StorageError> {
+        let state = self.states.find_one(doc! {"_id": key}, None).await?;
+        Ok(state.filter(|state| state.expires > DateTime::now()))
+    }
+
+    async fn locked_key(&self, key: &str) -> Result<Option<chrono::DateTime<Utc>>, StorageError> {
+        Ok(self.current(key).await?
+            .and_then(|state| state.locked_until)
+            .filter(|until| *until > DateTime::now())
+            .map(to_chrono))
+    }
+
+    /// When the account `user_id` and the IP `ip` may be tried again, None if right away.
+    pub async fn locked_until(&self, user_id: Option<&ObjectId>, ip: Option<IpAddr>) -> Result<Option<chrono::DateTime<Utc>>, StorageError> {
+        let account = match user_id {
+            Some(user_id) => self.locked_key(&account_key(user_id)).await?,
+            None => None,
+        };
+        let ip = match ip {
+            Some(ip) => self.locked_key(&ip_key(&ip)).await?,
+            None => None,
+        };
+        Ok(account.max(ip))
+    }
+
+    /// Fails with `Locked` if the account `user_id` or the IP `ip` is locked, for the services answering io errors.
+    pub async fn check(&self, user_id: Option<&ObjectId>, ip: Option<IpAddr>) -> Result<(), io::Error> {
+        match self.locked_until(user_id, ip).await.map_err(io::Error::other)? {
+            Some(until) => Err(Locked(until).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a failure on `key` in a single update, starting over if its failures were forgotten, and returns the
+    /// state it leaves.
+    async fn count_failure(&self, key: &str, now: chrono::DateTime<Utc>, policy: &LockoutPolicy) -> Result<LockoutState, StorageError> {
+        let expires = to_bson(now + policy.reset);
+        loop {
+            let update = doc! {"$inc": {"failures": 1}, "$max": {"expires": expires}};
+            let options = FindOneAndUpdateOptions { return_new: true, ..Default::default() };
+            let current = doc! {"_id": key, "expires": {"$gt": to_bson(now)}};
+            if let Some(state) = self.states.find_one_and_update(current, update, options, None).await? {
+                return Ok(state);
+            }
+            let update = doc! {"$set": {"failures": 1, "lockouts": 0, "locked_until": Bson::Null, "expires": expires}};
+            let options = FindOneAndUpdateOptions { upsert: true, return_new: true, ..Default::default() };
+            let forgotten = doc! {"_id": key, "expires": {"$lte": to_bson(now)}};
+            match self.states.find_one_and_update(forgotten, update, options, None).await {
+                Ok(Some(state)) => return Ok(state),
+                // Another failure started it over first, and is counted on again
+                Ok(None) => continue,
+                Err(e) if e.is_duplicate_key() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Counts a failure on `key`, locking it each time it reaches the threshold of `policy`. Returns the end of the
+    /// lockout it started along with the failures that led to it.
+    async fn fail(&self, key: &str, policy: &LockoutPolicy) -> Result<Option<(chrono::DateTime<Utc>, u32)>, StorageError> {
+        let now = to_chrono(DateTime::now());
+        let state = self.count_failure(key, now, policy).await?;
+        // Every count is seen by a single failure, only the one reaching the threshold locks
+        if state.failures == 0 || state.failures % policy.threshold != 0 {
+            return Ok(None);
+        }
+        // Failures count from zero again once locked, those made meanwhile are kept
+        let update = doc! {"$inc": {"failures": -(policy.threshold as i32), "lockouts": 1}};
+        let options = FindOneAndUpdateOptions { return_new: true, ..Default::default() };
+        let Some(state) = self.states.find_one_and_update(doc! {"_id": key}, update, options, None).await? else {
+            return Ok(None);
+        };
+        let locked_until = now + policy.duration(state.lockouts);
+        let update = doc! {"$max": {"locked_until": to_bson(locked_until), "expires": to_bson(locked_until + policy.reset)}};
+        self.states.update_one(doc! {"_id": key}, update, None).await?;
+        Ok(Some((locked_until, policy.threshold)))
+    }
+
+    /// Counts a failed attempt against the account `user_id` and from the IP `ip`, locking them once they reach their
+    /// threshold, and tells the owner of the account when it gets locked. Returns the end of the lockout it started.
+    pub async fn record_failure(&self, user_id: Option<&ObjectId>, ip: Option<IpAddr>) -> Result<Option<chrono::DateTime<Utc>>, StorageError> {
+        let mut locked = None;
+        if let Some(ip) = ip {
+            if let Some((until, failures)) = self.fail(&ip_key(&ip), &self.ip_policy()).await? {
+                warn!("IP {} locked until {} after {} failed attempts", ip, until, failures);
+                locked = Some(until);
+            }
+        }
+        if let Some(user_id) = user_id {
+            if let Some((until, failures)) = self.fail(&account_key(user_id), &self.account_policy()).await? {
+                warn!("Account {} locked until {} after {} failed attempts", user_id, until, failures);
+                if let Some(user) = self.users.find_one(doc! {"_id": user_id}, None).await? {
+                    self.mail.send_account_locked_mail(&user.email, user.locale.as_deref(), failures, until).await;
+                }
+                locked = locked.max(Some(until));
+            }
+        }
+        Ok(locked)
+    }
+
+    /// Records a failed attempt and answers `error`, or `Locked` if the attempt started a lockout.
+    pub async fn reject(&self, user_id: Option<&ObjectId>, ip: Option<IpAddr>, error: io::Error) -> io::Error {
+        match self.record_failure(user_id, ip).await {
+            Ok(Some(until)) => Locked(until).into(),
+            Ok(None) => error,
+            Err(e) => io::Error::other(e),
+        }
+    }
+
+    /// Makes `attempt` on the account `user_id` from `ip` unless either is locked, and counts it as failed when it fails
+    /// with one of `failures`.
+    pub async fn attempt<T>(&self, user_id: Option<&ObjectId>, ip: Option<IpAddr>, failures: &[io::ErrorKind],
+                            attempt: impl Future<Output = Result<T, io::Error>>) -> Result<T, io::Error> {
+        self.check(user_id, ip).await?;
+        match attempt.await {
+            Err(err) if failures.contains(&err.kind()) => Err(self.reject(user_id, ip, err).await),
+            result => result,
+        }
+    }
+
+    /// Forgets the failures against the account `user_id` once its owner signed in. Those from the IP are kept, so
+    /// signing in to an account of one's own does not clear them.
+    pub async fn record_success(&self, user_id: &ObjectId) -> Result<(), StorageError> {
+        self.states.delete_one(doc! {"_id": account_key(user_id)}, None).await?;
+        Ok(())
+    }
+
+    /// Lifts the lockout of the account `user_id` and forgets its failures. False if there were none.
+    pub async fn unlock_account(&self, user_id: &ObjectId) -> Result<bool, StorageError> {
+        Ok(self.states.delete_one(doc! {"_id": account_key(user_id)}, None).await?.deleted_count > 0)
+    }
+
+    /// Lifts the lockout of `ip` and forgets its failures. False if there were none.
+    pub async fn unlock_ip(&self, ip: &IpAddr) -> Result<bool, StorageError> {
+        Ok(self.states.delete_one(doc! {"_id": ip_key(ip)}, None).await?.deleted_count > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use rocket_db_pools::mongodb::bson::{doc, oid::ObjectId};
+
+    use crate::framework::config::{ConfigHandle, OxidizeConfig};
+    use crate::framework::testing::load_test_env;
+    use crate::framework::translator::OxidizeTranslator;
+    use crate::modules::mail::service::MailOracle;
+    use crate::modules::storage::Storage;
+
+    use super::{Locked, LockoutPolicy, Lockouts};
+
+    #[test]
+    fn test_lockout_duration() {
+        let policy = LockoutPolicy {
+            threshold: 3,
+            base: chrono::Duration::seconds(60),
+            max: chrono::Duration::seconds(300),
+            reset: chrono::Duration::seconds(3600),
+        };
+        assert_eq!(policy.duration(1), chrono::Duration::seconds(60));
+        assert_eq!(policy.duration(2), chrono::Duration::seconds(120));
+        assert_eq!(policy.duration(3), chrono::Duration::seconds(240));
+        assert_eq!(policy.duration(4), chrono::Duration::seconds(300));
+        assert_eq!(policy.duration(u32::MAX), chrono::Duration::seconds(300));
+    }
+
+    #[tokio::test]
+    async fn test_lockouts() {
+        load_test_env();
+        let mut config = OxidizeConfig::new().expect("Error creating config");
+        config.env.auth_lockout_account_threshold = Some(2);
+        config.env.auth_lockout_ip_threshold = Some(3);
+        config.env.auth_lockout_base_secs = Some(60);
+        let config = Arc::new(config);
+        let storage = Arc::new(Storage::memory(config.clone()));
+        let translator = Arc::new(OxidizeTranslator::new(config.clone()));
+        let mail = Arc::new(MailOracle::new(ConfigHandle::new(config.clone()), storage.clone(), translator));
+        let lockouts = Lockouts::new(ConfigHandle::new(config), &storage, mail);
+        let user_id = ObjectId::new();
+        let other_id = ObjectId::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(lockouts.locked_until(Some(&user_id), Some(ip)).await.unwrap(), None);
+        assert_eq!(lockouts.record_failure(Some(&user_id), Some(ip)).await.unwrap(), None);
+        let until = lockouts.record_failure(Some(&user_id), Some(ip)).await.unwrap().expect("Account not locked");
+        assert!(until > Utc::now() + chrono::Duration::seconds(50));
+        assert_eq!(lockouts.locked_until(Some(&user_id), None).await.unwrap(), Some(until));
+        let error = lockouts.check(Some(&user_id), None).await.unwrap_err();
+        assert_eq!(Locked::from_io(&error), Some(Locked(until)));
+
+        // The IP has its own threshold, and locks every account tried from it
+        assert_eq!(lockouts.locked_until(Some(&other_id), Some(ip)).await.unwrap(), None);
+        let ip_until = lockouts.record_failure(Some(&other_id), Some(ip)).await.unwrap().expect("IP not locked");
+        assert_eq!(lockouts.locked_until(Some(&other_id), Some(ip)).await.unwrap(), Some(ip_until));
+        assert_eq!(lockouts.locked_until(Some(&other_id), None).await.unwrap(), None);
+        assert!(lockouts.unlock_ip(&ip).await.unwrap());
+        assert!(!lockouts.unlock_ip(&ip).await.unwrap());
+
+        // Each lockout lasts twice as long as the previous one
+        assert!(lockouts.unlock_account(&user_id).await.unwrap());
+        assert!(lockouts.check(Some(&user_id), Some(ip)).await.is_ok());
+        let state = |failures: u32, lockouts: u32| super::LockoutState {
+            _id: super::account_key(&user_id),
+            failures,
+            lockouts,
+            locked_until: None,
+            expires: super::to_bson(Utc::now() + chrono::Duration::seconds(60)),
+        };
+        lockouts.states.insert_one(&state(1, 2), None).await.unwrap();
+        let until = lockouts.record_failure(Some(&user_id), None).await.unwrap().expect("Account not locked");
+        assert!(until > Utc::now() + chrono::Duration::seconds(230));
+
+        // Signing in forgets the failures
+        lockouts.unlock_account(&user_id).await.unwrap();
+        lockouts.record_failure(Some(&user_id), None).await.unwrap();
+        lockouts.record_success(&user_id).await.unwrap();
+        assert_eq!(lockouts.record_failure(Some(&user_id), None).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_failures() {
+        load_test_env();
+        let mut config = OxidizeConfig::new().expect("Error creating config");
+        config.env.auth_lockout_account_threshold = Some(5);
+        let config = Arc::new(config);
+        let storage = Arc::new(Storage::memory(config.clone()));
+        let translator = Arc::new(OxidizeTranslator::new(config.clone()));
+        let mail = Arc::new(MailOracle::new(ConfigHandle::new(config.clone()), storage.clone(), translator));
+        let lockouts = Arc::new(Lockouts::new(ConfigHandle::new(config), &storage, mail));
+        let user_id = ObjectId::new();
+
+        // Every parallel failure is counted, and each threshold reached starts one lockout
+        let attempts = (0..12).map(|_| {
+            let lockouts = lockouts.clone();
+            tokio::spawn(async move { lockouts.record_failure(Some(&user_id), None).await.unwrap() })
+        });
+        let locked = futures::future::join_all(attempts).await.into_iter()
+            .filter(|result| result.as_ref().expect("Failure task panicked").is_some())
+            .count();
+        assert_eq!(locked, 2);
+        let state = lockouts.states.find_one(doc! {"_id": super::account_key(&user_id)}, None).await.unwrap().unwrap();
+        assert_eq!((state.failures, state.lockouts), (2, 2));
+        assert!(state.locked_until.is_some());
+    }
+}
@@ -1,45 +1,189 @@
 use std::io;
+use std::net::IpAddr;
 
 use rocket::{routes, Route};
-use rocket::{get, http::Status, response::status, serde::json::Json, State};
+use log::error;
+use rocket::{delete, get, post, http::Status, response::status, serde::json::Json, State};
 use rocket_db_pools::mongodb::bson::oid::ObjectId;
 
 use crate::framework::app::App;
-use crate::modules::user::guard::OxidizeSession;
+use crate::framework::catchers::TooManyRequests;
+use crate::modules::lockout::service::Locked;
+use crate::modules::user::dto::User;
+use crate::modules::user::guard::{AdminSession, OxidizeSession, TokenSession};
 
-use super::dto::EmailVerification;
+use super::dto::{EmailChangeRequest, EmailVerification, PendingEmailChange, VerificationStatus};
+use super::outbox::{OutboxMessage, OutboxStatus};
+use super::service::ResendError;
 
-#[get("/mail/verifications/start-verification", format = "application/json")]
-pub async fn start_verification(app: &State<App>, session: OxidizeSession) -> status::Custom<Json<Option<bool>>> {
-    let verification = app.mail.start_verification(&session.user).await;
-    match verification {
-        Some(_) => status::Custom(Status::Ok, Json::from(Some(true))),
-        None => status::Custom(Status::Conflict, Json::from(None))
+const OUTBOX_PAGE_SIZE: i64 = 100;
+
+/// Sends a new verification email, at most once per cooldown per user and a few times per window per IP.
+#[post("/mail/verifications/start-verification")]
+pub async fn start_verification(app: &State<App>, session: OxidizeSession, ip: Option<IpAddr>) -> Result<status::Custom<Json<Option<bool>>>, TooManyRequests<Json<VerificationStatus>>> {
+    match app.mail.resend_verification(&session.user, ip).await {
+        Ok(_) => Ok(status::Custom(Status::Ok, Json::from(Some(true)))),
+        Err(ResendError::Verified) => Ok(status::Custom(Status::Conflict, Json::from(None))),
+        Err(ResendError::Throttled(until)) => {
+            Err(TooManyRequests::with_body(until, Json(VerificationStatus { verified: false, next_resend: Some(until) })))
+        }
+        Err(ResendError::Storage(e)) => {
+            error!("Error starting verification for user {}: {}", session.user.email, e);
+            Ok(status::Custom(Status::InternalServerError, Json::from(None)))
+        }
     }
 }
 
-#[get("/mail/verifications/<id>/verify/<secret>", format = "application/json")]
-pub async fn finish_verification(app: &State<App>, id:&str, secret: String, session: OxidizeSession
-) -> status::Custom<Json<Option<EmailVerification>>> {
-    let verification_id = ObjectId::parse_str(id);
-    if verification_id.is_err() {
-        return status::Custom(Status::BadRequest, Json::from(None));
+/// Whether the email of the user is verified, and when a new verification email can be asked for.
+#[get("/mail/verifications/status", format = "application/json")]
+pub async fn verification_status(app: &State<App>, session: OxidizeSession, ip: Option<IpAddr>) -> status::Custom<Json<Option<VerificationStatus>>> {
+    match app.mail.verification_status(&session.user, ip).await {
+        Ok(verification_status) => status::Custom(Status::Ok, Json::from(Some(verification_status))),
+        Err(e) => {
+            error!("Error reading verification status of user {}: {}", session.user.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
     }
-    match app.mail.finish_verification(&session.user._id.unwrap(), &verification_id.unwrap(), secret.as_str()).await {
-        Ok(verif) => status::Custom(Status::Ok, Json::from(Some(verif))),
+}
+
+/// Wrong secrets, and verifications of other users, count as failed attempts against the user.
+#[get("/mail/verifications/<id>/verify/<secret>", format = "application/json")]
+pub async fn finish_verification(app: &State<App>, id:&str, secret: String, session: OxidizeSession, ip: Option<IpAddr>
+) -> Result<status::Custom<Json<Option<EmailVerification>>>, TooManyRequests> {
+    let Ok(verification_id) = ObjectId::parse_str(id) else {
+        return Ok(status::Custom(Status::BadRequest, Json::from(None)));
+    };
+    let user_id = session.user._id.unwrap();
+    let attempt = app.mail.finish_verification(&user_id, &verification_id, secret.as_str());
+    match app.lockouts.attempt(Some(&user_id), ip, &[io::ErrorKind::PermissionDenied, io::ErrorKind::InvalidData], attempt).await {
+        Ok(verif) => Ok(status::Custom(Status::Ok, Json::from(Some(verif)))),
         Err(err) => {
-            if err.kind() == io::ErrorKind::NotFound {
-                return status::Custom(Status::NotFound, Json::from(None));
+            if let Some(locked) = Locked::from_io(&err) {
+                Err(locked.into())
+            } else if err.kind() == io::ErrorKind::NotFound {
+                Ok(status::Custom(Status::NotFound, Json::from(None)))
             } else if err.kind() == io::ErrorKind::PermissionDenied {
-                return status::Custom(Status::Unauthorized, Json::from(None));
+                Ok(status::Custom(Status::Unauthorized, Json::from(None)))
             } else if err.kind() == io::ErrorKind::InvalidData{
-                return status::Custom(Status::Conflict, Json::from(None));
+                Ok(status::Custom(Status::Conflict, Json::from(None)))
             } else {
-                return status::Custom(Status::InternalServerError, Json::from(None));}
+                error!("Error finishing verification {} of user {}: {}", verification_id, session.user.email, err);
+                Ok(status::Custom(Status::InternalServerError, Json::from(None)))
+            }
+        }
+    }
+}
+
+/// Asks to replace the email of the user, see `MailOracle::request_email_change`.
+#[post("/mail/email-changes", format = "application/json", data = "<request>")]
+pub async fn request_email_change(app: &State<App>, request: Json<EmailChangeRequest>, token: TokenSession) -> status::Custom<Json<Option<PendingEmailChange>>> {
+    match app.mail.request_email_change(&token.session.user, &request.email).await {
+        Ok(change) => status::Custom(Status::Accepted, Json::from(Some(PendingEmailChange::from(&change)))),
+        Err(err) => match err.kind() {
+            io::ErrorKind::InvalidInput => status::Custom(Status::BadRequest, Json::from(None)),
+            io::ErrorKind::AlreadyExists => status::Custom(Status::Conflict, Json::from(None)),
+            _ => {
+                error!("Error requesting email change for user {}: {}", token.session.user.email, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    }
+}
+
+/// The pending email change of the user.
+#[get("/mail/email-changes", format = "application/json")]
+pub async fn pending_email_change(app: &State<App>, session: OxidizeSession) -> status::Custom<Json<Option<PendingEmailChange>>> {
+    match app.mail.email_changes.find_by_user(&session.user._id.unwrap()).await {
+        Ok(Some(change)) => status::Custom(Status::Ok, Json::from(Some(PendingEmailChange::from(&change)))),
+        Ok(None) => status::Custom(Status::NotFound, Json::from(None)),
+        Err(e) => {
+            error!("Error reading email change of user {}: {}", session.user.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Applies an email change from the link sent to the new address. Wrong secrets count as failed attempts against the
+/// user, like those of the verifications.
+#[get("/mail/email-changes/<id>/confirm/<secret>", format = "application/json")]
+pub async fn confirm_email_change(app: &State<App>, id: &str, secret: String, token: TokenSession, ip: Option<IpAddr>)
+    -> Result<status::Custom<Json<Option<User>>>, TooManyRequests> {
+    let Ok(change_id) = ObjectId::parse_str(id) else {
+        return Ok(status::Custom(Status::BadRequest, Json::from(None)));
+    };
+    let user_id = token.session.user._id.unwrap();
+    Ok(match app.mail.confirm_email_change(&app.lockouts, &user_id, &change_id, &secret, ip).await {
+        Ok(user) => status::Custom(Status::Ok, Json::from(Some(user))),
+        Err(err) => match (Locked::from_io(&err), err.kind()) {
+            (Some(locked), _) => return Err(locked.into()),
+            (None, io::ErrorKind::NotFound) => status::Custom(Status::NotFound, Json::from(None)),
+            (None, io::ErrorKind::PermissionDenied) => status::Custom(Status::Unauthorized, Json::from(None)),
+            (None, io::ErrorKind::InvalidData | io::ErrorKind::AlreadyExists) => status::Custom(Status::Conflict, Json::from(None)),
+            _ => {
+                error!("Error confirming email change {}: {}", change_id, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    })
+}
+
+/// Drops the pending email change of the user.
+#[delete("/mail/email-changes")]
+pub async fn cancel_email_change(app: &State<App>, token: TokenSession) -> status::Custom<Json<Option<bool>>> {
+    match app.mail.email_changes.remove(&token.session.user._id.unwrap(), None).await {
+        Ok(true) => status::Custom(Status::Ok, Json::from(Some(true))),
+        Ok(false) => status::Custom(Status::NotFound, Json::from(None)),
+        Err(e) => {
+            error!("Error cancelling email change of user {}: {}", token.session.user.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Oldest messages of the outbox, optionally in a single status, e.g. `/mail/outbox?status=dead`. Admins only.
+#[get("/mail/outbox?<status>", format = "application/json")]
+pub async fn list_outbox(app: &State<App>, status: Option<OutboxStatus>, _admin: AdminSession) -> status::Custom<Json<Option<Vec<OutboxMessage>>>> {
+    match app.mail.outbox.list(status, OUTBOX_PAGE_SIZE).await {
+        Ok(messages) => status::Custom(Status::Ok, Json::from(Some(messages))),
+        Err(e) => {
+            error!("Error listing mail outbox: {}", e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Sends a dead-lettered message again with a fresh set of attempts. Admins only.
+#[post("/mail/outbox/<id>/requeue")]
+pub async fn requeue_outbox_message(app: &State<App>, id: &str, _admin: AdminSession) -> status::Custom<Json<Option<OutboxMessage>>> {
+    let Ok(id) = ObjectId::parse_str(id) else {
+        return status::Custom(Status::BadRequest, Json::from(None));
+    };
+    let outbox = &app.mail.outbox;
+    match outbox.requeue(&id).await {
+        Ok(Some(message)) => {
+            app.mail.wake_outbox();
+            status::Custom(Status::Ok, Json::from(Some(message)))
+        }
+        // Only dead messages can be requeued, the others are still handled by the worker
+        Ok(None) => match outbox.find(&id).await {
+            Ok(Some(_)) => status::Custom(Status::Conflict, Json::from(None)),
+            Ok(None) => status::Custom(Status::NotFound, Json::from(None)),
+            Err(e) => {
+                error!("Error reading outbox message {}: {}", id, e);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+        Err(e) => {
+            error!("Error requeuing outbox message {}: {}", id, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
         }
     }
 }
 
 pub fn get_routes() -> Vec<Route> {
-    routes![start_verification, finish_verification]
+    routes![
+        start_verification, verification_status, finish_verification,
+        request_email_change, pending_email_change, confirm_email_change, cancel_email_change,
+        list_outbox, requeue_outbox_message,
+    ]
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use chrono::{DateTime, Utc};
-use rocket_db_pools::mongodb::bson::oid::ObjectId;
+use rocket_db_pools::mongodb::bson::{self, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 
 
@@ -13,4 +13,51 @@ pub struct EmailVerification {
     pub created: DateTime<Utc>,
     pub updated: DateTime<Utc>,
     pub verified: bool
-}
\ No newline at end of file
+}
+
+/// Whether the user verified their email, and when they may ask for the email again.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct VerificationStatus {
+    pub verified: bool,
+    /// Set while resending is throttled, None when it is allowed right away or the email is already verified.
+    pub next_resend: Option<DateTime<Utc>>,
+}
+
+/// A new email address waiting to be confirmed from a link sent to it. The user keeps the old one until then.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EmailChange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub old_email: String,
+    pub new_email: String,
+    /// Digest of the secret of the confirmation link, see `auth::secret_digest`.
+    pub secret: String,
+    pub created: DateTime<Utc>,
+    /// The change is dropped after this date.
+    pub expires: bson::DateTime,
+}
+
+/// Body of an email change request.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EmailChangeRequest {
+    pub email: String,
+}
+
+/// An email change as shown to its user, without the secret that only the new address receives.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct PendingEmailChange {
+    pub _id: Option<ObjectId>,
+    pub new_email: String,
+    pub expires: DateTime<Utc>,
+}
+
+impl From<&EmailChange> for PendingEmailChange {
+    fn from(change: &EmailChange) -> Self {
+        PendingEmailChange {
+            _id: change._id,
+            new_email: change.new_email.clone(),
+            expires: DateTime::from_timestamp_millis(change.expires.timestamp_millis()).unwrap_or_default(),
+        }
+    }
+}
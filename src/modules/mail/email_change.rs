@@ -0,0 +1,46 @@
+use rocket_db_pools::mongodb::bson::{doc, oid::ObjectId, DateTime};
+
+use crate::modules::storage::{Collection, Session, Storage, StorageError};
+use super::dto::EmailChange;
+
+pub const EMAIL_CHANGES_COLLECTION: &str = "email_changes";
+
+/// Pending email changes, one per user at most. Expired ones are ignored until the TTL index drops them.
+pub struct EmailChanges {
+    pub changes: Collection<EmailChange>,
+}
+
+impl EmailChanges {
+    pub fn new(storage: &Storage) -> Self {
+        storage.add_collection(EMAIL_CHANGES_COLLECTION);
+        Self { changes: storage.collection(EMAIL_CHANGES_COLLECTION) }
+    }
+
+    /// The change with `id`, unless it expired.
+    pub async fn find(&self, id: &ObjectId) -> Result<Option<EmailChange>, StorageError> {
+        self.changes.find_one(doc! {"_id": id, "expires": {"$gt": DateTime::now()}}, None).await
+    }
+
+    /// The pending change of `user_id`, unless it expired.
+    pub async fn find_by_user(&self, user_id: &ObjectId) -> Result<Option<EmailChange>, StorageError> {
+        self.changes.find_one(doc! {"user_id": user_id, "expires": {"$gt": DateTime::now()}}, None).await
+    }
+
+    /// Whether another user than `user_id` is confirming `email`.
+    pub async fn is_claimed(&self, email: &str, user_id: &ObjectId) -> Result<bool, StorageError> {
+        let filter = doc! {"new_email": email, "user_id": {"$ne": user_id}, "expires": {"$gt": DateTime::now()}};
+        Ok(self.changes.find_one(filter, None).await?.is_some())
+    }
+
+    /// Stores `change` in place of any previous change of its user, and of expired changes of its address.
+    pub async fn replace(&self, change: &EmailChange) -> Result<Option<ObjectId>, StorageError> {
+        self.changes.delete_many(doc! {"user_id": change.user_id}, None).await?;
+        self.changes.delete_many(doc! {"new_email": &change.new_email, "expires": {"$lte": DateTime::now()}}, None).await?;
+        Ok(self.changes.insert_one(change, None).await?.inserted_id.as_object_id())
+    }
+
+    /// Drops the pending change of `user_id`. Returns whether there was one.
+    pub async fn remove(&self, user_id: &ObjectId, session: Option<&mut Session>) -> Result<bool, StorageError> {
+        Ok(self.changes.delete_many(doc! {"user_id": user_id}, session).await?.deleted_count > 0)
+    }
+}
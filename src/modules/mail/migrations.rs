@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rocket_db_pools::mongodb::bson::{doc, Document};
+
+use crate::modules::storage::migration::Migration;
+use crate::modules::storage::{Index, Storage, StorageError};
+use super::email_change::EMAIL_CHANGES_COLLECTION;
+use super::outbox::OUTBOX_COLLECTION;
+
+/// Sent messages are kept this long in the outbox.
+const SENT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Unique index on `email_verifications.user_id`, a user has a single verification.
+pub struct CreateVerificationsUserIndex;
+
+#[async_trait]
+impl Migration for CreateVerificationsUserIndex {
+    fn version(&self) -> i64 { 2 }
+
+    fn name(&self) -> &'static str { "create_email_verifications_user_id_index" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let index = Index::new("user_id_1", doc! { "user_id": 1 }).unique();
+        storage.collection::<Document>("email_verifications").create_index(index).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>("email_verifications").drop_index("user_id_1").await
+    }
+}
+
+/// Indexes the outbox by due date for the worker, and expires sent messages after a week.
+pub struct CreateOutboxIndexes;
+
+#[async_trait]
+impl Migration for CreateOutboxIndexes {
+    fn version(&self) -> i64 { 3 }
+
+    fn name(&self) -> &'static str { "create_mail_outbox_indexes" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let outbox = storage.collection::<Document>(OUTBOX_COLLECTION);
+        outbox.create_index(Index::new("status_1_next_attempt_1", doc! { "status": 1, "next_attempt": 1 })).await?;
+        outbox.create_index(Index::new("sent_1", doc! { "sent": 1 }).expire_after(SENT_RETENTION)).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        let outbox = storage.collection::<Document>(OUTBOX_COLLECTION);
+        outbox.drop_index("status_1_next_attempt_1").await?;
+        outbox.drop_index("sent_1").await
+    }
+}
+
+/// A user has a single pending email change, an address is claimed by a single one, and expired changes are dropped.
+pub struct CreateEmailChangesIndexes;
+
+#[async_trait]
+impl Migration for CreateEmailChangesIndexes {
+    fn version(&self) -> i64 { 5 }
+
+    fn name(&self) -> &'static str { "create_email_changes_indexes" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let changes = storage.collection::<Document>(EMAIL_CHANGES_COLLECTION);
+        changes.create_index(Index::new("user_id_1", doc! { "user_id": 1 }).unique()).await?;
+        changes.create_index(Index::new("new_email_1", doc! { "new_email": 1 }).unique()).await?;
+        changes.create_index(Index::new("expires_1", doc! { "expires": 1 }).expire_after(Duration::ZERO)).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        let changes = storage.collection::<Document>(EMAIL_CHANGES_COLLECTION);
+        changes.drop_index("user_id_1").await?;
+        changes.drop_index("new_email_1").await?;
+        changes.drop_index("expires_1").await
+    }
+}
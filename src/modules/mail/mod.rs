@@ -1,3 +1,8 @@
 pub mod service;
 pub mod controller;
-pub mod dto;
\ No newline at end of file
+pub mod dto;
+pub mod email_change;
+pub mod migrations;
+pub mod outbox;
+pub mod template;
+pub mod transport;
@@ -0,0 +1,270 @@
+use std::time::Duration;
+
+use std::fmt;
+
+use rocket::http::impl_from_uri_param_identity;
+use rocket::http::uri::fmt::{Formatter, Query, UriDisplay};
+use rocket::FromFormField;
+use rocket_db_pools::mongodb::bson::{self, doc, oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::framework::config::OxidizeConfig;
+use crate::modules::storage::{Collection, FindOneAndUpdateOptions, FindOptions, Session, Storage, StorageError};
+use super::transport::Email;
+
+pub const OUTBOX_COLLECTION: &str = "mail_outbox";
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 30_000;
+const DEFAULT_RETRY_MAX_BACKOFF_MS: u64 = 3_600_000;
+/// A message claimed by a worker that crashed is picked up again after this long.
+const SENDING_LEASE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxStatus {
+    Pending,
+    Sending,
+    Sent,
+    /// Gave up after `mail_max_attempts`, waits for an admin to requeue it.
+    Dead,
+}
+
+impl OutboxStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OutboxStatus::Pending => "pending",
+            OutboxStatus::Sending => "sending",
+            OutboxStatus::Sent => "sent",
+            OutboxStatus::Dead => "dead",
+        }
+    }
+}
+
+impl UriDisplay<Query> for OutboxStatus {
+    fn fmt(&self, f: &mut Formatter<'_, Query>) -> fmt::Result {
+        f.write_value(self.as_str())
+    }
+}
+
+impl_from_uri_param_identity!([Query] OutboxStatus);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub email: Email,
+    pub status: OutboxStatus,
+    pub attempts: u32,
+    pub next_attempt: DateTime,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    pub created: DateTime,
+    #[serde(default)]
+    pub sent: Option<DateTime>,
+}
+
+fn after(duration: Duration) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + duration.as_millis() as i64)
+}
+
+/// Delay before the next attempt, doubling with every failed attempt.
+/// ```
+/// use std::time::Duration;
+/// use oxidize::modules::mail::outbox::backoff;
+/// let base = Duration::from_secs(30);
+/// assert_eq!(backoff(3, base, Duration::from_secs(3600)), Duration::from_secs(120));
+/// assert_eq!(backoff(10, base, Duration::from_secs(3600)), Duration::from_secs(3600));
+/// ```
+pub fn backoff(attempts: u32, base: Duration, max: Duration) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    base.saturating_mul(factor).min(max)
+}
+
+/// Persistent queue of outgoing emails.
+pub struct Outbox {
+    pub messages: Collection<OutboxMessage>,
+}
+
+impl Outbox {
+    pub fn new(storage: &Storage) -> Self {
+        storage.add_collection(OUTBOX_COLLECTION);
+        Self { messages: storage.collection(OUTBOX_COLLECTION) }
+    }
+
+    /// Queues an email to be sent as soon as possible, optionally as part of a session.
+    pub async fn enqueue(&self, email: Email, session: Option<&mut Session>) -> Result<OutboxMessage, StorageError> {
+        let mut message = OutboxMessage {
+            _id: None,
+            email,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            next_attempt: DateTime::now(),
+            last_error: None,
+            created: DateTime::now(),
+            sent: None,
+        };
+        message._id = self.messages.insert_one(&message, session).await?.inserted_id.as_object_id();
+        Ok(message)
+    }
+
+    /// Claims the next message due for sending, including those whose sender stopped without reporting back.
+    pub async fn claim(&self) -> Result<Option<OutboxMessage>, StorageError> {
+        // The lease of a message being sent is kept in next_attempt
+        let statuses = vec![OutboxStatus::Pending.as_str(), OutboxStatus::Sending.as_str()];
+        let filter = doc! { "status": { "$in": statuses }, "next_attempt": { "$lte": DateTime::now() } };
+        let update = doc! { "$set": { "status": OutboxStatus::Sending.as_str(), "next_attempt": after(SENDING_LEASE) } };
+        let options = FindOneAndUpdateOptions { sort: Some(doc! { "next_attempt": 1 }), return_new: true, ..Default::default() };
+        self.messages.find_one_and_update(filter, update, options, None).await
+    }
+
+    pub async fn mark_sent(&self, id: &ObjectId) -> Result<(), StorageError> {
+        let update = doc! { "$set": { "status": OutboxStatus::Sent.as_str(), "sent": DateTime::now(), "last_error": bson::Bson::Null } };
+        self.messages.update_one(doc! { "_id": id }, update, None).await?;
+        Ok(())
+    }
+
+    /// Records a failed attempt, scheduling a retry or moving the message to the dead letters.
+    pub async fn mark_failed(&self, message: &OutboxMessage, error: &str, config: &OxidizeConfig) -> Result<OutboxStatus, StorageError> {
+        let env = &config.env;
+        let attempts = message.attempts + 1;
+        let status = if attempts >= env.mail_max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS) {
+            OutboxStatus::Dead
+        } else {
+            OutboxStatus::Pending
+        };
+        let delay = backoff(attempts,
+            Duration::from_millis(env.mail_retry_backoff_ms.unwrap_or(DEFAULT_RETRY_BACKOFF_MS)),
+            Duration::from_millis(env.mail_retry_max_backoff_ms.unwrap_or(DEFAULT_RETRY_MAX_BACKOFF_MS)));
+        let update = doc! { "$set": {
+            "status": status.as_str(),
+            "attempts": attempts,
+            "next_attempt": after(delay),
+            "last_error": error,
+        } };
+        self.messages.update_one(doc! { "_id": message._id }, update, None).await?;
+        Ok(status)
+    }
+
+    /// Messages in a status, or all of them, oldest first.
+    pub async fn list(&self, status: Option<OutboxStatus>, limit: i64) -> Result<Vec<OutboxMessage>, StorageError> {
+        let filter = match status {
+            Some(status) => doc! { "status": status.as_str() },
+            None => doc! {},
+        };
+        let options = FindOptions { sort: Some(doc! { "created": 1 }), limit: Some(limit), ..Default::default() };
+        self.messages.find(filter, options, None).await
+    }
+
+    pub async fn find(&self, id: &ObjectId) -> Result<Option<OutboxMessage>, StorageError> {
+        self.messages.find_one(doc! { "_id": id }, None).await
+    }
+
+    /// Gives a dead message a fresh set of attempts. Returns None if there is no dead message with that id.
+    pub async fn requeue(&self, id: &ObjectId) -> Result<Option<OutboxMessage>, StorageError> {
+        let update = doc! { "$set": { "status": OutboxStatus::Pending.as_str(), "attempts": 0, "next_attempt": DateTime::now() } };
+        let options = FindOneAndUpdateOptions { return_new: true, ..Default::default() };
+        self.messages.find_one_and_update(doc! { "_id": id, "status": OutboxStatus::Dead.as_str() }, update, options, None).await
+    }
+
+    /// Number of messages claimed by a sender that has not reported back yet.
+    pub async fn in_flight(&self) -> Result<u64, StorageError> {
+        self.messages.count_documents(doc! { "status": OutboxStatus::Sending.as_str() }, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    use crate::framework::config::ConfigHandle;
+    use crate::framework::testing::load_test_env;
+    use crate::framework::translator::OxidizeTranslator;
+    use crate::modules::mail::service::MailOracle;
+    use crate::modules::mail::transport::MailTransport;
+
+    use super::*;
+
+    struct FailingTransport;
+
+    #[async_trait]
+    impl MailTransport for FailingTransport {
+        async fn send(&self, _email: &Email) -> Result<(), io::Error> {
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Relay unreachable"))
+        }
+    }
+
+    fn oracle(transport: Arc<dyn MailTransport>) -> MailOracle {
+        load_test_env();
+        let mut config = OxidizeConfig::new().expect("Error reading env variables");
+        config.env.mail_max_attempts = Some(2);
+        config.env.mail_retry_backoff_ms = Some(0);
+        let config = Arc::new(config);
+        let storage = Arc::new(Storage::memory(config.clone()));
+        let translator = Arc::new(OxidizeTranslator::new(config.clone()));
+        MailOracle::with_transport(ConfigHandle::new(config), storage, translator, transport)
+    }
+
+    fn email() -> Email {
+        Email {
+            from: String::from("sender@example.com"),
+            reply_to: String::from("sender@example.com"),
+            to: String::from("someone@example.com"),
+            subject: String::from("Subject"),
+            body: String::from("Body"),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_sends_queued_mail() {
+        let mail = oracle(Arc::new(crate::modules::mail::transport::MemoryMailTransport::new()));
+        mail.enqueue(email(), None).await.expect("Error queuing email");
+        assert_eq!(mail.process_outbox().await.expect("Error processing outbox"), 1);
+        assert_eq!(mail.transport().as_memory().unwrap().sent_to("someone@example.com").len(), 1);
+
+        let messages = mail.outbox.list(None, 10).await.expect("Error listing outbox");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].status, OutboxStatus::Sent);
+        assert!(messages[0].sent.is_some());
+        assert_eq!(mail.process_outbox().await.expect("Error processing outbox"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_retries_and_dead_letters() {
+        let mail = oracle(Arc::new(FailingTransport));
+        mail.enqueue(email(), None).await.expect("Error queuing email");
+
+        // First failure schedules a retry, the second one exhausts the attempts
+        assert_eq!(mail.process_outbox().await.expect("Error processing outbox"), 0);
+        let dead = mail.outbox.list(Some(OutboxStatus::Dead), 10).await.expect("Error listing outbox");
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, 2);
+        assert_eq!(dead[0].last_error.as_deref(), Some("Relay unreachable"));
+        assert!(mail.outbox.claim().await.expect("Error claiming message").is_none());
+
+        // Requeuing gives it a fresh set of attempts, only dead messages can be requeued
+        let id = dead[0]._id.unwrap();
+        let requeued = mail.outbox.requeue(&id).await.expect("Error requeuing").expect("Message not requeued");
+        assert_eq!(requeued.status, OutboxStatus::Pending);
+        assert_eq!(requeued.attempts, 0);
+        assert!(mail.outbox.requeue(&id).await.expect("Error requeuing").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_reclaims_expired_lease() {
+        let mail = oracle(Arc::new(FailingTransport));
+        let message = mail.outbox.enqueue(email(), None).await.expect("Error queuing email");
+        let claimed = mail.outbox.claim().await.expect("Error claiming message").expect("Nothing to claim");
+        assert_eq!(claimed._id, message._id);
+        assert!(mail.outbox.claim().await.expect("Error claiming message").is_none());
+        assert_eq!(mail.outbox.in_flight().await.unwrap(), 1);
+
+        // A sender that never reported back loses the message once its lease is over
+        let update = doc! { "$set": { "next_attempt": DateTime::now() } };
+        mail.outbox.messages.update_one(doc! { "_id": message._id }, update, None).await.unwrap();
+        assert!(mail.outbox.claim().await.expect("Error claiming message").is_some());
+    }
+}
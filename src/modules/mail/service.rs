@@ -1,46 +1,156 @@
 extern crate rand;
 extern crate base64;
 
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use chrono::Utc;
-use log::error;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
 use rand::Rng;
 use rand::distributions::Alphanumeric;
 use base64::Engine;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use lettre::Address;
 use rocket::uri;
+use tera::Context;
+use tokio::sync::Notify;
 use rocket_db_pools::mongodb::bson::{self, doc};
 use rocket_db_pools::mongodb::bson::oid::ObjectId;
-use rocket_db_pools::mongodb::options::IndexOptions;
-use rocket_db_pools::mongodb::results::{InsertOneResult, UpdateResult};
-use rocket_db_pools::mongodb::{Collection, IndexModel};
-use rocket_db_pools::mongodb::error::Error;
-use crate::framework::config::OxidizeConfig;
-use crate::framework::translator::OxidizeTranslator;
-use crate::modules::mongo::service::MongoOracle;
+use crate::framework::auth::secret_digest;
+use crate::framework::config::ConfigHandle;
+use crate::framework::translator::{OxidizeTranslator, DEFAULT_LOCALE};
+use crate::modules::lockout::service::Lockouts;
+use crate::modules::storage::{Collection, Session, Storage, StorageError, UpdateResult};
+use crate::modules::throttle::service::Throttle;
 use crate::modules::user::dto::User;
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
 use std::io;
-use super::dto::EmailVerification;
+use super::dto::{EmailChange, EmailVerification, VerificationStatus};
+use super::email_change::EmailChanges;
+use super::outbox::{Outbox, OutboxStatus};
+use super::template::MailTemplates;
+use super::transport::{self, Email, MailTransport};
+
+const DEFAULT_OUTBOX_POLL_MS: u64 = 5_000;
+const DEFAULT_RESEND_COOLDOWN_SECS: u64 = 60;
+const DEFAULT_RESEND_IP_LIMIT: u32 = 10;
+const DEFAULT_RESEND_IP_WINDOW_SECS: u64 = 3_600;
+const DEFAULT_EMAIL_CHANGE_TTL_SECS: u64 = 86_400;
+
+/// Why a verification email was not sent again.
+#[derive(Debug)]
+pub enum ResendError {
+    /// The email is already verified.
+    Verified,
+    /// Too many emails were asked for, by the user or from the same IP, until the given time.
+    Throttled(DateTime<Utc>),
+    Storage(StorageError),
+}
+
+impl From<StorageError> for ResendError {
+    fn from(e: StorageError) -> Self {
+        ResendError::Storage(e)
+    }
+}
+
 pub struct MailOracle {
-    pub config: Arc<OxidizeConfig>,
-    pub mongo: Arc<MongoOracle>,
+    pub config: ConfigHandle,
+    pub storage: Arc<Storage>,
     pub verifications: Collection<EmailVerification>,
+    pub outbox: Outbox,
+    pub email_changes: EmailChanges,
     pub translator: Arc<OxidizeTranslator>,
+    pub templates: MailTemplates,
+    /// Limits how often verification emails are sent again from the same IP.
+    pub throttle: Throttle,
+    transport: RwLock<Arc<dyn MailTransport>>,
+    /// Wakes the outbox worker when a message is queued.
+    queued: Notify,
 }
 
 impl MailOracle {
 
-    pub fn new( config: Arc<OxidizeConfig>, mongo: Arc<MongoOracle>, translator: Arc<OxidizeTranslator> ) -> Self {
-        let db = mongo.db.as_ref().expect("Database not initialized");
-        mongo.add_collection("email_verifications");
-        let verifications: Collection<EmailVerification> = db.collection("email_verifications");
-        Self {config, mongo, verifications, translator}
+    /// Creates the oracle with the transport selected in the configuration.
+    pub fn new( config: ConfigHandle, storage: Arc<Storage>, translator: Arc<OxidizeTranslator> ) -> Self {
+        let transport = transport::from_config(&config.current()).expect("Invalid mail transport configuration");
+        Self::with_transport(config, storage, translator, Arc::from(transport))
+    }
+
+    pub fn with_transport(config: ConfigHandle, storage: Arc<Storage>, translator: Arc<OxidizeTranslator>, transport: Arc<dyn MailTransport>) -> Self {
+        storage.add_collection("email_verifications");
+        let verifications: Collection<EmailVerification> = storage.collection("email_verifications");
+        let outbox = Outbox::new(&storage);
+        let email_changes = EmailChanges::new(&storage);
+        let templates = MailTemplates::new(MailTemplates::template_dir(), translator.clone()).expect("Failed to load email templates");
+        let throttle = Throttle::new(&storage);
+        Self {config, storage, verifications, outbox, email_changes, translator, templates, throttle, transport: RwLock::new(transport), queued: Notify::new()}
+    }
+
+    pub fn transport(&self) -> Arc<dyn MailTransport> {
+        self.transport.read().unwrap().clone()
+    }
+
+    /// Rebuilds the transport from the current configuration, keeping the previous one if it is invalid.
+    pub fn reload_transport(&self) {
+        match transport::from_config(&self.config.current()) {
+            Ok(transport) => *self.transport.write().unwrap() = Arc::from(transport),
+            Err(e) => error!("Error reloading mail transport, keeping the previous one: {}", e),
+        }
+    }
+
+    /// Queues an email in the outbox, the worker sends it in the background.
+    pub async fn enqueue(&self, email: Email, session: Option<&mut Session>) -> Result<(), StorageError> {
+        let message = self.outbox.enqueue(email, session).await?;
+        info!("Email to {} queued as {:?}", message.email.to, message._id);
+        self.wake_outbox();
+        Ok(())
+    }
+
+    /// Lets the outbox worker look for due messages right away.
+    pub fn wake_outbox(&self) {
+        self.queued.notify_one();
+    }
+
+    /// Sends every due message of the outbox, rescheduling or dead-lettering the ones that fail. Returns how many were sent.
+    pub async fn process_outbox(&self) -> Result<usize, StorageError> {
+        let mut sent = 0;
+        while let Some(message) = self.outbox.claim().await? {
+            let id = message._id.expect("Outbox message without id");
+            match self.transport().send(&message.email).await {
+                Ok(()) => {
+                    self.outbox.mark_sent(&id).await?;
+                    info!("Email {} sent to {}", id, message.email.to);
+                    sent += 1;
+                }
+                Err(e) => {
+                    let status = self.outbox.mark_failed(&message, &e.to_string(), &self.config.current()).await?;
+                    if status == OutboxStatus::Dead {
+                        error!("Email {} to {} dead-lettered after {} attempts: {}", id, message.email.to, message.attempts + 1, e);
+                    } else {
+                        warn!("Email {} to {} failed, retrying later: {}", id, message.email.to, e);
+                    }
+                }
+            }
+        }
+        Ok(sent)
     }
 
-    fn generate_random_url_safe_string(&self, length: usize) -> String {
+    /// Runs the outbox worker until the process stops, waking up on every queued message or every `mail_outbox_poll_ms`.
+    pub fn spawn_outbox_worker(self: &Arc<Self>) {
+        let oracle = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = oracle.process_outbox().await {
+                    error!("Error processing mail outbox: {}", e);
+                }
+                let poll = oracle.config.current().env.mail_outbox_poll_ms.unwrap_or(DEFAULT_OUTBOX_POLL_MS);
+                let _ = tokio::time::timeout(Duration::from_millis(poll), oracle.queued.notified()).await;
+            }
+        });
+    }
+
+    /// Random secret for the links sent by email, `length` alphanumeric characters encoded in base64url.
+    pub fn generate_random_url_safe_string(&self, length: usize) -> String {
         // Generate a random string of the given length
         let random_string: String = rand::thread_rng()
             .sample_iter(&Alphanumeric)
@@ -54,36 +164,112 @@ impl MailOracle {
         encoded
     }
 
-    pub async fn start_verification(&self , user: &User) -> Option<EmailVerification>{
-        let secret = self.generate_random_url_safe_string(self.config.env.default_email_verification_key_length);
-
-        let previous_verification = self.find_verification_by_user_id(&user._id.clone().expect("User id not found")).await;
-        let mut verification = if previous_verification.is_some() {
-            let mut tmp = previous_verification.unwrap();
-            tmp.updated = Utc::now();
-            tmp.verified = false;
-            tmp.secret = secret;
-            self.update(&tmp).await.expect("Error updating verification on start step");
-            tmp
-        } else {
-            let mut tmp =EmailVerification { 
-                user_id: user._id.clone().expect("User id not found"),
-                email:user.email.clone(), 
-                secret , 
-                _id:None, 
-                created: Utc::now(),
-                updated: Utc::now(),
-                verified: false
-            };
-            let inserted_id = self.create(&tmp).await.expect("Error saving verification on start step");
-            tmp._id = Some(inserted_id);
-            tmp
+    /// Creates the verification of the user, or resets it with a new secret if there is one, without sending any email.
+    pub async fn prepare_verification(&self, user: &User, mut session: Option<&mut Session>) -> Result<EmailVerification, StorageError> {
+        let secret = self.generate_random_url_safe_string(self.config.current().env.default_email_verification_key_length);
+        let user_id = user._id.expect("User id not found");
+
+        let filter = doc! {"user_id": user_id};
+        let previous_verification = self.verifications.find_one(filter, session.as_deref_mut()).await?;
+        let verification = match previous_verification {
+            Some(mut tmp) => {
+                tmp.updated = Utc::now();
+                tmp.verified = false;
+                tmp.secret = secret;
+                self.update(&tmp, session).await?;
+                tmp
+            }
+            None => {
+                let mut tmp = EmailVerification {
+                    user_id,
+                    email: user.email.clone(),
+                    secret,
+                    _id: None,
+                    created: Utc::now(),
+                    updated: Utc::now(),
+                    verified: false
+                };
+                tmp._id = self.create(&tmp, session).await?;
+                tmp
+            }
         };
-        verification._id = Some(verification._id.clone().expect("Verification id not found"));
-        self.send_verification_mail(&verification.email, verification.clone()).await;
-        Some(verification)
+        Ok(verification)
+    }
+
+    pub async fn start_verification(&self , user: &User) -> Option<EmailVerification>{
+        match self.prepare_verification(user, None).await {
+            Ok(verification) => {
+                self.send_verification_mail(verification.clone(), user.locale.as_deref()).await;
+                Some(verification)
+            }
+            Err(e) => {
+                error!("Error starting verification for user {}: {}", user.email, e);
+                None
+            }
+        }
+    }
+
+    fn resend_ip_key(ip: Option<IpAddr>) -> String {
+        format!("verification_resend:{}", ip.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("unknown")))
+    }
+
+    /// Hits allowed per IP and the window they are counted in.
+    fn resend_ip_limit(&self) -> (u32, Duration) {
+        let config = self.config.current();
+        let limit = config.env.verification_resend_ip_limit.unwrap_or(DEFAULT_RESEND_IP_LIMIT);
+        let window = config.env.verification_resend_ip_window_secs.unwrap_or(DEFAULT_RESEND_IP_WINDOW_SECS);
+        (limit, Duration::from_secs(window))
+    }
+
+    /// End of the cooldown that follows the last email of `verification`, if it has not passed yet.
+    fn resend_cooldown(&self, verification: &EmailVerification) -> Option<DateTime<Utc>> {
+        let cooldown = self.config.current().env.verification_resend_cooldown_secs.unwrap_or(DEFAULT_RESEND_COOLDOWN_SECS);
+        let until = verification.updated + chrono::Duration::seconds(cooldown as i64);
+        Some(until).filter(|until| *until > Utc::now())
     }
 
+    /// Whether `user` verified their email, and when they may ask for a new email from `ip`.
+    pub async fn verification_status(&self, user: &User, ip: Option<IpAddr>) -> Result<VerificationStatus, StorageError> {
+        let user_id = user._id.expect("User id not found");
+        let verification = self.verifications.find_one(doc! {"user_id": user_id}, None).await?;
+        if verification.as_ref().is_some_and(|verification| verification.verified) {
+            return Ok(VerificationStatus { verified: true, next_resend: None });
+        }
+        let (limit, _) = self.resend_ip_limit();
+        let ip_retry = self.throttle.retry_at(&Self::resend_ip_key(ip), limit).await?;
+        let user_retry = verification.as_ref().and_then(|verification| self.resend_cooldown(verification));
+        Ok(VerificationStatus { verified: false, next_resend: user_retry.max(ip_retry) })
+    }
+
+    /// Sends a new verification email to `user`, unless it is verified or a cooldown for the user or `ip` is running.
+    pub async fn resend_verification(&self, user: &User, ip: Option<IpAddr>) -> Result<EmailVerification, ResendError> {
+        let user_id = user._id.expect("User id not found");
+        let verification = self.verifications.find_one(doc! {"user_id": user_id}, None).await?;
+        if let Some(verification) = &verification {
+            if verification.verified {
+                return Err(ResendError::Verified);
+            }
+            if let Some(until) = self.resend_cooldown(verification) {
+                return Err(ResendError::Throttled(until));
+            }
+        }
+        let (limit, window) = self.resend_ip_limit();
+        if let Some(until) = self.throttle.hit(&Self::resend_ip_key(ip), limit, window).await? {
+            return Err(ResendError::Throttled(until));
+        }
+        let verification = self.prepare_verification(user, None).await?;
+        self.send_verification_mail(verification.clone(), user.locale.as_deref()).await;
+        Ok(verification)
+    }
+
+    /// Whether `user` verified the email address it has now.
+    pub async fn is_verified(&self, user: &User) -> Result<bool, StorageError> {
+        let Some(user_id) = user._id else {
+            return Ok(false);
+        };
+        let filter = doc! {"user_id": user_id, "email": &user.email, "verified": true};
+        Ok(self.verifications.find_one(filter, None).await?.is_some())
+    }
 
     pub async fn find_verification_by_email(&self, email: &str) -> Option<EmailVerification> {
         let filter = doc! {"email": email};
@@ -116,24 +302,19 @@ impl MailOracle {
         match self.verifications.find_one(filter, None).await {
             Ok(verification) => verification,
             Err(e) => {
-                error!("Error finding user_id verification with {}: {}", user_id.to_string(), e);
+                error!("Error finding user_id verification with {}: {}", user_id, e);
                 None
             }
         }
     }
 
 
-    async fn create(&self, verification: &EmailVerification) -> Option<ObjectId> {
-        let new_verification_result: Result<InsertOneResult, Error> = self
-            .verifications
-            .insert_one(verification, None)
-            .await;
-        
-        match new_verification_result {
-            Ok(resp) =>  resp.inserted_id.as_object_id(),
+    async fn create(&self, verification: &EmailVerification, session: Option<&mut Session>) -> Result<Option<ObjectId>, StorageError> {
+        match self.verifications.insert_one(verification, session).await {
+            Ok(resp) => Ok(resp.inserted_id.as_object_id()),
             Err(e) => {
                 error!("Error creating verification: {}", e);
-                None
+                Err(e)
             }
         }
     }
@@ -150,7 +331,7 @@ impl MailOracle {
 
         if secret == verification.secret{
             verification.verified = true;
-            self.update(&verification).await.expect("Error updating verification on finsih step");
+            self.update(&verification, None).await.map_err(io::Error::other)?;
             Ok(verification)
         }
         else{
@@ -159,67 +340,165 @@ impl MailOracle {
 
     }
 
-    async fn update(&self, verification: &EmailVerification) -> Option<UpdateResult> {
-        let filter = doc! {"_id": &{verification._id}};
-        
-        let update_doc = doc! {"$set": bson::to_document(&verification).expect("Failed to convert EmailVerification to Document")};
-        let verify_res = self
-            .verifications
-            .update_one(filter, update_doc, None)
-            .await;
-        match verify_res {
-            Ok(up_verification) => Some(up_verification),
-            Err(e) =>{
-                error!("Error updating user with id {}: {}", verification._id?, e);
-                None
-            }  
+    async fn update(&self, verification: &EmailVerification, session: Option<&mut Session>) -> Result<UpdateResult, StorageError> {
+        let filter = doc! {"_id": verification._id};
+
+        let update_doc = doc! {"$set": bson::to_document(&verification)?};
+        let verify_res = self.verifications.update_one(filter, update_doc, session).await;
+        if let Err(e) = &verify_res {
+            error!("Error updating verification with id {:?}: {}", verification._id, e);
         }
+        verify_res
     }
 
-    pub async fn initialize_db(&self) -> Result<(), Error> {
-        // Create unique index on email field
-        let index = IndexModel::builder().keys(doc! { "user_id": 1 }).
-            options(IndexOptions::builder().unique(true).build()).build();
-
-        self.verifications.create_index(index,None)
-            .await.expect("Error creating index for email in verifications.");
-        Ok(())
+    /// Renders the `template` email in `locale` and queues it for `to`.
+    async fn send_template(&self, to: &str, template: &str, subject: String, locale: &str, context: &Context) {
+        let config = self.config.current();
+        let rendered = match self.templates.render(template, locale, context) {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                error!("Could not render {} email to {}: {}", template, to, e);
+                return;
+            }
+        };
+        let email = Email {
+            from: config.env.email_sender_from.clone(),
+            reply_to: config.env.email_reply_to.clone(),
+            to: to.to_string(),
+            subject,
+            body: rendered.text,
+            html: Some(rendered.html),
+            inline_images: rendered.inline_images,
+        };
+        if let Err(e) = self.enqueue(email, None).await {
+            error!("Could not queue {} email to {}: {}", template, to, e);
+        }
     }
 
-    pub async fn send_verification_mail(&self, mail_to: &str, verification: EmailVerification)  {
+    /// Queues the verification email, in `locale` or the closest available one.
+    pub async fn send_verification_mail(&self, verification: EmailVerification, locale: Option<&str>)  {
+        let mail_to = verification.email.as_str();
+        let locale = locale.unwrap_or(DEFAULT_LOCALE);
         let link = uri!(crate::modules::mail::controller::finish_verification(
             id=verification._id.unwrap().to_string(), 
             secret=verification.secret.clone()));
-        if self.config.env.run_mode == "dev" {
-            println!("Email verification link: {}",link.to_string());
-            return;
+
+        let mut context = Context::new();
+        context.insert("email", mail_to);
+        context.insert("link", &link.to_string());
+        let subject = self.translator.get_in(locale, "verify_email_subject", Some(vec![("email", mail_to.to_string().into())]));
+        self.send_template(mail_to, "verify_email", subject, locale, &context).await;
+    }
+
+    /// Queues the email with a sign-in `link`, valid for `ttl_secs`, in `locale` or the closest available one.
+    pub async fn send_magic_link_mail(&self, to: &str, locale: Option<&str>, link: &str, ttl_secs: u64) {
+        let locale = locale.unwrap_or(DEFAULT_LOCALE);
+        let mut context = Context::new();
+        context.insert("link", link);
+        context.insert("minutes", &ttl_secs.div_ceil(60));
+        let subject = self.translator.get_in(locale, "magic_link_subject", None);
+        self.send_template(to, "magic_link", subject, locale, &context).await;
+    }
+
+    /// Tells the owner of an account that it is locked after `failures` failed attempts, until `until`.
+    pub async fn send_account_locked_mail(&self, to: &str, locale: Option<&str>, failures: u32, until: DateTime<Utc>) {
+        let locale = locale.unwrap_or(DEFAULT_LOCALE);
+        let mut context = Context::new();
+        context.insert("failures", &failures);
+        context.insert("until", &until.format("%Y-%m-%d %H:%M UTC").to_string());
+        let subject = self.translator.get_in(locale, "account_locked_subject", None);
+        self.send_template(to, "account_locked", subject, locale, &context).await;
+    }
+
+    /// Starts replacing the email of `user` with `new_email`: the new address gets a confirmation link and the old one
+    /// a notice, and nothing changes until the link is followed. Replaces the pending change of the user, if any.
+    pub async fn request_email_change(&self, user: &User, new_email: &str) -> Result<EmailChange, io::Error> {
+        let user_id = user._id.expect("User id not found");
+        let new_email = new_email.trim();
+        if new_email.parse::<Address>().is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid email address"));
+        }
+        if new_email.eq_ignore_ascii_case(&user.email) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The email address is already the current one"));
+        }
+        // The address stays free in `users` until the change is confirmed, so it is reserved here instead
+        let users: Collection<User> = self.storage.collection("users");
+        if users.find_one(doc! {"email": new_email}, None).await.map_err(io::Error::other)?.is_some()
+            || self.email_changes.is_claimed(new_email, &user_id).await.map_err(io::Error::other)? {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "The email address is already in use"));
         }
-        
-        self.translator.get("verify_email_body", None);
-        let email_body = self.translator.get("verify_email_body", Some(vec![("link", link.to_string().into())]));
-        // Define the email content and sender/recipient details
-        let email = Message::builder()
-        .from(self.config.env.email_sender_from.parse().unwrap())
-        .reply_to(self.config.env.email_reply_to.parse().unwrap())
-        .to(mail_to.parse().unwrap())
-        .subject(self.translator.get("verify_email_subject", None))
-        .body(email_body)
-        .unwrap();
-
-        // Define SMTP server credentials
-        let creds = Credentials::new(self.config.env.smtp_user.clone(), self.config.env.smtp_password.to_string());
-
-        // Connect to an SMTP relay server
-        let mailer = SmtpTransport::relay(&self.config.env.smtp_host)
-            .unwrap()
-            .credentials(creds)
-            .build();
-
-        // Send the email
-        match mailer.send(&email) {
-            Ok(_) => println!("Email sent successfully!"),
-            Err(e) => eprintln!("Could not send email: {:?}", e),
+
+        let ttl = self.config.current().env.email_change_ttl_secs.unwrap_or(DEFAULT_EMAIL_CHANGE_TTL_SECS);
+        let secret = self.generate_random_url_safe_string(self.config.current().env.default_email_verification_key_length);
+        let mut change = EmailChange {
+            _id: None,
+            user_id,
+            old_email: user.email.clone(),
+            new_email: new_email.to_string(),
+            secret: secret_digest(&secret),
+            created: Utc::now(),
+            expires: bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + ttl as i64 * 1000),
+        };
+        change._id = match self.email_changes.replace(&change).await {
+            Ok(id) => id,
+            Err(e) if e.is_duplicate_key() => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "The email address is already in use")),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+
+        let locale = user.locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+        let link = uri!(crate::modules::mail::controller::confirm_email_change(
+            id=change._id.unwrap().to_string(),
+            secret=secret));
+        let mut context = Context::new();
+        context.insert("email", &change.new_email);
+        context.insert("old_email", &change.old_email);
+        context.insert("link", &link.to_string());
+        let subject = self.translator.get_in(locale, "email_change_subject", None);
+        self.send_template(&change.new_email, "email_change", subject, locale, &context).await;
+        let subject = self.translator.get_in(locale, "email_change_notice_subject", None);
+        self.send_template(&change.old_email, "email_change_notice", subject, locale, &context).await;
+        Ok(change)
+    }
+
+    /// Applies the email change `change_id` of `user_id` if `secret` matches: the user gets the new address, and the
+    /// verification moves to it, verified by the confirmation itself. Returns the updated user. Changes of other users
+    /// and wrong secrets count as failed attempts against `user_id` and `ip` in `lockouts`.
+    pub async fn confirm_email_change(&self, lockouts: &Lockouts, user_id: &ObjectId, change_id: &ObjectId, secret: &str, ip: Option<IpAddr>)
+        -> Result<User, io::Error> {
+        lockouts.check(Some(user_id), ip).await?;
+        let Some(change) = self.email_changes.find(change_id).await.map_err(io::Error::other)? else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Email change not found"));
+        };
+        if user_id != &change.user_id {
+            let error = io::Error::new(io::ErrorKind::PermissionDenied, "Wrong user id in email change");
+            return Err(lockouts.reject(Some(user_id), ip, error).await);
+        }
+        if secret_digest(secret) != change.secret {
+            let error = io::Error::new(io::ErrorKind::InvalidData, "Email change secret does not match");
+            return Err(lockouts.reject(Some(user_id), ip, error).await);
+        }
+
+        let users: Collection<User> = self.storage.collection("users");
+        let context = (&users, &self.verifications, &self.email_changes, &change);
+        let applied = self.storage.transaction(context, |session, (users, verifications, changes, change)| Box::pin(async move {
+            let now = bson::to_bson(&Utc::now()).expect("Failed to convert date");
+            users.update_one(doc! {"_id": change.user_id}, doc! {"$set": {"email": &change.new_email}}, Some(&mut *session)).await?;
+            let verification = doc! {"$set": {"email": &change.new_email, "verified": true, "updated": now}};
+            verifications.update_one(doc! {"user_id": change.user_id}, verification, Some(&mut *session)).await?;
+            changes.remove(&change.user_id, Some(session)).await
+        })).await;
+        match applied {
+            Ok(_) => {}
+            // Someone signed up with the address in the meantime
+            Err(e) if e.is_duplicate_key() => {
+                self.email_changes.remove(user_id, None).await.map_err(io::Error::other)?;
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "The email address is already in use"));
+            }
+            Err(e) => return Err(io::Error::other(e)),
         }
+        info!("Email of user {} changed from {} to {}", user_id, change.old_email, change.new_email);
+        users.find_one(doc! {"_id": user_id}, None).await.map_err(io::Error::other)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "User not found"))
     }
     
 }
@@ -227,16 +506,17 @@ impl MailOracle {
 mod tests {
     use std::sync::Arc;
 
-    use crate::{framework::config::OxidizeConfig, modules::mongo::service::MongoOracle};
+    use crate::framework::{config::OxidizeConfig, testing::load_test_env};
 
     use super::*;
 
     #[tokio::test]
     async fn test_ranodom_string_generator() {
+        load_test_env();
         let config = Arc::new(OxidizeConfig::new().expect("Error while getting config"));
-        let mongo = Arc::new(MongoOracle::new(config.clone()).await);
+        let storage = Arc::new(Storage::memory(config.clone()));
         let translator = Arc::new(OxidizeTranslator::new(config.clone()));
-        let mail = MailOracle::new(config, mongo, translator);
+        let mail = MailOracle::new(ConfigHandle::new(config), storage, translator);
         let length = 32;
         let result = mail.generate_random_url_safe_string(length);
 
@@ -251,7 +531,7 @@ mod tests {
         // Optional: Check the length of the generated string
         // This is tricky because URL_SAFE_NO_PAD encoding length depends on the input length
         // Base64 encoding increases the length by ~4/3, so we can check for some reasonable bounds
-        let expected_min_length = (length * 4 / 3) as usize;
+        let expected_min_length = length * 4 / 3;
         assert!(result.len() >= expected_min_length, "Encoded string is too short");
     }
 }
\ No newline at end of file
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use fluent::FluentValue;
+use log::{error, info, warn};
+use regex::Regex;
+use tera::{Context, Tera, Value};
+
+use crate::framework::translator::OxidizeTranslator;
+use super::transport::InlineImage;
+
+/// Both parts of an email rendered from its templates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedMail {
+    pub text: String,
+    pub html: String,
+    /// Images referenced from the HTML as `cid:<name>`.
+    pub inline_images: Vec<InlineImage>,
+}
+
+/// Email templates, rendered with tera. Every email `<name>` has a `<name>.html.tera` and a `<name>.txt.tera`,
+/// usually extending `layout.html.tera` and `layout.txt.tera`. A template in a locale directory, e.g.
+/// `es-ES/<name>.html.tera`, takes precedence for the locales falling back to it. The copy comes from the Fluent resources through
+/// `{{ t(key="message", param=value) }}`, the rules of `style.css` are inlined into the HTML, and the images in
+/// `images/` are attached when the HTML refers to them as `cid:<file stem>`.
+pub struct MailTemplates {
+    dir: PathBuf,
+    translator: Arc<OxidizeTranslator>,
+    loaded: RwLock<Arc<LoadedTemplates>>,
+}
+
+struct LoadedTemplates {
+    tera: Tera,
+    stylesheet: Stylesheet,
+    images: HashMap<String, InlineImage>,
+}
+
+impl MailTemplates {
+    /// Directory holding the email templates.
+    pub fn template_dir() -> PathBuf {
+        let mut base = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        base.push("templates");
+        base.push("mail");
+        base
+    }
+
+    pub fn new(dir: PathBuf, translator: Arc<OxidizeTranslator>) -> Result<Self, io::Error> {
+        let loaded = LoadedTemplates::load(&dir)?;
+        Ok(Self { dir, translator, loaded: RwLock::new(Arc::new(loaded)) })
+    }
+
+    /// Loads the templates again. The previous ones stay in use if they fail to load.
+    pub fn reload(&self) {
+        match LoadedTemplates::load(&self.dir) {
+            Ok(loaded) => {
+                *self.loaded.write().unwrap() = Arc::new(loaded);
+                info!("Email templates reloaded");
+            }
+            Err(e) => error!("Error reloading email templates, keeping the previous ones: {}", e),
+        }
+    }
+
+    /// Renders both parts of the email `name` in the best available match of `locale`, which the templates get as
+    /// `locale`.
+    pub fn render(&self, name: &str, locale: &str, context: &Context) -> Result<RenderedMail, io::Error> {
+        let loaded = self.loaded.read().unwrap().clone();
+        let chain = self.translator.fallback_chain(locale);
+        // Functions only see their arguments, so `t` is bound to the locale on a copy of the templates
+        let mut tera = loaded.tera.clone();
+        tera.register_function("t", translate(self.translator.clone(), chain[0].clone()));
+        let mut context = context.clone();
+        context.insert("locale", &chain[0]);
+
+        let render = |kind: &str| {
+            let template = loaded.template_name(name, kind, &chain)?;
+            tera.render(&template, &context)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Error rendering {}: {}", template, error_chain(&e))))
+        };
+        let text = render("txt")?;
+        let html = loaded.stylesheet.inline(&render("html")?);
+        let inline_images = loaded.referenced_images(&html)?;
+        Ok(RenderedMail { text, html, inline_images })
+    }
+}
+
+impl LoadedTemplates {
+    fn load(dir: &Path) -> Result<Self, io::Error> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let glob = dir.join("**").join("*.tera");
+        let mut tera = Tera::new(&glob.to_string_lossy())
+            .map_err(|e| invalid(format!("Error loading email templates from {}: {}", dir.display(), error_chain(&e))))?;
+        tera.autoescape_on(vec![".html.tera"]);
+        tera.set_escape_fn(escape_html);
+
+        let stylesheet = match fs::read_to_string(dir.join("style.css")) {
+            Ok(css) => Stylesheet::parse(&css),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Stylesheet::default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut images = HashMap::new();
+        let images_dir = dir.join("images");
+        if images_dir.is_dir() {
+            for entry in fs::read_dir(&images_dir)? {
+                let path = entry?.path();
+                let (Some(stem), Some(extension)) = (path.file_stem(), path.extension()) else {
+                    continue;
+                };
+                let content_type = match extension.to_string_lossy().to_lowercase().as_str() {
+                    "png" => "image/png",
+                    "jpg" | "jpeg" => "image/jpeg",
+                    "gif" => "image/gif",
+                    _ => {
+                        warn!("Skipping email image {} of unknown type", path.display());
+                        continue;
+                    }
+                };
+                let content_id = stem.to_string_lossy().to_string();
+                let data = fs::read(&path)?;
+                images.insert(content_id.clone(), InlineImage { content_id, content_type: String::from(content_type), data });
+            }
+        }
+        Ok(Self { tera, stylesheet, images })
+    }
+
+    fn template_name(&self, name: &str, kind: &str, locales: &[String]) -> Result<String, io::Error> {
+        let default = format!("{}.{}.tera", name, kind);
+        let names: Vec<&str> = self.tera.get_template_names().collect();
+        locales.iter().map(|locale| format!("{}/{}", locale, default))
+            .chain([default.clone()])
+            .find(|candidate| names.contains(&candidate.as_str()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Email template {}.{}.tera not found", name, kind)))
+    }
+
+    fn referenced_images(&self, html: &str) -> Result<Vec<InlineImage>, io::Error> {
+        static CID: OnceLock<Regex> = OnceLock::new();
+        let cid = CID.get_or_init(|| Regex::new(r"cid:([A-Za-z0-9_.-]+)").unwrap());
+        let mut images: Vec<InlineImage> = vec![];
+        for capture in cid.captures_iter(html) {
+            let content_id = &capture[1];
+            if images.iter().any(|image| image.content_id == content_id) {
+                continue;
+            }
+            let image = self.images.get(content_id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Email image {} not found", content_id)))?;
+            images.push(image.clone());
+        }
+        Ok(images)
+    }
+}
+
+/// Escapes the characters that are significant in HTML. Unlike the escaping of tera, slashes are kept so links stay
+/// readable in the source of the email.
+fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Tera errors keep the cause of a failure in their source chain.
+fn error_chain(error: &tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// `t(key="message", ...)`, the Fluent message `key` in `locale` with the other arguments as its parameters.
+fn translate(translator: Arc<OxidizeTranslator>, locale: String) -> impl tera::Function {
+    move |args: &HashMap<String, Value>| {
+        let key = args.get("key").and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("t() requires a `key` argument"))?;
+        let params = args.iter()
+            .filter(|(name, _)| name.as_str() != "key")
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::Number(number) => FluentValue::from(number.as_f64().unwrap_or_default()),
+                    Value::String(string) => FluentValue::from(string.clone()),
+                    other => FluentValue::from(other.to_string()),
+                };
+                (name.as_str(), value)
+            })
+            .collect();
+        Ok(Value::String(translator.get_in(&locale, key, Some(params))))
+    }
+}
+
+/// Rules of a stylesheet that can be inlined: `tag`, `.class` and `tag.class` selectors.
+#[derive(Debug, Default)]
+struct Stylesheet {
+    rules: Vec<StyleRule>,
+}
+
+#[derive(Debug)]
+struct StyleRule {
+    tag: Option<String>,
+    class: Option<String>,
+    declarations: String,
+}
+
+impl StyleRule {
+    fn specificity(&self) -> u8 {
+        self.tag.is_some() as u8 + 2 * self.class.is_some() as u8
+    }
+
+    fn applies(&self, tag: &str, classes: &[&str]) -> bool {
+        self.tag.as_deref().is_none_or(|rule_tag| rule_tag.eq_ignore_ascii_case(tag))
+            && self.class.as_deref().is_none_or(|class| classes.contains(&class))
+    }
+}
+
+impl Stylesheet {
+    fn parse(css: &str) -> Self {
+        static COMMENT: OnceLock<Regex> = OnceLock::new();
+        static RULE: OnceLock<Regex> = OnceLock::new();
+        static SELECTOR: OnceLock<Regex> = OnceLock::new();
+        let comment = COMMENT.get_or_init(|| Regex::new(r"(?s)/\*.*?\*/").unwrap());
+        let rule = RULE.get_or_init(|| Regex::new(r"([^{}]+)\{([^{}]*)\}").unwrap());
+        let selector = SELECTOR.get_or_init(|| Regex::new(r"^([A-Za-z][A-Za-z0-9]*)?(?:\.([A-Za-z0-9_-]+))?$").unwrap());
+
+        let css = comment.replace_all(css, "");
+        let mut rules = vec![];
+        for capture in rule.captures_iter(&css) {
+            // Double quotes would end the style attribute
+            let declarations = capture[2].split(';')
+                .map(|declaration| declaration.trim().replace('"', "'"))
+                .filter(|declaration| !declaration.is_empty())
+                .collect::<Vec<_>>()
+                .join("; ");
+            for part in capture[1].split(',').map(str::trim) {
+                match selector.captures(part) {
+                    Some(parsed) if !part.is_empty() => rules.push(StyleRule {
+                        tag: parsed.get(1).map(|tag| tag.as_str().to_lowercase()),
+                        class: parsed.get(2).map(|class| class.as_str().to_string()),
+                        declarations: declarations.clone(),
+                    }),
+                    _ => warn!("Email stylesheet selector {} cannot be inlined, skipping it", part),
+                }
+            }
+        }
+        // Stable, so rules of the same specificity keep the order of the stylesheet
+        rules.sort_by_key(StyleRule::specificity);
+        Self { rules }
+    }
+
+    /// Adds the matching rules to the `style` attribute of every element. Declarations already in it win.
+    fn inline(&self, html: &str) -> String {
+        static TAG: OnceLock<Regex> = OnceLock::new();
+        static CLASS: OnceLock<Regex> = OnceLock::new();
+        static STYLE: OnceLock<Regex> = OnceLock::new();
+        if self.rules.is_empty() {
+            return html.to_string();
+        }
+        let tag = TAG.get_or_init(|| Regex::new(r"<([A-Za-z][A-Za-z0-9]*)((?:\s[^<>]*?)?)(/?)>").unwrap());
+        let class = CLASS.get_or_init(|| Regex::new(r#"\sclass\s*=\s*"([^"]*)""#).unwrap());
+        let style = STYLE.get_or_init(|| Regex::new(r#"\sstyle\s*=\s*"([^"]*)""#).unwrap());
+
+        tag.replace_all(html, |capture: &regex::Captures| {
+            let (name, attributes, closing) = (&capture[1], &capture[2], &capture[3]);
+            let classes: Vec<&str> = class.captures(attributes)
+                .map(|found| found.get(1).unwrap().as_str().split_whitespace().collect())
+                .unwrap_or_default();
+            let mut declarations: Vec<&str> = self.rules.iter()
+                .filter(|rule| rule.applies(name, &classes))
+                .map(|rule| rule.declarations.as_str())
+                .filter(|declarations| !declarations.is_empty())
+                .collect();
+            if declarations.is_empty() {
+                return capture[0].to_string();
+            }
+            let existing = style.captures(attributes).map(|found| found.get(1).unwrap().as_str().trim().trim_end_matches(';').to_string());
+            if let Some(existing) = existing.as_deref().filter(|existing| !existing.is_empty()) {
+                declarations.push(existing);
+            }
+            let attributes = style.replace(attributes, "");
+            format!("<{}{} style=\"{}\"{}>", name, attributes.trim_end(), declarations.join("; "), closing)
+        }).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rocket_db_pools::mongodb::bson::oid::ObjectId;
+    use tera::Context;
+
+    use crate::framework::{config::OxidizeConfig, testing::load_test_env, translator::OxidizeTranslator};
+
+    use super::*;
+
+    fn translator() -> Arc<OxidizeTranslator> {
+        load_test_env();
+        let config = Arc::new(OxidizeConfig::new().expect("Error reading env variables"));
+        Arc::new(OxidizeTranslator::new(config))
+    }
+
+    #[test]
+    fn test_inline_css() {
+        let stylesheet = Stylesheet::parse("/* base */ p { margin: 0 } .button, a.button { color: \"red\"; } div p { color: blue }");
+        assert_eq!(stylesheet.rules.len(), 3);
+        let html = stylesheet.inline("<p>Hi</p><a class=\"big button\" style=\"padding: 1px;\" href=\"/x\">Go</a><br/>");
+        assert_eq!(html, "<p style=\"margin: 0\">Hi</p><a class=\"big button\" href=\"/x\" style=\"color: 'red'; color: 'red'; padding: 1px\">Go</a><br/>");
+    }
+
+    #[test]
+    fn test_verify_email_template() {
+        let templates = MailTemplates::new(MailTemplates::template_dir(), translator()).expect("Error loading templates");
+        let mut context = Context::new();
+        context.insert("email", "someone@example.com");
+        context.insert("link", "/mail/verifications/1/verify/secret");
+        let rendered = templates.render("verify_email", "en-US", &context).expect("Error rendering template");
+
+        assert!(rendered.text.contains("/mail/verifications/1/verify/secret"));
+        assert!(!rendered.text.contains('<'));
+        assert!(rendered.html.contains("href=\"/mail/verifications/1/verify/secret\""));
+        assert!(rendered.html.contains("<html lang=\"en-US\">"));
+        assert!(rendered.html.contains("style=\""));
+        assert!(rendered.html.contains("title=\"Opens the verification page\""));
+        assert!(rendered.text.contains("on Oxidize."));
+        assert_eq!(rendered.inline_images.len(), 1);
+        assert_eq!(rendered.inline_images[0].content_id, "logo");
+        assert_eq!(rendered.inline_images[0].content_type, "image/png");
+    }
+
+    #[test]
+    fn test_translated_template() {
+        let templates = MailTemplates::new(MailTemplates::template_dir(), translator()).expect("Error loading templates");
+        let mut context = Context::new();
+        context.insert("email", "someone@example.com");
+        context.insert("link", "/mail/verifications/1/verify/secret");
+        let rendered = templates.render("verify_email", "es", &context).expect("Error rendering template");
+        assert!(rendered.html.contains("<html lang=\"es-ES\">"));
+        assert!(rendered.html.contains("Verificar correo"));
+        assert!(rendered.text.contains("Si el botón no funciona"));
+    }
+
+    #[test]
+    fn test_localized_templates() {
+        let dir = std::env::temp_dir().join(format!("oxidize_templates_{}", ObjectId::new().to_hex()));
+        std::fs::create_dir_all(dir.join("es-ES")).unwrap();
+        std::fs::write(dir.join("layout.txt.tera"), "{% block content %}{% endblock content %}\n-- {{ t(key=\"test\") }}").unwrap();
+        std::fs::write(dir.join("hello.txt.tera"), "{% extends \"layout.txt.tera\" %}{% block content %}Hello {{ name }}{% endblock content %}").unwrap();
+        std::fs::write(dir.join("hello.html.tera"), "<p>Hello {{ name }} <img src=\"cid:missing\"></p>").unwrap();
+        std::fs::write(dir.join("es-ES/hello.txt.tera"), "Hola {{ name }}").unwrap();
+        let templates = MailTemplates::new(dir.clone(), translator()).expect("Error loading templates");
+        let mut context = Context::new();
+        context.insert("name", "<Ana>");
+
+        // Referenced images have to exist
+        let error = templates.render("hello", "en-US", &context).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        std::fs::write(dir.join("hello.html.tera"), "<p>Hello {{ name }}</p>").unwrap();
+        templates.reload();
+
+        let rendered = templates.render("hello", "en-US", &context).expect("Error rendering template");
+        assert_eq!(rendered.text, "Hello <Ana>\n-- This is a test");
+        assert_eq!(rendered.html, "<p>Hello &lt;Ana&gt;</p>");
+        let rendered = templates.render("hello", "es-MX", &context).expect("Error rendering template");
+        assert_eq!(rendered.text, "Hola <Ana>");
+        assert!(templates.render("goodbye", "en-US", &context).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, MultiPart, SinglePart};
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::info;
+use rocket_db_pools::mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::framework::config::OxidizeConfig;
+
+const DEFAULT_MAIL_DIR: &str = "target/mail";
+
+/// An image shipped with the email, referenced from the HTML part as `cid:<content_id>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InlineImage {
+    pub content_id: String,
+    pub content_type: String,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// An email ready to be handed to a transport.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Email {
+    pub from: String,
+    pub reply_to: String,
+    pub to: String,
+    pub subject: String,
+    /// Plain text part.
+    pub body: String,
+    /// HTML alternative of the body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inline_images: Vec<InlineImage>,
+}
+
+impl Email {
+    /// Builds the MIME message, failing on malformed addresses. With an HTML part the message is
+    /// `multipart/alternative`, and the inline images travel next to the HTML in a `multipart/related` part.
+    pub fn message(&self) -> Result<Message, io::Error> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let address = |value: &str| value.parse()
+            .map_err(|e| invalid(format!("Invalid address {}: {}", value, e)));
+        let builder = Message::builder()
+            .from(address(&self.from)?)
+            .reply_to(address(&self.reply_to)?)
+            .to(address(&self.to)?)
+            .subject(self.subject.clone());
+        let Some(html) = &self.html else {
+            return builder.body(self.body.clone()).map_err(|e| invalid(e.to_string()));
+        };
+        let alternative = MultiPart::alternative().singlepart(SinglePart::plain(self.body.clone()));
+        let alternative = if self.inline_images.is_empty() {
+            alternative.singlepart(SinglePart::html(html.clone()))
+        } else {
+            let mut related = MultiPart::related().singlepart(SinglePart::html(html.clone()));
+            for image in &self.inline_images {
+                let content_type = ContentType::parse(&image.content_type)
+                    .map_err(|e| invalid(format!("Invalid content type {}: {}", image.content_type, e)))?;
+                related = related.singlepart(Attachment::new_inline(image.content_id.clone()).body(image.data.clone(), content_type));
+            }
+            alternative.multipart(related)
+        };
+        builder.multipart(alternative).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Links found in the body, absolute ones as well as paths of this server. The isolation marks fluent puts around
+    /// placeables count as separators.
+    /// ```
+    /// use oxidize::modules::mail::transport::Email;
+    /// let email = Email { body: String::from("Click on /mail/verify/1, or https://example.com."), ..Default::default() };
+    /// assert_eq!(email.links(), vec!["/mail/verify/1", "https://example.com"]);
+    /// ```
+    pub fn links(&self) -> Vec<String> {
+        self.body.split(|c: char| c.is_whitespace() || c == '\u{2068}' || c == '\u{2069}')
+            .map(|word| word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']))
+            .filter(|word| word.starts_with("http://") || word.starts_with("https://") || (word.starts_with('/') && word.len() > 1))
+            .map(String::from)
+            .collect()
+    }
+}
+
+/// Delivers emails. Selected through `mail_transport`.
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), io::Error>;
+
+    /// The capturing transport, when this is one. Lets tests inspect what was sent.
+    fn as_memory(&self) -> Option<&MemoryMailTransport> {
+        None
+    }
+}
+
+/// Builds the transport named in the configuration. Defaults to SMTP, or to files in `dev` mode.
+pub fn from_config(config: &OxidizeConfig) -> Result<Box<dyn MailTransport>, io::Error> {
+    let env = &config.env;
+    let default = if env.run_mode == "dev" { "file" } else { "smtp" };
+    match env.mail_transport.as_deref().unwrap_or(default) {
+        "smtp" => Ok(Box::new(SmtpMailTransport::new(config)?)),
+        "file" => Ok(Box::new(FileMailTransport::new(config)?)),
+        "memory" => Ok(Box::new(MemoryMailTransport::new())),
+        other => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown mail transport: {}", other))),
+    }
+}
+
+/// Sends through an SMTP relay, reusing pooled connections.
+pub struct SmtpMailTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailTransport {
+    /// TLS follows `smtp_tls`: `implicit` (default, port 465), `starttls` (port 587) or `none` (port 25).
+    pub fn new(config: &OxidizeConfig) -> Result<Self, io::Error> {
+        let env = &config.env;
+        let invalid = |e: lettre::transport::smtp::Error| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let mut builder = match env.smtp_tls.as_deref().unwrap_or("implicit") {
+            "implicit" => AsyncSmtpTransport::<Tokio1Executor>::relay(&env.smtp_host).map_err(invalid)?,
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&env.smtp_host).map_err(invalid)?,
+            "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&env.smtp_host),
+            other => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown smtp_tls mode: {}", other))),
+        };
+        builder = builder.credentials(Credentials::new(env.smtp_user.clone(), env.smtp_password.to_string()));
+        if let Some(port) = env.smtp_port {
+            builder = builder.port(port);
+        }
+        if let Some(ms) = env.smtp_timeout_ms {
+            builder = builder.timeout(Some(Duration::from_millis(ms)));
+        }
+        if let Some(size) = env.smtp_pool_max_size {
+            builder = builder.pool_config(PoolConfig::new().max_size(size));
+        }
+        Ok(Self { mailer: builder.build() })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpMailTransport {
+    async fn send(&self, email: &Email) -> Result<(), io::Error> {
+        self.mailer.send(email.message()?).await.map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// Writes every email to `mail_dir`, as `.eml` files or in a maildir (`mail_dir_format=maildir`).
+pub struct FileMailTransport {
+    dir: PathBuf,
+    maildir: bool,
+}
+
+impl FileMailTransport {
+    pub fn new(config: &OxidizeConfig) -> Result<Self, io::Error> {
+        let dir = PathBuf::from(config.env.mail_dir.as_deref().unwrap_or(DEFAULT_MAIL_DIR));
+        let maildir = match config.env.mail_dir_format.as_deref().unwrap_or("eml") {
+            "eml" => false,
+            "maildir" => true,
+            other => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown mail_dir_format: {}", other))),
+        };
+        Ok(Self { dir, maildir })
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileMailTransport {
+    async fn send(&self, email: &Email) -> Result<(), io::Error> {
+        let contents = email.message()?.formatted();
+        let name = ObjectId::new().to_hex();
+        let path = if self.maildir {
+            // Maildir readers only pick up complete files, so they are written to tmp and moved to new
+            for sub in ["tmp", "new", "cur"] {
+                tokio::fs::create_dir_all(self.dir.join(sub)).await?;
+            }
+            let tmp = self.dir.join("tmp").join(&name);
+            tokio::fs::write(&tmp, contents).await?;
+            let path = self.dir.join("new").join(&name);
+            tokio::fs::rename(&tmp, &path).await?;
+            path
+        } else {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            let path = self.dir.join(format!("{}.eml", name));
+            tokio::fs::write(&path, contents).await?;
+            path
+        };
+        info!("Email to {} written to {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+/// Keeps sent emails in memory so tests can assert on them.
+#[derive(Default)]
+pub struct MemoryMailTransport {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every email sent so far, oldest first.
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn sent_to(&self, to: &str) -> Vec<Email> {
+        self.sent.lock().unwrap().iter().filter(|email| email.to == to).cloned().collect()
+    }
+
+    pub fn last_sent_to(&self, to: &str) -> Option<Email> {
+        self.sent_to(to).pop()
+    }
+
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+}
+
+#[async_trait]
+impl MailTransport for MemoryMailTransport {
+    async fn send(&self, email: &Email) -> Result<(), io::Error> {
+        // Malformed addresses fail like they would on a real transport
+        email.message()?;
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+
+    fn as_memory(&self) -> Option<&MemoryMailTransport> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framework::{config::OxidizeConfig, testing::load_test_env};
+
+    use super::*;
+
+    fn email() -> Email {
+        Email {
+            from: String::from("sender@example.com"),
+            reply_to: String::from("sender@example.com"),
+            to: String::from("someone@example.com"),
+            subject: String::from("Subject"),
+            body: String::from("Go to https://example.com/verify?a=1."),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let transport = MemoryMailTransport::new();
+        transport.send(&email()).await.expect("Error sending email");
+        let sent = transport.last_sent_to("someone@example.com").expect("Email not captured");
+        assert_eq!(sent.links(), vec!["https://example.com/verify?a=1"]);
+
+        let invalid = Email { to: String::from("not an address"), ..email() };
+        assert!(transport.send(&invalid).await.is_err());
+        assert_eq!(transport.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_file_transport() {
+        load_test_env();
+        let dir = std::env::temp_dir().join(format!("oxidize_mail_{}", ObjectId::new().to_hex()));
+        let mut config = OxidizeConfig::new().expect("Error reading env variables");
+        config.env.mail_dir = Some(dir.to_string_lossy().to_string());
+        config.env.mail_dir_format = Some(String::from("maildir"));
+        FileMailTransport::new(&config).unwrap().send(&email()).await.expect("Error writing email");
+
+        let written: Vec<_> = std::fs::read_dir(dir.join("new")).unwrap().collect();
+        assert_eq!(written.len(), 1);
+        let contents = std::fs::read_to_string(written[0].as_ref().unwrap().path()).unwrap();
+        assert!(contents.contains("To: someone@example.com"));
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        config.env.mail_dir_format = Some(String::from("mbox"));
+        assert!(FileMailTransport::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_multipart_message() {
+        let email = Email {
+            html: Some(String::from("<p>Hi</p><img src=\"cid:logo\">")),
+            inline_images: vec![InlineImage {
+                content_id: String::from("logo"),
+                content_type: String::from("image/png"),
+                data: vec![0x89, b'P', b'N', b'G'],
+            }],
+            ..email()
+        };
+        let formatted = String::from_utf8(email.message().expect("Error building message").formatted()).unwrap();
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(formatted.contains("multipart/related"));
+        assert!(formatted.contains("Content-Type: text/plain"));
+        assert!(formatted.contains("Content-Type: text/html"));
+        assert!(formatted.contains("Content-ID: <logo>"));
+        assert!(formatted.contains("Content-Disposition: inline"));
+
+        let invalid = Email { inline_images: vec![InlineImage { content_type: String::from("nonsense"), ..email.inline_images[0].clone() }], ..email };
+        assert!(invalid.message().is_err());
+    }
+}
@@ -1,9 +1,15 @@
 use async_trait::async_trait;
-use rocket_db_pools::mongodb::{bson::oid::ObjectId, error::Error, results::{DeleteResult, InsertOneResult, UpdateResult}};
+use rocket_db_pools::mongodb::bson::oid::ObjectId;
+use storage::migration::Migration;
+use storage::{DeleteResult, InsertOneResult, UpdateResult};
 
 pub mod mongo;
+pub mod storage;
 pub mod user;
 pub mod mail;
+pub mod throttle;
+pub mod auth;
+pub mod lockout;
 
 #[async_trait]
 #[allow(dead_code)]
@@ -13,5 +19,21 @@ pub trait CRUDMongo<T> {
     async fn update(&self, item: T) -> Option<UpdateResult>;
     async fn delete(&self, id: ObjectId) -> Option<DeleteResult>;
     async fn find_by_email(&self, email: &str) -> Option<T>;
-    async fn initialize_db(&self) -> Result<(), Error>;
+}
+
+/// Every migration of every module, the migrator sorts them by version.
+pub fn migrations() -> Vec<Box<dyn Migration>> {
+    vec![
+        Box::new(user::migrations::CreateUsersEmailIndex),
+        Box::new(mail::migrations::CreateVerificationsUserIndex),
+        Box::new(mail::migrations::CreateOutboxIndexes),
+        Box::new(throttle::migrations::CreateThrottleExpiryIndex),
+        Box::new(mail::migrations::CreateEmailChangesIndexes),
+        Box::new(auth::migrations::CreateChallengesExpiryIndex),
+        Box::new(user::migrations::CreateUserKeysIndexes),
+        Box::new(auth::migrations::CreateSigningKeysExpiryIndex),
+        Box::new(auth::migrations::CreateMagicLinksExpiryIndex),
+        Box::new(lockout::migrations::CreateLockoutsExpiryIndex),
+        Box::new(user::migrations::CreateApiKeysIndexes),
+    ]
 }
\ No newline at end of file
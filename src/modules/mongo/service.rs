@@ -1,59 +1,137 @@
+use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::info;
-use rocket_db_pools::mongodb::{ bson::Document, error::Error, Client, Database};
-use std::sync::Mutex;
+use log::{info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rocket_db_pools::mongodb::bson::doc;
+use rocket_db_pools::mongodb::options::{Acknowledgment, ClientOptions, ReadConcern, Tls, TlsOptions, WriteConcern};
+use rocket_db_pools::mongodb::{error::Error, Client, Database};
 
 use crate::framework::config::OxidizeConfig;
 
+const DEFAULT_CONNECT_RETRIES: u32 = 3;
+const DEFAULT_CONNECT_BACKOFF_MS: u64 = 500;
+
 pub struct MongoOracle {
     pub client: Option<Client>,
     pub db: Option<Database>,
     pub config: Arc<OxidizeConfig>,
-    pub collections: Mutex<Vec<String>>,
+    /// Whether the deployment is a replica set or sharded cluster, the only ones supporting transactions.
+    pub supports_transactions: bool,
 }
 
 impl MongoOracle {
+    /// Connects to MongoDB, retrying with exponential backoff until the server answers a ping.
+    pub async fn new( config : Arc<OxidizeConfig>) -> Result<Self, Error> {
+        let options = Self::client_options(&config).await?;
+        let client = Client::with_options(options)?;
+        let db = client.database(&config.env.mongodb_database_name);
+        Self::wait_for_server(&db, &config).await?;
+        info!("Connected successfully to MongoDB");
+        let hello = db.run_command(doc! { "hello": 1 }, None).await?;
+        let supports_transactions = hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid");
+        if !supports_transactions {
+            warn!("MongoDB is running standalone, transactions such as creating a user with its verification will run \
+                without atomicity. Run a replica set, a single node is enough, to make them atomic");
+        }
+        Ok(Self { client: Some(client), db: Some(db), config, supports_transactions })
+    }
 
-    pub async fn drop_database(&self) -> Result<(), Error> {
-        if let Some(db) = &self.db {
-            let collections = self.collections.lock().unwrap().clone();
-            for collection_name in collections {
-                db.collection::<Document>(&collection_name).drop(None).await?;
+    /// Connection string from `mongodb_uri`, or built from the host and credential settings.
+    pub fn connection_uri(config: &OxidizeConfig) -> String {
+        if let Some(uri) = &config.env.mongodb_uri {
+            return uri.clone();
+        }
+        format!("mongodb://{}:{}@{}:{}/{}",
+            utf8_percent_encode(&config.env.mongo_test_user, NON_ALPHANUMERIC),
+            utf8_percent_encode(&config.env.mongo_test_password, NON_ALPHANUMERIC),
+            config.env.mongodb_host,
+            config.env.mongodb_port,
+            config.env.mongodb_database_name)
+    }
+
+    /// Parses the connection string and applies the structured options on top of it.
+    pub async fn client_options(config: &OxidizeConfig) -> Result<ClientOptions, Error> {
+        let env = &config.env;
+        let mut options = ClientOptions::parse(Self::connection_uri(config)).await?;
+
+        if let Some(source) = &env.mongodb_auth_source {
+            if let Some(credential) = options.credential.as_mut() {
+                credential.source = Some(source.clone());
             }
         }
-        Ok(())
+        if let Some(name) = &env.mongodb_replica_set {
+            options.repl_set_name = Some(name.clone());
+        }
+        let tls_files = env.mongodb_tls_ca_file.is_some() || env.mongodb_tls_cert_key_file.is_some();
+        match env.mongodb_tls {
+            Some(false) => options.tls = Some(Tls::Disabled),
+            Some(true) => options.tls = Some(Tls::Enabled(Self::tls_options(config))),
+            None if tls_files => options.tls = Some(Tls::Enabled(Self::tls_options(config))),
+            None => {}
+        }
+        if let Some(level) = &env.mongodb_read_concern {
+            options.read_concern = Some(Self::read_concern(level)?);
+        }
+        if let Some(w) = &env.mongodb_write_concern {
+            let acknowledgment = match w.parse::<u32>() {
+                Ok(nodes) => Acknowledgment::Nodes(nodes),
+                Err(_) => Acknowledgment::from(w.clone()),
+            };
+            options.write_concern = Some(WriteConcern::builder().w(acknowledgment).build());
+        }
+        if let Some(size) = env.mongodb_min_pool_size {
+            options.min_pool_size = Some(size);
+        }
+        if let Some(size) = env.mongodb_max_pool_size {
+            options.max_pool_size = Some(size);
+        }
+        if let Some(ms) = env.mongodb_connect_timeout_ms {
+            options.connect_timeout = Some(Duration::from_millis(ms));
+        }
+        if let Some(ms) = env.mongodb_server_selection_timeout_ms {
+            options.server_selection_timeout = Some(Duration::from_millis(ms));
+        }
+        Ok(options)
     }
 
-    pub fn add_collection(&self, collection_name: &str) {
-        let mut collections = self.collections.lock().unwrap();
-        collections.push(collection_name.to_string());
+    fn tls_options(config: &OxidizeConfig) -> TlsOptions {
+        TlsOptions::builder()
+            .ca_file_path(config.env.mongodb_tls_ca_file.as_ref().map(PathBuf::from))
+            .cert_key_file_path(config.env.mongodb_tls_cert_key_file.as_ref().map(PathBuf::from))
+            .allow_invalid_certificates(config.env.mongodb_tls_allow_invalid_certificates)
+            .build()
     }
 
-    pub fn remove_collection(&self, collection_name: &str) {
-        let mut collections = self.collections.lock().unwrap();
-        if let Some(index) = collections.iter().position(|c| c == collection_name) {
-            collections.remove(index);
+    fn read_concern(level: &str) -> Result<ReadConcern, Error> {
+        match level {
+            "local" => Ok(ReadConcern::local()),
+            "majority" => Ok(ReadConcern::majority()),
+            "linearizable" => Ok(ReadConcern::linearizable()),
+            "available" => Ok(ReadConcern::available()),
+            "snapshot" => Ok(ReadConcern::snapshot()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown read concern level: {}", level)).into()),
         }
     }
 
-    pub async fn new( config : Arc<OxidizeConfig>) -> Self {
-        let uri = format!("mongodb://{}:{}@{}:{}/{}", 
-            config.env.mongo_test_user, 
-            config.env.mongo_test_password, 
-            config.env.mongodb_host, 
-            config.env.mongodb_port, 
-            config.env.mongodb_database_name);
-        let client_result = Client::with_uri_str(&uri).await;
-        let client = match client_result {
-            Ok(cli) => {
-                info!("Connected successfully to MongoDB");
-                cli
+    async fn wait_for_server(db: &Database, config: &OxidizeConfig) -> Result<(), Error> {
+        let retries = config.env.mongodb_connect_retries.unwrap_or(DEFAULT_CONNECT_RETRIES);
+        let mut backoff = Duration::from_millis(config.env.mongodb_connect_backoff_ms.unwrap_or(DEFAULT_CONNECT_BACKOFF_MS));
+        let mut attempt = 0;
+        loop {
+            match db.run_command(doc! { "ping": 1 }, None).await {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    warn!("Could not reach MongoDB ({}), retry {}/{} in {:?}", e, attempt, retries, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
             }
-            Err(err) => panic!("Error when connecting to MongoDB: {}", err)
-        };
-        let db = client.database(&config.env.mongodb_database_name);
-        Self { client: Some(client), db: Some(db), config,  collections: Mutex::new(vec![]), }
+        }
     }
 
     pub fn close(&mut self) {
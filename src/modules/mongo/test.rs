@@ -1,68 +1,50 @@
 use std::sync::Arc;
-use crate::{framework::config::OxidizeConfig, modules::mongo::service::MongoOracle};
-use dotenv::dotenv;
-use rocket_db_pools::mongodb::bson::Document;
-
-#[tokio::test]
-async fn test_add_and_remove_collections() {
-    dotenv().ok();
-
-    let config = Arc::new(OxidizeConfig::new().expect("Failed to load config"));
-    let mongo = MongoOracle::new(config.clone()).await;
-
-    mongo.add_collection("test_collection_1");
-    mongo.add_collection("test_collection_2");
-
-    {
-        let collections = mongo.collections.lock().unwrap();
-        assert!(collections.contains(&"test_collection_1".to_string()));
-        assert!(collections.contains(&"test_collection_2".to_string()));
-    }
-
-    mongo.remove_collection("test_collection_1");
-
-    {
-        let collections = mongo.collections.lock().unwrap();
-        assert!(!collections.contains(&"test_collection_1".to_string()));
-        assert!(collections.contains(&"test_collection_2".to_string()));
-    }
-}
-
-#[tokio::test]
-async fn test_drop_database() {
-    dotenv().ok();
-
-    let config = Arc::new(OxidizeConfig::new().expect("Failed to load config"));
-    let mongo = MongoOracle::new(config.clone()).await;
-
-    // Create test collections
-    if let Some(db) = &mongo.db {
-        db.collection::<Document>("test_collection_1").insert_one(Document::new(), None).await.unwrap();
-        db.collection::<Document>("test_collection_2").insert_one(Document::new(), None).await.unwrap();
-    }
-
-    // Add collections to drop
-    mongo.add_collection("test_collection_1");
-    mongo.add_collection("test_collection_2");
-
-    // Drop the specified collections
-    mongo.drop_database().await.unwrap();
-
-    // Verify collections no longer exist
-    if let Some(db) = &mongo.db {
-        let collections: Vec<String> = db.list_collection_names(None).await.unwrap();
-        assert!(!collections.contains(&"test_collection_1".to_string()));
-        assert!(!collections.contains(&"test_collection_2".to_string()));
-    }
-}
+use crate::framework::{config::OxidizeConfig, testing::load_test_env};
+use crate::modules::mongo::service::MongoOracle;
 
 #[tokio::test]
+#[ignore = "requires a running MongoDB"]
 async fn test_new_mongo() {
+    load_test_env();
     let config = OxidizeConfig::new().expect("Error reading env variables");
-    let mongo = MongoOracle::new(Arc::new(config)).await;
+    let mongo = MongoOracle::new(Arc::new(config)).await.expect("Error connecting to MongoDB");
     assert!(mongo.config.env.mongo_test_password.is_ascii());
     assert!(mongo.config.env.mongo_test_user.is_ascii());
     assert!(mongo.config.env.mongo_test_password.is_ascii());
     assert!(mongo.config.env.mongodb_host.is_ascii());
 
 }
+
+#[test]
+fn test_connection_uri_encodes_credentials() {
+    load_test_env();
+    let mut config = OxidizeConfig::new().expect("Error reading env variables");
+    config.env.mongo_test_user = String::from("user@domain");
+    config.env.mongo_test_password = String::from("p@ss:w/rd%");
+    let uri = MongoOracle::connection_uri(&config);
+    assert!(uri.starts_with("mongodb://user%40domain:p%40ss%3Aw%2Frd%25@"));
+
+    config.env.mongodb_uri = Some(String::from("mongodb://localhost:27017/?replicaSet=rs0"));
+    assert_eq!(MongoOracle::connection_uri(&config), "mongodb://localhost:27017/?replicaSet=rs0");
+}
+
+#[tokio::test]
+async fn test_client_options() {
+    load_test_env();
+    let mut config = OxidizeConfig::new().expect("Error reading env variables");
+    config.env.mongo_test_password = String::from("p@ss:w/rd%");
+    config.env.mongodb_auth_source = Some(String::from("admin"));
+    config.env.mongodb_replica_set = Some(String::from("rs0"));
+    config.env.mongodb_max_pool_size = Some(42);
+    config.env.mongodb_read_concern = Some(String::from("majority"));
+    config.env.mongodb_write_concern = Some(String::from("2"));
+    let options = MongoOracle::client_options(&config).await.expect("Error building client options");
+    let credential = options.credential.expect("No credential in options");
+    assert_eq!(credential.password.as_deref(), Some("p@ss:w/rd%"));
+    assert_eq!(credential.source.as_deref(), Some("admin"));
+    assert_eq!(options.repl_set_name.as_deref(), Some("rs0"));
+    assert_eq!(options.max_pool_size, Some(42));
+
+    config.env.mongodb_read_concern = Some(String::from("eventually"));
+    assert!(MongoOracle::client_options(&config).await.is_err());
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rocket_db_pools::mongodb::bson::{oid::ObjectId, Bson, DateTime, Document};
+
+use super::query::{self, apply_update, index_key, is_replacement, matches, same_key, upsert_seed};
+use super::{DeleteResult, FindOneAndUpdateOptions, FindOptions, Index, Session, StorageCollection, StorageEngine,
+    StorageError, UpdateOptions, UpdateResult};
+
+#[derive(Debug, Clone, Default)]
+struct MemoryCollection {
+    documents: Vec<Document>,
+    indexes: Vec<Index>,
+}
+
+impl MemoryCollection {
+    fn position(&self, id: &Bson) -> Option<usize> {
+        self.documents.iter().position(|document| document.get("_id") == Some(id))
+    }
+
+    /// Removes the documents whose TTL index says they have expired.
+    fn purge_expired(&mut self) {
+        let now = DateTime::now().timestamp_millis();
+        for index in &self.indexes {
+            let (Some(expire_after), Some(path)) = (index.expire_after, index.keys.keys().next()) else {
+                continue;
+            };
+            self.documents.retain(|document| match document.get(path) {
+                Some(Bson::DateTime(date)) => date.timestamp_millis() + expire_after.as_millis() as i64 > now,
+                _ => true,
+            });
+        }
+    }
+
+    /// Fails if `document` would break the `_id` or a unique index, ignoring the document at `position`.
+    fn check_unique(&self, document: &Document, position: Option<usize>) -> Result<(), StorageError> {
+        let others = || self.documents.iter().enumerate().filter(move |(i, _)| Some(*i) != position).map(|(_, d)| d);
+        if let Some(id) = document.get("_id") {
+            if others().any(|other| other.get("_id") == Some(id)) {
+                return Err(StorageError::DuplicateKey(format!("_id {}", id)));
+            }
+        }
+        for index in self.indexes.iter().filter(|index| index.unique) {
+            let key = index_key(document, &index.keys);
+            if others().any(|other| same_key(&index_key(other, &index.keys), &key)) {
+                return Err(StorageError::DuplicateKey(format!("index {}", index.name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+type Collections = HashMap<String, MemoryCollection>;
+
+/// Change made inside a transaction, undone if it aborts.
+#[derive(Debug)]
+enum Undo {
+    Insert { collection: String, id: Bson },
+    Replace { collection: String, previous: Document },
+    Delete { collection: String, previous: Document },
+}
+
+/// Session of the memory backend. Transactions are atomic, but their writes are visible before they commit.
+#[derive(Debug, Default)]
+pub struct MemorySession {
+    undo: Option<Vec<Undo>>,
+}
+
+impl MemorySession {
+    fn record(&mut self, undo: Undo) {
+        if let Some(log) = self.undo.as_mut() {
+            log.push(undo);
+        }
+    }
+}
+
+fn memory_session(session: Option<&mut Session>) -> Result<Option<&mut MemorySession>, StorageError> {
+    match session {
+        None => Ok(None),
+        Some(Session::Memory(session)) => Ok(Some(session)),
+        Some(_) => Err(StorageError::Other(String::from("Session belongs to another storage backend"))),
+    }
+}
+
+/// Keeps every collection in the memory of the process. Supports the filters, update operators and indexes
+/// the modules use, see [`query`].
+#[derive(Default)]
+pub struct MemoryEngine {
+    data: Arc<Mutex<Collections>>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl StorageEngine for MemoryEngine {
+    fn collection(&self, name: &str) -> Arc<dyn StorageCollection> {
+        Arc::new(MemoryCollectionHandle { name: name.to_string(), data: self.data.clone() })
+    }
+
+    async fn collection_names(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self.data.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn supports_transactions(&self) -> bool {
+        true
+    }
+
+    async fn start_session(&self) -> Result<Session, StorageError> {
+        Ok(Session::Memory(MemorySession::default()))
+    }
+
+    async fn start_transaction(&self, session: &mut Session) -> Result<(), StorageError> {
+        let session = memory_session(Some(session))?.expect("Session is present");
+        session.undo = Some(vec![]);
+        Ok(())
+    }
+
+    async fn commit_transaction(&self, session: &mut Session) -> Result<(), StorageError> {
+        let session = memory_session(Some(session))?.expect("Session is present");
+        session.undo = None;
+        Ok(())
+    }
+
+    async fn abort_transaction(&self, session: &mut Session) -> Result<(), StorageError> {
+        let session = memory_session(Some(session))?.expect("Session is present");
+        let mut data = self.data.lock().unwrap();
+        for undo in session.undo.take().unwrap_or_default().into_iter().rev() {
+            match undo {
+                Undo::Insert { collection, id } => {
+                    let collection = data.entry(collection).or_default();
+                    if let Some(position) = collection.position(&id) {
+                        collection.documents.remove(position);
+                    }
+                }
+                Undo::Replace { collection, previous } => {
+                    let collection = data.entry(collection).or_default();
+                    match previous.get("_id").and_then(|id| collection.position(id)) {
+                        Some(position) => collection.documents[position] = previous,
+                        None => collection.documents.push(previous),
+                    }
+                }
+                Undo::Delete { collection, previous } => data.entry(collection).or_default().documents.push(previous),
+            }
+        }
+        Ok(())
+    }
+}
+
+struct MemoryCollectionHandle {
+    name: String,
+    data: Arc<Mutex<Collections>>,
+}
+
+impl MemoryCollectionHandle {
+    /// Positions of the matching documents, in sort order when given.
+    fn matching(collection: &MemoryCollection, filter: &Document, sort: Option<&Document>) -> Result<Vec<usize>, StorageError> {
+        let mut positions = vec![];
+        for (position, document) in collection.documents.iter().enumerate() {
+            if matches(document, filter)? {
+                positions.push(position);
+            }
+        }
+        if let Some(specification) = sort {
+            let mut sorted: Vec<Document> = positions.iter().map(|p| collection.documents[*p].clone()).collect();
+            query::sort(&mut sorted, specification);
+            positions = sorted.iter().filter_map(|d| d.get("_id").and_then(|id| collection.position(id))).collect();
+        }
+        Ok(positions)
+    }
+
+    /// Updates the document at `position` and returns it as it was before.
+    fn update_at(&self, collection: &mut MemoryCollection, position: usize, update: &Document,
+        session: &mut Option<&mut MemorySession>) -> Result<Document, StorageError> {
+        let previous = collection.documents[position].clone();
+        let mut updated = previous.clone();
+        apply_update(&mut updated, update, false)?;
+        if updated.get("_id") != previous.get("_id") {
+            return Err(StorageError::InvalidQuery(String::from("_id is immutable")));
+        }
+        if updated != previous {
+            collection.check_unique(&updated, Some(position))?;
+            collection.documents[position] = updated;
+            if let Some(session) = session.as_deref_mut() {
+                session.record(Undo::Replace { collection: self.name.clone(), previous: previous.clone() });
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Inserts the document an upsert builds when nothing matches.
+    fn upsert(&self, collection: &mut MemoryCollection, filter: &Document, update: &Document,
+        session: &mut Option<&mut MemorySession>) -> Result<Document, StorageError> {
+        let mut document = if is_replacement(update) { Document::new() } else { upsert_seed(filter)? };
+        apply_update(&mut document, update, true)?;
+        if !document.contains_key("_id") {
+            document.insert("_id", ObjectId::new());
+        }
+        self.insert(collection, document.clone(), session)?;
+        Ok(document)
+    }
+
+    fn insert(&self, collection: &mut MemoryCollection, document: Document,
+        session: &mut Option<&mut MemorySession>) -> Result<Bson, StorageError> {
+        collection.check_unique(&document, None)?;
+        let id = document.get("_id").cloned().expect("Documents have an _id");
+        collection.documents.push(document);
+        if let Some(session) = session.as_deref_mut() {
+            session.record(Undo::Insert { collection: self.name.clone(), id: id.clone() });
+        }
+        Ok(id)
+    }
+}
+
+#[async_trait]
+impl StorageCollection for MemoryCollectionHandle {
+    async fn insert_one(&self, mut document: Document, session: Option<&mut Session>) -> Result<Bson, StorageError> {
+        let mut session = memory_session(session)?;
+        if !document.contains_key("_id") {
+            document.insert("_id", ObjectId::new());
+        }
+        let mut data = self.data.lock().unwrap();
+        let collection = data.entry(self.name.clone()).or_default();
+        self.insert(collection, document, &mut session)
+    }
+
+    async fn find(&self, filter: Document, options: FindOptions, session: Option<&mut Session>) -> Result<Vec<Document>, StorageError> {
+        memory_session(session)?;
+        let mut data = self.data.lock().unwrap();
+        let Some(collection) = data.get_mut(&self.name) else {
+            return Ok(vec![]);
+        };
+        collection.purge_expired();
+        let positions = Self::matching(collection, &filter, options.sort.as_ref())?;
+        let limit = match options.limit {
+            Some(limit) if limit != 0 => limit.unsigned_abs() as usize,
+            _ => usize::MAX,
+        };
+        Ok(positions.into_iter()
+            .skip(options.skip.unwrap_or(0) as usize)
+            .take(limit)
+            .map(|position| collection.documents[position].clone())
+            .collect())
+    }
+
+    async fn count_documents(&self, filter: Document, session: Option<&mut Session>) -> Result<u64, StorageError> {
+        Ok(self.find(filter, FindOptions::default(), session).await?.len() as u64)
+    }
+
+    async fn update(&self, filter: Document, update: Document, options: UpdateOptions, session: Option<&mut Session>) -> Result<UpdateResult, StorageError> {
+        if options.many && is_replacement(&update) {
+            return Err(StorageError::InvalidQuery(String::from("Replacements only apply to a single document")));
+        }
+        let mut session = memory_session(session)?;
+        let mut data = self.data.lock().unwrap();
+        let collection = data.entry(self.name.clone()).or_default();
+        collection.purge_expired();
+        let mut positions = Self::matching(collection, &filter, None)?;
+        if !options.many {
+            positions.truncate(1);
+        }
+        let mut result = UpdateResult { matched_count: positions.len() as u64, ..Default::default() };
+        if positions.is_empty() && options.upsert {
+            let document = self.upsert(collection, &filter, &update, &mut session)?;
+            result.upserted_id = document.get("_id").cloned();
+            return Ok(result);
+        }
+        for position in positions {
+            let previous = self.update_at(collection, position, &update, &mut session)?;
+            if collection.documents[position] != previous {
+                result.modified_count += 1;
+            }
+        }
+        Ok(result)
+    }
+
+    async fn find_one_and_update(&self, filter: Document, update: Document, options: FindOneAndUpdateOptions, session: Option<&mut Session>) -> Result<Option<Document>, StorageError> {
+        let mut session = memory_session(session)?;
+        let mut data = self.data.lock().unwrap();
+        let collection = data.entry(self.name.clone()).or_default();
+        collection.purge_expired();
+        let Some(position) = Self::matching(collection, &filter, options.sort.as_ref())?.first().copied() else {
+            if !options.upsert {
+                return Ok(None);
+            }
+            let document = self.upsert(collection, &filter, &update, &mut session)?;
+            return Ok(options.return_new.then_some(document));
+        };
+        let previous = self.update_at(collection, position, &update, &mut session)?;
+        Ok(Some(if options.return_new { collection.documents[position].clone() } else { previous }))
+    }
+
+    async fn delete(&self, filter: Document, many: bool, session: Option<&mut Session>) -> Result<DeleteResult, StorageError> {
+        let mut session = memory_session(session)?;
+        let mut data = self.data.lock().unwrap();
+        let Some(collection) = data.get_mut(&self.name) else {
+            return Ok(DeleteResult::default());
+        };
+        let mut positions = Self::matching(collection, &filter, None)?;
+        if !many {
+            positions.truncate(1);
+        }
+        positions.sort_unstable();
+        for position in positions.iter().rev() {
+            let previous = collection.documents.remove(*position);
+            if let Some(session) = session.as_deref_mut() {
+                session.record(Undo::Delete { collection: self.name.clone(), previous });
+            }
+        }
+        Ok(DeleteResult { deleted_count: positions.len() as u64 })
+    }
+
+    async fn create_index(&self, index: Index) -> Result<(), StorageError> {
+        let mut data = self.data.lock().unwrap();
+        let collection = data.entry(self.name.clone()).or_default();
+        if collection.indexes.iter().any(|existing| existing.name == index.name) {
+            return Ok(());
+        }
+        if index.unique {
+            let keys: Vec<Vec<Bson>> = collection.documents.iter().map(|d| index_key(d, &index.keys)).collect();
+            for (i, key) in keys.iter().enumerate() {
+                if keys[..i].iter().any(|other| same_key(other, key)) {
+                    return Err(StorageError::DuplicateKey(format!("index {}", index.name)));
+                }
+            }
+        }
+        collection.indexes.push(index);
+        Ok(())
+    }
+
+    async fn drop_index(&self, name: &str) -> Result<(), StorageError> {
+        let mut data = self.data.lock().unwrap();
+        let collection = data.get_mut(&self.name);
+        match collection.and_then(|c| c.indexes.iter().position(|index| index.name == name).map(|p| (c, p))) {
+            Some((collection, position)) => {
+                collection.indexes.remove(position);
+                Ok(())
+            }
+            None => Err(StorageError::Other(format!("Index {} not found in {}", name, self.name))),
+        }
+    }
+
+    async fn drop_collection(&self) -> Result<(), StorageError> {
+        self.data.lock().unwrap().remove(&self.name);
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use rocket_db_pools::mongodb::bson::{doc, oid::ObjectId, DateTime, Document};
+use serde::{Deserialize, Serialize};
+
+use super::{Collection, FindOptions, Storage, StorageError};
+
+const MIGRATIONS_COLLECTION: &str = "_migrations";
+const LOCK_COLLECTION: &str = "_migrations_lock";
+const LOCK_ID: &str = "migrations";
+/// A lock older than this is considered abandoned by a crashed instance.
+const LOCK_TTL: Duration = Duration::from_secs(300);
+const LOCK_WAIT: Duration = Duration::from_secs(60);
+const LOCK_POLL: Duration = Duration::from_millis(500);
+
+/// A versioned change to the database schema. Versions must be unique and are applied in ascending order.
+#[async_trait]
+pub trait Migration: Send + Sync {
+    fn version(&self) -> i64;
+    fn name(&self) -> &'static str;
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError>;
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError>;
+}
+
+/// Entry of the `_migrations` collection, one per applied migration.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MigrationRecord {
+    #[serde(rename = "_id")]
+    pub version: i64,
+    pub name: String,
+    pub applied: DateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub applied: Option<DateTime>,
+}
+
+/// Applies and rolls back migrations, holding a lock in the database so only one instance migrates at a time.
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+///     use std::sync::Arc;
+///     use oxidize::framework::config::OxidizeConfig;
+///     use oxidize::modules::{self, storage::{migration::Migrator, Storage}};
+/// #   oxidize::framework::testing::load_test_env();
+///     let config = Arc::new(OxidizeConfig::new().unwrap());
+///     let storage = Arc::new(Storage::connect(config).await.unwrap());
+///     let applied = Migrator::new(storage, modules::migrations()).up().await.unwrap();
+/// # }
+/// ```
+pub struct Migrator {
+    pub storage: Arc<Storage>,
+    migrations: Vec<Box<dyn Migration>>,
+    records: Collection<MigrationRecord>,
+    locks: Collection<Document>,
+}
+
+impl Migrator {
+    pub fn new(storage: Arc<Storage>, mut migrations: Vec<Box<dyn Migration>>) -> Self {
+        let records = storage.collection(MIGRATIONS_COLLECTION);
+        let locks = storage.collection(LOCK_COLLECTION);
+        storage.add_collection(MIGRATIONS_COLLECTION);
+        storage.add_collection(LOCK_COLLECTION);
+        migrations.sort_by_key(|m| m.version());
+        Self { storage, migrations, records, locks }
+    }
+
+    async fn applied(&self) -> Result<Vec<MigrationRecord>, StorageError> {
+        let options = FindOptions { sort: Some(doc! { "_id": 1 }), ..Default::default() };
+        self.records.find(doc! {}, options, None).await
+    }
+
+    /// Lists every known migration and when it was applied.
+    pub async fn status(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        let applied = self.applied().await?;
+        Ok(self.migrations.iter().map(|m| MigrationStatus {
+            version: m.version(),
+            name: m.name().to_string(),
+            applied: applied.iter().find(|r| r.version == m.version()).map(|r| r.applied),
+        }).collect())
+    }
+
+    /// Applies all pending migrations and returns their versions.
+    pub async fn up(&self) -> Result<Vec<i64>, StorageError> {
+        let owner = self.lock().await?;
+        let result = self.apply_pending().await;
+        self.unlock(&owner).await?;
+        result
+    }
+
+    /// Rolls back the last `steps` applied migrations and returns their versions.
+    pub async fn down(&self, steps: usize) -> Result<Vec<i64>, StorageError> {
+        let owner = self.lock().await?;
+        let result = self.roll_back(steps).await;
+        self.unlock(&owner).await?;
+        result
+    }
+
+    async fn apply_pending(&self) -> Result<Vec<i64>, StorageError> {
+        let applied = self.applied().await?;
+        let mut versions = vec![];
+        for migration in &self.migrations {
+            if applied.iter().any(|r| r.version == migration.version()) {
+                continue;
+            }
+            info!("Applying migration {} {}", migration.version(), migration.name());
+            migration.up(&self.storage).await?;
+            let record = MigrationRecord {
+                version: migration.version(),
+                name: migration.name().to_string(),
+                applied: DateTime::now(),
+            };
+            self.records.insert_one(&record, None).await?;
+            versions.push(migration.version());
+        }
+        Ok(versions)
+    }
+
+    async fn roll_back(&self, steps: usize) -> Result<Vec<i64>, StorageError> {
+        let applied = self.applied().await?;
+        let mut versions = vec![];
+        for record in applied.iter().rev().take(steps) {
+            let migration = self.migrations.iter().find(|m| m.version() == record.version)
+                .ok_or_else(|| StorageError::Other(
+                    format!("Migration {} {} is applied but unknown to this build", record.version, record.name)))?;
+            info!("Rolling back migration {} {}", migration.version(), migration.name());
+            migration.down(&self.storage).await?;
+            self.records.delete_one(doc! { "_id": record.version }, None).await?;
+            versions.push(record.version);
+        }
+        Ok(versions)
+    }
+
+    async fn lock(&self) -> Result<String, StorageError> {
+        let owner = ObjectId::new().to_hex();
+        let deadline = tokio::time::Instant::now() + LOCK_WAIT;
+        loop {
+            let expires = DateTime::from_millis(DateTime::now().timestamp_millis() + LOCK_TTL.as_millis() as i64);
+            let lock = doc! { "_id": LOCK_ID, "owner": &owner, "expires": expires };
+            match self.locks.insert_one(&lock, None).await {
+                Ok(_) => return Ok(owner),
+                Err(e) if e.is_duplicate_key() => {
+                    let stale = self.locks.delete_one(doc! { "_id": LOCK_ID, "expires": { "$lt": DateTime::now() } }, None).await?;
+                    if stale.deleted_count > 0 {
+                        warn!("Removed an expired migrations lock");
+                        continue;
+                    }
+                    if tokio::time::Instant::now() >= deadline {
+                        return Err(StorageError::Other(String::from("Migrations are locked by another instance")));
+                    }
+                    tokio::time::sleep(LOCK_POLL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn unlock(&self, owner: &str) -> Result<(), StorageError> {
+        self.locks.delete_one(doc! { "_id": LOCK_ID, "owner": owner }, None).await?;
+        Ok(())
+    }
+}
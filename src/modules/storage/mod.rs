@@ -0,0 +1,419 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use rocket_db_pools::mongodb::bson::{self, Bson, Document};
+use rocket_db_pools::mongodb::ClientSession;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::framework::config::OxidizeConfig;
+use crate::modules::mongo::service::MongoOracle;
+
+pub mod memory;
+pub mod migration;
+pub mod mongo;
+pub mod query;
+#[cfg(test)] mod test;
+
+/// Transactions are retried on transient errors until this much time has passed since they started.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// A unique index rejected the write.
+    DuplicateKey(String),
+    /// A filter or update uses something the backend does not understand.
+    InvalidQuery(String),
+    Serialization(String),
+    Mongo(rocket_db_pools::mongodb::error::Error),
+    Other(String),
+}
+
+impl StorageError {
+    pub fn is_duplicate_key(&self) -> bool {
+        matches!(self, StorageError::DuplicateKey(_))
+    }
+
+    /// Whether the whole transaction can be retried.
+    pub fn is_transient(&self) -> bool {
+        use rocket_db_pools::mongodb::error::TRANSIENT_TRANSACTION_ERROR;
+        matches!(self, StorageError::Mongo(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR))
+    }
+
+    /// Whether the commit can be retried because its outcome is unknown.
+    pub fn is_unknown_commit_result(&self) -> bool {
+        use rocket_db_pools::mongodb::error::UNKNOWN_TRANSACTION_COMMIT_RESULT;
+        matches!(self, StorageError::Mongo(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT))
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::DuplicateKey(message) => write!(f, "Duplicate key: {}", message),
+            StorageError::InvalidQuery(message) => write!(f, "Invalid query: {}", message),
+            StorageError::Serialization(message) => write!(f, "Serialization error: {}", message),
+            StorageError::Mongo(e) => write!(f, "{}", e),
+            StorageError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rocket_db_pools::mongodb::error::Error> for StorageError {
+    fn from(error: rocket_db_pools::mongodb::error::Error) -> Self {
+        use rocket_db_pools::mongodb::error::{ErrorKind, WriteFailure};
+        match error.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000 => StorageError::DuplicateKey(e.message.clone()),
+            // Upserts through findAndModify report it as a command error
+            ErrorKind::Command(e) if e.code == 11000 => StorageError::DuplicateKey(e.message.clone()),
+            _ => StorageError::Mongo(error),
+        }
+    }
+}
+
+impl From<bson::ser::Error> for StorageError {
+    fn from(error: bson::ser::Error) -> Self {
+        StorageError::Serialization(error.to_string())
+    }
+}
+
+impl From<bson::de::Error> for StorageError {
+    fn from(error: bson::de::Error) -> Self {
+        StorageError::Serialization(error.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertOneResult {
+    pub inserted_id: Bson,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdateResult {
+    pub matched_count: u64,
+    pub modified_count: u64,
+    pub upserted_id: Option<Bson>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeleteResult {
+    pub deleted_count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FindOptions {
+    pub sort: Option<Document>,
+    pub skip: Option<u64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpdateOptions {
+    pub many: bool,
+    pub upsert: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FindOneAndUpdateOptions {
+    pub sort: Option<Document>,
+    pub upsert: bool,
+    /// Return the document as it is after the update instead of before it.
+    pub return_new: bool,
+}
+
+/// Index definition, applied by every backend.
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub name: String,
+    pub keys: Document,
+    pub unique: bool,
+    /// Documents expire this long after the date stored in the (single) indexed field.
+    pub expire_after: Option<Duration>,
+}
+
+impl Index {
+    pub fn new(name: &str, keys: Document) -> Self {
+        Self { name: name.to_string(), keys, unique: false, expire_after: None }
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn expire_after(mut self, expire_after: Duration) -> Self {
+        self.expire_after = Some(expire_after);
+        self
+    }
+}
+
+/// A session groups operations, and allows running them inside a transaction.
+pub enum Session {
+    Mongo(Box<ClientSession>),
+    Memory(memory::MemorySession),
+}
+
+/// Untyped access to a collection of a backend.
+#[async_trait]
+pub trait StorageCollection: Send + Sync {
+    async fn insert_one(&self, document: Document, session: Option<&mut Session>) -> Result<Bson, StorageError>;
+    async fn find(&self, filter: Document, options: FindOptions, session: Option<&mut Session>) -> Result<Vec<Document>, StorageError>;
+    async fn count_documents(&self, filter: Document, session: Option<&mut Session>) -> Result<u64, StorageError>;
+    async fn update(&self, filter: Document, update: Document, options: UpdateOptions, session: Option<&mut Session>) -> Result<UpdateResult, StorageError>;
+    async fn find_one_and_update(&self, filter: Document, update: Document, options: FindOneAndUpdateOptions, session: Option<&mut Session>) -> Result<Option<Document>, StorageError>;
+    async fn delete(&self, filter: Document, many: bool, session: Option<&mut Session>) -> Result<DeleteResult, StorageError>;
+    async fn create_index(&self, index: Index) -> Result<(), StorageError>;
+    async fn drop_index(&self, name: &str) -> Result<(), StorageError>;
+    async fn drop_collection(&self) -> Result<(), StorageError>;
+}
+
+/// A persistence backend.
+#[async_trait]
+pub trait StorageEngine: Send + Sync {
+    fn collection(&self, name: &str) -> Arc<dyn StorageCollection>;
+    async fn collection_names(&self) -> Result<Vec<String>, StorageError>;
+    fn supports_transactions(&self) -> bool;
+    async fn start_session(&self) -> Result<Session, StorageError>;
+    async fn start_transaction(&self, session: &mut Session) -> Result<(), StorageError>;
+    async fn commit_transaction(&self, session: &mut Session) -> Result<(), StorageError>;
+    async fn abort_transaction(&self, session: &mut Session) -> Result<(), StorageError>;
+}
+
+/// Typed view over a collection, (de)serializing `T` to and from documents.
+pub struct Collection<T> {
+    inner: Arc<dyn StorageCollection>,
+    name: String,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Collection<T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), name: self.name.clone(), _type: PhantomData }
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync,
+{
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn insert_one(&self, item: &T, session: Option<&mut Session>) -> Result<InsertOneResult, StorageError> {
+        let document = bson::to_document(item)?;
+        let inserted_id = self.inner.insert_one(document, session).await?;
+        Ok(InsertOneResult { inserted_id })
+    }
+
+    pub async fn find_one(&self, filter: Document, session: Option<&mut Session>) -> Result<Option<T>, StorageError> {
+        let options = FindOptions { limit: Some(1), ..Default::default() };
+        let found = self.inner.find(filter, options, session).await?;
+        found.into_iter().next().map(bson::from_document).transpose().map_err(StorageError::from)
+    }
+
+    pub async fn find(&self, filter: Document, options: FindOptions, session: Option<&mut Session>) -> Result<Vec<T>, StorageError> {
+        let found = self.inner.find(filter, options, session).await?;
+        found.into_iter().map(|document| bson::from_document(document).map_err(StorageError::from)).collect()
+    }
+
+    pub async fn count_documents(&self, filter: Document, session: Option<&mut Session>) -> Result<u64, StorageError> {
+        self.inner.count_documents(filter, session).await
+    }
+
+    pub async fn update_one(&self, filter: Document, update: Document, session: Option<&mut Session>) -> Result<UpdateResult, StorageError> {
+        self.inner.update(filter, update, UpdateOptions::default(), session).await
+    }
+
+    pub async fn update_many(&self, filter: Document, update: Document, session: Option<&mut Session>) -> Result<UpdateResult, StorageError> {
+        self.inner.update(filter, update, UpdateOptions { many: true, upsert: false }, session).await
+    }
+
+    /// Updates the first matching document, or inserts one built from the filter and the update.
+    pub async fn upsert_one(&self, filter: Document, update: Document, session: Option<&mut Session>) -> Result<UpdateResult, StorageError> {
+        self.inner.update(filter, update, UpdateOptions { many: false, upsert: true }, session).await
+    }
+
+    /// Atomically updates a document and returns it.
+    pub async fn find_one_and_update(&self, filter: Document, update: Document, options: FindOneAndUpdateOptions, session: Option<&mut Session>) -> Result<Option<T>, StorageError> {
+        let found = self.inner.find_one_and_update(filter, update, options, session).await?;
+        found.map(bson::from_document).transpose().map_err(StorageError::from)
+    }
+
+    pub async fn delete_one(&self, filter: Document, session: Option<&mut Session>) -> Result<DeleteResult, StorageError> {
+        self.inner.delete(filter, false, session).await
+    }
+
+    pub async fn delete_many(&self, filter: Document, session: Option<&mut Session>) -> Result<DeleteResult, StorageError> {
+        self.inner.delete(filter, true, session).await
+    }
+
+    pub async fn create_index(&self, index: Index) -> Result<(), StorageError> {
+        self.inner.create_index(index).await
+    }
+
+    pub async fn drop_index(&self, name: &str) -> Result<(), StorageError> {
+        self.inner.drop_index(name).await
+    }
+
+    pub async fn drop(&self) -> Result<(), StorageError> {
+        self.inner.drop_collection().await
+    }
+}
+
+/// Backend selected through `storage_backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    Mongo,
+    Memory,
+}
+
+impl StorageBackend {
+    pub fn from_config(config: &OxidizeConfig) -> Result<Self, StorageError> {
+        match config.env.storage_backend.as_deref() {
+            None | Some("mongo") => Ok(StorageBackend::Mongo),
+            Some("memory") => Ok(StorageBackend::Memory),
+            Some(other) => Err(StorageError::Other(format!("Unknown storage backend: {}", other))),
+        }
+    }
+}
+
+/// Entry point to persistence. Hands out collections of the configured backend and keeps track of the
+/// collections in use so they can be dropped together.
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+///     use std::sync::Arc;
+///     use oxidize::framework::config::OxidizeConfig;
+///     use oxidize::modules::storage::Storage;
+///     use rocket_db_pools::mongodb::bson::{doc, Document};
+/// #   oxidize::framework::testing::load_test_env();
+///     let storage = Storage::memory(Arc::new(OxidizeConfig::new().unwrap()));
+///     let things = storage.collection::<Document>("things");
+///     things.insert_one(&doc! { "name": "thing" }, None).await.unwrap();
+/// # }
+/// ```
+pub struct Storage {
+    engine: Arc<dyn StorageEngine>,
+    pub config: Arc<OxidizeConfig>,
+    pub collections: Mutex<Vec<String>>,
+}
+
+impl Storage {
+    /// Connects to the backend named in the configuration.
+    pub async fn connect(config: Arc<OxidizeConfig>) -> Result<Self, StorageError> {
+        match StorageBackend::from_config(&config)? {
+            StorageBackend::Mongo => {
+                let mongo = MongoOracle::new(config.clone()).await?;
+                Ok(Self::with_engine(Arc::new(mongo::MongoEngine::new(mongo)), config))
+            }
+            StorageBackend::Memory => Ok(Self::memory(config)),
+        }
+    }
+
+    /// Storage kept in the memory of the process, mostly meant for tests.
+    pub fn memory(config: Arc<OxidizeConfig>) -> Self {
+        Self::with_engine(Arc::new(memory::MemoryEngine::new()), config)
+    }
+
+    pub fn with_engine(engine: Arc<dyn StorageEngine>, config: Arc<OxidizeConfig>) -> Self {
+        Self { engine, config, collections: Mutex::new(vec![]) }
+    }
+
+    pub fn collection<T>(&self, name: &str) -> Collection<T> {
+        Collection { inner: self.engine.collection(name), name: name.to_string(), _type: PhantomData }
+    }
+
+    pub async fn collection_names(&self) -> Result<Vec<String>, StorageError> {
+        self.engine.collection_names().await
+    }
+
+    pub fn add_collection(&self, collection_name: &str) {
+        let mut collections = self.collections.lock().unwrap();
+        collections.push(collection_name.to_string());
+    }
+
+    pub fn remove_collection(&self, collection_name: &str) {
+        let mut collections = self.collections.lock().unwrap();
+        if let Some(index) = collections.iter().position(|c| c == collection_name) {
+            collections.remove(index);
+        }
+    }
+
+    /// Drops every collection registered through `add_collection`.
+    pub async fn drop_database(&self) -> Result<(), StorageError> {
+        let collections = self.collections.lock().unwrap().clone();
+        for collection_name in collections {
+            self.engine.collection(&collection_name).drop_collection().await?;
+        }
+        Ok(())
+    }
+
+    pub fn supports_transactions(&self) -> bool {
+        self.engine.supports_transactions()
+    }
+
+    pub async fn start_session(&self) -> Result<Session, StorageError> {
+        self.engine.start_session().await
+    }
+
+    /// Runs `callback` as a unit of work inside a transaction. The whole callback is retried on transient errors
+    /// and the commit is retried when its result is unknown. `context` is handed to every attempt.
+    /// On backends without transactions, like standalone MongoDB servers, the callback runs once in a plain session.
+    /// ```
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// # use std::sync::Arc;
+    /// # use oxidize::framework::config::OxidizeConfig;
+    /// # use oxidize::modules::storage::Storage;
+    /// # use rocket_db_pools::mongodb::bson::{doc, Document};
+    /// # oxidize::framework::testing::load_test_env();
+    /// # let storage = Storage::memory(Arc::new(OxidizeConfig::new().unwrap()));
+    /// let things = storage.collection::<Document>("things");
+    /// storage.transaction(things, |session, things| Box::pin(async move {
+    ///     things.insert_one(&doc! { "a": 1 }, Some(&mut *session)).await?;
+    ///     things.insert_one(&doc! { "b": 2 }, Some(session)).await?;
+    ///     Ok(())
+    /// })).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn transaction<R, C, F>(&self, mut context: C, mut callback: F) -> Result<R, StorageError>
+    where
+        F: for<'a> FnMut(&'a mut Session, &'a mut C) -> BoxFuture<'a, Result<R, StorageError>>,
+    {
+        let mut session = self.engine.start_session().await?;
+        if !self.engine.supports_transactions() {
+            return callback(&mut session, &mut context).await;
+        }
+        let deadline = Instant::now() + TRANSACTION_TIMEOUT;
+        'transaction: loop {
+            self.engine.start_transaction(&mut session).await?;
+            let value = match callback(&mut session, &mut context).await {
+                Ok(value) => value,
+                Err(e) => {
+                    // The transaction may already be aborted by the server
+                    let _ = self.engine.abort_transaction(&mut session).await;
+                    if e.is_transient() && Instant::now() < deadline {
+                        continue 'transaction;
+                    }
+                    return Err(e);
+                }
+            };
+            loop {
+                match self.engine.commit_transaction(&mut session).await {
+                    Ok(()) => return Ok(value),
+                    Err(e) if e.is_unknown_commit_result() && Instant::now() < deadline => continue,
+                    Err(e) if e.is_transient() && Instant::now() < deadline => continue 'transaction,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use rocket_db_pools::mongodb::bson::{Bson, Document};
+use rocket_db_pools::mongodb::options::{self, IndexOptions, ReturnDocument};
+use rocket_db_pools::mongodb::{results, ClientSession, Collection, Database, IndexModel};
+
+use crate::modules::mongo::service::MongoOracle;
+use super::query::is_replacement;
+use super::{DeleteResult, FindOneAndUpdateOptions, FindOptions, Index, Session, StorageCollection, StorageEngine,
+    StorageError, UpdateOptions, UpdateResult};
+
+fn mongo_session(session: Option<&mut Session>) -> Result<Option<&mut ClientSession>, StorageError> {
+    match session {
+        None => Ok(None),
+        Some(Session::Mongo(session)) => Ok(Some(session.as_mut())),
+        Some(_) => Err(StorageError::Other(String::from("Session belongs to another storage backend"))),
+    }
+}
+
+fn mongo_session_required(session: &mut Session) -> Result<&mut ClientSession, StorageError> {
+    Ok(mongo_session(Some(session))?.expect("Session is present"))
+}
+
+impl From<results::UpdateResult> for UpdateResult {
+    fn from(result: results::UpdateResult) -> Self {
+        Self { matched_count: result.matched_count, modified_count: result.modified_count, upserted_id: result.upserted_id }
+    }
+}
+
+/// MongoDB backed storage.
+pub struct MongoEngine {
+    mongo: MongoOracle,
+}
+
+impl MongoEngine {
+    pub fn new(mongo: MongoOracle) -> Self {
+        Self { mongo }
+    }
+
+    fn db(&self) -> &Database {
+        self.mongo.db.as_ref().expect("Database not initialized")
+    }
+}
+
+#[async_trait]
+impl StorageEngine for MongoEngine {
+    fn collection(&self, name: &str) -> Arc<dyn StorageCollection> {
+        Arc::new(MongoCollection { collection: self.db().collection(name) })
+    }
+
+    async fn collection_names(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self.db().list_collection_names(None).await?)
+    }
+
+    fn supports_transactions(&self) -> bool {
+        self.mongo.supports_transactions
+    }
+
+    async fn start_session(&self) -> Result<Session, StorageError> {
+        let client = self.mongo.client.as_ref().expect("Client not initialized");
+        Ok(Session::Mongo(Box::new(client.start_session(None).await?)))
+    }
+
+    async fn start_transaction(&self, session: &mut Session) -> Result<(), StorageError> {
+        Ok(mongo_session_required(session)?.start_transaction(None).await?)
+    }
+
+    async fn commit_transaction(&self, session: &mut Session) -> Result<(), StorageError> {
+        Ok(mongo_session_required(session)?.commit_transaction().await?)
+    }
+
+    async fn abort_transaction(&self, session: &mut Session) -> Result<(), StorageError> {
+        Ok(mongo_session_required(session)?.abort_transaction().await?)
+    }
+}
+
+struct MongoCollection {
+    collection: Collection<Document>,
+}
+
+#[async_trait]
+impl StorageCollection for MongoCollection {
+    async fn insert_one(&self, document: Document, session: Option<&mut Session>) -> Result<Bson, StorageError> {
+        let result = match mongo_session(session)? {
+            Some(session) => self.collection.insert_one_with_session(document, None, session).await?,
+            None => self.collection.insert_one(document, None).await?,
+        };
+        Ok(result.inserted_id)
+    }
+
+    async fn find(&self, filter: Document, options: FindOptions, session: Option<&mut Session>) -> Result<Vec<Document>, StorageError> {
+        let options = options::FindOptions::builder().sort(options.sort).skip(options.skip).limit(options.limit).build();
+        match mongo_session(session)? {
+            Some(session) => {
+                let mut cursor = self.collection.find_with_session(filter, options, session).await?;
+                Ok(cursor.stream(session).try_collect().await?)
+            }
+            None => Ok(self.collection.find(filter, options).await?.try_collect().await?),
+        }
+    }
+
+    async fn count_documents(&self, filter: Document, session: Option<&mut Session>) -> Result<u64, StorageError> {
+        Ok(match mongo_session(session)? {
+            Some(session) => self.collection.count_documents_with_session(filter, None, session).await?,
+            None => self.collection.count_documents(filter, None).await?,
+        })
+    }
+
+    async fn update(&self, filter: Document, update: Document, options: UpdateOptions, session: Option<&mut Session>) -> Result<UpdateResult, StorageError> {
+        let session = mongo_session(session)?;
+        if is_replacement(&update) {
+            if options.many {
+                return Err(StorageError::InvalidQuery(String::from("Replacements only apply to a single document")));
+            }
+            let replace = options::ReplaceOptions::builder().upsert(options.upsert).build();
+            let result = match session {
+                Some(session) => self.collection.replace_one_with_session(filter, update, replace, session).await?,
+                None => self.collection.replace_one(filter, update, replace).await?,
+            };
+            return Ok(result.into());
+        }
+        let update_options = options::UpdateOptions::builder().upsert(options.upsert).build();
+        let result = match (session, options.many) {
+            (Some(session), false) => self.collection.update_one_with_session(filter, update, update_options, session).await?,
+            (Some(session), true) => self.collection.update_many_with_session(filter, update, update_options, session).await?,
+            (None, false) => self.collection.update_one(filter, update, update_options).await?,
+            (None, true) => self.collection.update_many(filter, update, update_options).await?,
+        };
+        Ok(result.into())
+    }
+
+    async fn find_one_and_update(&self, filter: Document, update: Document, options: FindOneAndUpdateOptions, session: Option<&mut Session>) -> Result<Option<Document>, StorageError> {
+        let session = mongo_session(session)?;
+        let return_document = if options.return_new { ReturnDocument::After } else { ReturnDocument::Before };
+        if is_replacement(&update) {
+            let replace = options::FindOneAndReplaceOptions::builder()
+                .sort(options.sort).upsert(options.upsert).return_document(return_document).build();
+            return Ok(match session {
+                Some(session) => self.collection.find_one_and_replace_with_session(filter, update, replace, session).await?,
+                None => self.collection.find_one_and_replace(filter, update, replace).await?,
+            });
+        }
+        let find_options = options::FindOneAndUpdateOptions::builder()
+            .sort(options.sort).upsert(options.upsert).return_document(return_document).build();
+        Ok(match session {
+            Some(session) => self.collection.find_one_and_update_with_session(filter, update, find_options, session).await?,
+            None => self.collection.find_one_and_update(filter, update, find_options).await?,
+        })
+    }
+
+    async fn delete(&self, filter: Document, many: bool, session: Option<&mut Session>) -> Result<DeleteResult, StorageError> {
+        let result = match (mongo_session(session)?, many) {
+            (Some(session), false) => self.collection.delete_one_with_session(filter, None, session).await?,
+            (Some(session), true) => self.collection.delete_many_with_session(filter, None, session).await?,
+            (None, false) => self.collection.delete_one(filter, None).await?,
+            (None, true) => self.collection.delete_many(filter, None).await?,
+        };
+        Ok(DeleteResult { deleted_count: result.deleted_count })
+    }
+
+    async fn create_index(&self, index: Index) -> Result<(), StorageError> {
+        let options = IndexOptions::builder()
+            .name(index.name)
+            .unique(index.unique)
+            .expire_after(index.expire_after)
+            .build();
+        let model = IndexModel::builder().keys(index.keys).options(options).build();
+        self.collection.create_index(model, None).await?;
+        Ok(())
+    }
+
+    async fn drop_index(&self, name: &str) -> Result<(), StorageError> {
+        Ok(self.collection.drop_index(name, None).await?)
+    }
+
+    async fn drop_collection(&self) -> Result<(), StorageError> {
+        Ok(self.collection.drop(None).await?)
+    }
+}
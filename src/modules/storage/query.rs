@@ -0,0 +1,391 @@
+//! Evaluation of MongoDB style filters, updates and sorts over plain documents, used by the memory backend.
+use std::cmp::Ordering;
+
+use rocket_db_pools::mongodb::bson::{Bson, Document};
+
+use super::StorageError;
+
+/// Whether the update replaces the whole document instead of using update operators.
+pub fn is_replacement(update: &Document) -> bool {
+    update.keys().next().is_none_or(|key| !key.starts_with('$'))
+}
+
+/// Values found at a dotted path. Arrays along the path are traversed element by element.
+pub fn get_path<'a>(document: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let mut values = vec![];
+    let (head, rest) = split_path(path);
+    if let Some(value) = document.get(head) {
+        collect_path(value, rest, &mut values);
+    }
+    values
+}
+
+fn split_path(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    }
+}
+
+fn collect_path<'a>(value: &'a Bson, rest: Option<&str>, values: &mut Vec<&'a Bson>) {
+    let Some(rest) = rest else {
+        values.push(value);
+        return;
+    };
+    let (head, tail) = split_path(rest);
+    match value {
+        Bson::Document(document) => {
+            if let Some(value) = document.get(head) {
+                collect_path(value, tail, values);
+            }
+        }
+        Bson::Array(items) => {
+            if let Ok(index) = head.parse::<usize>() {
+                if let Some(value) = items.get(index) {
+                    collect_path(value, tail, values);
+                }
+            } else {
+                for item in items {
+                    collect_path(item, Some(rest), values);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Single value at a dotted path, without traversing arrays.
+fn get_single<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let (head, rest) = split_path(path);
+    let value = document.get(head)?;
+    match rest {
+        None => Some(value),
+        Some(rest) => match value {
+            Bson::Document(inner) => get_single(inner, rest),
+            Bson::Array(items) => {
+                let (index, tail) = split_path(rest);
+                let item = items.get(index.parse::<usize>().ok()?)?;
+                match (tail, item) {
+                    (None, item) => Some(item),
+                    (Some(tail), Bson::Document(inner)) => get_single(inner, tail),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Sets the value at a dotted path, creating intermediate documents.
+fn set_path(document: &mut Document, path: &str, value: Bson) -> Result<(), StorageError> {
+    let (head, rest) = split_path(path);
+    let Some(rest) = rest else {
+        document.insert(head, value);
+        return Ok(());
+    };
+    if !document.contains_key(head) {
+        document.insert(head, Document::new());
+    }
+    match document.get_mut(head) {
+        Some(Bson::Document(inner)) => set_path(inner, rest, value),
+        Some(Bson::Array(items)) => {
+            let (index, tail) = split_path(rest);
+            let index = index.parse::<usize>()
+                .map_err(|_| StorageError::InvalidQuery(format!("Cannot set {} inside an array", path)))?;
+            let item = items.get_mut(index)
+                .ok_or_else(|| StorageError::InvalidQuery(format!("Index out of bounds in {}", path)))?;
+            match (tail, item) {
+                (None, item) => {
+                    *item = value;
+                    Ok(())
+                }
+                (Some(tail), Bson::Document(inner)) => set_path(inner, tail, value),
+                _ => Err(StorageError::InvalidQuery(format!("Cannot set {}", path))),
+            }
+        }
+        _ => Err(StorageError::InvalidQuery(format!("Cannot set {} on a non document value", path))),
+    }
+}
+
+fn remove_path(document: &mut Document, path: &str) {
+    let (head, rest) = split_path(path);
+    match rest {
+        None => {
+            document.remove(head);
+        }
+        Some(rest) => {
+            if let Some(Bson::Document(inner)) = document.get_mut(head) {
+                remove_path(inner, rest);
+            }
+        }
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Compares two values of the same kind, numbers are compared across their representations.
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::Int32(a), Bson::Int32(b)) => Some(a.cmp(b)),
+        (Bson::Int64(a), Bson::Int64(b)) => Some(a.cmp(b)),
+        (Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_), Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_)) =>
+            as_f64(a)?.partial_cmp(&as_f64(b)?),
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => Some((a.time, a.increment).cmp(&(b.time, b.increment))),
+        (Bson::Null, Bson::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+fn equals(a: &Bson, b: &Bson) -> bool {
+    match compare(a, b) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => a == b,
+    }
+}
+
+/// Rank of the kind of a value in the MongoDB sort order, used when comparing different kinds.
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::MaxKey => 13,
+        _ => 12,
+    }
+}
+
+fn sort_compare(a: Option<&Bson>, b: Option<&Bson>) -> Ordering {
+    let (a, b) = (a.unwrap_or(&Bson::Null), b.unwrap_or(&Bson::Null));
+    compare(a, b).unwrap_or_else(|| type_rank(a).cmp(&type_rank(b)))
+}
+
+/// Whether any value at the path, or any element of an array value, satisfies `predicate`.
+fn any_value(values: &[&Bson], predicate: &dyn Fn(&Bson) -> bool) -> bool {
+    values.iter().any(|value| predicate(value) || matches!(value, Bson::Array(items) if items.iter().any(predicate)))
+}
+
+fn is_operator_document(value: &Bson) -> bool {
+    matches!(value, Bson::Document(document) if document.keys().next().is_some_and(|key| key.starts_with('$')))
+}
+
+/// Whether the document matches a filter.
+pub fn matches(document: &Document, filter: &Document) -> Result<bool, StorageError> {
+    for (key, condition) in filter {
+        let matched = match key.as_str() {
+            "$and" => filters(condition)?.iter().try_fold(true, |all, f| Ok::<_, StorageError>(all && matches(document, f)?))?,
+            "$or" => filters(condition)?.iter().try_fold(false, |any, f| Ok::<_, StorageError>(any || matches(document, f)?))?,
+            "$nor" => !filters(condition)?.iter().try_fold(false, |any, f| Ok::<_, StorageError>(any || matches(document, f)?))?,
+            key if key.starts_with('$') => return Err(StorageError::InvalidQuery(format!("Unsupported operator {}", key))),
+            path => matches_condition(&get_path(document, path), condition)?,
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn filters(condition: &Bson) -> Result<Vec<&Document>, StorageError> {
+    match condition {
+        Bson::Array(items) => items.iter().map(|item| match item {
+            Bson::Document(document) => Ok(document),
+            _ => Err(StorageError::InvalidQuery(String::from("Logical operators expect an array of filters"))),
+        }).collect(),
+        _ => Err(StorageError::InvalidQuery(String::from("Logical operators expect an array of filters"))),
+    }
+}
+
+fn matches_condition(values: &[&Bson], condition: &Bson) -> Result<bool, StorageError> {
+    let Bson::Document(operators) = condition else {
+        return Ok(matches_equal(values, condition));
+    };
+    if !is_operator_document(condition) {
+        return Ok(matches_equal(values, condition));
+    }
+    for (operator, operand) in operators {
+        let matched = match operator.as_str() {
+            "$eq" => matches_equal(values, operand),
+            "$ne" => !matches_equal(values, operand),
+            "$gt" => any_value(values, &|v| compare(v, operand) == Some(Ordering::Greater)),
+            "$gte" => any_value(values, &|v| matches!(compare(v, operand), Some(Ordering::Greater | Ordering::Equal))),
+            "$lt" => any_value(values, &|v| compare(v, operand) == Some(Ordering::Less)),
+            "$lte" => any_value(values, &|v| matches!(compare(v, operand), Some(Ordering::Less | Ordering::Equal))),
+            "$in" => in_array(operand)?.iter().any(|candidate| matches_equal(values, candidate)),
+            "$nin" => !in_array(operand)?.iter().any(|candidate| matches_equal(values, candidate)),
+            "$exists" => values.is_empty() != operand.as_bool().unwrap_or(true),
+            "$not" => !matches_condition(values, operand)?,
+            other => return Err(StorageError::InvalidQuery(format!("Unsupported operator {}", other))),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn in_array(operand: &Bson) -> Result<&Vec<Bson>, StorageError> {
+    match operand {
+        Bson::Array(items) => Ok(items),
+        _ => Err(StorageError::InvalidQuery(String::from("$in and $nin expect an array"))),
+    }
+}
+
+fn matches_equal(values: &[&Bson], target: &Bson) -> bool {
+    if matches!(target, Bson::Null) && values.is_empty() {
+        return true;
+    }
+    any_value(values, &|value| equals(value, target))
+}
+
+/// Sorts documents by a sort specification like `{ "created": -1, "_id": 1 }`.
+pub fn sort(documents: &mut [Document], specification: &Document) {
+    documents.sort_by(|a, b| {
+        for (path, direction) in specification {
+            let ordering = sort_compare(get_path(a, path).first().copied(), get_path(b, path).first().copied());
+            let ordering = if as_f64(direction).unwrap_or(1.0) < 0.0 { ordering.reverse() } else { ordering };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    });
+}
+
+/// Document an upsert starts from: the equality conditions of the filter.
+pub fn upsert_seed(filter: &Document) -> Result<Document, StorageError> {
+    let mut document = Document::new();
+    for (key, condition) in filter {
+        if key == "$and" {
+            for inner in filters(condition)? {
+                for (key, value) in upsert_seed(inner)? {
+                    document.insert(key, value);
+                }
+            }
+        } else if key.starts_with('$') {
+            continue;
+        } else if !is_operator_document(condition) {
+            set_path(&mut document, key, condition.clone())?;
+        } else if let Some(value) = condition.as_document().and_then(|operators| operators.get("$eq")) {
+            set_path(&mut document, key, value.clone())?;
+        }
+    }
+    Ok(document)
+}
+
+/// Applies an update to the document. `inserting` enables `$setOnInsert` for upserts.
+pub fn apply_update(document: &mut Document, update: &Document, inserting: bool) -> Result<(), StorageError> {
+    if is_replacement(update) {
+        let id = document.get("_id").cloned();
+        *document = update.clone();
+        if let Some(id) = id {
+            document.insert("_id", id);
+        }
+        return Ok(());
+    }
+    for (operator, fields) in update {
+        let fields = fields.as_document()
+            .ok_or_else(|| StorageError::InvalidQuery(format!("{} expects a document", operator)))?;
+        for (path, operand) in fields {
+            match operator.as_str() {
+                "$set" => set_path(document, path, operand.clone())?,
+                "$setOnInsert" => if inserting {
+                    set_path(document, path, operand.clone())?;
+                },
+                "$unset" => remove_path(document, path),
+                "$inc" => {
+                    let incremented = match (get_single(document, path), operand) {
+                        (None, operand) => operand.clone(),
+                        (Some(Bson::Int32(a)), Bson::Int32(b)) => Bson::Int32(a + b),
+                        (Some(Bson::Int64(a)), Bson::Int32(b)) => Bson::Int64(a + *b as i64),
+                        (Some(Bson::Int32(a)), Bson::Int64(b)) => Bson::Int64(*a as i64 + b),
+                        (Some(Bson::Int64(a)), Bson::Int64(b)) => Bson::Int64(a + b),
+                        (Some(current), operand) => match (as_f64(current), as_f64(operand)) {
+                            (Some(a), Some(b)) => Bson::Double(a + b),
+                            _ => return Err(StorageError::InvalidQuery(format!("Cannot increment {}", path))),
+                        },
+                    };
+                    set_path(document, path, incremented)?;
+                }
+                "$max" => {
+                    if sort_compare(get_single(document, path), Some(operand)) == Ordering::Less {
+                        set_path(document, path, operand.clone())?;
+                    }
+                }
+                "$push" | "$addToSet" => {
+                    let mut items = array_at(document, path)?;
+                    for item in each(operand) {
+                        if operator == "$push" || !items.iter().any(|existing| equals(existing, &item)) {
+                            items.push(item);
+                        }
+                    }
+                    set_path(document, path, Bson::Array(items))?;
+                }
+                "$pull" => {
+                    let mut items = array_at(document, path)?;
+                    let mut kept = Vec::with_capacity(items.len());
+                    for item in items.drain(..) {
+                        let pulled = match (operand, &item) {
+                            (Bson::Document(condition), Bson::Document(inner)) if !is_operator_document(operand) => matches(inner, condition)?,
+                            _ => matches_condition(&[&item], operand)?,
+                        };
+                        if !pulled {
+                            kept.push(item);
+                        }
+                    }
+                    set_path(document, path, Bson::Array(kept))?;
+                }
+                other => return Err(StorageError::InvalidQuery(format!("Unsupported update operator {}", other))),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn array_at(document: &Document, path: &str) -> Result<Vec<Bson>, StorageError> {
+    match get_single(document, path) {
+        None => Ok(vec![]),
+        Some(Bson::Array(items)) => Ok(items.clone()),
+        Some(_) => Err(StorageError::InvalidQuery(format!("{} is not an array", path))),
+    }
+}
+
+/// Items added by `$push` and `$addToSet`, either a single value or the values of `$each`.
+fn each(operand: &Bson) -> Vec<Bson> {
+    match operand {
+        Bson::Document(document) if document.contains_key("$each") => match document.get("$each") {
+            Some(Bson::Array(items)) => items.clone(),
+            Some(item) => vec![item.clone()],
+            None => vec![],
+        },
+        item => vec![item.clone()],
+    }
+}
+
+/// Key of a document in an index, missing fields count as null like in MongoDB.
+pub fn index_key(document: &Document, keys: &Document) -> Vec<Bson> {
+    keys.keys().map(|path| get_path(document, path).first().map_or(Bson::Null, |value| (*value).clone())).collect()
+}
+
+pub fn same_key(a: &[Bson], b: &[Bson]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equals(a, b))
+}
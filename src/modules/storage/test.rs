@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use rocket_db_pools::mongodb::bson::{doc, Bson, Document};
+use crate::framework::testing::TestDatabase;
+use crate::modules::{self, storage::{migration::Migrator, FindOneAndUpdateOptions, FindOptions, Index, Storage, StorageError}};
+
+async fn storage(database: &TestDatabase) -> Storage {
+    Storage::connect(database.config.clone()).await.expect("Error connecting to storage")
+}
+
+#[tokio::test]
+async fn test_add_and_remove_collections() {
+    let database = TestDatabase::new();
+    let storage = storage(&database).await;
+
+    storage.add_collection("test_collection_1");
+    storage.add_collection("test_collection_2");
+
+    {
+        let collections = storage.collections.lock().unwrap();
+        assert!(collections.contains(&"test_collection_1".to_string()));
+        assert!(collections.contains(&"test_collection_2".to_string()));
+    }
+
+    storage.remove_collection("test_collection_1");
+
+    {
+        let collections = storage.collections.lock().unwrap();
+        assert!(!collections.contains(&"test_collection_1".to_string()));
+        assert!(collections.contains(&"test_collection_2".to_string()));
+    }
+}
+
+#[tokio::test]
+async fn test_drop_database() {
+    let database = TestDatabase::new();
+    let storage = storage(&database).await;
+
+    // Create test collections
+    storage.collection::<Document>("test_collection_1").insert_one(&Document::new(), None).await.unwrap();
+    storage.collection::<Document>("test_collection_2").insert_one(&Document::new(), None).await.unwrap();
+
+    // Add collections to drop
+    storage.add_collection("test_collection_1");
+    storage.add_collection("test_collection_2");
+
+    // Drop the specified collections
+    storage.drop_database().await.unwrap();
+
+    // Verify collections no longer exist
+    let collections = storage.collection_names().await.unwrap();
+    assert!(!collections.contains(&"test_collection_1".to_string()));
+    assert!(!collections.contains(&"test_collection_2".to_string()));
+}
+
+#[tokio::test]
+async fn test_queries() {
+    let database = TestDatabase::new();
+    let storage = storage(&database).await;
+    let things = storage.collection::<Document>("test_queries");
+    things.insert_one(&doc! { "name": "a", "count": 1, "tags": ["red", "blue"], "owner": { "age": 30 } }, None).await.unwrap();
+    things.insert_one(&doc! { "name": "b", "count": 2_i64, "tags": ["green"], "owner": { "age": 40 } }, None).await.unwrap();
+    things.insert_one(&doc! { "name": "c", "count": 3.5 }, None).await.unwrap();
+
+    let names = |found: Vec<Document>| found.iter().map(|d| d.get_str("name").unwrap().to_string()).collect::<Vec<_>>();
+    let find = |filter: Document| {
+        let things = things.clone();
+        async move { things.find(filter, FindOptions { sort: Some(doc! { "name": 1 }), ..Default::default() }, None).await.unwrap() }
+    };
+
+    assert_eq!(names(find(doc! { "count": { "$gte": 2 } }).await), vec!["b", "c"]);
+    assert_eq!(names(find(doc! { "count": { "$lt": 2.5, "$ne": 1 } }).await), vec!["b"]);
+    assert_eq!(names(find(doc! { "tags": "blue" }).await), vec!["a"]);
+    assert_eq!(names(find(doc! { "tags": { "$in": ["green", "blue"] } }).await), vec!["a", "b"]);
+    assert_eq!(names(find(doc! { "owner.age": { "$gt": 35 } }).await), vec!["b"]);
+    assert_eq!(names(find(doc! { "tags": { "$exists": false } }).await), vec!["c"]);
+    assert_eq!(names(find(doc! { "$or": [{ "name": "a" }, { "count": 3.5 }] }).await), vec!["a", "c"]);
+    assert_eq!(names(find(doc! { "name": { "$nin": ["a", "b"] } }).await), vec!["c"]);
+
+    let sorted = things.find(doc! {}, FindOptions { sort: Some(doc! { "count": -1 }), skip: Some(1), limit: Some(1) }, None).await.unwrap();
+    assert_eq!(names(sorted), vec!["b"]);
+    assert_eq!(things.count_documents(doc! { "owner": { "$exists": true } }, None).await.unwrap(), 2);
+    assert!(matches!(things.find(doc! { "$where": "1" }, FindOptions::default(), None).await, Err(StorageError::InvalidQuery(_))));
+}
+
+#[tokio::test]
+async fn test_updates() {
+    let database = TestDatabase::new();
+    let storage = storage(&database).await;
+    let things = storage.collection::<Document>("test_updates");
+    things.insert_one(&doc! { "name": "a", "count": 1, "tags": ["red"] }, None).await.unwrap();
+
+    let update = doc! {
+        "$set": { "nested.value": true },
+        "$inc": { "count": 2 },
+        "$push": { "tags": { "$each": ["blue", "red"] } },
+        "$unset": { "name": "" },
+    };
+    let result = things.update_one(doc! { "name": "a" }, update, None).await.unwrap();
+    assert_eq!((result.matched_count, result.modified_count), (1, 1));
+    let thing = things.find_one(doc! { "count": 3 }, None).await.unwrap().expect("Updated document not found");
+    assert_eq!(thing.get_document("nested").unwrap().get_bool("value"), Ok(true));
+    assert!(!thing.contains_key("name"));
+
+    things.update_many(doc! {}, doc! { "$pull": { "tags": "red" }, "$addToSet": { "tags": "blue" } }, None).await.unwrap();
+    let thing = things.find_one(doc! {}, None).await.unwrap().unwrap();
+    assert_eq!(thing.get_array("tags").unwrap(), &vec![Bson::from("blue")]);
+
+    // Upserts start from the equality conditions of the filter
+    let result = things.upsert_one(doc! { "name": "b" }, doc! { "$setOnInsert": { "count": 0 } }, None).await.unwrap();
+    assert!(result.upserted_id.is_some());
+    let updated = things.find_one_and_update(doc! { "name": "b" }, doc! { "$inc": { "count": 1 } },
+        FindOneAndUpdateOptions { return_new: true, ..Default::default() }, None).await.unwrap().unwrap();
+    assert_eq!(updated.get_i32("count"), Ok(1));
+
+    // $max only raises values, and sets missing ones
+    let update = doc! { "$max": { "count": 0, "peak": 5 } };
+    things.update_one(doc! { "name": "b" }, update, None).await.unwrap();
+    let update = doc! { "$max": { "peak": 7 } };
+    let updated = things.find_one_and_update(doc! { "name": "b" }, update,
+        FindOneAndUpdateOptions { return_new: true, ..Default::default() }, None).await.unwrap().unwrap();
+    assert_eq!((updated.get_i32("count"), updated.get_i32("peak")), (Ok(1), Ok(7)));
+}
+
+#[tokio::test]
+async fn test_unique_index() {
+    let database = TestDatabase::new();
+    let storage = storage(&database).await;
+    let things = storage.collection::<Document>("test_unique_index");
+    things.create_index(Index::new("email_1", doc! { "email": 1 }).unique()).await.unwrap();
+
+    things.insert_one(&doc! { "email": "a@example.com" }, None).await.unwrap();
+    let duplicate = things.insert_one(&doc! { "email": "a@example.com" }, None).await;
+    assert!(duplicate.unwrap_err().is_duplicate_key());
+
+    things.insert_one(&doc! { "email": "b@example.com" }, None).await.unwrap();
+    let duplicate = things.update_one(doc! { "email": "b@example.com" }, doc! { "$set": { "email": "a@example.com" } }, None).await;
+    assert!(duplicate.unwrap_err().is_duplicate_key());
+
+    things.drop_index("email_1").await.unwrap();
+    things.insert_one(&doc! { "email": "a@example.com" }, None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_migrations() {
+    let database = TestDatabase::new();
+    let storage = Arc::new(storage(&database).await);
+    let migrator = Migrator::new(storage.clone(), modules::migrations());
+
+    // Everything is applied once, in order
+    let applied = migrator.up().await.expect("Error applying migrations");
+    assert_eq!(applied, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    assert!(migrator.up().await.expect("Error applying migrations").is_empty());
+    let status = migrator.status().await.expect("Error listing migrations");
+    assert!(status.iter().all(|m| m.applied.is_some()));
+
+    // Rolling back only undoes the latest one
+    let rolled_back = migrator.down(1).await.expect("Error rolling back migrations");
+    assert_eq!(rolled_back, vec![11]);
+    let status = migrator.status().await.expect("Error listing migrations");
+    assert!(status[9].applied.is_some());
+    assert!(status[10].applied.is_none());
+
+    assert_eq!(migrator.up().await.expect("Error applying migrations"), vec![11]);
+}
+
+#[tokio::test]
+async fn test_transaction() {
+    let database = TestDatabase::new();
+    let storage = storage(&database).await;
+    let things = storage.collection::<Document>("test_transactions");
+
+    // Both writes are committed together
+    storage.transaction(things.clone(), |session, things| Box::pin(async move {
+        things.insert_one(&doc! { "name": "first" }, Some(&mut *session)).await?;
+        things.insert_one(&doc! { "name": "second" }, Some(session)).await?;
+        Ok(())
+    })).await.expect("Error running transaction");
+    assert_eq!(things.count_documents(doc! {}, None).await.unwrap(), 2);
+
+    // A failing unit of work is rolled back
+    let result: Result<(), _> = storage.transaction(things.clone(), |session, things| Box::pin(async move {
+        things.insert_one(&doc! { "name": "third" }, Some(&mut *session)).await?;
+        things.update_one(doc! { "name": "first" }, doc! { "$set": { "name": "changed" } }, Some(&mut *session)).await?;
+        things.delete_one(doc! { "name": "second" }, Some(session)).await?;
+        Err(StorageError::Other(String::from("Abort")))
+    })).await;
+    assert!(result.is_err());
+    if storage.supports_transactions() {
+        assert_eq!(things.count_documents(doc! {}, None).await.unwrap(), 2);
+        assert_eq!(things.count_documents(doc! { "name": { "$in": ["first", "second"] } }, None).await.unwrap(), 2);
+    }
+}
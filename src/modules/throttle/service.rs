@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use rocket_db_pools::mongodb::bson::{doc, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::modules::storage::{Collection, FindOneAndUpdateOptions, Storage, StorageError};
+
+pub const THROTTLE_COLLECTION: &str = "throttles";
+
+/// Hits counted for a key since the start of its current window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThrottleWindow {
+    /// What is throttled, e.g. `verification_resend:127.0.0.1`.
+    pub _id: String,
+    pub count: u32,
+    pub started: DateTime,
+    /// End of the window, the document is dropped after it.
+    pub expires: DateTime,
+}
+
+/// Fixed window rate limits shared by every instance through the storage.
+pub struct Throttle {
+    pub windows: Collection<ThrottleWindow>,
+}
+
+fn to_chrono(date: DateTime) -> chrono::DateTime<Utc> {
+    Utc.timestamp_millis_opt(date.timestamp_millis()).single().unwrap_or_else(Utc::now)
+}
+
+impl Throttle {
+    pub fn new(storage: &Storage) -> Self {
+        storage.add_collection(THROTTLE_COLLECTION);
+        Self { windows: storage.collection(THROTTLE_COLLECTION) }
+    }
+
+    async fn current(&self, key: &str) -> Result<Option<ThrottleWindow>, StorageError> {
+        let window = self.windows.find_one(doc! {"_id": key}, None).await?;
+        Ok(window.filter(|window| window.expires > DateTime::now()))
+    }
+
+    /// When `key` may be hit again with `limit` hits per window, None if right away. Does not count a hit.
+    pub async fn retry_at(&self, key: &str, limit: u32) -> Result<Option<chrono::DateTime<Utc>>, StorageError> {
+        Ok(self.current(key).await?
+            .filter(|current| current.count >= limit)
+            .map(|current| to_chrono(current.expires)))
+    }
+
+    /// Counts a hit on `key`, and returns when the current `window` ends if it had `limit` hits already. Counted in
+    /// a single update, so parallel hits cannot all get through.
+    pub async fn hit(&self, key: &str, limit: u32, window: Duration) -> Result<Option<chrono::DateTime<Utc>>, StorageError> {
+        let current = loop {
+            let now = DateTime::now();
+            let options = FindOneAndUpdateOptions { return_new: true, ..Default::default() };
+            let filter = doc! {"_id": key, "expires": {"$gt": now}};
+            if let Some(current) = self.windows.find_one_and_update(filter, doc! {"$inc": {"count": 1}}, options, None).await? {
+                break current;
+            }
+            let expires = DateTime::from_millis(now.timestamp_millis() + window.as_millis() as i64);
+            let update = doc! {"$set": {"count": 1, "started": now, "expires": expires}};
+            let options = FindOneAndUpdateOptions { upsert: true, return_new: true, ..Default::default() };
+            let ended = doc! {"_id": key, "expires": {"$lte": now}};
+            match self.windows.find_one_and_update(ended, update, options, None).await {
+                Ok(Some(current)) => break current,
+                // Another hit started the window first, and is counted on again
+                Ok(None) => continue,
+                Err(e) if e.is_duplicate_key() => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        Ok((current.count > limit).then(|| to_chrono(current.expires)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::framework::config::OxidizeConfig;
+    use crate::framework::testing::load_test_env;
+    use crate::modules::storage::Storage;
+
+    use super::Throttle;
+
+    #[tokio::test]
+    async fn test_throttle() {
+        load_test_env();
+        let config = Arc::new(OxidizeConfig::new().expect("Error creating config"));
+        let storage = Storage::memory(config);
+        let throttle = Throttle::new(&storage);
+        let window = Duration::from_secs(60);
+
+        assert_eq!(throttle.retry_at("key", 2).await.unwrap(), None);
+        assert_eq!(throttle.hit("key", 2, window).await.unwrap(), None);
+        assert_eq!(throttle.hit("key", 2, window).await.unwrap(), None);
+        let retry_at = throttle.hit("key", 2, window).await.unwrap().expect("Third hit was let through");
+        assert!(retry_at > chrono::Utc::now() + chrono::Duration::seconds(50));
+        assert_eq!(throttle.retry_at("key", 2).await.unwrap(), Some(retry_at));
+        assert_eq!(throttle.hit("other", 2, window).await.unwrap(), None);
+
+        // A new window starts once the previous one ends
+        assert_eq!(throttle.hit("short", 1, Duration::from_millis(50)).await.unwrap(), None);
+        assert!(throttle.hit("short", 1, Duration::from_millis(50)).await.unwrap().is_some());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(throttle.hit("short", 1, Duration::from_millis(50)).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_hits() {
+        load_test_env();
+        let config = Arc::new(OxidizeConfig::new().expect("Error creating config"));
+        let throttle = Arc::new(Throttle::new(&Storage::memory(config)));
+        let hits = (0..20).map(|_| {
+            let throttle = throttle.clone();
+            tokio::spawn(async move { throttle.hit("key", 5, Duration::from_secs(60)).await.unwrap() })
+        });
+        let passed = futures::future::join_all(hits).await.into_iter()
+            .filter(|result| result.as_ref().expect("Hit task panicked").is_none())
+            .count();
+        assert_eq!(passed, 5);
+    }
+}
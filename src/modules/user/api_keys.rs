@@ -0,0 +1,90 @@
+use std::io;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Utc;
+use rand::RngCore;
+use rocket_db_pools::mongodb::bson::{self, doc, oid::ObjectId};
+
+use crate::framework::auth::secret_digest;
+use crate::modules::storage::{Collection, FindOptions, Storage, StorageError};
+use super::dto::{ApiKey, NewApiKey};
+
+pub const API_KEYS_COLLECTION: &str = "api_keys";
+
+const SECRET_BYTES: usize = 32;
+
+/// The API keys of the users, for scripts that cannot sign tokens.
+pub struct ApiKeys {
+    pub keys: Collection<ApiKey>,
+}
+
+impl ApiKeys {
+    pub fn new(storage: &Storage) -> Self {
+        storage.add_collection(API_KEYS_COLLECTION);
+        Self { keys: storage.collection(API_KEYS_COLLECTION) }
+    }
+
+    /// Creates a key for `user_id` and returns it along with its `<id>.<secret>` credential, of which only a digest
+    /// is kept. Fails with InvalidInput if it has no name or scope, or expired already.
+    pub async fn create(&self, user_id: &ObjectId, new_key: NewApiKey) -> Result<(ApiKey, String), io::Error> {
+        let mut scopes = Vec::new();
+        for scope in new_key.scopes {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        if new_key.name.trim().is_empty() || scopes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "An API key needs a name and a scope"));
+        }
+        let mut secret = [0u8; SECRET_BYTES];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = URL_SAFE_NO_PAD.encode(secret);
+        let mut key = ApiKey {
+            _id: None,
+            user_id: *user_id,
+            name: new_key.name.trim().to_string(),
+            scopes,
+            secret: secret_digest(&secret),
+            created: Utc::now(),
+            last_used: None,
+            expires: new_key.expires,
+        };
+        if key.is_expired() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The API key expired already"));
+        }
+        key._id = self.keys.insert_one(&key, None).await.map_err(io::Error::other)?.inserted_id.as_object_id();
+        let id = key._id.ok_or_else(|| io::Error::other("API key inserted without id"))?;
+        Ok((key, format!("{}.{}", id.to_hex(), secret)))
+    }
+
+    /// Every key of `user_id`, expired ones included, oldest first.
+    pub async fn list(&self, user_id: &ObjectId) -> Result<Vec<ApiKey>, StorageError> {
+        let options = FindOptions { sort: Some(doc! {"created": 1}), ..Default::default() };
+        self.keys.find(doc! {"user_id": user_id}, options, None).await
+    }
+
+    /// The key `id`, unless it expired. Its secret still has to be checked.
+    pub async fn find(&self, id: &ObjectId) -> Result<Option<ApiKey>, StorageError> {
+        let key = self.keys.find_one(doc! {"_id": id}, None).await?;
+        Ok(key.filter(|key| !key.is_expired()))
+    }
+
+    /// Records that `id` just authenticated a request.
+    pub async fn touch(&self, id: &ObjectId) -> Result<(), StorageError> {
+        let now = bson::to_bson(&Utc::now()).map_err(|e| StorageError::Serialization(e.to_string()))?;
+        self.keys.update_one(doc! {"_id": id}, doc! {"$set": {"last_used": now}}, None).await?;
+        Ok(())
+    }
+
+    /// Removes the key `id` of `user_id`. Returns whether there was one.
+    pub async fn revoke(&self, user_id: &ObjectId, id: &ObjectId) -> Result<bool, StorageError> {
+        Ok(self.keys.delete_one(doc! {"_id": id, "user_id": user_id}, None).await?.deleted_count > 0)
+    }
+
+    /// Removes every key of `user_id`.
+    pub async fn remove_all(&self, user_id: &ObjectId) -> Result<(), StorageError> {
+        self.keys.delete_many(doc! {"user_id": user_id}, None).await?;
+        Ok(())
+    }
+}
@@ -1,27 +1,60 @@
+use log::error;
 use rocket::{delete, get, post, put};
 use rocket::{routes, State};
 use rocket_db_pools::mongodb::bson::oid::ObjectId;
-use super::dto::User;
+use std::io;
+use super::dto::{ApiKeyInfo, IssuedApiKey, NewApiKey, NewUserKey, User, UserKey};
 use crate::modules::CRUDMongo;
 use crate::framework::app::App;
-use crate::modules::user::guard::UpdateAuthGuard;
+use crate::framework::auth::{KeyError, MIN_RSA_KEY_BITS};
+use crate::framework::catchers::ErrorResponse;
+use crate::framework::translator::Locale;
+use crate::modules::user::guard::{OxidizeSession, TokenSession, UpdateAuthGuard};
 use rocket::serde::json::Json;
 use rocket::response::status;
 use rocket::http::Status;
 use rocket::Route;
+use fluent::FluentValue;
+
+/// 422 response explaining in the locale of the request why a public key was refused.
+fn invalid_public_key(app: &App, locale: &Locale, err: KeyError) -> status::Custom<Json<ErrorResponse>> {
+    let params = match err {
+        KeyError::TooShort { bits } => Some(vec![("bits", FluentValue::from(bits)), ("min_bits", FluentValue::from(MIN_RSA_KEY_BITS))]),
+        _ => None,
+    };
+    let error = app.translator.get_in(locale, &format!("public_key_{}", err.code()), params);
+    status::Custom(Status::UnprocessableEntity, Json(ErrorResponse { status: Status::UnprocessableEntity.code, error }))
+}
 
 #[post("/user", format = "application/json", data = "<user>")]
-pub async fn create_user(app: &State<App>, user: Json<User>) -> status::Custom<Json<Option<User>>> {
-    let new_id = app.users.create(user.0.to_owned()).await;
-    match new_id {
-        Some(id) => {
-            let mut created_user = user.0.to_owned();
-            created_user._id = id.inserted_id.as_object_id();
-            app.mail.start_verification(&created_user).await.expect("Error starting email verification");
+pub async fn create_user(app: &State<App>, user: Json<User>, locale: Locale) -> Result<status::Custom<Json<Option<User>>>, status::Custom<Json<ErrorResponse>>> {
+    let mut user = user.0;
+    user.normalize_public_key().map_err(|e| invalid_public_key(app, &locale, e))?;
+    if user.locale.is_none() && locale.negotiated {
+        user.locale = Some(locale.id);
+    }
+    // The user and its email verification are written in a single transaction
+    let context = (&app.users, app.mail.as_ref(), user);
+    let created = app.storage.transaction(context, |session, (users, mail, user)| Box::pin(async move {
+        let Some(id) = users.insert(user, Some(&mut *session)).await? else {
+            return Ok(None);
+        };
+        let mut created_user = user.to_owned();
+        created_user._id = id.inserted_id.as_object_id();
+        let verification = mail.prepare_verification(&created_user, Some(session)).await?;
+        Ok(Some((created_user, verification)))
+    })).await;
+    Ok(match created {
+        Ok(Some((created_user, verification))) => {
+            app.mail.send_verification_mail(verification, created_user.locale.as_deref()).await;
             status::Custom(Status::Created, Json::from(Some(created_user)))
         }
-        None => status::Custom(Status::Conflict, Json::from(None)) // Return 409 Conflict if the user already exists
-    }
+        Ok(None) => status::Custom(Status::Conflict, Json::from(None)), // Return 409 Conflict if the user already exists
+        Err(e) => {
+            error!("Error creating user: {}", e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    })
 }
 
 #[get("/user/<id>", format = "application/json")]
@@ -48,12 +81,19 @@ pub async fn find_user_by_email(app: &State<App>, email: String) -> status::Cust
 }
 
 #[put("/user/<_id>", format = "application/json", data = "<user>")]
-pub async fn update_user(app: &State<App>, _id:String,  user: Json<User>, _target: UpdateAuthGuard) -> status::Custom<Option<Json<User>>> {
-    let updated_user = app.users.update(user.0.to_owned()).await;
-    match updated_user {
-        Some(_) => status::Custom(Status::Ok, Some(user)),
-        None => status::Custom(Status::InternalServerError, None),
+pub async fn update_user(app: &State<App>, _id:String,  user: Json<User>, target: UpdateAuthGuard, locale: Locale) -> Result<status::Custom<Json<Option<User>>>, status::Custom<Json<ErrorResponse>>> {
+    let mut user = user.0;
+    // The email only changes through the confirmed flow of /mail/email-changes
+    if user.email != target.user_before_update.email {
+        return Ok(status::Custom(Status::BadRequest, Json::from(None)));
     }
+    user.normalize_public_key().map_err(|e| invalid_public_key(app, &locale, e))?;
+    user._id = target.user_before_update._id;
+    let updated_user = app.users.update(user.to_owned()).await;
+    Ok(match updated_user {
+        Some(_) => status::Custom(Status::Ok, Json::from(Some(user))),
+        None => status::Custom(Status::InternalServerError, Json::from(None)),
+    })
 }
 
 #[delete("/user/<id>", format = "application/json")]
@@ -76,6 +116,93 @@ pub async fn delete_user(app: &State<App>, id: String , __target: UpdateAuthGuar
     }
 }
 
+/// Registers another public key for the signed in user, e.g. for a new device.
+#[post("/user/keys", format = "application/json", data = "<key>")]
+pub async fn add_key(app: &State<App>, key: Json<NewUserKey>, token: TokenSession) -> status::Custom<Json<Option<UserKey>>> {
+    match app.users.register_key(&token.session.user._id.unwrap(), key.0).await {
+        Ok(key) => status::Custom(Status::Created, Json::from(Some(key))),
+        Err(err) => match err.kind() {
+            io::ErrorKind::InvalidInput => status::Custom(Status::BadRequest, Json::from(None)),
+            io::ErrorKind::AlreadyExists => status::Custom(Status::Conflict, Json::from(None)),
+            _ => {
+                error!("Error registering key for user {}: {}", token.session.user.email, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    }
+}
+
+/// The keys registered by the signed in user, expired ones included.
+#[get("/user/keys", format = "application/json")]
+pub async fn list_keys(app: &State<App>, session: OxidizeSession) -> status::Custom<Json<Option<Vec<UserKey>>>> {
+    match app.users.keys.list(&session.user._id.unwrap()).await {
+        Ok(keys) => status::Custom(Status::Ok, Json::from(Some(keys))),
+        Err(e) => {
+            error!("Error listing keys of user {}: {}", session.user.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Revokes a key of the signed in user, the tokens it signed stop being accepted.
+#[delete("/user/keys/<kid>")]
+pub async fn revoke_key(app: &State<App>, kid: &str, token: TokenSession) -> status::Custom<Json<Option<bool>>> {
+    match app.users.keys.revoke(&token.session.user._id.unwrap(), kid).await {
+        Ok(true) => status::Custom(Status::Ok, Json::from(Some(true))),
+        Ok(false) => status::Custom(Status::NotFound, Json::from(None)),
+        Err(e) => {
+            error!("Error revoking key {} of user {}: {}", kid, token.session.user.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Creates an API key for the signed in user, answering its credential this once.
+#[post("/user/api-keys", format = "application/json", data = "<key>")]
+pub async fn create_api_key(app: &State<App>, key: Json<NewApiKey>, token: TokenSession) -> status::Custom<Json<Option<IssuedApiKey>>> {
+    match app.users.api_keys.create(&token.session.user._id.unwrap(), key.0).await {
+        Ok((key, api_key)) => status::Custom(Status::Created, Json::from(Some(IssuedApiKey { key: ApiKeyInfo::from(&key), api_key }))),
+        Err(err) => match err.kind() {
+            io::ErrorKind::InvalidInput => status::Custom(Status::BadRequest, Json::from(None)),
+            _ => {
+                error!("Error creating API key for user {}: {}", token.session.user.email, err);
+                status::Custom(Status::InternalServerError, Json::from(None))
+            }
+        },
+    }
+}
+
+/// The API keys of the signed in user, expired ones included.
+#[get("/user/api-keys", format = "application/json")]
+pub async fn list_api_keys(app: &State<App>, session: OxidizeSession) -> status::Custom<Json<Option<Vec<ApiKeyInfo>>>> {
+    match app.users.api_keys.list(&session.user._id.unwrap()).await {
+        Ok(keys) => status::Custom(Status::Ok, Json::from(Some(keys.iter().map(ApiKeyInfo::from).collect()))),
+        Err(e) => {
+            error!("Error listing API keys of user {}: {}", session.user.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
+/// Revokes an API key of the signed in user, it stops authenticating right away.
+#[delete("/user/api-keys/<id>")]
+pub async fn revoke_api_key(app: &State<App>, id: &str, token: TokenSession) -> status::Custom<Json<Option<bool>>> {
+    let Ok(id) = ObjectId::parse_str(id) else {
+        return status::Custom(Status::BadRequest, Json::from(None));
+    };
+    match app.users.api_keys.revoke(&token.session.user._id.unwrap(), &id).await {
+        Ok(true) => status::Custom(Status::Ok, Json::from(Some(true))),
+        Ok(false) => status::Custom(Status::NotFound, Json::from(None)),
+        Err(e) => {
+            error!("Error revoking API key {} of user {}: {}", id, token.session.user.email, e);
+            status::Custom(Status::InternalServerError, Json::from(None))
+        }
+    }
+}
+
 pub fn get_routes() -> Vec<Route> {
-    routes![create_user, delete_user, update_user, read_user, find_user_by_email]
+    routes![
+        create_user, delete_user, update_user, read_user, find_user_by_email, add_key, list_keys, revoke_key,
+        create_api_key, list_api_keys, revoke_api_key,
+    ]
 }
\ No newline at end of file
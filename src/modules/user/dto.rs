@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use rocket::serde::{Deserialize, Serialize};
 use rocket_db_pools::mongodb::bson::oid::ObjectId;
 use fake::faker::internet::en::FreeEmail;
@@ -5,6 +6,7 @@ use fake::faker::internet::en::Password;
 use fake::faker::lorem::en::Paragraph as Lorem;
 use fake::Fake;
 
+use crate::framework::auth::{parse_public_key, KeyError, KeyType};
 use crate::framework::testing::Mock;
 
 #[derive(Debug, Deserialize, Serialize,Clone)]
@@ -15,6 +17,22 @@ pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub _id : Option<ObjectId>,
     pub public_key : String,
+    /// Fingerprint of `public_key`, set by the server when the key is validated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key_fingerprint : Option<String>,
+    /// Preferred locale for emails, e.g. `es-ES`. Set from `Accept-Language` at signup when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale : Option<String>,
+}
+
+impl User {
+    /// Validates `public_key`, replacing it with its normalized form and setting its fingerprint.
+    pub fn normalize_public_key(&mut self) -> Result<(), KeyError> {
+        let key = parse_public_key(&self.public_key)?;
+        self.public_key = key.pem;
+        self.public_key_fingerprint = Some(key.fingerprint);
+        Ok(())
+    }
 }
 
 impl Mock for User {
@@ -25,11 +43,127 @@ impl Mock for User {
             password: Password(8..20).fake(),
             description: Lorem(10..100).fake(),
             public_key: pub_key,
+            public_key_fingerprint: None,
             _id: None,
+            locale: None,
         }
     }
 }
 
+/// A public key registered by a user besides `User.public_key`, e.g. one per device. Tokens name it in their `kid`
+/// header.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct UserKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    /// Thumbprint of the key, see `auth::key_thumbprint`.
+    pub kid: String,
+    pub algorithm: KeyType,
+    pub label: String,
+    pub public_key: String,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub last_used: Option<DateTime<Utc>>,
+    /// The key stops verifying tokens after this date, if any.
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl UserKey {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+}
+
+/// Body of a key registration.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NewUserKey {
+    pub public_key: String,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// What an API key may do. Scopes do not imply one another.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    /// `GET` and `HEAD` requests.
+    Read,
+    /// Every other request.
+    Write,
+    /// The routes of admins, when the user is one.
+    Admin,
+}
+
+/// A long-lived credential of a user for scripts, sent as `Authorization: ApiKey <id>.<secret>`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Digest of the secret, see `auth::secret_digest`.
+    pub secret: String,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub last_used: Option<DateTime<Utc>>,
+    /// The key stops authenticating after this date, if any.
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+}
+
+/// An API key as shown to its user, without its secret.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        ApiKeyInfo {
+            id: key._id.map(|id| id.to_hex()).unwrap_or_default(),
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+            created: key.created,
+            last_used: key.last_used,
+            expires: key.expires,
+        }
+    }
+}
+
+/// Body of an API key creation.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// A new API key, along with the value of its `Authorization` header, only handed out this once.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IssuedApiKey {
+    #[serde(flatten)]
+    pub key: ApiKeyInfo,
+    /// `<id>.<secret>`.
+    pub api_key: String,
+}
+
 pub enum UserRoles{
     GUEST,
     USER,
@@ -1,14 +1,80 @@
 use chrono::Utc;
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use log::{error, warn};
+use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
 use rocket::{Request, request::{self, FromRequest, Outcome}};
-use rocket::http::Status;
+use rocket::http::{Method, Status};
 use rocket_db_pools::mongodb::bson::oid::ObjectId;
-use crate::{framework::{app::App, auth::Claims}, modules::CRUDMongo};
-use super::dto::User;
+use std::net::IpAddr;
+use crate::{framework::{app::App, auth::{secret_digest, verifying_key, Claims, SERVER_TOKEN_ISSUER}, catchers::RetryAfter}, modules::CRUDMongo};
+use super::dto::{ApiKey, ApiScope, User};
+
+/// The user a bearer token stands for. Tokens issued by the server are checked with the server signing key named by
+/// their `kid` header, the others with the key of the user named by their `kid` header, or its signup key when there
+/// is none, in the algorithm of that key (RS512, EdDSA or ES256), and must expire within
+/// `client_token_max_lifetime_secs`. Users with TOTP enabled only get server tokens, issued once the second factor is
+/// checked, as their own tokens would skip it.
+async fn authenticate(app: &App, token: &str) -> Result<User, Status> {
+    let header = decode_header(token).map_err(|_| Status::Unauthorized)?;
+    let mut validation = Validation::new(header.alg);
+    validation.insecure_disable_signature_validation();
+    let claims = decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation).map_err(|_| Status::Unauthorized)?.claims;
+    let issued_by_server = claims.iss.as_deref() == Some(SERVER_TOKEN_ISSUER);
+    let claims = match issued_by_server {
+        true => app.auth.verify_token(token).await.ok_or(Status::Unauthorized)?,
+        false => claims,
+    };
+    let Ok(id) = ObjectId::parse_str(&claims.user_id) else {
+        return Err(Status::BadRequest);
+    };
+    let Some(user) = app.users.read(id).await else {
+        return Err(Status::NotFound);
+    };
+    if issued_by_server {
+        return Ok(user);
+    }
+
+    // The key named by `kid` among the registered keys of the user, the one it signed up with otherwise
+    let public_key = match &header.kid {
+        Some(kid) => match app.users.keys.find(&id, kid).await {
+            Ok(Some(key)) => key.public_key,
+            Ok(None) => return Err(Status::Unauthorized),
+            Err(e) => {
+                error!("Error reading key {} of user {}: {}", kid, id, e);
+                return Err(Status::InternalServerError);
+            }
+        },
+        None => user.public_key.clone(),
+    };
+    // Only the algorithm of the key is accepted
+    let (decoding_key, algorithm) = verifying_key(&public_key).ok_or(Status::Unauthorized)?;
+    let Ok(token_data) = decode::<Claims>(token, &decoding_key, &Validation::new(algorithm)) else {
+        return Err(Status::Unauthorized);
+    };
+    let current_time = Utc::now().timestamp() as usize;
+    if token_data.claims.exp < current_time || !app.auth.accepts_client_expiry(&token_data.claims) {
+        return Err(Status::Unauthorized);
+    }
+    match app.auth.two_factor.is_enabled(&id).await {
+        Ok(false) => {}
+        Ok(true) => return Err(Status::Unauthorized),
+        Err(e) => {
+            error!("Error reading TOTP factor of user {}: {}", id, e);
+            return Err(Status::InternalServerError);
+        }
+    }
+    if let Some(kid) = &header.kid {
+        if let Err(e) = app.users.keys.touch(kid).await {
+            warn!("Error recording use of key {}: {}", kid, e);
+        }
+    }
+    Ok(user)
+}
 
 pub struct OxidizeSession {
     pub token: Option<String>,
     pub user: User,
+    /// The key of the request when it was authenticated with an API key rather than a token.
+    pub api_key: Option<ApiKey>,
 }
 
 #[rocket::async_trait]
@@ -20,42 +86,157 @@ impl<'r> FromRequest<'r> for OxidizeSession {
             return Outcome::Error((Status::Unauthorized, ()));
         }
         let auth_value = auth_header.unwrap();
+        if auth_value.starts_with("ApiKey ") {
+            return match ApiKeySession::from_request(request).await {
+                Outcome::Success(api_key) => Outcome::Success(api_key.session),
+                Outcome::Error(e) => Outcome::Error(e),
+                Outcome::Forward(status) => Outcome::Forward(status),
+            };
+        }
         if !auth_value.starts_with("Bearer ") {
             return Outcome::Error((Status::BadRequest, ()));
         }
         let token = &auth_value[7..];
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.insecure_disable_signature_validation();
-        let token_data = decode::<Claims>(token, &DecodingKey::from_secret("dummy_secret".as_ref()), &validation).expect("Invalid token");
-        let result = ObjectId::parse_str(&token_data.claims.user_id);
-        let id = if result.is_ok() {
-            result.unwrap()
-        } else {
-            return Outcome::Error((Status::BadRequest, ()));
-        };
         let app = request.rocket().state::<App>().expect("Error retrieving app");
-        let user = if let Some(user) = app.users.read(id).await {
-            user
-        } else {
-            return Outcome::Error((Status::NotFound, ()));
-        };
-        let user_public_key = user.public_key.clone();
-        let decoding_key = DecodingKey::from_rsa_pem(user_public_key.as_bytes()).expect("Invalid public key");
+        match authenticate(app, token).await {
+            Ok(user) => Outcome::Success(OxidizeSession {user, token:Some(token.to_owned()), api_key: None }),
+            Err(status) => Outcome::Error((status, ())),
+        }
+    }
+}
 
-        let validation_result = decode::<Claims>(token, &decoding_key, &Validation::new(Algorithm::RS512)); 
-        if validation_result.is_err(){
+/// The session of a user authenticated by one of its API keys, sent as `Authorization: ApiKey <id>.<secret>`. The key
+/// needs the `read` scope for `GET` and `HEAD` requests and the `write` scope for the others. Wrong secrets count as
+/// failed attempts against the user and the IP of the request, and keys are not checked while either is locked.
+pub struct ApiKeySession {
+    pub session: OxidizeSession,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKeySession {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let auth_header = request.headers().get_one("Authorization");
+        let Some((id, secret)) = auth_header.and_then(|auth_value| auth_value.strip_prefix("ApiKey ")?.split_once('.')) else {
             return Outcome::Error((Status::Unauthorized, ()));
+        };
+        let app = request.rocket().state::<App>().expect("Error retrieving app");
+        let ip = request.client_ip();
+        let key = match ObjectId::parse_str(id) {
+            Ok(id) => match app.users.api_keys.find(&id).await {
+                Ok(key) => key,
+                Err(e) => {
+                    error!("Error reading API key {}: {}", id, e);
+                    return Outcome::Error((Status::InternalServerError, ()));
+                }
+            },
+            Err(_) => None,
+        };
+        let user_id = key.as_ref().map(|key| key.user_id);
+        match app.lockouts.locked_until(user_id.as_ref(), ip).await {
+            Ok(Some(until)) => return locked_out(request, until),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Error reading lockout of API key {}: {}", id, e);
+                return Outcome::Error((Status::InternalServerError, ()));
+            }
         }
-        let token_data = validation_result.unwrap();
-        let current_time = Utc::now().timestamp() as usize;
-        if token_data.claims.exp < current_time {
+        let Some(key) = key.filter(|key| key.secret == secret_digest(secret)) else {
+            return failed_attempt(request, app, user_id.as_ref(), ip).await;
+        };
+        let Some(user) = app.users.read(key.user_id).await else {
             return Outcome::Error((Status::Unauthorized, ()));
+        };
+        let scope = match request.method() {
+            Method::Get | Method::Head => ApiScope::Read,
+            _ => ApiScope::Write,
+        };
+        if !key.scopes.contains(&scope) {
+            return Outcome::Error((Status::Forbidden, ()));
+        }
+        if let Some(id) = &key._id {
+            if let Err(e) = app.users.api_keys.touch(id).await {
+                warn!("Error recording use of API key {}: {}", id, e);
+            }
+        }
+        Outcome::Success(ApiKeySession { session: OxidizeSession { token: None, user, api_key: Some(key) } })
+    }
+}
+
+/// A session authenticated by a token, for the routes managing the credentials of the user. API keys are refused with
+/// `403 Forbidden`, as they could otherwise register a signing key, another key or a new email address, and get more
+/// access than their scopes give.
+pub struct TokenSession {
+    pub session: OxidizeSession,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TokenSession {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if request.headers().get_one("Authorization").is_some_and(|auth_value| auth_value.starts_with("ApiKey ")) {
+            return Outcome::Error((Status::Forbidden, ()));
+        }
+        match OxidizeSession::from_request(request).await {
+            Outcome::Success(session) => Outcome::Success(TokenSession { session }),
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(status) => Outcome::Forward(status),
+        }
+    }
+}
+
+/// A session of a user listed in `admin_emails` who verified that address, so signing up with it is not enough. API
+/// keys also need the `admin` scope.
+pub struct AdminSession {
+    pub session: OxidizeSession,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminSession {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let session = match OxidizeSession::from_request(request).await {
+            Outcome::Success(session) => session,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let app = request.rocket().state::<App>().expect("Error retrieving app");
+        if !app.config.current().is_admin(&session.user.email)
+            || session.api_key.as_ref().is_some_and(|key| !key.scopes.contains(&ApiScope::Admin)) {
+            return Outcome::Error((Status::Forbidden, ()));
+        }
+        match app.mail.is_verified(&session.user).await {
+            Ok(true) => Outcome::Success(AdminSession { session }),
+            Ok(false) => Outcome::Error((Status::Forbidden, ())),
+            Err(e) => {
+                error!("Error reading email verification of admin {}: {}", session.user.email, e);
+                Outcome::Error((Status::InternalServerError, ()))
+            }
         }
-        return Outcome::Success(OxidizeSession {user:user.to_owned(), token:Some(token.to_owned()) });
     }
 }
 
+/// Refuses the request with `429 Too Many Requests` until the lockout ends.
+fn locked_out<T>(request: &Request<'_>, until: chrono::DateTime<Utc>) -> request::Outcome<T, ()> {
+    request.local_cache(|| RetryAfter(Some(until)));
+    Outcome::Error((Status::TooManyRequests, ()))
+}
+
+/// Counts a failed attempt against `user_id` and `ip`, refusing the request with `401 Unauthorized`, or with
+/// `429 Too Many Requests` if the attempt locked either.
+async fn failed_attempt<T>(request: &Request<'_>, app: &App, user_id: Option<&ObjectId>, ip: Option<IpAddr>) -> request::Outcome<T, ()> {
+    match app.lockouts.record_failure(user_id, ip).await {
+        Ok(Some(until)) => locked_out(request, until),
+        Ok(None) => Outcome::Error((Status::Unauthorized, ())),
+        Err(e) => {
+            error!("Error recording failed attempt: {}", e);
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
 
+/// A session of the user named by the first parameter of the route. Tokens failing to authenticate count as failed
+/// attempts against that user and the IP of the request, and are not checked while either is locked.
 pub struct UpdateAuthGuard{
     pub user_before_update : User,
 }
@@ -66,10 +247,7 @@ impl<'r> FromRequest<'r> for UpdateAuthGuard {
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
         let user_id = request.param::<String>(1).and_then(|param| param.ok()).expect("No User ID in request");
-        let result = ObjectId::parse_str(&user_id);
-        let id = if result.is_ok() {
-            result.unwrap()
-        } else {
+        let Ok(id) = ObjectId::parse_str(&user_id) else {
             return Outcome::Error((Status::BadRequest, ()));
         };
         let app = request.rocket().state::<App>().expect("Error retrieving app");
@@ -80,32 +258,22 @@ impl<'r> FromRequest<'r> for UpdateAuthGuard {
         };
 
         let auth_header = request.headers().get_one("Authorization");
-        if let Some(auth_value) = auth_header {
-            if auth_value.starts_with("Bearer ") {
-                let token = &auth_value[7..];
-
-                // Retrieve the user's public key for token verification
-                let user_public_key = user.public_key.clone();
-                let decoding_key = DecodingKey::from_rsa_pem(user_public_key.as_bytes()).expect("Invalid public key");
-
-                match decode::<Claims>(token, &decoding_key, &Validation::new(Algorithm::RS512)) {
-                    Ok(token_data) => {
-                        let current_time = Utc::now().timestamp() as usize;
-                        if token_data.claims.exp < current_time {
-                            return Outcome::Error((Status::Unauthorized, ()));
-                        }
-
-                        if user_id != token_data.claims.user_id {
-                            return Outcome::Error((Status::BadRequest, ()));
-                        }
-                        return Outcome::Success(UpdateAuthGuard {user_before_update:user.to_owned()});
-                    }
-                    Err(_) => {
-                        return Outcome::Error((Status::Unauthorized, ()));
-                    }
+        if let Some(token) = auth_header.and_then(|auth_value| auth_value.strip_prefix("Bearer ")) {
+            let ip = request.client_ip();
+            match app.lockouts.locked_until(Some(&id), ip).await {
+                Ok(Some(until)) => return locked_out(request, until),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error reading lockout of user {}: {}", id, e);
+                    return Outcome::Error((Status::InternalServerError, ()));
                 }
             }
+            return match authenticate(app, token).await {
+                Ok(token_user) if token_user._id != user._id => Outcome::Error((Status::BadRequest, ())),
+                Ok(_) => Outcome::Success(UpdateAuthGuard {user_before_update:user.to_owned()}),
+                Err(_) => failed_attempt(request, app, Some(&id), ip).await,
+            };
         }
         Outcome::Error((Status::Unauthorized, ()))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,58 @@
+use chrono::Utc;
+use rocket_db_pools::mongodb::bson::{self, doc, oid::ObjectId};
+
+use crate::modules::storage::{Collection, FindOptions, Storage, StorageError};
+use super::dto::UserKey;
+
+pub const USER_KEYS_COLLECTION: &str = "user_keys";
+
+/// The public keys users register besides the one they signed up with.
+pub struct UserKeys {
+    pub keys: Collection<UserKey>,
+}
+
+impl UserKeys {
+    pub fn new(storage: &Storage) -> Self {
+        storage.add_collection(USER_KEYS_COLLECTION);
+        Self { keys: storage.collection(USER_KEYS_COLLECTION) }
+    }
+
+    /// Registers `key`. Fails with a duplicate key error if it is registered already, by this user or another one.
+    pub async fn add(&self, key: &UserKey) -> Result<Option<ObjectId>, StorageError> {
+        // The unique index is only created by the migrations, so duplicates are looked for first as well
+        if self.keys.find_one(doc! {"kid": &key.kid}, None).await?.is_some() {
+            return Err(StorageError::DuplicateKey(format!("Key {} is already registered", key.kid)));
+        }
+        Ok(self.keys.insert_one(key, None).await?.inserted_id.as_object_id())
+    }
+
+    /// Every key of `user_id`, expired ones included, oldest first.
+    pub async fn list(&self, user_id: &ObjectId) -> Result<Vec<UserKey>, StorageError> {
+        let options = FindOptions { sort: Some(doc! {"created": 1}), ..Default::default() };
+        self.keys.find(doc! {"user_id": user_id}, options, None).await
+    }
+
+    /// The key `kid` of `user_id`, unless it expired.
+    pub async fn find(&self, user_id: &ObjectId, kid: &str) -> Result<Option<UserKey>, StorageError> {
+        let key = self.keys.find_one(doc! {"user_id": user_id, "kid": kid}, None).await?;
+        Ok(key.filter(|key| !key.is_expired()))
+    }
+
+    /// Records that `kid` just verified a token.
+    pub async fn touch(&self, kid: &str) -> Result<(), StorageError> {
+        let now = bson::to_bson(&Utc::now()).map_err(|e| StorageError::Serialization(e.to_string()))?;
+        self.keys.update_one(doc! {"kid": kid}, doc! {"$set": {"last_used": now}}, None).await?;
+        Ok(())
+    }
+
+    /// Removes the key `kid` of `user_id`. Returns whether there was one.
+    pub async fn revoke(&self, user_id: &ObjectId, kid: &str) -> Result<bool, StorageError> {
+        Ok(self.keys.delete_one(doc! {"user_id": user_id, "kid": kid}, None).await?.deleted_count > 0)
+    }
+
+    /// Removes every key of `user_id`.
+    pub async fn remove_all(&self, user_id: &ObjectId) -> Result<(), StorageError> {
+        self.keys.delete_many(doc! {"user_id": user_id}, None).await?;
+        Ok(())
+    }
+}
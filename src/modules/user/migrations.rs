@@ -0,0 +1,66 @@
+use async_trait::async_trait;
+use rocket_db_pools::mongodb::bson::{doc, Document};
+
+use crate::modules::storage::migration::Migration;
+use crate::modules::storage::{Index, Storage, StorageError};
+use super::api_keys::API_KEYS_COLLECTION;
+use super::keys::USER_KEYS_COLLECTION;
+
+/// Unique index on `users.email`.
+pub struct CreateUsersEmailIndex;
+
+#[async_trait]
+impl Migration for CreateUsersEmailIndex {
+    fn version(&self) -> i64 { 1 }
+
+    fn name(&self) -> &'static str { "create_users_email_index" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let index = Index::new("email_1", doc! { "email": 1 }).unique();
+        storage.collection::<Document>("users").create_index(index).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>("users").drop_index("email_1").await
+    }
+}
+
+/// A key is registered once, and the keys of a user are looked up together.
+pub struct CreateUserKeysIndexes;
+
+#[async_trait]
+impl Migration for CreateUserKeysIndexes {
+    fn version(&self) -> i64 { 7 }
+
+    fn name(&self) -> &'static str { "create_user_keys_indexes" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        let keys = storage.collection::<Document>(USER_KEYS_COLLECTION);
+        keys.create_index(Index::new("kid_1", doc! { "kid": 1 }).unique()).await?;
+        keys.create_index(Index::new("user_id_1", doc! { "user_id": 1 })).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        let keys = storage.collection::<Document>(USER_KEYS_COLLECTION);
+        keys.drop_index("kid_1").await?;
+        keys.drop_index("user_id_1").await
+    }
+}
+
+/// The API keys of a user are looked up together.
+pub struct CreateApiKeysIndexes;
+
+#[async_trait]
+impl Migration for CreateApiKeysIndexes {
+    fn version(&self) -> i64 { 11 }
+
+    fn name(&self) -> &'static str { "create_api_keys_indexes" }
+
+    async fn up(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>(API_KEYS_COLLECTION).create_index(Index::new("user_id_1", doc! { "user_id": 1 })).await
+    }
+
+    async fn down(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.collection::<Document>(API_KEYS_COLLECTION).drop_index("user_id_1").await
+    }
+}
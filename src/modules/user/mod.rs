@@ -2,5 +2,8 @@ pub mod dto;
 pub mod service;
 pub mod controller;
 pub mod guard;
+pub mod keys;
+pub mod api_keys;
+pub mod migrations;
 #[cfg(test)]
 mod test;
\ No newline at end of file
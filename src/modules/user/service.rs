@@ -1,19 +1,22 @@
+use std::io;
 use std::sync::Arc;
+use chrono::Utc;
 use async_trait::async_trait;
 use rocket_db_pools::mongodb::bson::{ self, doc};
 use rocket_db_pools::mongodb::bson::oid::ObjectId;
-use rocket_db_pools::mongodb::options::IndexOptions;
-use rocket_db_pools::mongodb::results::{DeleteResult, InsertOneResult, UpdateResult};
-use rocket_db_pools::mongodb::{Collection, IndexModel};
-use rocket_db_pools::mongodb::error::Error;
 use log::{error, warn};
-use crate::modules::mongo::service::MongoOracle;
+use crate::modules::storage::{Collection, DeleteResult, InsertOneResult, Session, Storage, StorageError, UpdateResult};
 use crate::modules::CRUDMongo;
-use super::dto::User;
+use crate::framework::auth::parse_public_key;
+use super::dto::{NewUserKey, User, UserKey};
+use super::keys::UserKeys;
+use super::api_keys::ApiKeys;
 
 pub struct UserService {
-    pub mongo: Arc<MongoOracle>,
+    pub storage: Arc<Storage>,
     pub users: Collection<User>,
+    pub keys: UserKeys,
+    pub api_keys: ApiKeys,
 }
 
 #[async_trait]
@@ -22,20 +25,8 @@ impl CRUDMongo<User> for UserService{
     /// Creates a user and returns Some(InsertOneResult) or none if the user already exists in the system with that email
     /// 
     async fn create(&self, user: User) -> Option<InsertOneResult> {
-        // Check if a user with the given email already exists
-        let existing_user = self.find_by_email(&user.email).await;
-        if existing_user.is_some() {
-            warn!("User with email {} already exists", user.email);
-            return None;
-        }
-
-        let new_user_result: Result<InsertOneResult, Error> = self
-            .users
-            .insert_one(user.to_owned(), None)
-            .await;
-        
-        match new_user_result {
-            Ok(resp) => Some(resp),
+        match self.insert(&user, None).await {
+            Ok(resp) => resp,
             Err(e) => {
                 error!("Error creating user: {}", e);
                 None
@@ -93,30 +84,75 @@ impl CRUDMongo<User> for UserService{
             .delete_one(filter, None)
             .await;
         match user {
-            Ok(res) => Some(res),
+            Ok(res) => {
+                // The keys of the user go with it
+                if let Err(e) = self.keys.remove_all(&id).await {
+                    error!("Error deleting keys of user with id {}: {}", id, e);
+                }
+                if let Err(e) = self.api_keys.remove_all(&id).await {
+                    error!("Error deleting API keys of user with id {}: {}", id, e);
+                }
+                Some(res)
+            }
             Err(e) => {
                 error!("Error deleting user with id {}: {}", id, e);
                 None
             }
         }
     }
+}
+
+impl UserService {
+    /// Inserts a user, optionally as part of a session. Returns Ok(None) if the email is already registered.
+    pub async fn insert(&self, user: &User, mut session: Option<&mut Session>) -> Result<Option<InsertOneResult>, StorageError> {
+        // Check if a user with the given email already exists
+        let filter = doc! {"email": &user.email};
+        if self.users.find_one(filter, session.as_deref_mut()).await?.is_some() {
+            warn!("User with email {} already exists", user.email);
+            return Ok(None);
+        }
 
-    async fn initialize_db(&self) -> Result<(), Error> {
-        // Create unique index on email field
-        let index = IndexModel::builder().keys(doc! { "email": 1 }).
-            options(IndexOptions::builder().unique(true).build()).build();
+        match self.users.insert_one(user, session).await {
+            Ok(resp) => Ok(Some(resp)),
+            Err(e) if e.is_duplicate_key() => {
+                warn!("User with email {} already exists", user.email);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        self.users.create_index(index,None)
-            .await.expect("Error creating index for email in users.");
-        Ok(())
+    /// Registers `new_key` for `user_id`, normalized by `parse_public_key`. Fails with InvalidInput if it is refused
+    /// by `parse_public_key` or it expired already, and with AlreadyExists if the key is registered already.
+    pub async fn register_key(&self, user_id: &ObjectId, new_key: NewUserKey) -> Result<UserKey, io::Error> {
+        let public_key = parse_public_key(&new_key.public_key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut key = UserKey {
+            _id: None,
+            user_id: *user_id,
+            kid: public_key.fingerprint,
+            algorithm: public_key.key_type,
+            label: new_key.label,
+            public_key: public_key.pem,
+            created: Utc::now(),
+            last_used: None,
+            expires: new_key.expires,
+        };
+        if key.is_expired() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The key expired already"));
+        }
+        key._id = match self.keys.add(&key).await {
+            Ok(id) => id,
+            Err(e) if e.is_duplicate_key() => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "The key is already registered")),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        Ok(key)
     }
-}
 
-impl UserService {
-    pub fn new(mongo: Arc<MongoOracle>) -> Self {
-        let db = mongo.db.as_ref().expect("Database not initialized");
-        let users: Collection<User> = db.collection("users");
-        mongo.add_collection("users");
-        Self { mongo, users }
+    pub fn new(storage: Arc<Storage>) -> Self {
+        let users: Collection<User> = storage.collection("users");
+        storage.add_collection("users");
+        let keys = UserKeys::new(&storage);
+        let api_keys = ApiKeys::new(&storage);
+        Self { storage, users, keys, api_keys }
     }
 }
\ No newline at end of file
@@ -16,12 +16,14 @@ fn test_new_user() {
         password: password_slice.to_string(),
         description,
         public_key: String::from("randompublickey"),
+        public_key_fingerprint: None,
         _id: None,
+        locale: None,
     };
 
     assert!(user.email == email_slice);
     assert!(user.password == password_slice);
-    assert!(user._id == None);
+    assert!(user._id.is_none());
     assert!(user.public_key == "randompublickey");
 }
 
@@ -32,6 +34,6 @@ fn test_fake_user() {
     assert!(user.email.contains("@"));
     assert!(user.password.len() >= 8);
     assert!(user.description.len() >= 10);
-    assert!(user._id == None);
-    assert!(user.public_key.len() > 0);
+    assert!(user._id.is_none());
+    assert!(!user.public_key.is_empty());
 }
\ No newline at end of file
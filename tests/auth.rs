@@ -0,0 +1,404 @@
+mod test {
+    use jsonwebtoken::jwk::JwkSet;
+    use jsonwebtoken::{crypto, decode_header, Algorithm, DecodingKey, EncodingKey};
+    use oxidize::framework::auth::{decode_server_token, generate_jwt_token, generate_p256_key_pair_pem, generate_rsa_key_pair_pem, generate_server_token, KeyType};
+    use oxidize::framework::testing::{Mock, TestingRuntime};
+    use oxidize::modules::auth::controller::DEVICE_COOKIE;
+    use oxidize::modules::auth::dto::{IssuedChallenge, IssuedToken, LoginResponse, MagicLinkSent, RecoveryCodes, TotpEnrollment};
+    use oxidize::modules::auth::totp::{totp_code, totp_step};
+    use oxidize::modules::mail::dto::VerificationStatus;
+    use oxidize::modules::user::dto::User;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::serde::json::json;
+    use rocket::uri;
+    use rocket_db_pools::mongodb::bson::{doc, Document};
+
+    #[tokio::test]
+    async fn test_challenge_login() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let authenticated = runtime.authenticated_user().await;
+
+        let response = client.post(uri!(oxidize::modules::auth::controller::create_challenge))
+            .header(ContentType::JSON)
+            .body(json!({"email": authenticated.user.email}).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Created);
+        let challenge: IssuedChallenge = response.into_json().await.expect("Invalid challenge");
+
+        let key = EncodingKey::from_rsa_pem(authenticated.private_key.as_bytes()).expect("Invalid private key");
+        let signature = crypto::sign(challenge.nonce.as_bytes(), &key, Algorithm::RS512).expect("Error signing nonce");
+        let verify = |challenge_id: &str, signature: &str| client.post(uri!(oxidize::modules::auth::controller::verify_challenge))
+            .header(ContentType::JSON)
+            .body(json!({"challenge_id": challenge_id, "signature": signature}).to_string());
+
+        // A bad signature spends the challenge
+        let response = verify(&challenge.challenge_id, "bm90IGEgc2lnbmF0dXJl").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(verify(&challenge.challenge_id, &signature).dispatch().await.status(), Status::NotFound);
+        assert_eq!(verify("not an id", &signature).dispatch().await.status(), Status::BadRequest);
+
+        let response = client.post(uri!(oxidize::modules::auth::controller::create_challenge))
+            .header(ContentType::JSON)
+            .body(json!({"email": authenticated.user.email}).to_string())
+            .dispatch().await;
+        let challenge: IssuedChallenge = response.into_json().await.expect("Invalid challenge");
+        let signature = crypto::sign(challenge.nonce.as_bytes(), &key, Algorithm::RS512).expect("Error signing nonce");
+        let response = verify(&challenge.challenge_id, &signature).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let issued: IssuedToken = response.into_json().await.expect("Invalid token");
+        assert_eq!(issued.token_type, "Bearer");
+        assert_eq!(issued.expires_in, 900);
+
+        // The server token authenticates like a token signed by the user
+        let response = client.get("/mail/verifications/status")
+            .header(Header::new("Accept", "application/json"))
+            .header(Header::new("Authorization", format!("Bearer {}", issued.token)))
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let status: VerificationStatus = response.into_json().await.expect("Invalid status");
+        assert!(!status.verified);
+
+        // Downstream services verify it with the published keys
+        let response = client.get(uri!(oxidize::modules::auth::controller::jwks)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let jwks: JwkSet = response.into_json().await.expect("Invalid JWKS");
+        let kid = decode_header(&issued.token).expect("Invalid token header").kid.expect("No kid in server token");
+        let jwk = jwks.find(&kid).expect("Signing key not published");
+        let decoding_key = DecodingKey::from_jwk(jwk).expect("Invalid JWK");
+        let claims = decode_server_token(&issued.token, &decoding_key).expect("Server token does not verify");
+        assert_eq!(claims.user_id, authenticated.user._id.unwrap().to_hex());
+
+        // Tokens signed with another key are refused, whatever kid they claim
+        let (_, forging_key) = generate_p256_key_pair_pem();
+        let forging_key = KeyType::P256.encoding_key(&forging_key).expect("Invalid private key");
+        for kid in [kid.as_str(), "unknown"] {
+            let forged = generate_server_token(&authenticated.user._id.unwrap().to_hex(), kid, &forging_key, chrono::Duration::hours(1))
+                .expect("Error generating token");
+            let response = client.get("/mail/verifications/status")
+                .header(Header::new("Accept", "application/json"))
+                .header(Header::new("Authorization", format!("Bearer {}", forged)))
+                .dispatch().await;
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+
+        // A few challenges per IP and window
+        let app = runtime.app();
+        let mut config = (*app.config.current()).clone();
+        config.env.auth_challenge_ip_limit = Some(2);
+        app.config.replace(config);
+        let challenge = |ip: &str| client.post(uri!(oxidize::modules::auth::controller::create_challenge))
+            .header(ContentType::JSON)
+            .header(Header::new("X-Real-IP", ip.to_string()))
+            .body(json!({"email": authenticated.user.email}).to_string());
+        assert_eq!(challenge("192.0.2.1").dispatch().await.status(), Status::Created);
+        assert_eq!(challenge("192.0.2.1").dispatch().await.status(), Status::Created);
+        let response = challenge("192.0.2.1").dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert!(response.headers().get_one("Retry-After").is_some());
+        assert_eq!(challenge("192.0.2.2").dispatch().await.status(), Status::Created);
+    }
+
+    #[tokio::test]
+    async fn test_compact_keys() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let status = |token: String| client.get("/mail/verifications/status")
+            .header(Header::new("Accept", "application/json"))
+            .header(Header::new("Authorization", format!("Bearer {}", token)));
+
+        for key_type in [KeyType::Ed25519, KeyType::P256] {
+            let (public_key, private_key) = key_type.generate_key_pair_pem();
+            let mut user = User::mock();
+            user.public_key = public_key;
+            let user_id = runtime.app().users.insert(&user, None).await
+                .expect("Error while inserting user").expect("Mock user already exists")
+                .inserted_id.as_object_id().expect("No user id").to_hex();
+
+            let token = generate_jwt_token(&user_id, &private_key, chrono::Duration::hours(1)).expect("Error generating token");
+            assert_eq!(status(token).dispatch().await.status(), Status::Ok);
+
+            // Tokens in another algorithm than the registered key are refused
+            let (_, rsa_private_key) = generate_rsa_key_pair_pem();
+            let token = generate_jwt_token(&user_id, &rsa_private_key, chrono::Duration::hours(1)).expect("Error generating token");
+            assert_eq!(status(token).dispatch().await.status(), Status::Unauthorized);
+
+            // Challenges are signed in the algorithm of the key too
+            let response = client.post(uri!(oxidize::modules::auth::controller::create_challenge))
+                .header(ContentType::JSON)
+                .body(json!({"email": user.email}).to_string())
+                .dispatch().await;
+            let challenge: IssuedChallenge = response.into_json().await.expect("Invalid challenge");
+            let key = key_type.encoding_key(&private_key).expect("Invalid private key");
+            let signature = crypto::sign(challenge.nonce.as_bytes(), &key, key_type.algorithm()).expect("Error signing nonce");
+            let response = client.post(uri!(oxidize::modules::auth::controller::verify_challenge))
+                .header(ContentType::JSON)
+                .body(json!({"challenge_id": challenge.challenge_id, "signature": signature}).to_string())
+                .dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_totp_login() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let authenticated = runtime.authenticated_user().await;
+        let admin = runtime.authenticated_user().await;
+        let user_id = authenticated.user._id.unwrap();
+        let key = EncodingKey::from_rsa_pem(authenticated.private_key.as_bytes()).expect("Invalid private key");
+        let login = || async {
+            let response = client.post(uri!(oxidize::modules::auth::controller::create_challenge))
+                .header(ContentType::JSON)
+                .body(json!({"email": authenticated.user.email}).to_string())
+                .dispatch().await;
+            let challenge: IssuedChallenge = response.into_json().await.expect("Invalid challenge");
+            let signature = crypto::sign(challenge.nonce.as_bytes(), &key, Algorithm::RS512).expect("Error signing nonce");
+            client.post(uri!(oxidize::modules::auth::controller::verify_challenge))
+                .header(ContentType::JSON)
+                .body(json!({"challenge_id": challenge.challenge_id, "signature": signature}).to_string())
+                .dispatch().await
+        };
+        let step_up = |step_up: &IssuedChallenge, code: &str| client.post(uri!(oxidize::modules::auth::controller::verify_step_up))
+            .header(ContentType::JSON)
+            .body(json!({"challenge_id": step_up.challenge_id, "nonce": step_up.nonce, "code": code}).to_string());
+
+        // Enrollment hands out a secret, only enforced once a first code confirms it
+        let response = client.post(uri!(oxidize::modules::auth::controller::enroll_totp)).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::Created);
+        let enrollment: TotpEnrollment = response.into_json().await.expect("Invalid enrollment");
+        assert!(enrollment.provisioning_uri.starts_with("otpauth://totp/Oxidize:"));
+        let secret = data_encoding::BASE32_NOPAD.decode(enrollment.secret.as_bytes()).unwrap();
+        let step = totp_step(chrono::Utc::now());
+        assert_eq!(login().await.status(), Status::Ok);
+        // Secrets are encrypted in the users collection and never part of a user
+        let stored = runtime.app().storage.collection::<Document>("users").find_one(doc! {"_id": user_id}, None).await.unwrap().unwrap();
+        assert!(!stored.get_document("totp").unwrap().get_str("secret").unwrap().contains(&enrollment.secret));
+        let response = client.get(uri!(oxidize::modules::user::controller::read_user(user_id.to_hex()))).dispatch().await;
+        assert!(!response.into_string().await.unwrap().contains("totp"));
+
+        let confirm = |code: String| client.post(uri!(oxidize::modules::auth::controller::confirm_totp))
+            .header(ContentType::JSON)
+            .header(authenticated.header())
+            .body(json!({"code": code}).to_string());
+        assert_eq!(confirm(String::from("123")).dispatch().await.status(), Status::BadRequest);
+        let response = confirm(totp_code(&secret, step)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let recovery: RecoveryCodes = response.into_json().await.expect("Invalid recovery codes");
+        // Tokens signed by the user would skip the second factor, only server tokens are accepted from now on
+        let response = client.post(uri!(oxidize::modules::auth::controller::enroll_totp)).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // Signing the challenge now only gets a step-up challenge, spent by a wrong code
+        let response = login().await;
+        assert_eq!(response.status(), Status::Accepted);
+        let LoginResponse::StepUp { step_up: challenge } = response.into_json().await.expect("Invalid login response") else {
+            panic!("No step-up challenge");
+        };
+        assert_eq!(step_up(&challenge, "000000").dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(step_up(&challenge, &totp_code(&secret, step + 1)).dispatch().await.status(), Status::NotFound);
+        let wrong_nonce = IssuedChallenge { nonce: String::from("nope"), ..challenge.clone() };
+        assert_eq!(step_up(&wrong_nonce, &totp_code(&secret, step + 1)).dispatch().await.status(), Status::NotFound);
+
+        let response = login().await;
+        let LoginResponse::StepUp { step_up: challenge } = response.into_json().await.expect("Invalid login response") else {
+            panic!("No step-up challenge");
+        };
+        let response = step_up(&challenge, &totp_code(&secret, step + 1)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let issued: IssuedToken = response.into_json().await.expect("Invalid token");
+        assert!(decode_header(&issued.token).unwrap().kid.is_some());
+        let server_header = Header::new("Authorization", format!("Bearer {}", issued.token));
+        let response = client.post(uri!(oxidize::modules::auth::controller::confirm_totp))
+            .header(ContentType::JSON)
+            .header(server_header.clone())
+            .body(json!({"code": totp_code(&secret, step + 1)}).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.post(uri!(oxidize::modules::auth::controller::enroll_totp)).header(server_header).dispatch().await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        // Recovery codes work once, a code replayed does not
+        let response = login().await;
+        let LoginResponse::StepUp { step_up: challenge } = response.into_json().await.expect("Invalid login response") else {
+            panic!("No step-up challenge");
+        };
+        assert_eq!(step_up(&challenge, &totp_code(&secret, step + 1)).dispatch().await.status(), Status::Unauthorized);
+        for expected in [Status::Ok, Status::Unauthorized] {
+            let response = login().await;
+            let LoginResponse::StepUp { step_up: challenge } = response.into_json().await.expect("Invalid login response") else {
+                panic!("No step-up challenge");
+            };
+            assert_eq!(step_up(&challenge, &recovery.recovery_codes[0]).dispatch().await.status(), expected);
+        }
+
+        // Admins reset the factor of users who lost it
+        let reset = || client.delete(uri!(oxidize::modules::auth::controller::reset_totp(user_id.to_hex()))).header(admin.header());
+        assert_eq!(reset().dispatch().await.status(), Status::Forbidden);
+        let app = runtime.app();
+        let mut config = (*app.config.current()).clone();
+        config.env.admin_emails = Some(admin.user.email.clone());
+        app.config.replace(config);
+        runtime.verify_email(&admin.user).await;
+        assert_eq!(reset().dispatch().await.status(), Status::Ok);
+        assert_eq!(reset().dispatch().await.status(), Status::NotFound);
+        assert_eq!(login().await.status(), Status::Ok);
+
+        // Users disable it themselves with a code
+        let response = client.post(uri!(oxidize::modules::auth::controller::enroll_totp)).header(authenticated.header()).dispatch().await;
+        let enrollment: TotpEnrollment = response.into_json().await.expect("Invalid enrollment");
+        let secret = data_encoding::BASE32_NOPAD.decode(enrollment.secret.as_bytes()).unwrap();
+        let step = totp_step(chrono::Utc::now());
+        assert_eq!(confirm(totp_code(&secret, step - 1)).dispatch().await.status(), Status::Ok);
+        let response = login().await;
+        let LoginResponse::StepUp { step_up: challenge } = response.into_json().await.expect("Invalid login response") else {
+            panic!("No step-up challenge");
+        };
+        let issued: IssuedToken = step_up(&challenge, &totp_code(&secret, step)).dispatch().await.into_json().await.expect("Invalid token");
+        let disable = |code: String, header: Header<'static>| client.delete(uri!(oxidize::modules::auth::controller::disable_totp))
+            .header(ContentType::JSON)
+            .header(header)
+            .body(json!({"code": code}).to_string());
+        let server_header = Header::new("Authorization", format!("Bearer {}", issued.token));
+        assert_eq!(disable(totp_code(&secret, step + 1), authenticated.header()).dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(disable(totp_code(&secret, step), server_header.clone()).dispatch().await.status(), Status::BadRequest);
+        assert_eq!(disable(totp_code(&secret, step + 1), server_header).dispatch().await.status(), Status::Ok);
+        assert_eq!(login().await.status(), Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_magic_link() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let authenticated = runtime.authenticated_user().await;
+        let email = authenticated.user.email.clone();
+        let request = |email: &str| client.post(uri!(oxidize::modules::auth::controller::request_magic_link))
+            .header(ContentType::JSON)
+            .body(json!({"email": email}).to_string());
+
+        // The link is emailed and the browser gets a device cookie
+        let response = request(&email).dispatch().await;
+        assert_eq!(response.status(), Status::Accepted);
+        let sent: MagicLinkSent = response.into_json().await.expect("Invalid answer");
+        assert!(sent.expires > chrono::Utc::now());
+        let device = client.cookies().get(DEVICE_COOKIE).expect("No device cookie").value().to_string();
+        let mails = runtime.sent_mails(&email).await;
+        assert_eq!(mails.len(), 1);
+        let links = mails[0].links();
+        assert_eq!(links.len(), 1);
+        assert!(links[0].starts_with("/auth/magic-link/"));
+        // Only digests of the secrets are stored
+        let stored = runtime.app().auth.magic_links.find_one(doc! {}, None).await.unwrap().unwrap();
+        assert!(!links[0].contains(&stored.secret));
+
+        // It only works from the device that asked for it, and once
+        let response = client.get(links[0].clone()).cookie((DEVICE_COOKIE, "another device")).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.get(links[0].clone()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let LoginResponse::Token(issued) = response.into_json().await.expect("Invalid token") else {
+            panic!("Step-up asked for without TOTP");
+        };
+        let response = client.get("/mail/verifications/status")
+            .header(Header::new("Accept", "application/json"))
+            .header(Header::new("Authorization", format!("Bearer {}", issued.token)))
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(client.get(links[0].clone()).dispatch().await.status(), Status::NotFound);
+        let wrong_secret = format!("{}x", links[0]);
+        assert_eq!(client.get(wrong_secret).dispatch().await.status(), Status::NotFound);
+
+        // The device keeps its cookie, and unknown emails get the same answer without an email
+        let response = request("nobody@example.com").dispatch().await;
+        assert_eq!(response.status(), Status::Accepted);
+        assert_eq!(client.cookies().get(DEVICE_COOKIE).unwrap().value(), device);
+        assert!(runtime.sent_mails("nobody@example.com").await.is_empty());
+
+        // A few links per email and window
+        assert_eq!(request(&email).dispatch().await.status(), Status::Accepted);
+        assert_eq!(request(&email).dispatch().await.status(), Status::Accepted);
+        let response = request(&email).dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert!(response.headers().get_one("Retry-After").is_some());
+        assert_eq!(runtime.sent_mails(&email).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let authenticated = runtime.authenticated_user().await;
+        let admin = runtime.authenticated_user().await;
+        let user_id = authenticated.user._id.unwrap();
+        let app = runtime.app();
+        let mut config = (*app.config.current()).clone();
+        config.env.auth_lockout_account_threshold = Some(3);
+        config.env.auth_lockout_ip_threshold = Some(2);
+        app.config.replace(config);
+        let (_, other_key) = generate_rsa_key_pair_pem();
+        let login = |email: &str, private_key: &str, ip: &str| {
+            let email = email.to_string();
+            let key = EncodingKey::from_rsa_pem(private_key.as_bytes()).expect("Invalid private key");
+            let ip = ip.to_string();
+            async move {
+                let response = client.post(uri!(oxidize::modules::auth::controller::create_challenge))
+                    .header(ContentType::JSON)
+                    .body(json!({"email": email}).to_string())
+                    .dispatch().await;
+                let challenge: IssuedChallenge = response.into_json().await.expect("Invalid challenge");
+                let signature = crypto::sign(challenge.nonce.as_bytes(), &key, Algorithm::RS512).expect("Error signing nonce");
+                client.post(uri!(oxidize::modules::auth::controller::verify_challenge))
+                    .header(ContentType::JSON)
+                    .header(Header::new("X-Real-IP", ip))
+                    .body(json!({"challenge_id": challenge.challenge_id, "signature": signature}).to_string())
+                    .dispatch().await
+            }
+        };
+        let email = authenticated.user.email.clone();
+
+        // Failures from several IPs add up against the account, which is locked even for the right key
+        assert_eq!(login(&email, &other_key, "192.0.2.1").await.status(), Status::Unauthorized);
+        assert_eq!(login(&email, &other_key, "192.0.2.2").await.status(), Status::Unauthorized);
+        let response = login(&email, &other_key, "192.0.2.3").await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: i64 = response.headers().get_one("Retry-After").expect("No Retry-After").parse().unwrap();
+        assert!(retry_after > 50 && retry_after <= 60);
+        assert_eq!(login(&email, &authenticated.private_key, "192.0.2.4").await.status(), Status::TooManyRequests);
+        let mails = runtime.sent_mails(&email).await;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].subject, "Your account was locked");
+        assert!(mails[0].body.contains('3'));
+
+        // Admins unlock it
+        let unlock = || client.delete(uri!(oxidize::modules::lockout::controller::unlock_account(user_id.to_hex()))).header(admin.header());
+        assert_eq!(unlock().dispatch().await.status(), Status::Forbidden);
+        let mut config = (*app.config.current()).clone();
+        config.env.admin_emails = Some(admin.user.email.clone());
+        app.config.replace(config);
+        runtime.verify_email(&admin.user).await;
+        assert_eq!(unlock().dispatch().await.status(), Status::Ok);
+        assert_eq!(unlock().dispatch().await.status(), Status::NotFound);
+        assert_eq!(login(&email, &authenticated.private_key, "192.0.2.4").await.status(), Status::Ok);
+
+        // Tokens failing the update guard count too, and the lockout answers through the catcher
+        let delete = |token: &str| client.delete(uri!(oxidize::modules::user::controller::delete_user(user_id.to_hex())))
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", token)));
+        assert_eq!(delete("not a token").dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(delete("not a token").dispatch().await.status(), Status::Unauthorized);
+        let response = delete("not a token").dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert!(response.headers().get_one("Retry-After").is_some());
+        assert_eq!(delete(&authenticated.token).dispatch().await.status(), Status::TooManyRequests);
+        assert_eq!(unlock().dispatch().await.status(), Status::Ok);
+
+        // An IP failing against unknown accounts is locked for every account
+        assert_eq!(login("nobody@example.com", &other_key, "198.51.100.1").await.status(), Status::Unauthorized);
+        assert_eq!(login("nobody@example.com", &other_key, "198.51.100.1").await.status(), Status::TooManyRequests);
+        assert_eq!(login(&email, &authenticated.private_key, "198.51.100.1").await.status(), Status::TooManyRequests);
+        assert_eq!(login(&email, &authenticated.private_key, "198.51.100.2").await.status(), Status::Ok);
+        let response = client.delete(uri!(oxidize::modules::lockout::controller::unlock_ip("198.51.100.1"))).header(admin.header()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(login(&email, &authenticated.private_key, "198.51.100.1").await.status(), Status::Ok);
+    }
+}
@@ -1,29 +1,32 @@
 mod test { 
-    use oxidize::framework::app::App;
-    use oxidize::framework::auth::{generate_jwt_token, generate_rsa_key_pair_pem};
-    use oxidize::framework::config::OxidizeConfig;
-    use oxidize::framework:: testing::{Mock, TestingRuntime};
+    use oxidize::framework::config::ConfigHandle;
+    use oxidize::framework:: testing::{Mock, TestDatabase, TestingRuntime};
     use oxidize::framework::translator::OxidizeTranslator;
+    use oxidize::modules::mail::dto::{PendingEmailChange, VerificationStatus};
+    use oxidize::modules::mail::outbox::{OutboxMessage, OutboxStatus};
     use oxidize::modules::mail::service::MailOracle;
-    use oxidize::modules::mongo::service::MongoOracle;
-    use oxidize::modules::CRUDMongo;
+    use oxidize::modules::mail::transport::Email;
+    use oxidize::modules::storage::migration::Migrator;
+    use oxidize::modules::storage::Storage;
+    use oxidize::modules;
     use oxidize::modules::user::dto::User;
-    use rocket::http::Header;
+    use oxidize::modules::CRUDMongo;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::serde::json::json;
     use rocket::uri;
-    use rocket_db_pools::mongodb::bson::oid::ObjectId;
+    use rocket_db_pools::mongodb::bson::{oid::ObjectId, DateTime};
     use std::sync::Arc;
-    use tokio;
 
     #[tokio::test]
     async fn test_mail_verifications() {
-        let config = Arc::new(OxidizeConfig::new().expect("Could not load oxidize config"));
-        let mongo = Arc::new(MongoOracle::new(config.clone()).await);
+        let database = TestDatabase::new();
+        let config = database.config.clone();
+        let storage = Arc::new(Storage::connect(config.clone()).await.expect("Error connecting to storage"));
         let translator = Arc::new(OxidizeTranslator::new(config.clone()));
-        let mail = MailOracle::new(config, mongo.clone(), translator );
+        let mail = MailOracle::new(ConfigHandle::new(config), storage.clone(), translator );
         let mut user = User::mock();
         user._id = Some(ObjectId::new());
-        mongo.drop_database().await.expect("Error dropping database");
-        mail.initialize_db().await.expect("Error while initializing database");
+        Migrator::new(storage.clone(), modules::migrations()).up().await.expect("Error while migrating database");
 
         //Step 1: Create a verification and insert it into the system
         let verification = mail.start_verification(&user).await.expect("Could not start email verification");
@@ -45,22 +48,19 @@ mod test {
 
     #[tokio::test]
     async fn test_verification_endpoint() {
-        let client = &TestingRuntime::get().await.client;
-        let mut user = User::mock();
-        let (public_key, secret_key) = generate_rsa_key_pair_pem();
-        user.public_key = public_key;
-        let rocket = client.rocket();
-        let app = rocket.state::<App>().expect("Could not get app state");
-        user._id = app.users.create(user.clone()).await.expect("Could not create user").inserted_id.as_object_id();
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let app = runtime.app();
+        let authenticated = runtime.authenticated_user().await;
+        let user = authenticated.user.clone();
 
-        let response = client.get(uri!(oxidize::modules::mail::controller::start_verification)).dispatch().await;
+        let response = client.post(uri!(oxidize::modules::mail::controller::start_verification)).dispatch().await;
         assert_eq!(response.status(), rocket::http::Status::Unauthorized);
 
-        let token = generate_jwt_token(&user._id.expect("no user id").to_string(), &secret_key, chrono::Duration::hours(1)).expect("Error generating token");
-        let auth_header = String::from("Bearer ") + token.as_str();
+        let auth_header = String::from("Bearer ") + authenticated.token.as_str();
 
         //Step 1: Start verification
-        let response = client.get(uri!(oxidize::modules::mail::controller::start_verification))
+        let response = client.post(uri!(oxidize::modules::mail::controller::start_verification))
         .header(Header::new("Authorization", auth_header.clone()))
         .dispatch().await;
 
@@ -70,6 +70,17 @@ mod test {
         assert!(verification.email == user.email);
         assert!(verification.user_id == user._id.unwrap());
 
+        //check that the email with the verification link was sent
+        let mails = runtime.sent_mails(&user.email).await;
+        assert_eq!(mails.len(), 1);
+        let link = uri!(oxidize::modules::mail::controller::finish_verification(
+            id=verification._id.unwrap().to_string(),
+            secret=verification.secret.clone()));
+        assert_eq!(mails[0].links(), vec![link.to_string()]);
+        let html = mails[0].html.as_deref().expect("Verification email without HTML part");
+        assert!(html.contains(&format!("href=\"{}\"", link)));
+        assert_eq!(mails[0].inline_images[0].content_id, "logo");
+
         //Step 2a: Finish verification with incorrect secret throws conflict
         let response = client.get(uri!(oxidize::modules::mail::controller::finish_verification(
             id=verification._id.unwrap().to_string(), 
@@ -111,5 +122,255 @@ mod test {
 
 
     }
-}
 
+    #[tokio::test]
+    async fn test_verification_throttling() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let app = runtime.app();
+        let authenticated = runtime.authenticated_user().await;
+        let start = uri!(oxidize::modules::mail::controller::start_verification);
+        let status = uri!(oxidize::modules::mail::controller::verification_status);
+        let mut config = (*app.config.current()).clone();
+        config.env.verification_resend_cooldown_secs = Some(60);
+        config.env.verification_resend_ip_limit = Some(2);
+        app.config.replace(config.clone());
+
+        let response = client.get(status.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: VerificationStatus = response.into_json().await.expect("Invalid verification status");
+        assert_eq!(body, VerificationStatus { verified: false, next_resend: None });
+
+        //The user waits for the cooldown between two emails
+        let response = client.post(start.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.post(start.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: i64 = response.headers().get_one("Retry-After").expect("No Retry-After header").parse().unwrap();
+        assert!(retry_after > 50 && retry_after <= 60);
+        let body: VerificationStatus = response.into_json().await.expect("Invalid verification status");
+        let next_resend = body.next_resend.expect("No next resend time");
+        let response = client.get(status.clone()).header(authenticated.header()).dispatch().await;
+        let body: VerificationStatus = response.into_json().await.expect("Invalid verification status");
+        assert_eq!(body.next_resend, Some(next_resend));
+        assert_eq!(runtime.sent_mails(&authenticated.user.email).await.len(), 1);
+
+        //An IP only gets a few emails per window, whatever the user
+        config.env.verification_resend_cooldown_secs = Some(0);
+        app.config.replace(config);
+        let response = client.post(start.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.post(start.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let body: VerificationStatus = response.into_json().await.expect("Invalid verification status");
+        assert!(body.next_resend.expect("No next resend time") > next_resend);
+        let response = client.post(start.clone()).header(authenticated.header())
+            .remote("192.0.2.1:4000".parse().unwrap())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(runtime.sent_mails(&authenticated.user.email).await.len(), 3);
+
+        //Verified users get no more emails
+        let verification = app.mail.find_verification_by_user_id(&authenticated.user._id.unwrap()).await.expect("Could not find verification");
+        app.mail.finish_verification(&authenticated.user._id.unwrap(), &verification._id.unwrap(), &verification.secret).await
+            .expect("Error finishing verification");
+        let response = client.post(start).header(authenticated.header())
+            .remote("192.0.2.2:4000".parse().unwrap())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Conflict);
+        let response = client.get(status).header(authenticated.header()).dispatch().await;
+        let body: VerificationStatus = response.into_json().await.expect("Invalid verification status");
+        assert_eq!(body, VerificationStatus { verified: true, next_resend: None });
+    }
+
+    #[tokio::test]
+    async fn test_email_change() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let app = runtime.app();
+        let authenticated = runtime.authenticated_user().await;
+        let other = runtime.authenticated_user().await;
+        let user = authenticated.user.clone();
+        let user_id = user._id.unwrap();
+        app.mail.prepare_verification(&user, None).await.expect("Error creating verification");
+        let changes = uri!(oxidize::modules::mail::controller::request_email_change);
+        let request = |email: &str| client.post(changes.clone())
+            .header(ContentType::JSON)
+            .header(authenticated.header())
+            .body(json!({"email": email}).to_string());
+
+        //The email cannot be changed through a plain update
+        let mut updated = user.clone();
+        updated.email = String::from("new@example.com");
+        let response = client.put(uri!(oxidize::modules::user::controller::update_user(user_id.to_hex())))
+            .header(ContentType::JSON)
+            .header(authenticated.header())
+            .body(json!(updated).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        //Invalid, unchanged and taken addresses are refused
+        assert_eq!(request("not an email").dispatch().await.status(), Status::BadRequest);
+        assert_eq!(request(&user.email).dispatch().await.status(), Status::BadRequest);
+        assert_eq!(request(&other.user.email).dispatch().await.status(), Status::Conflict);
+
+        //The new address gets a confirmation link, the old one a notice, and nothing changes yet
+        let response = request("new@example.com").dispatch().await;
+        assert_eq!(response.status(), Status::Accepted);
+        let pending: PendingEmailChange = response.into_json().await.expect("Invalid email change");
+        assert_eq!(pending.new_email, "new@example.com");
+        let change = app.mail.email_changes.find_by_user(&user_id).await.unwrap().expect("No pending email change");
+        let mails = runtime.sent_mails("new@example.com").await;
+        assert_eq!(mails.len(), 1);
+        let links = mails[0].links();
+        assert_eq!(links.len(), 1);
+        let confirm = links[0].clone();
+        assert!(confirm.starts_with(&format!("/mail/email-changes/{}/confirm/", change._id.unwrap())));
+        // Only a digest of the secret is stored
+        assert!(!confirm.contains(&change.secret));
+        let notices = runtime.sent_mails(&user.email).await;
+        assert_eq!(notices.len(), 1);
+        assert!(notices[0].body.contains("new@example.com"));
+        assert!(notices[0].links().is_empty());
+        assert!(app.users.find_by_email("new@example.com").await.is_none());
+        let response = client.get(changes.clone()).header(authenticated.header()).dispatch().await;
+        let body: PendingEmailChange = response.into_json().await.expect("Invalid email change");
+        assert_eq!(body, pending);
+
+        //A pending address cannot be claimed by someone else
+        let response = client.post(changes.clone())
+            .header(ContentType::JSON)
+            .header(other.header())
+            .body(json!({"email": "new@example.com"}).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        //Only the user, with the secret sent to the new address, confirms it
+        let wrong_secret = uri!(oxidize::modules::mail::controller::confirm_email_change(
+            id=change._id.unwrap().to_string(),
+            secret="wrong"));
+        assert_eq!(client.get(wrong_secret).header(authenticated.header()).dispatch().await.status(), Status::Conflict);
+        assert_eq!(client.get(confirm.clone()).header(other.header()).dispatch().await.status(), Status::Unauthorized);
+        let response = client.get(confirm.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let changed: User = response.into_json().await.expect("Invalid user");
+        assert_eq!(changed.email, "new@example.com");
+        assert_eq!(app.users.find_by_email("new@example.com").await.expect("Email not changed")._id, Some(user_id));
+        let verification = app.mail.find_verification_by_user_id(&user_id).await.expect("Could not find verification");
+        assert_eq!(verification.email, "new@example.com");
+        assert!(verification.verified);
+        assert_eq!(client.get(confirm).header(authenticated.header()).dispatch().await.status(), Status::NotFound);
+        let cancel = uri!(oxidize::modules::mail::controller::cancel_email_change);
+        assert_eq!(client.delete(cancel.clone()).header(authenticated.header()).dispatch().await.status(), Status::NotFound);
+
+        //An address registered while the change was pending is not taken over
+        let response = request("late@example.com").dispatch().await;
+        assert_eq!(response.status(), Status::Accepted);
+        let mut late = User::mock();
+        late.email = String::from("late@example.com");
+        app.users.insert(&late, None).await.expect("Error inserting user").expect("User already exists");
+        let confirm = runtime.sent_mails("late@example.com").await[0].links()[0].clone();
+        assert_eq!(client.get(confirm).header(authenticated.header()).dispatch().await.status(), Status::Conflict);
+        assert_eq!(app.users.find_by_email("new@example.com").await.expect("Email changed")._id, Some(user_id));
+
+        //A pending change can be cancelled
+        assert_eq!(request("other@example.com").dispatch().await.status(), Status::Accepted);
+        assert_eq!(client.delete(cancel).header(authenticated.header()).dispatch().await.status(), Status::Ok);
+        assert!(app.mail.email_changes.find_by_user(&user_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_endpoints() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let app = runtime.app();
+        let authenticated = runtime.authenticated_user().await;
+
+        let dead = OutboxMessage {
+            _id: None,
+            email: Email {
+                from: String::from("sender@example.com"),
+                reply_to: String::from("sender@example.com"),
+                to: String::from("someone@example.com"),
+                subject: String::from("Subject"),
+                body: String::from("Body"),
+                ..Default::default()
+            },
+            status: OutboxStatus::Dead,
+            attempts: 5,
+            next_attempt: DateTime::now(),
+            last_error: Some(String::from("Relay unreachable")),
+            created: DateTime::now(),
+            sent: None,
+        };
+        let id = app.mail.outbox.messages.insert_one(&dead, None).await.expect("Error inserting message")
+            .inserted_id.as_object_id().unwrap();
+        let list = uri!(oxidize::modules::mail::controller::list_outbox(status=Some(OutboxStatus::Dead)));
+        let requeue = uri!(oxidize::modules::mail::controller::requeue_outbox_message(id=id.to_string()));
+
+        //Only admins can see the outbox
+        let response = client.get(list.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Forbidden);
+        let mut config = (*app.config.current()).clone();
+        config.env.admin_emails = Some(format!("admin@example.com, {}", authenticated.user.email));
+        app.config.replace(config);
+        //Being listed is not enough until the address is verified, anyone could sign up with it
+        let response = client.get(list.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Forbidden);
+        runtime.verify_email(&authenticated.user).await;
+
+        let response = client.get(list.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Ok);
+        let messages: Vec<OutboxMessage> = response.into_json().await.expect("Invalid outbox listing");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]._id, Some(id));
+
+        //Requeued messages are sent again
+        let response = client.post(requeue.clone()).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Ok);
+        assert_eq!(runtime.sent_mails("someone@example.com").await.len(), 1);
+        let response = client.post(requeue).header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Conflict);
+
+        let response = client.post(uri!(oxidize::modules::mail::controller::requeue_outbox_message(id=ObjectId::new().to_string())))
+            .header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::NotFound);
+        let response = client.post(uri!(oxidize::modules::mail::controller::requeue_outbox_message(id="nope")))
+            .header(authenticated.header()).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn test_verification_mail_locale() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+
+        //The locale of a new user comes from Accept-Language, and its emails are written in it
+        let user = User::mock();
+        let response = client.post(uri!(oxidize::modules::user::controller::create_user))
+            .header(rocket::http::ContentType::JSON)
+            .header(Header::new("Accept-Language", "fr-CH, es-MX;q=0.8, en;q=0.5"))
+            .body(rocket::serde::json::json!(user).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Created);
+        let created: User = response.into_json().await.expect("Invalid user");
+        assert_eq!(created.locale.as_deref(), Some("es-ES"));
+        let mails = runtime.sent_mails(&user.email).await;
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].subject.starts_with("Verifica el correo"));
+        assert!(mails[0].html.as_deref().unwrap().contains("lang=\"es-ES\""));
+
+        //A locale given at signup wins, unknown ones fall back to the default locale
+        let user = User { locale: Some(String::from("fr-FR")), ..User::mock() };
+        let response = client.post(uri!(oxidize::modules::user::controller::create_user))
+            .header(rocket::http::ContentType::JSON)
+            .header(Header::new("Accept-Language", "es"))
+            .body(rocket::serde::json::json!(user).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Created);
+        let created: User = response.into_json().await.expect("Invalid user");
+        assert_eq!(created.locale.as_deref(), Some("fr-FR"));
+        let mails = runtime.sent_mails(&user.email).await;
+        assert!(mails[0].subject.starts_with("Verify your email"));
+    }
+}
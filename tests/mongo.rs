@@ -1,18 +1,22 @@
 use std::sync::Arc;
 
-use oxidize::{framework::config::OxidizeConfig, modules::mongo::service::MongoOracle};
+use oxidize::{framework::{config::OxidizeConfig, testing::load_test_env}, modules::mongo::service::MongoOracle};
 #[tokio::test]
+#[ignore = "requires a running MongoDB"]
 async fn test_new_connection() {
+    load_test_env();
     let config = Arc::new(OxidizeConfig::new().expect("Could not load env variables"));
-    let mongo_oracle = MongoOracle::new(config).await;
+    let mongo_oracle = MongoOracle::new(config).await.expect("Error connecting to MongoDB");
     assert!(mongo_oracle.client.is_some());
     assert!(mongo_oracle.db.is_some());
 }
 
 #[tokio::test]
+#[ignore = "requires a running MongoDB"]
 async fn test_close_connection() {
+    load_test_env();
     let config = Arc::new(OxidizeConfig::new().expect("Could not load env variables"));
-    let mut mongo_oracle = MongoOracle::new(config).await;
+    let mut mongo_oracle = MongoOracle::new(config).await.expect("Error connecting to MongoDB");
     mongo_oracle.close();
     assert!(mongo_oracle.client.is_none());
     assert!(mongo_oracle.db.is_none());
@@ -1,24 +1,25 @@
 mod test {
-    use oxidize::framework::auth::{generate_jwt_token, generate_rsa_key_pair_pem};
-    use oxidize::framework::config::OxidizeConfig;
-    use oxidize::framework:: testing::{Mock, TestingRuntime};
-    use oxidize::modules::mongo::service::MongoOracle;
+    use oxidize::framework::auth::{generate_ed25519_key_pair_pem, generate_jwt_token, generate_jwt_token_with_kid, generate_rsa_key_pair_pem, key_thumbprint, KeyType};
+    use oxidize::framework::catchers::ErrorResponse;
+    use oxidize::framework:: testing::{Mock, TestDatabase, TestingRuntime};
+    use oxidize::modules::storage::Storage;
     use oxidize::modules::user::service::UserService;
     use oxidize::modules::CRUDMongo;
-    use oxidize::modules::user::dto::User;
+    use oxidize::modules::user::dto::{ApiKeyInfo, ApiScope, IssuedApiKey, User, UserKey};
     use rocket::http::Header;
     use rocket_db_pools::mongodb::bson::oid::ObjectId;
     use std::sync::Arc;
-    use tokio;
     use rocket::{http::{ContentType, Status}, local::asynchronous::LocalResponse, uri};
     use rocket::serde::json::json;
+    use rsa::pkcs1::EncodeRsaPublicKey;
+    use rsa::pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding};
+    use rsa::{RsaPrivateKey, RsaPublicKey};
 
     #[tokio::test]
     async fn test_user_service_crud_operations() {
-        let config = Arc::new(OxidizeConfig::new().expect("Could not load oxidize config"));
-        let mongo_oracle = Arc::new(MongoOracle::new(config).await);
-        mongo_oracle.drop_database().await.expect("Error dropping database");
-        let user_service = UserService::new(mongo_oracle);
+        let database = TestDatabase::new();
+        let storage = Arc::new(Storage::connect(database.config.clone()).await.expect("Error connecting to storage"));
+        let user_service = UserService::new(storage);
 
         let user = User::mock();
 
@@ -52,24 +53,25 @@ mod test {
 
     //find by email returns none on a non-existent user with a non existent email.
     let retrieved_user = user_service.find_by_email(String::from("thisemaildoesnotexist@gmail.com").as_str()).await;
-    assert_eq!(retrieved_user.is_none(), true);
+    assert!(retrieved_user.is_none());
 
     // Test update Operation updated correctly everything
     let retrieved_user = user_service.read(user_id.to_owned()).await.expect("Failed to read updated user");
     assert_eq!(retrieved_user.description, String::from("Updated Description"));
 
     // Test Delete Operation
-    let delete_res = user_service.delete(user_id.clone()).await.expect("Failed to delete user");
+    let delete_res = user_service.delete(user_id).await.expect("Failed to delete user");
     assert_eq!(delete_res.deleted_count, 1);
 
     // Verify Deletion
-    let deleted_user = user_service.read(user_id.clone()).await;
+    let deleted_user = user_service.read(user_id).await;
     assert!(deleted_user.is_none());
 }
 
     #[tokio::test]
     async fn test_user_controller_crud_operations() {
-        let client = &TestingRuntime::get().await.client;
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
         let (public, private) = generate_rsa_key_pair_pem();
         let (_, malicious_private) = generate_rsa_key_pair_pem();
 
@@ -99,7 +101,7 @@ mod test {
         assert!(non_existing_user.is_none());
 
         // Step 2: Read the user by ID
-        let user_id = created_user._id.clone().expect("User ID should be present");
+        let user_id = created_user._id.expect("User ID should be present");
         let read_response: LocalResponse = client.get(uri!(oxidize::modules::user::controller::read_user(user_id.to_hex())))
             .header(ContentType::JSON)
             .dispatch().await;
@@ -146,7 +148,9 @@ mod test {
             password: String::from("updated_password"),
             description: String::from("Updated Description"),
             public_key: user.public_key.clone(),
-            _id: Some(user_id.clone()),
+            public_key_fingerprint: None,
+            _id: Some(user_id),
+            locale: None,
         };
 
         let update_response: LocalResponse = client.put(uri!(oxidize::modules::user::controller::update_user(user_id.to_hex())))
@@ -189,7 +193,9 @@ mod test {
             password: String::from("updated_password"),
             description: String::from("Updated Description"),
             public_key: user.public_key.clone(),
-            _id: Some(user_id.clone()),
+            public_key_fingerprint: None,
+            _id: Some(user_id),
+            locale: None,
         };
 
         let update_response: LocalResponse = client.put(uri!(oxidize::modules::user::controller::update_user(user_id.to_hex())))
@@ -210,6 +216,17 @@ mod test {
 
         assert_eq!(update_response.status(), Status::Unauthorized);
 
+        // Step 4f: Update the user with a token expiring too far away returns 401, even without a configured limit
+        let long_lived_token = generate_jwt_token(&user_id.to_string(), &private, chrono::Duration::days(365)).expect("Error generating token");
+        let long_lived_auth_header = String::from("Bearer ") + long_lived_token.as_str();
+        let update_response: LocalResponse = client.put(uri!(oxidize::modules::user::controller::update_user(user_id.to_hex())))
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", long_lived_auth_header))
+            .body(json!(updated_user).to_string())
+            .dispatch().await;
+
+        assert_eq!(update_response.status(), Status::Unauthorized);
+
         // Step 5a: Delete the user returns 401 if unauthenticated
         let delete_response: LocalResponse = client.delete(uri!(oxidize::modules::user::controller::delete_user(user_id.to_hex())))
             .header(ContentType::JSON)
@@ -242,4 +259,218 @@ mod test {
         assert_eq!(read_deleted_response.status(), Status::NotFound);
     }
 
+    #[tokio::test]
+    async fn test_user_keys() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let authenticated = runtime.authenticated_user().await;
+        let other = runtime.authenticated_user().await;
+        let user_id = authenticated.user._id.unwrap().to_hex();
+        let (public_key, private_key) = generate_ed25519_key_pair_pem();
+        let add = |body: serde_json::Value| client.post(uri!(oxidize::modules::user::controller::add_key))
+            .header(ContentType::JSON)
+            .header(authenticated.header())
+            .body(body.to_string());
+        let status = |token: String| client.get("/mail/verifications/status")
+            .header(Header::new("Accept", "application/json"))
+            .header(Header::new("Authorization", format!("Bearer {}", token)));
+
+        let response = add(json!({"public_key": public_key, "label": "sensor"})).dispatch().await;
+        assert_eq!(response.status(), Status::Created);
+        let key: UserKey = response.into_json().await.expect("Invalid key");
+        assert_eq!(key.kid, key_thumbprint(&public_key).unwrap());
+        assert_eq!(key.algorithm, KeyType::Ed25519);
+        assert!(key.last_used.is_none());
+        assert_eq!(add(json!({"public_key": public_key})).dispatch().await.status(), Status::Conflict);
+        assert_eq!(add(json!({"public_key": "not a key"})).dispatch().await.status(), Status::BadRequest);
+        let (expired_key, _) = generate_ed25519_key_pair_pem();
+        let expires = chrono::Utc::now() - chrono::Duration::hours(1);
+        assert_eq!(add(json!({"public_key": expired_key, "expires": expires})).dispatch().await.status(), Status::BadRequest);
+
+        // Tokens name the key that signed them
+        let token = generate_jwt_token_with_kid(&user_id, Some(&key.kid), &private_key, chrono::Duration::hours(1)).unwrap();
+        assert_eq!(status(token.clone()).dispatch().await.status(), Status::Ok);
+        let without_kid = generate_jwt_token(&user_id, &private_key, chrono::Duration::hours(1)).unwrap();
+        assert_eq!(status(without_kid).dispatch().await.status(), Status::Unauthorized);
+        let unknown_kid = generate_jwt_token_with_kid(&user_id, Some("unknown"), &private_key, chrono::Duration::hours(1)).unwrap();
+        assert_eq!(status(unknown_kid).dispatch().await.status(), Status::Unauthorized);
+        // The signup key keeps working
+        assert_eq!(status(authenticated.token.clone()).dispatch().await.status(), Status::Ok);
+        // Keys only verify the tokens of their user
+        let other_id = other.user._id.unwrap().to_hex();
+        let stolen = generate_jwt_token_with_kid(&other_id, Some(&key.kid), &private_key, chrono::Duration::hours(1)).unwrap();
+        assert_eq!(status(stolen).dispatch().await.status(), Status::Unauthorized);
+
+        let response = client.get(uri!(oxidize::modules::user::controller::list_keys))
+            .header(ContentType::JSON)
+            .header(authenticated.header())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let keys: Vec<UserKey> = response.into_json().await.expect("Invalid keys");
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].label, "sensor");
+        assert!(keys[0].last_used.is_some());
+
+        // Expired keys stop verifying tokens
+        let (short_lived_key, short_lived_private_key) = generate_ed25519_key_pair_pem();
+        let expires = chrono::Utc::now() + chrono::Duration::seconds(1);
+        let response = add(json!({"public_key": short_lived_key, "expires": expires})).dispatch().await;
+        let short_lived: UserKey = response.into_json().await.expect("Invalid key");
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        let token_of_expired = generate_jwt_token_with_kid(&user_id, Some(&short_lived.kid), &short_lived_private_key, chrono::Duration::hours(1)).unwrap();
+        assert_eq!(status(token_of_expired).dispatch().await.status(), Status::Unauthorized);
+
+        // Revoked keys too
+        let revoke = |kid: String| client.delete(uri!(oxidize::modules::user::controller::revoke_key(kid))).header(authenticated.header());
+        assert_eq!(revoke(key.kid.clone()).dispatch().await.status(), Status::Ok);
+        assert_eq!(status(token).dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(revoke(key.kid.clone()).dispatch().await.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_user_public_key_validation() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let create = |user: &User| client.post(uri!(oxidize::modules::user::controller::create_user))
+            .header(ContentType::JSON)
+            .header(Header::new("Accept-Language", "es-ES"))
+            .body(json!(user).to_string());
+
+        // Garbage is refused with a translated validation error instead of panicking later
+        let user = User { public_key: String::from("randompublickey"), ..User::mock() };
+        let response = create(&user).dispatch().await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: ErrorResponse = response.into_json().await.expect("Invalid error body");
+        assert_eq!(body.status, 422);
+        assert!(body.error.contains("PEM"));
+
+        // So are short RSA keys
+        let short_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap().to_public_key();
+        let user = User { public_key: short_key.to_public_key_pem(LineEnding::LF).unwrap(), ..User::mock() };
+        let response = create(&user).dispatch().await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: ErrorResponse = response.into_json().await.expect("Invalid error body");
+        assert!(body.error.contains("2048") && body.error.contains("1024"));
+
+        // PKCS#1 keys are stored as SubjectPublicKeyInfo along with their fingerprint
+        let (public_key, private_key) = generate_rsa_key_pair_pem();
+        let pkcs1 = RsaPublicKey::from_public_key_pem(&public_key).unwrap().to_pkcs1_pem(LineEnding::CRLF).unwrap();
+        let user = User { public_key: pkcs1, ..User::mock() };
+        let response = create(&user).dispatch().await;
+        assert_eq!(response.status(), Status::Created);
+        let created: User = response.into_json().await.expect("Invalid user");
+        assert_eq!(created.public_key, public_key.replace("\r\n", "\n"));
+        assert_eq!(created.public_key_fingerprint, key_thumbprint(&public_key));
+
+        // Updates are validated too
+        let user_id = created._id.unwrap();
+        let token = generate_jwt_token(&user_id.to_hex(), &private_key, chrono::Duration::hours(1)).unwrap();
+        let update = |user: &User| client.put(uri!(oxidize::modules::user::controller::update_user(user_id.to_hex())))
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .body(json!(user).to_string());
+        let (ed25519_key, _) = generate_ed25519_key_pair_pem();
+        let response = update(&User { public_key: String::from("randompublickey"), ..created.clone() }).dispatch().await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let response = update(&User { public_key: ed25519_key.clone(), public_key_fingerprint: None, ..created.clone() }).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let updated: User = response.into_json().await.expect("Invalid user");
+        assert_eq!(updated.public_key_fingerprint, key_thumbprint(&ed25519_key));
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let runtime = TestingRuntime::new().await;
+        let client = &runtime.client;
+        let authenticated = runtime.authenticated_user().await;
+        let create = |body: serde_json::Value| client.post(uri!(oxidize::modules::user::controller::create_api_key))
+            .header(ContentType::JSON)
+            .header(authenticated.header())
+            .body(body.to_string());
+        let with_key = |api_key: &str| Header::new("Authorization", format!("ApiKey {}", api_key));
+        let status = |api_key: &str| client.get("/mail/verifications/status")
+            .header(Header::new("Accept", "application/json"))
+            .header(with_key(api_key));
+
+        // The credential is only answered on creation, and only a digest of its secret is stored
+        let response = create(json!({"name": "backup script", "scopes": ["read"]})).dispatch().await;
+        assert_eq!(response.status(), Status::Created);
+        let issued: IssuedApiKey = response.into_json().await.expect("Invalid API key");
+        assert_eq!(issued.key.name, "backup script");
+        assert_eq!(issued.key.scopes, vec![ApiScope::Read]);
+        let (id, secret) = issued.api_key.split_once('.').expect("Invalid credential");
+        assert_eq!(id, issued.key.id);
+        let stored = runtime.app().users.api_keys.find(&ObjectId::parse_str(id).unwrap()).await.unwrap().expect("API key not stored");
+        assert_ne!(stored.secret, secret);
+        let response = client.get(uri!(oxidize::modules::user::controller::list_api_keys)).header(authenticated.header()).dispatch().await;
+        let body = response.into_string().await.unwrap();
+        assert!(!body.contains(secret) && !body.contains(&stored.secret));
+        let keys: Vec<ApiKeyInfo> = serde_json::from_str(&body).expect("Invalid API keys");
+        assert_eq!(keys, vec![issued.key.clone()]);
+
+        // It authenticates the requests its scopes allow, as its user
+        assert_eq!(status(&issued.api_key).dispatch().await.status(), Status::Ok);
+        let response = client.get(uri!(oxidize::modules::user::controller::list_api_keys)).header(with_key(&issued.api_key)).dispatch().await;
+        let keys: Vec<ApiKeyInfo> = response.into_json().await.expect("Invalid API keys");
+        assert!(keys[0].last_used.is_some());
+        let response = client.post("/mail/verifications/start-verification").header(with_key(&issued.api_key)).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.post(uri!(oxidize::modules::user::controller::create_api_key))
+            .header(ContentType::JSON)
+            .header(with_key(&issued.api_key))
+            .body(json!({"name": "another", "scopes": ["read"]}).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = create(json!({"name": "writer", "scopes": ["write"]})).dispatch().await;
+        let writer: IssuedApiKey = response.into_json().await.expect("Invalid API key");
+        let response = client.post("/mail/verifications/start-verification").header(with_key(&writer.api_key)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // Credentials are only managed with tokens, or a key could give itself more access than its scopes
+        let (public_key, _) = generate_rsa_key_pair_pem();
+        let code = json!({"code": "123456"}).to_string();
+        let credential_routes = [
+            client.post(uri!(oxidize::modules::user::controller::add_key)).header(ContentType::JSON).body(json!({"public_key": public_key}).to_string()),
+            client.delete(uri!(oxidize::modules::user::controller::revoke_key("kid"))),
+            client.post(uri!(oxidize::modules::user::controller::create_api_key)).header(ContentType::JSON)
+                .body(json!({"name": "admin", "scopes": ["admin"]}).to_string()),
+            client.delete(uri!(oxidize::modules::user::controller::revoke_api_key(id))),
+            client.post(uri!(oxidize::modules::auth::controller::enroll_totp)),
+            client.post(uri!(oxidize::modules::auth::controller::confirm_totp)).header(ContentType::JSON).body(code.clone()),
+            client.delete(uri!(oxidize::modules::auth::controller::disable_totp)).header(ContentType::JSON).body(code),
+            client.post(uri!(oxidize::modules::mail::controller::request_email_change)).header(ContentType::JSON)
+                .body(json!({"email": "takeover@example.com"}).to_string()),
+            client.get(uri!(oxidize::modules::mail::controller::confirm_email_change(id, "secret"))).header(Header::new("Accept", "application/json")),
+            client.delete(uri!(oxidize::modules::mail::controller::cancel_email_change)),
+        ];
+        for request in credential_routes {
+            let uri = request.uri().to_string();
+            assert_eq!(request.header(with_key(&writer.api_key)).dispatch().await.status(), Status::Forbidden, "{} took an API key", uri);
+        }
+        assert_eq!(runtime.app().users.keys.list(&authenticated.user._id.unwrap()).await.unwrap().len(), 0);
+
+        // Admin routes also need the admin scope
+        let app = runtime.app();
+        let mut config = (*app.config.current()).clone();
+        config.env.admin_emails = Some(authenticated.user.email.clone());
+        app.config.replace(config);
+        runtime.verify_email(&authenticated.user).await;
+        let outbox = |api_key: &str| client.get("/mail/outbox").header(Header::new("Accept", "application/json")).header(with_key(api_key));
+        assert_eq!(outbox(&issued.api_key).dispatch().await.status(), Status::Forbidden);
+        let response = create(json!({"name": "admin", "scopes": ["read", "admin"]})).dispatch().await;
+        let admin: IssuedApiKey = response.into_json().await.expect("Invalid API key");
+        assert_eq!(outbox(&admin.api_key).dispatch().await.status(), Status::Ok);
+
+        // Wrong secrets, expired and revoked keys are refused
+        assert_eq!(status(&format!("{}.wrong", id)).dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(status("garbage").dispatch().await.status(), Status::Unauthorized);
+        let response = create(json!({"name": "old", "scopes": ["read"], "expires": "2000-01-01T00:00:00Z"})).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(create(json!({"name": " ", "scopes": ["read"]})).dispatch().await.status(), Status::BadRequest);
+        assert_eq!(create(json!({"name": "none", "scopes": []})).dispatch().await.status(), Status::BadRequest);
+        let revoke = || client.delete(uri!(oxidize::modules::user::controller::revoke_api_key(id))).header(authenticated.header());
+        assert_eq!(revoke().dispatch().await.status(), Status::Ok);
+        assert_eq!(revoke().dispatch().await.status(), Status::NotFound);
+        assert_eq!(status(&issued.api_key).dispatch().await.status(), Status::Unauthorized);
+    }
 }